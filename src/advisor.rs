@@ -0,0 +1,101 @@
+use std::fmt;
+use std::time::Duration;
+
+/// What held the tools up the most while they ran
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bound {
+	Cpu,
+	Io,
+	Mixed,
+}
+
+/// CPU time the tools took for each second they ran, above which they are
+/// taken to be CPU-bound; it may well exceed 1 with encoders using several
+/// threads.
+pub const CPU_BOUND: f64 = 0.8;
+/// The same, below which they are taken to be waiting for reads and writes.
+pub const IO_BOUND: f64 = 0.4;
+
+const IO_BOUND_ADVICE: &str = "run was predominantly IO-bound — consider increasing --jobs or staging inputs locally";
+
+/// CPU time the tools took relative to how long they ran for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Utilization(f64);
+
+impl Utilization {
+	/// Utilization of tools that took `cpu` over `wall`, unless they never
+	/// ran.
+	pub fn new(cpu: Duration, wall: Duration) -> Option<Self> {
+		(!wall.is_zero()).then(|| Utilization(cpu.as_secs_f64() / wall.as_secs_f64()))
+	}
+
+	pub fn bound(self) -> Bound {
+		match self.0 {
+			x if x > CPU_BOUND => Bound::Cpu,
+			x if x < IO_BOUND => Bound::Io,
+			_ => Bound::Mixed,
+		}
+	}
+
+	/// What to make of a whole run being this utilized, if anything.
+	pub fn advice(self) -> Option<&'static str> {
+		match self.bound() {
+			Bound::Io => Some(IO_BOUND_ADVICE),
+			Bound::Cpu | Bound::Mixed => None,
+		}
+	}
+}
+
+impl fmt::Display for Utilization {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let bound = match self.bound() {
+			Bound::Cpu => "CPU-bound",
+			Bound::Io => "IO-bound",
+			Bound::Mixed => "CPU- and IO-bound",
+		};
+
+		write!(f, "{} ({:.0} % CPU)", bound, self.0 * 100.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::{Bound, Utilization};
+
+	fn utilization(cpu_ms: u64, wall_ms: u64) -> Option<Utilization> {
+		Utilization::new(Duration::from_millis(cpu_ms), Duration::from_millis(wall_ms))
+	}
+
+	#[test]
+	fn classifies_by_share_of_cpu_time() {
+		assert_eq!(utilization(900, 1000).unwrap().bound(), Bound::Cpu);
+		// several threads at once
+		assert_eq!(utilization(3500, 1000).unwrap().bound(), Bound::Cpu);
+		assert_eq!(utilization(800, 1000).unwrap().bound(), Bound::Mixed);
+		assert_eq!(utilization(400, 1000).unwrap().bound(), Bound::Mixed);
+		assert_eq!(utilization(390, 1000).unwrap().bound(), Bound::Io);
+		assert_eq!(utilization(0, 1000).unwrap().bound(), Bound::Io);
+	}
+
+	#[test]
+	fn needs_tools_to_have_run() {
+		assert_eq!(utilization(0, 0), None);
+		assert_eq!(utilization(10, 0), None);
+	}
+
+	#[test]
+	fn advises_only_on_io_bound_runs() {
+		assert!(utilization(100, 1000).unwrap().advice().unwrap().contains("--jobs"));
+		assert_eq!(utilization(600, 1000).unwrap().advice(), None);
+		assert_eq!(utilization(2000, 1000).unwrap().advice(), None);
+	}
+
+	#[test]
+	fn tells_share_of_cpu_time() {
+		assert_eq!(utilization(250, 1000).unwrap().to_string(), "IO-bound (25 % CPU)");
+		assert_eq!(utilization(600, 1000).unwrap().to_string(), "CPU- and IO-bound (60 % CPU)");
+		assert_eq!(utilization(1500, 1000).unwrap().to_string(), "CPU-bound (150 % CPU)");
+	}
+}
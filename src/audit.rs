@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use crate::context::Context;
+use crate::fsutil;
+use crate::image::Backend;
+use crate::options::{AuditCommand, AuditOptions};
+use crate::terminal::Terminal;
+
+/// What the first entry of a log follows
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Record of a file replaced by its output, a line of JSON in the log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+	/// Hash of the line of the entry before, or [`GENESIS`]
+	pub previous: String,
+	/// When the file was replaced, in RFC 3339
+	pub time: String,
+	pub original: Digest,
+	pub new: Digest,
+	/// What converted the file
+	pub tool: String,
+	/// What the tool says of its version, if it is one that tells
+	pub tool_version: Option<String>,
+	pub shrink_ray_version: String,
+	/// Effective value of every option, as `--print-config` shows it
+	pub settings: BTreeMap<String, String>,
+}
+
+/// Contents of a file at some point
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Digest {
+	pub path: PathBuf,
+	pub size: u64,
+	pub blake3: String,
+}
+
+impl Digest {
+	/// Hashes the file at `path`, recorded in canonical form.
+	pub async fn of(path: &Path) -> io::Result<Digest> {
+		let path = path.to_path_buf();
+		tokio::task::spawn_blocking(move || {
+			trace!("hashing `{}`", path.display());
+			let mut hasher = blake3::Hasher::new();
+			hasher.update_reader(File::open(&path)?)?;
+			let blake3 = hasher.finalize().to_hex().to_string();
+			Ok(Digest { path: fsutil::canonical_path(&path), size: hasher.count(), blake3 })
+		})
+		.await?
+	}
+}
+
+/// Log opened to append entries to
+#[derive(Debug)]
+pub struct AuditLog {
+	path: PathBuf,
+	file: File,
+	/// Hash of the last entry
+	head: String,
+	/// What each tool said of its version, once asked
+	versions: HashMap<&'static str, Option<String>>,
+}
+
+impl AuditLog {
+	/// Opens the log at `path` for appending, checking it first so that no
+	/// entries get chained to a log which is not intact anymore.
+	pub fn open(path: &Path) -> Result<Self, crate::Error> {
+		let io_error = |x| crate::Error::AuditLog(path.to_path_buf(), x);
+		let head = match File::open(path) {
+			Ok(file) => match verify(BufReader::new(file)) {
+				Ok(x) => x.head,
+				Err(ChainError::Io(x)) => return Err(io_error(x)),
+				Err(x) => return Err(crate::Error::AuditChain(path.to_path_buf(), x.to_string())),
+			},
+			Err(x) if x.kind() == io::ErrorKind::NotFound => GENESIS.to_string(),
+			Err(x) => return Err(io_error(x)),
+		};
+
+		let file = OpenOptions::new().create(true).append(true).open(path).map_err(io_error)?;
+		Ok(AuditLog { path: path.to_path_buf(), file, head, versions: HashMap::new() })
+	}
+
+	/// Appends `entry`, chained to the last one, and makes sure it is on disk.
+	fn append(&mut self, mut entry: Entry) -> Result<(), crate::Error> {
+		entry.previous = self.head.clone();
+		let line = serde_json::to_string(&entry).map_err(io::Error::from).and_then(|line| {
+			// a partial line is cut off, which shows when the log is checked
+			self.file.write_all(format!("{}\n", line).as_bytes())?;
+			self.file.sync_data()?;
+			Ok(line)
+		});
+
+		let line = line.map_err(|x| crate::Error::AuditLog(self.path.clone(), x))?;
+		self.head = hash(line.as_bytes());
+		Ok(())
+	}
+}
+
+/// Logs that `original` was replaced by `new`, converted with `tool` and
+/// `settings`, if there is a log.
+pub async fn record(
+	context: &mut Context, original: Digest, new: Digest, tool: &str, settings: &[(String, String)],
+) -> Result<(), crate::Error> {
+	if context.audit.is_none() {
+		return Ok(());
+	}
+
+	let tool_version = tool_version(context, tool).await;
+	let entry = Entry {
+		previous: String::new(),
+		time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+		original,
+		new,
+		tool: tool.to_string(),
+		tool_version,
+		shrink_ray_version: env!("CARGO_PKG_VERSION").to_string(),
+		settings: settings.iter().cloned().collect(),
+	};
+
+	debug!("logging replacement of `{}`", entry.original.path.display());
+	context.audit.as_ref().expect("there is a log").borrow_mut().append(entry)
+}
+
+/// First line of what the built-in `tool` says of its version; user tools are
+/// not asked, as there is no telling how.
+async fn tool_version(context: &mut Context, tool: &str) -> Option<String> {
+	let (binary, arg) = match tool {
+		x if x == Backend::Gm.name() => ("gm", "version"),
+		x if x == Backend::ImageMagick.name() => ("magick", "-version"),
+		x if x.starts_with("ffmpeg") => ("ffmpeg", "-version"),
+		_ => return None,
+	};
+
+	if let Some(x) = context.audit.as_ref()?.borrow().versions.get(binary) {
+		return x.clone();
+	}
+
+	let version = match context.command(binary) {
+		Ok(mut command) => {
+			command.arg(arg);
+			match context.output(command).await {
+				Ok(x) if x.status.success() => {
+					String::from_utf8_lossy(&x.stdout).lines().next().map(|x| x.trim().to_string())
+				}
+				Ok(x) => {
+					debug!("unable to tell the version of {}: {}", binary, x.status);
+					None
+				}
+				Err(x) => {
+					debug!("unable to tell the version of {}: {}", binary, x);
+					None
+				}
+			}
+		}
+		Err(_) => None,
+	};
+
+	context.audit.as_ref()?.borrow_mut().versions.insert(binary, version.clone());
+	version
+}
+
+fn hash(line: &[u8]) -> String {
+	blake3::hash(line).to_hex().to_string()
+}
+
+/// Intact log
+#[derive(Debug, PartialEq, Eq)]
+pub struct Chain {
+	pub entries: usize,
+	/// Hash of the last entry; noting it elsewhere makes changes to the end
+	/// of the log show too
+	pub head: String,
+}
+
+/// Why a log is not intact
+#[derive(Debug)]
+pub enum ChainError {
+	/// Entry number `.0` (from 1) is not intact, or not where it belongs
+	Entry(usize, String),
+	Io(io::Error),
+}
+
+impl fmt::Display for ChainError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ChainError::Entry(line, problem) => write!(f, "entry {}: {}", line, problem),
+			ChainError::Io(x) => x.fmt(f),
+		}
+	}
+}
+
+/// Checks that every entry of the log read from `reader` is whole, and follows
+/// the one before.
+///
+/// A changed entry no longer matches the hash in the entry after it, while
+/// entries removed, added or reordered do not follow the one before. Only the
+/// last entry can be changed, or entries removed from the end, unnoticed; the
+/// hash of the last entry tells.
+pub fn verify(mut reader: impl BufRead) -> Result<Chain, ChainError> {
+	let mut head = GENESIS.to_string();
+	let mut entries = 0;
+	let mut line = Vec::new();
+	loop {
+		line.clear();
+		if reader.read_until(b'\n', &mut line).map_err(ChainError::Io)? == 0 {
+			return Ok(Chain { entries, head });
+		}
+
+		entries += 1;
+		let broken = |x: String| ChainError::Entry(entries, x);
+		let Some(contents) = line.strip_suffix(b"\n") else {
+			return Err(broken("cut off".into()));
+		};
+
+		let entry: Entry = serde_json::from_slice(contents).map_err(|x| broken(format!("not an entry: {}", x)))?;
+		if entry.previous != head {
+			return Err(broken("does not follow the entry before it, which was changed or removed".into()));
+		}
+
+		head = hash(contents);
+	}
+}
+
+/// Runs the `audit` subcommand.
+pub fn run(terminal: &mut Terminal, options: &AuditOptions) -> bool {
+	let AuditCommand::Verify { path } = &options.command;
+	let chain = File::open(path).map_err(ChainError::Io).and_then(|x| verify(BufReader::new(x)));
+	match chain {
+		Ok(chain) => {
+			terminal.write_audit_valid(path, chain.entries, &chain.head);
+			true
+		}
+		Err(ChainError::Io(x)) => {
+			eprintln!("{}", crate::Error::AuditLog(path.clone(), x));
+			false
+		}
+		Err(x) => {
+			eprintln!("{}", crate::Error::AuditChain(path.clone(), x.to_string()));
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use std::path::PathBuf;
+
+	use super::{hash, verify, Chain, ChainError, Digest, Entry, GENESIS};
+
+	fn entry(previous: &str, file: &str) -> Entry {
+		let digest = |size, byte: char| Digest { path: PathBuf::from(file), size, blake3: byte.to_string().repeat(64) };
+		Entry {
+			previous: previous.to_string(),
+			time: "2024-05-01T12:00:00.000Z".into(),
+			original: digest(4096, 'a'),
+			new: digest(2048, 'b'),
+			tool: "GraphicsMagick".into(),
+			tool_version: Some("GraphicsMagick 1.3.42 2023-09-23 Q16".into()),
+			shrink_ray_version: "0.2.0".into(),
+			settings: BTreeMap::from([("units".into(), "\"binary\"".into())]),
+		}
+	}
+
+	/// Log of entries for `files`, each chained to the one before.
+	fn log(files: &[&str]) -> Vec<String> {
+		let mut previous = GENESIS.to_string();
+		let mut lines = Vec::new();
+		for file in files {
+			let line = serde_json::to_string(&entry(&previous, file)).unwrap();
+			previous = hash(line.as_bytes());
+			lines.push(line);
+		}
+
+		lines
+	}
+
+	fn check(lines: &[String]) -> Result<Chain, ChainError> {
+		let contents: String = lines.iter().map(|x| format!("{}\n", x)).collect();
+		verify(contents.as_bytes())
+	}
+
+	fn broken_entry(result: Result<Chain, ChainError>) -> usize {
+		match result {
+			Err(ChainError::Entry(x, _)) => x,
+			x => panic!("expected a broken entry, got {:?}", x),
+		}
+	}
+
+	#[test]
+	fn verifies_intact_chains() {
+		let lines = log(&["/a.jpg", "/b.jpg", "/c.mp4"]);
+		let chain = check(&lines).unwrap();
+		assert_eq!(chain, Chain { entries: 3, head: hash(lines[2].as_bytes()) });
+		assert_eq!(check(&[]).unwrap(), Chain { entries: 0, head: GENESIS.into() });
+	}
+
+	#[test]
+	fn detects_corrupted_entries() {
+		let mut lines = log(&["/a.jpg", "/b.jpg", "/c.mp4"]);
+		lines[1] = lines[1].replace("\"size\":2048", "\"size\":2049");
+		// the entry itself still parses, the next one does not follow it
+		assert_eq!(broken_entry(check(&lines)), 3);
+
+		let mut lines = log(&["/a.jpg", "/b.jpg"]);
+		lines[0] = lines[0].replace("/a.jpg", "/x.jpg");
+		assert_eq!(broken_entry(check(&lines)), 2);
+
+		let mut lines = log(&["/a.jpg", "/b.jpg"]);
+		lines[1].truncate(40);
+		assert_eq!(broken_entry(check(&lines)), 2);
+	}
+
+	#[test]
+	fn detects_removed_and_reordered_entries() {
+		let mut lines = log(&["/a.jpg", "/b.jpg", "/c.mp4"]);
+		lines.remove(1);
+		assert_eq!(broken_entry(check(&lines)), 2);
+
+		let mut lines = log(&["/a.jpg", "/b.jpg", "/c.mp4"]);
+		lines.remove(0);
+		assert_eq!(broken_entry(check(&lines)), 1);
+
+		let mut lines = log(&["/a.jpg", "/b.jpg", "/c.mp4"]);
+		lines.swap(1, 2);
+		assert_eq!(broken_entry(check(&lines)), 2);
+
+		// changing the end goes unnoticed but for the head
+		let lines = log(&["/a.jpg", "/b.jpg", "/c.mp4"]);
+		let head = check(&lines).unwrap().head;
+		assert_ne!(check(&lines[..2]).unwrap().head, head);
+	}
+
+	#[test]
+	fn detects_cut_off_and_foreign_lines() {
+		let lines = log(&["/a.jpg"]);
+		assert_eq!(broken_entry(verify(lines[0].as_bytes())), 1);
+
+		let mut lines = log(&["/a.jpg"]);
+		lines.push(String::new());
+		assert_eq!(broken_entry(check(&lines)), 2);
+
+		let mut lines = log(&["/a.jpg"]);
+		lines[0] = lines[0].replacen('{', "{\"extra\":1,", 1);
+		assert_eq!(broken_entry(check(&lines)), 1);
+	}
+}
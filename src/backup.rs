@@ -0,0 +1,194 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tracing::{debug, error, trace};
+
+use crate::fsutil;
+use crate::options::BackupOptions;
+use crate::terminal::Terminal;
+
+/// Where originals of replaced files are kept
+#[derive(Clone, Debug)]
+pub enum Backup {
+	/// Beside the input, with a suffix appended to its name
+	Suffix(OsString),
+	/// In a separate directory, mirroring the path of the input
+	Dir(PathBuf),
+}
+
+impl Backup {
+	pub const DEFAULT_SUFFIX: &'static str = ".orig";
+
+	pub fn new(options: &BackupOptions) -> Option<Self> {
+		match (&options.backup, &options.backup_dir) {
+			(_, Some(dir)) => Some(Backup::Dir(dir.clone())),
+			(Some(suffix), None) => Some(Backup::Suffix(OsString::from(suffix))),
+			(None, None) => None,
+		}
+	}
+
+	/// Where the original of `input` goes.
+	pub fn path(&self, input: &Path) -> Result<PathBuf, crate::Error> {
+		let name = input.file_name().unwrap_or(input.as_os_str());
+		match self {
+			Backup::Suffix(suffix) => {
+				let mut name = name.to_os_string();
+				name.push(suffix);
+				Ok(input.with_file_name(name))
+			}
+			Backup::Dir(_) => Ok(self.dir(input)?.join(name)),
+		}
+	}
+
+	/// Directory the backups of files beside `path` go to.
+	fn dir(&self, path: &Path) -> Result<PathBuf, crate::Error> {
+		let parent = fsutil::parent_dir(path);
+		match self {
+			Backup::Suffix(_) => Ok(parent.to_path_buf()),
+			// mirror the absolute path, so that it does not matter where we are
+			// run from
+			Backup::Dir(dir) => Ok(dir.join(fsutil::relative_structure(parent.canonicalize()?)?)),
+		}
+	}
+
+	/// Finds the backup of the original `converted` was made from, which may
+	/// have had another extension, returning it along with the original path.
+	pub async fn find(&self, converted: &Path) -> Result<(PathBuf, PathBuf), crate::Error> {
+		let stem = converted.file_stem().unwrap_or(converted.as_os_str());
+		let dir = self.dir(converted)?;
+		let suffix = match self {
+			Backup::Suffix(x) => x.as_os_str(),
+			Backup::Dir(_) => OsStr::new(""),
+		};
+
+		trace!("looking for backups of `{}` in `{}`", converted.display(), dir.display());
+		let mut found = Vec::new();
+		let mut entries = match fs::read_dir(&dir).await {
+			Ok(x) => x,
+			Err(x) if x.kind() == std::io::ErrorKind::NotFound => {
+				return Err(crate::Error::BackupNotFound(converted.to_path_buf()));
+			}
+			Err(x) => return Err(crate::Error::from(x)),
+		};
+
+		while let Some(entry) = entries.next_entry().await? {
+			let name = entry.file_name();
+			let Some(original) = strip_suffix(&name, suffix) else {
+				continue;
+			};
+
+			if Path::new(original).file_stem() == Some(stem) && entry.file_type().await?.is_file() {
+				let backup = match self {
+					Backup::Suffix(_) => converted.with_file_name(&name),
+					Backup::Dir(_) => entry.path(),
+				};
+
+				found.push((backup, converted.with_file_name(original)));
+			}
+		}
+
+		match found.len() {
+			0 => Err(crate::Error::BackupNotFound(converted.to_path_buf())),
+			1 => Ok(found.remove(0)),
+			_ => Err(crate::Error::BackupAmbiguous(
+				converted.to_path_buf(),
+				found.into_iter().map(|(x, _)| x).collect(),
+			)),
+		}
+	}
+}
+
+/// Writes originals to their backups; tests swap it for one which fails
+pub trait Writer {
+	/// Moves `original` to `backup`, or copies it there if `copy`, leaving
+	/// no partial backup behind if it fails.
+	async fn write(&self, original: &Path, backup: &Path, copy: bool) -> Result<(), crate::Error>;
+}
+
+/// [`Writer`] of the filesystem itself
+pub struct Files;
+
+impl Writer for Files {
+	async fn write(&self, original: &Path, backup: &Path, copy: bool) -> Result<(), crate::Error> {
+		if !copy {
+			// copies across filesystems beside the backup first
+			return fsutil::move_file(original, backup).await;
+		}
+
+		// the original is written over afterwards, which a hardlink would see
+		let result = fsutil::detached_copy(original, backup).await;
+		if result.is_err() && backup.exists() {
+			trace!("error raised; deleting partial backup `{}`", backup.display());
+			if let Err(x) = fs::remove_file(backup).await {
+				error!("failed to delete partial backup `{}`: {}", backup.display(), x);
+			}
+		}
+
+		result.map(|_| ())
+	}
+}
+
+/// Checks there is room for the backup of `original` at `backup`, unless it
+/// is only renamed there, the same way writing it would have failed, without
+/// having to undo anything.
+pub fn check_headroom(original: &Path, backup: &Path, copy: bool) -> Result<(), crate::Error> {
+	let dir = fsutil::parent_dir(backup);
+	if !copy && fsutil::same_filesystem(original, dir)? {
+		return Ok(());
+	}
+
+	let Some(headroom) = fsutil::headroom(dir)? else {
+		return Ok(());
+	};
+
+	trace!("{:?} left for backup `{}`", headroom, backup.display());
+	Ok(headroom.check(std::fs::metadata(original)?.len())?)
+}
+
+fn strip_suffix<'a>(name: &'a OsStr, suffix: &OsStr) -> Option<&'a OsStr> {
+	let name = name.to_str()?;
+	let suffix = suffix.to_str()?;
+	name.strip_suffix(suffix).filter(|x| !x.is_empty()).map(OsStr::new)
+}
+
+/// Puts the backups of `files` back in place of them.
+///
+/// Returns whether all files were restored.
+pub async fn restore(terminal: &mut Terminal, backup: &Backup, files: &[PathBuf]) -> bool {
+	let mut success = true;
+	for file in files {
+		match restore_file(backup, file).await {
+			Ok(backup) => terminal.write_restore(file, backup),
+			Err(x) => {
+				terminal.write_fail(file, x);
+				success = false;
+			}
+		}
+	}
+
+	success
+}
+
+async fn restore_file(backup: &Backup, converted: &Path) -> Result<PathBuf, crate::Error> {
+	if !converted.exists() {
+		return Err(crate::Error::InputNotFound(converted.to_path_buf()));
+	}
+
+	let (backup, original) = backup.find(converted).await?;
+	debug!("restoring `{}` from `{}`", original.display(), backup.display());
+	if original != converted && original.exists() {
+		return Err(crate::Error::OutputExists(original));
+	}
+
+	// a single rename atomically replaces the converted file if the names
+	// match; otherwise the converted file only goes once the original is back
+	trace!("renaming backup `{}` to `{}`", backup.display(), original.display());
+	fsutil::move_file(&backup, &original).await?;
+	if original != converted {
+		trace!("deleting converted file `{}`", converted.display());
+		fs::remove_file(converted).await?;
+	}
+
+	Ok(backup)
+}
@@ -0,0 +1,193 @@
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::mpsc;
+use tracing::{debug, trace};
+
+/// What a `gm batch` process says about the command it runs
+#[derive(Debug, Eq, PartialEq)]
+pub enum Reply {
+	Stdout(String),
+	Stderr(String),
+	Pass,
+	Fail,
+}
+
+/// A `gm batch` process running gm command after command, one a line on its
+/// standard input, sparing many small images a gm process each; every
+/// command is answered with a line telling whether it passed
+pub struct Batch {
+	child: Child,
+	stdin: ChildStdin,
+	replies: mpsc::UnboundedReceiver<Reply>,
+	/// Scratch directory it runs in, for as long as it runs
+	pub dir: PathBuf,
+}
+
+impl Batch {
+	const PASS: &'static str = "shrink-ray:pass";
+	const FAIL: &'static str = "shrink-ray:fail";
+	/// Arguments of `gm` keeping on after failed commands, with quotes
+	/// grouping words like in a shell
+	const ARGS: &'static [&'static str] = &[
+		"batch", "-escape", "unix", "-feedback", "on", "-stop-on-error", "off",
+		"-pass", Self::PASS, "-fail", Self::FAIL, "-",
+	];
+
+	/// Starts `gm`, given by `command` with its standard streams piped, in
+	/// `dir`.
+	pub fn spawn(mut command: Command, dir: PathBuf) -> std::io::Result<Self> {
+		command.args(Self::ARGS).current_dir(&dir).kill_on_drop(true);
+
+		debug!("spawning {:?}", command);
+		let mut child = command.spawn()?;
+		let stdin = child.stdin.take().unwrap();
+		let (sender, replies) = mpsc::unbounded_channel();
+		let (stdout, stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+		tokio::spawn(read(BufReader::new(stdout), BufReader::new(stderr), sender));
+		Ok(Batch { child, stdin, replies, dir })
+	}
+
+	/// The line giving `args` to `gm batch`, unless one of them cannot be put
+	/// on a line, or the way quotes and backslashes within it are taken differs
+	/// between gm versions.
+	pub fn line<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> Option<String> {
+		let mut line = String::new();
+		for arg in args {
+			let arg = arg.to_str()?;
+			if arg.contains(['\'', '"', '\\', '\n', '\r']) {
+				return None;
+			}
+
+			if !line.is_empty() {
+				line.push(' ');
+			}
+
+			line.push('\'');
+			line.push_str(arg);
+			line.push('\'');
+		}
+
+		line.push('\n');
+		Some(line)
+	}
+
+	pub fn id(&self) -> Option<u32> {
+		self.child.id()
+	}
+
+	/// Gives `line` to the process to run.
+	pub async fn send(&mut self, line: &str) -> std::io::Result<()> {
+		trace!("sending {:?} to `gm batch`", line);
+		self.stdin.write_all(line.as_bytes()).await?;
+		self.stdin.flush().await
+	}
+
+	/// What the process says next, or nothing once it is gone.
+	pub async fn recv(&mut self) -> Option<Reply> {
+		self.replies.recv().await
+	}
+
+	/// Lets the process finish once it has run all its commands, and waits
+	/// for it to exit.
+	pub async fn close(self) {
+		let Batch { mut child, stdin, .. } = self;
+		drop(stdin);
+		match child.wait().await {
+			Ok(status) => debug!("`gm batch` {}", status),
+			Err(x) => debug!("failed to wait for `gm batch`: {}", x),
+		}
+	}
+
+	/// Kills the process, e.g. after it has been interrupted, and waits for it
+	/// to exit.
+	pub async fn kill(mut self) {
+		if let Err(x) = self.child.kill().await {
+			debug!("failed to kill `gm batch`: {}", x);
+		}
+	}
+}
+
+/// Forwards the output of a `gm batch` process until it closes both of its
+/// outputs; error output comes first, so that the reasons of a failure come
+/// before the failure itself.
+async fn read(
+	mut stdout: impl AsyncBufReadExt + Unpin, mut stderr: impl AsyncBufReadExt + Unpin,
+	replies: mpsc::UnboundedSender<Reply>,
+) {
+	let (mut out_line, mut err_line) = (String::new(), String::new());
+	let (mut out_done, mut err_done) = (false, false);
+	while !out_done || !err_done {
+		let reply = tokio::select! {
+			biased;
+
+			result = stderr.read_line(&mut err_line), if !err_done => match result {
+				Ok(0) | Err(_) => {
+					err_done = true;
+					continue;
+				}
+				Ok(_) => Reply::Stderr(std::mem::take(&mut err_line)),
+			},
+
+			result = stdout.read_line(&mut out_line), if !out_done => match result {
+				Ok(0) | Err(_) => {
+					out_done = true;
+					continue;
+				}
+				Ok(_) => match std::mem::take(&mut out_line).trim_end() {
+					Batch::PASS => Reply::Pass,
+					Batch::FAIL => Reply::Fail,
+					x => Reply::Stdout(format!("{}\n", x)),
+				},
+			},
+		};
+
+		if replies.send(reply).is_err() {
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsStr;
+
+	use super::{read, Batch, Reply};
+
+	fn line(args: &[&str]) -> Option<String> {
+		Batch::line(args.iter().map(OsStr::new))
+	}
+
+	#[test]
+	fn quotes_commands() {
+		assert_eq!(line(&["convert", "/a/my photo.jpg", "-strip", "jpeg:/b/x.jpg"]).unwrap(),
+			"'convert' '/a/my photo.jpg' '-strip' 'jpeg:/b/x.jpg'\n");
+		assert_eq!(line(&["convert", "$HOME;*.jpg"]).unwrap(), "'convert' '$HOME;*.jpg'\n");
+
+		// left to processes of their own
+		assert_eq!(line(&["convert", "it's.jpg"]), None);
+		assert_eq!(line(&["convert", "a\\b.jpg"]), None);
+		assert_eq!(line(&["convert", "a\nb.jpg"]), None);
+	}
+
+	#[tokio::test]
+	async fn tells_replies_apart() {
+		let (sender, mut replies) = tokio::sync::mpsc::unbounded_channel();
+		let stdout = "shrink-ray:pass\nsome output\nshrink-ray:fail\n".as_bytes();
+		read(stdout, "gm convert: unable to open\n".as_bytes(), sender).await;
+
+		let mut all = Vec::new();
+		while let Some(x) = replies.recv().await {
+			all.push(x);
+		}
+
+		assert_eq!(all, [
+			Reply::Stderr("gm convert: unable to open\n".into()),
+			Reply::Pass,
+			Reply::Stdout("some output\n".into()),
+			Reply::Fail,
+		]);
+	}
+}
@@ -0,0 +1,62 @@
+/// Parses a size given on the command line, like `512`, `100K`, `1.5GiB` or
+/// `10 MB`. Single letters and `iB` suffixes stand for powers of 1024, `B`
+/// suffixes for powers of 1000.
+pub fn parse(value: &str) -> Result<u64, String> {
+	const UNITS: &[char] = &['K', 'M', 'G', 'T'];
+
+	let value = value.trim();
+	let split = value.find(|x: char| !x.is_ascii_digit() && x != '.').unwrap_or(value.len());
+	let (number, unit) = value.split_at(split);
+	let unit = unit.trim_start();
+	let number: f64 = number.parse().map_err(|_| format!("`{}` is not a size, like `100K` or `2M`", value))?;
+
+	let multiplier = match unit.to_ascii_uppercase().as_str() {
+		"" | "B" => 1,
+		x => {
+			let mut chars = x.chars();
+			let Some(power) = chars.next().and_then(|x| UNITS.iter().position(|y| *y == x)) else {
+				return Err(format!("unknown unit `{}`; expected one of K, M, G or T", unit));
+			};
+
+			let base: u64 = match chars.as_str() {
+				"" | "IB" => 1024,
+				"B" => 1000,
+				_ => return Err(format!("unknown unit `{}`; expected one of K, M, G or T", unit)),
+			};
+
+			base.pow(power as u32 + 1)
+		}
+	};
+
+	let bytes = (number * multiplier as f64).round();
+	if bytes >= u64::MAX as f64 {
+		return Err(format!("`{}` is too large", value));
+	}
+
+	Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::parse;
+
+	#[test]
+	fn parses_sizes_with_units() {
+		assert_eq!(parse("512"), Ok(512));
+		assert_eq!(parse("100K"), Ok(100 * 1024));
+		assert_eq!(parse("2m"), Ok(2 * 1024 * 1024));
+		assert_eq!(parse("1.5GiB"), Ok(3 * 512 * 1024 * 1024));
+		assert_eq!(parse("10 MB"), Ok(10_000_000));
+		assert_eq!(parse("7B"), Ok(7));
+	}
+
+	#[test]
+	fn rejects_malformed_sizes() {
+		assert!(parse("").is_err());
+		assert!(parse("K").is_err());
+		assert!(parse("10X").is_err());
+		assert!(parse("10KX").is_err());
+		assert!(parse("1.2.3").is_err());
+		assert!(parse("99999999T").is_err());
+	}
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+
+/// Most colors an image can have and still be a graphic however it looks,
+/// that is what fits in a palette
+const PALETTE: u64 = 256;
+/// Share of neighbouring pixels of the same shade above which an image is a
+/// graphic, with its areas of flat color; downscaling smooths out the noise
+/// of photos, but keeps flat areas flat
+const FLAT: f64 = 0.5;
+/// Difference in shade between neighbouring pixels which makes a sharp edge
+const EDGE: u8 = 64;
+/// Share of sharp edges above which an image with some flat areas is a
+/// graphic, like text or line art
+const EDGES: f64 = 0.15;
+
+/// What an image depicts, as far as picking a format is concerned
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Content {
+	/// Photographic, with smooth gradients and noise, which lossy formats
+	/// suit best
+	Photo,
+	/// Screenshots, diagrams, scans of text and the like, with flat areas and
+	/// sharp edges, which lossy formats smear
+	Graphic,
+}
+
+impl fmt::Display for Content {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Content::Photo => "photo",
+			Content::Graphic => "graphic",
+		})
+	}
+}
+
+/// Cheap measurements of an image to tell its content by
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Features {
+	/// Number of unique colors
+	pub colors: u64,
+	pub alpha: bool,
+	/// Share of neighbouring pixels of a thumbnail with the same shade
+	pub flat: f64,
+	/// Share of neighbouring pixels of a thumbnail with a sharp edge between
+	pub edges: f64,
+}
+
+impl Features {
+	/// Measures the grayscale `thumbnail` of an image with `colors` unique
+	/// colors.
+	pub fn measure(colors: u64, alpha: bool, thumbnail: &Thumbnail) -> Self {
+		let Thumbnail { width, height, pixels } = thumbnail;
+		let (mut pairs, mut flat, mut edges) = (0, 0, 0);
+		let mut compare = |a: u8, b: u8| {
+			pairs += 1;
+			match a.abs_diff(b) {
+				0 => flat += 1,
+				x if x >= EDGE => edges += 1,
+				_ => {}
+			}
+		};
+
+		for y in 0..*height {
+			for x in 0..*width {
+				let pixel = pixels[y * width + x];
+				if x + 1 < *width {
+					compare(pixel, pixels[y * width + x + 1]);
+				}
+
+				if y + 1 < *height {
+					compare(pixel, pixels[(y + 1) * width + x]);
+				}
+			}
+		}
+
+		let share = |x: u32| if pairs == 0 { 0.0 } else { x as f64 / pairs as f64 };
+		Features { colors, alpha, flat: share(flat), edges: share(edges) }
+	}
+}
+
+/// Tells what an image depicts from its `features`.
+pub fn classify(features: &Features) -> Content {
+	if features.colors <= PALETTE || features.flat >= FLAT || (features.edges >= EDGES && features.flat >= EDGES) {
+		Content::Graphic
+	} else {
+		Content::Photo
+	}
+}
+
+/// Downscaled grayscale image, with one byte per pixel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Thumbnail {
+	pub width: usize,
+	pub height: usize,
+	pub pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+	/// Parses the first image of a binary PGM file, with 8 bits per pixel.
+	pub fn parse_pgm(data: &[u8]) -> Option<Self> {
+		let mut rest = data.strip_prefix(b"P5")?;
+		let mut header = [0; 3];
+		for value in &mut header {
+			let start = rest.iter().position(|x| !x.is_ascii_whitespace())?;
+			let end = rest[start..].iter().position(|x| !x.is_ascii_digit()).map_or(rest.len(), |x| start + x);
+			*value = std::str::from_utf8(&rest[start..end]).ok()?.parse().ok()?;
+			rest = &rest[end..];
+		}
+
+		let [width, height, max] = header;
+		if max == 0 || max > u8::MAX as usize {
+			return None;
+		}
+
+		// a single whitespace character separates the header from the pixels
+		let pixels = rest.get(1..)?.get(..width * height)?.to_vec();
+		Some(Thumbnail { width, height, pixels })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{classify, Content, Features, Thumbnail};
+
+	const SIZE: usize = 64;
+
+	fn thumbnail(mut shade: impl FnMut(usize, usize) -> u8) -> Thumbnail {
+		let pixels = (0..SIZE * SIZE).map(|x| shade(x % SIZE, x / SIZE)).collect();
+		Thumbnail { width: SIZE, height: SIZE, pixels }
+	}
+
+	/// A sky fading into the horizon, with sensor noise
+	fn photo() -> Thumbnail {
+		let mut seed = 7u32;
+		thumbnail(|x, y| {
+			seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+			let noise = (seed >> 16) % 9;
+			(60 + x + y) as u8 + noise as u8
+		})
+	}
+
+	/// A window with a title bar and lines of text on a light background
+	fn screenshot() -> Thumbnail {
+		thumbnail(|x, y| match (x, y) {
+			(8..56, 4..8) => 40,
+			(10..54, 12..52) if y % 4 == 0 && x % 3 != 0 => 20,
+			(8..56, 8..56) => 255,
+			_ => 200,
+		})
+	}
+
+	/// Boxes connected by lines, black on white
+	fn diagram() -> Thumbnail {
+		thumbnail(|x, y| match (x, y) {
+			(8 | 24, 8..=24) | (8..=24, 8 | 24) => 0,
+			(40 | 56, 40..=56) | (40..=56, 40 | 56) => 0,
+			(24..=40, 32) | (32, 24..=40) => 0,
+			_ => 255,
+		})
+	}
+
+	#[test]
+	fn classifies_photos() {
+		let features = Features::measure(183_402, false, &photo());
+		assert!(features.flat < 0.2, "{:?}", features);
+		assert_eq!(classify(&features), Content::Photo);
+	}
+
+	#[test]
+	fn classifies_screenshots() {
+		let features = Features::measure(3_571, false, &screenshot());
+		assert_eq!(classify(&features), Content::Graphic);
+	}
+
+	#[test]
+	fn classifies_diagrams() {
+		let features = Features::measure(2, false, &diagram());
+		assert_eq!(classify(&features), Content::Graphic);
+
+		// even with antialiased lines
+		let features = Features::measure(4_096, false, &diagram());
+		assert_eq!(classify(&features), Content::Graphic);
+	}
+
+	#[test]
+	fn parses_pgm_thumbnails() {
+		let thumbnail = Thumbnail::parse_pgm(b"P5\n2 2\n255\n\x00\x40\x80\xff").unwrap();
+		assert_eq!(thumbnail, Thumbnail { width: 2, height: 2, pixels: vec![0, 64, 128, 255] });
+
+		assert!(Thumbnail::parse_pgm(b"P5\n2 2\n255\n\x00\x40").is_none());
+		assert!(Thumbnail::parse_pgm(b"P6\n1 1\n255\n\x00\x00\x00").is_none());
+		assert!(Thumbnail::parse_pgm(b"P5\n1 1\n65535\n\x00\x00").is_none());
+	}
+}
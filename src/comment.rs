@@ -8,22 +8,56 @@ pub enum CommentParseError {
 	#[error("not a shrink-ray comment")]
 	NotShrinkRay,
 	#[error("not a valid version string: {}", .0)]
-	NotVersion(#[from] semver::Error)
+	NotVersion(#[from] semver::Error),
+	#[error("not a valid settings hash: {}", .0)]
+	InvalidSettings(String),
+	#[error("not a valid quality: {}", .0)]
+	InvalidQuality(String),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Comment {
-	pub version: Version
+	pub version: Version,
+	/// Settings the file was converted with, unknown for files converted
+	/// before they were recorded
+	pub settings: Option<SettingsHash>,
+	/// Quality the image was encoded with, if not left to the encoder; spelled
+	/// out, unlike the other settings, for auditing
+	pub quality: Option<u8>,
 }
 
 impl Default for Comment {
 	fn default() -> Self {
 		let version = Version::from_str(env!("CARGO_PKG_VERSION")).unwrap();
-		Comment { version }
+		Comment { version, settings: None, quality: None }
 	}
 }
 
-const PREFIX: &'static str = "shrink-ray/";
+impl Comment {
+	const QUALITY_PREFIX: &'static str = "quality/";
+
+	/// Comment of a file converted with `settings`.
+	pub fn new(settings: Option<SettingsHash>) -> Self {
+		Comment { settings, ..Comment::default() }
+	}
+
+	/// The same comment, for an image encoded at `quality`.
+	pub fn with_quality(self, quality: Option<u8>) -> Self {
+		Comment { quality, ..self }
+	}
+
+	/// Whether the file was converted with the settings of `current`, as far as
+	/// can be told: files converted before the settings were recorded, or
+	/// recorded in a way this version does not know, count as such.
+	pub fn has_settings(&self, current: Option<&SettingsHash>) -> bool {
+		match (&self.settings, current) {
+			(Some(x), Some(y)) if x.schema == y.schema => x.hash == y.hash,
+			_ => true,
+		}
+	}
+}
+
+const PREFIX: &str = "shrink-ray/";
 
 impl FromStr for Comment {
   type Err = CommentParseError;
@@ -33,13 +67,135 @@ impl FromStr for Comment {
     	return Err(CommentParseError::NotShrinkRay)
     }
 
-    let version = Version::from_str(&s[PREFIX.len()..])?;
-    Ok(Comment { version })
+    let mut fields = s[PREFIX.len()..].split(' ');
+    let version = Version::from_str(fields.next().unwrap_or_default())?;
+    let mut comment = Comment { version, settings: None, quality: None };
+    for field in fields {
+    	match field.strip_prefix(Self::QUALITY_PREFIX) {
+    		Some(x) => {
+    			let quality = x.parse().map_err(|_| CommentParseError::InvalidQuality(field.to_string()))?;
+    			comment.quality = Some(quality);
+    		}
+    		None => comment.settings = Some(field.parse()?),
+    	}
+    }
+
+    Ok(comment)
   }
 }
 
 impl fmt::Display for Comment {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "{}{}", PREFIX, self.version)
+		write!(f, "{}{}", PREFIX, self.version)?;
+		if let Some(settings) = &self.settings {
+			write!(f, " {}", settings)?;
+		}
+
+		if let Some(quality) = self.quality {
+			write!(f, " {}{}", Self::QUALITY_PREFIX, quality)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Hash of the settings which affect how files are converted, as far as they
+/// differ from the defaults, so that options added later leave the hashes of
+/// earlier files as they were
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SettingsHash {
+	/// Version of the way the settings are hashed, only compared within one
+	schema: u32,
+	hash: String,
+}
+
+impl SettingsHash {
+	const SCHEMA: u32 = 1;
+	const PREFIX: &'static str = "settings/";
+
+	/// Hashes `settings`, pairs of option names and values, in any order.
+	pub fn of(settings: &[(String, String)]) -> Self {
+		let mut settings: Vec<_> = settings.iter().collect();
+		settings.sort();
+
+		let mut canonical = String::new();
+		for (key, value) in settings {
+			canonical.push_str(&format!("{:?}={:?}\n", key, value));
+		}
+
+		let hash = blake3::hash(canonical.as_bytes()).to_hex();
+		SettingsHash { schema: Self::SCHEMA, hash: hash[..16].to_string() }
+	}
+}
+
+impl FromStr for SettingsHash {
+	type Err = CommentParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || CommentParseError::InvalidSettings(s.to_string());
+		let (schema, hash) = s.strip_prefix(Self::PREFIX).and_then(|x| x.split_once(':')).ok_or_else(invalid)?;
+		let schema = schema.parse().map_err(|_| invalid())?;
+		if hash.is_empty() || !hash.bytes().all(|x| x.is_ascii_hexdigit()) {
+			return Err(invalid());
+		}
+
+		Ok(SettingsHash { schema, hash: hash.to_string() })
+	}
+}
+
+impl fmt::Display for SettingsHash {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}{}:{}", Self::PREFIX, self.schema, self.hash)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Comment, SettingsHash};
+
+	fn settings(pairs: &[(&str, &str)]) -> SettingsHash {
+		SettingsHash::of(&pairs.iter().map(|(x, y)| (x.to_string(), y.to_string())).collect::<Vec<_>>())
+	}
+
+	#[test]
+	fn parses_comments_with_settings() {
+		let comment: Comment = "shrink-ray/0.1.0".parse().unwrap();
+		assert_eq!(comment.settings, None);
+
+		let hashed = Comment::new(Some(settings(&[("streams", "\"all\"")])));
+		let comment: Comment = hashed.to_string().parse().unwrap();
+		assert_eq!(comment, hashed);
+		assert!(hashed.to_string().starts_with(&format!("shrink-ray/{} settings/1:", env!("CARGO_PKG_VERSION"))));
+
+		let quality = hashed.clone().with_quality(Some(85));
+		assert!(quality.to_string().ends_with(" quality/85"), "{}", quality);
+		assert_eq!(quality.to_string().parse::<Comment>().unwrap(), quality);
+
+		assert!("shrink-ray/0.2.0 settings/x:00".parse::<Comment>().is_err());
+		assert!("shrink-ray/0.2.0 quality/high".parse::<Comment>().is_err());
+		assert!("shrink-ray/0.2.0 whatever".parse::<Comment>().is_err());
+	}
+
+	#[test]
+	fn hashes_settings_canonically() {
+		let a = settings(&[("auto-format", "true"), ("streams", "\"all\"")]);
+		assert_eq!(a, settings(&[("streams", "\"all\""), ("auto-format", "true")]));
+		assert_ne!(a, settings(&[("auto-format", "true")]));
+		assert_ne!(a, settings(&[("auto-format", "true"), ("streams", "\"video\"")]));
+		// a value is not mistaken for another pair
+		assert_ne!(settings(&[("a", "1\nb=2")]), settings(&[("a", "1"), ("b", "2")]));
+	}
+
+	#[test]
+	fn tells_files_converted_with_other_settings() {
+		let current = settings(&[("streams", "\"all\"")]);
+		let comment = |x: &str| x.parse::<Comment>().unwrap();
+		assert!(Comment::new(Some(current.clone())).has_settings(Some(&current)));
+		assert!(!Comment::new(Some(settings(&[]))).has_settings(Some(&current)));
+
+		// unknown, so taken as the same
+		assert!(comment("shrink-ray/0.1.0").has_settings(Some(&current)));
+		assert!(comment("shrink-ray/0.3.0 settings/2:0123").has_settings(Some(&current)));
+		assert!(Comment::new(Some(current)).has_settings(None));
 	}
 }
@@ -0,0 +1,466 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use clap::error::{ContextKind, ContextValue};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use crate::comment::SettingsHash;
+use crate::options::Options;
+
+/// Options which only make sense on the command line
+const COMMAND_LINE_ONLY: &[&str] =
+	&["config", "profile", "print-config", "tar", "via-daemon", "progress-fd", "help", "version"];
+
+/// `$XDG_CONFIG_HOME/shrink-ray`, falling back to `~/.config/shrink-ray`.
+pub fn dir() -> Option<PathBuf> {
+	let config = match env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()) {
+		Some(x) => PathBuf::from(x),
+		None => PathBuf::from(env::var_os("HOME").filter(|x| !x.is_empty())?).join(".config"),
+	};
+
+	Some(config.join("shrink-ray"))
+}
+
+/// Where the effective value of an option comes from, from least to most
+/// important
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Layer {
+	Default,
+	/// The global settings of the configuration file
+	Config,
+	/// The settings of a profile of the configuration file
+	Profile(String),
+	CommandLine,
+}
+
+impl fmt::Display for Layer {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Layer::Default => f.write_str("default"),
+			Layer::Config => f.write_str("config"),
+			Layer::Profile(x) => write!(f, "profile `{}`", x),
+			Layer::CommandLine => f.write_str("command line"),
+		}
+	}
+}
+
+/// Values of options to use unless given on the command line, by their long
+/// names
+pub type Settings = BTreeMap<String, (toml::Value, Layer)>;
+
+/// Contents of the configuration file: default values of options, and named
+/// profiles of values to use instead, in `[profile.NAME]` tables
+#[derive(Debug, Default)]
+pub struct Config {
+	path: Option<PathBuf>,
+	global: toml::Table,
+	profiles: BTreeMap<String, toml::Table>,
+}
+
+impl Config {
+	pub fn default_path() -> Option<PathBuf> {
+		Some(dir()?.join("config.toml"))
+	}
+
+	/// Loads the configuration file at `path`, or the default one, which
+	/// unlike the former does not have to exist.
+	pub fn load(path: Option<&Path>) -> Result<Self, crate::Error> {
+		let (path, required) = match path {
+			Some(x) => (x.to_path_buf(), true),
+			None => match Self::default_path() {
+				Some(x) => (x, false),
+				None => return Ok(Config::default()),
+			},
+		};
+
+		let text = match std::fs::read_to_string(&path) {
+			Ok(x) => x,
+			Err(x) if x.kind() == std::io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+			Err(x) => return Err(crate::Error::ConfigFile(path, vec![x.to_string()])),
+		};
+
+		match Self::parse(&text) {
+			Ok(x) => Ok(Config { path: Some(path), ..x }),
+			Err(x) => Err(crate::Error::ConfigFile(path, x)),
+		}
+	}
+
+	fn parse(text: &str) -> Result<Self, Vec<String>> {
+		let mut global: toml::Table = toml::from_str(text).map_err(|x| match x.span() {
+			Some(span) => vec![format!("line {}: {}", text[..span.start].matches('\n').count() + 1, x.message())],
+			None => vec![x.message().to_string()],
+		})?;
+
+		let mut problems = Vec::new();
+		let mut profiles = BTreeMap::new();
+		match global.remove("profile") {
+			Some(toml::Value::Table(table)) => {
+				for (name, settings) in table {
+					match settings {
+						toml::Value::Table(x) => {
+							profiles.insert(name, x);
+						}
+						_ => problems.push(format!("`profile.{}` must be a table", name)),
+					}
+				}
+			}
+			Some(_) => problems.push("`profile` must be a table of profiles, like `[profile.NAME]`".into()),
+			None => {}
+		}
+
+		match problems.is_empty() {
+			true => Ok(Config { path: None, global, profiles }),
+			false => Err(problems),
+		}
+	}
+
+	/// Global settings, overridden by those of `profile` if given.
+	pub fn settings(&self, profile: Option<&str>) -> Result<Settings, crate::Error> {
+		let mut settings: Settings =
+			self.global.iter().map(|(key, value)| (key.clone(), (value.clone(), Layer::Config))).collect();
+
+		let Some(name) = profile else {
+			return Ok(settings);
+		};
+
+		let Some(profile) = self.profiles.get(name) else {
+			return Err(crate::Error::UnknownProfile(name.to_string(), self.profiles.keys().cloned().collect()));
+		};
+
+		for (key, value) in profile {
+			settings.insert(key.clone(), (value.clone(), Layer::Profile(name.to_string())));
+		}
+
+		Ok(settings)
+	}
+}
+
+/// How to point out the setting `key` of `layer` in the configuration file.
+fn name(key: &str, layer: &Layer) -> String {
+	match layer {
+		Layer::Profile(x) => format!("`profile.{}.{}`", x, key),
+		_ => format!("`{}`", key),
+	}
+}
+
+/// Turns `settings` into command line arguments to go before the actual ones,
+/// leaving out the options `given` there.
+fn arguments(
+	command: &clap::Command, settings: &Settings, given: impl Fn(&clap::Id) -> bool,
+) -> Result<Vec<OsString>, Vec<String>> {
+	let mut args = Vec::new();
+	let mut problems = Vec::new();
+	for (key, (value, layer)) in settings {
+		let name = name(key, layer);
+
+		let arg = command.get_arguments().find(|x| x.get_long() == Some(key));
+		let Some(arg) = arg.filter(|_| !COMMAND_LINE_ONLY.contains(&key.as_str())) else {
+			problems.push(format!("{}: unknown option", name));
+			continue;
+		};
+
+		if given(arg.get_id()) {
+			continue;
+		}
+
+		let values = match value {
+			toml::Value::Array(x) => x.iter().collect(),
+			x => vec![x],
+		};
+
+		for value in values {
+			let value = match value {
+				toml::Value::String(x) => x.clone(),
+				toml::Value::Integer(x) => x.to_string(),
+				toml::Value::Float(x) => x.to_string(),
+				toml::Value::Boolean(x) => x.to_string(),
+				toml::Value::Datetime(x) => x.to_string(),
+				toml::Value::Array(_) | toml::Value::Table(_) => {
+					problems.push(format!("{}: expected a value or a list of values", name));
+					continue;
+				}
+			};
+
+			if arg.get_action().takes_values() {
+				args.push(OsString::from(format!("--{}={}", key, value)));
+			} else {
+				match value.as_str() {
+					"true" => args.push(OsString::from(format!("--{}", key))),
+					"false" => {}
+					_ => problems.push(format!("{}: expected `true` or `false`", name)),
+				}
+			}
+		}
+	}
+
+	match problems.is_empty() {
+		true => Ok(args),
+		false => Err(problems),
+	}
+}
+
+/// Parses the command line on top of the settings of the configuration file,
+/// and of the profile chosen, exiting on errors like [`clap::Parser::parse`].
+/// With `--print-config`, prints the effective settings and exits.
+///
+/// This happens before tracing is set up, so there is nothing to log to.
+pub fn parse_options() -> Options {
+	let args: Vec<OsString> = env::args_os().collect();
+	let command = Options::command();
+	let given = command.clone().get_matches_from(&args);
+
+	// settings are for conversions, not for the subcommands
+	if given.subcommand_name().is_some() {
+		return Options::from_arg_matches(&given).unwrap_or_else(|x| x.exit());
+	}
+
+	let config = Config::load(given.get_one::<PathBuf>("config").map(PathBuf::as_path)).unwrap_or_else(|x| exit(x));
+	let settings = config.settings(given.get_one::<String>("profile").map(String::as_str)).unwrap_or_else(|x| exit(x));
+	let is_given = |id: &clap::Id| given.value_source(id.as_str()) == Some(ValueSource::CommandLine);
+	let path = || config.path.clone().unwrap_or_default();
+	let defaults = arguments(&command, &settings, is_given);
+	let defaults = defaults.unwrap_or_else(|x| exit(crate::Error::ConfigFile(path(), x)));
+
+	let merged: Vec<_> = args.iter().take(1).chain(&defaults).chain(args.iter().skip(1)).cloned().collect();
+	let matches = command.clone().try_get_matches_from(&merged).unwrap_or_else(|x| {
+		// the command line alone is fine, so the settings are not
+		let message = x.kind().as_str().unwrap_or("invalid value").to_string();
+		let problem = x.to_string().lines().next().map(|x| x.trim_start_matches("error: ").to_string());
+		let problem = problem.unwrap_or(message);
+		let problem = match offending(&x).and_then(|key| settings.get_key_value(key)) {
+			Some((key, (_, layer))) => format!("{}: {}", name(key, layer), problem),
+			None => problem,
+		};
+		exit(crate::Error::ConfigFile(path(), vec![problem]))
+	});
+
+	if matches.get_flag("print_config") {
+		print!("{}", render(&command, &matches, &settings, is_given));
+		std::process::exit(0);
+	}
+
+	options(&command, &matches, merged).unwrap_or_else(|x| x.exit())
+}
+
+/// Parses arguments merged with the settings of the configuration file
+/// already, as [`Options::argv`] holds them, like those of the runs handed
+/// over to the daemon.
+pub fn parse_merged(args: Vec<OsString>) -> Result<Options, clap::Error> {
+	let command = Options::command();
+	let matches = command.clone().try_get_matches_from(&args)?;
+	options(&command, &matches, args)
+}
+
+fn options(command: &clap::Command, matches: &ArgMatches, argv: Vec<OsString>) -> Result<Options, clap::Error> {
+	let mut options = Options::from_arg_matches(matches)?;
+	options.settings = effective(command, matches).map(|(x, value)| (x.get_long().unwrap().into(), value)).collect();
+	options.settings_hash = Some(SettingsHash::of(&conversion_settings(command, matches)));
+	options.argv = argv;
+	Ok(options)
+}
+
+/// Groups of the options which change how files are converted
+const CONVERSION_GROUPS: &[&str] = &["ImageOptions", "VideoOptions"];
+
+/// Options which change how files are converted, where they differ from their
+/// defaults, by their long names, so that options added later do not change
+/// anything for the runs which leave them as they are.
+fn conversion_settings(command: &clap::Command, matches: &ArgMatches) -> Vec<(String, String)> {
+	let conversion: Vec<_> = command
+		.get_groups()
+		.filter(|x| CONVERSION_GROUPS.contains(&x.get_id().as_str()))
+		.flat_map(|x| x.get_args())
+		.collect();
+
+	effective(command, matches)
+		.filter(|(arg, _)| conversion.contains(&arg.get_id()))
+		.filter(|(arg, _)| {
+			let id = arg.get_id().as_str();
+			let values = matches.get_raw(id).into_iter().flatten();
+			matches.value_source(id) != Some(ValueSource::DefaultValue)
+				&& !values.eq(arg.get_default_values().iter().map(|x| x.as_os_str()))
+		})
+		.map(|(arg, value)| (arg.get_long().unwrap().to_string(), value))
+		.collect()
+}
+
+/// Long name of the option `error` is about, if any.
+fn offending(error: &clap::Error) -> Option<&str> {
+	match error.get(ContextKind::InvalidArg)? {
+		ContextValue::String(x) => x.strip_prefix("--")?.split([' ', '=']).next(),
+		_ => None,
+	}
+}
+
+fn exit(error: crate::Error) -> ! {
+	eprintln!("{}", error);
+	std::process::exit(1);
+}
+
+/// Lists the effective value of every option that has one, as a
+/// configuration file with the layer each comes from.
+fn render(
+	command: &clap::Command, matches: &ArgMatches, settings: &Settings, given: impl Fn(&clap::Id) -> bool,
+) -> String {
+	let mut out = String::new();
+	for (arg, value) in effective(command, matches) {
+		let key = arg.get_long().unwrap();
+		let layer = if given(arg.get_id()) {
+			Layer::CommandLine
+		} else if let Some((_, layer)) = settings.get(key) {
+			layer.clone()
+		} else {
+			Layer::Default
+		};
+
+		out.push_str(&format!("{} = {} # {}\n", key, value, layer));
+	}
+
+	out
+}
+
+/// Options that can go in the configuration file and have a value, with that
+/// value as it would be written there.
+fn effective<'a>(command: &'a clap::Command, matches: &'a ArgMatches) -> impl Iterator<Item = (&'a clap::Arg, String)> {
+	command.get_arguments().filter_map(|arg| {
+		arg.get_long().filter(|x| !COMMAND_LINE_ONLY.contains(x))?;
+		let values = matches.get_raw(arg.get_id().as_str())?;
+
+		let literal = |x: &std::ffi::OsStr| {
+			let x = x.to_string_lossy();
+			match x.parse::<f64>().is_ok() || x == "true" || x == "false" {
+				true => x.into_owned(),
+				false => format!("{:?}", x),
+			}
+		};
+
+		let values: Vec<_> = values.map(literal).collect();
+		let value = match values.as_slice() {
+			[x] if !matches!(arg.get_action(), clap::ArgAction::Append) => x.clone(),
+			x => format!("[{}]", x.join(", ")),
+		};
+
+		Some((arg, value))
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::CommandFactory;
+
+	use super::{arguments, conversion_settings, offending, Config, Layer};
+	use crate::options::Options;
+
+	const CONFIG: &str = r#"
+		no-grow = true
+		units = "decimal"
+		jobs = 2
+
+		[profile.share]
+		jobs = 4
+		keep-metadata = ["icc", "exif"]
+
+		[profile.web]
+		no-grow = false
+	"#;
+
+	#[test]
+	fn profiles_override_global_settings() {
+		let config = Config::parse(CONFIG).unwrap();
+		let global = config.settings(None).unwrap();
+		assert_eq!(global["jobs"], (toml::Value::Integer(2), Layer::Config));
+		assert!(!global.contains_key("keep-metadata"));
+
+		let share = config.settings(Some("share")).unwrap();
+		let profile = Layer::Profile("share".into());
+		assert_eq!(share["jobs"], (toml::Value::Integer(4), profile.clone()));
+		assert_eq!(share["keep-metadata"].1, profile);
+		assert_eq!(share["no-grow"], (toml::Value::Boolean(true), Layer::Config));
+		assert_eq!(share["units"].1, Layer::Config);
+
+		let web = config.settings(Some("web")).unwrap();
+		assert_eq!(web["no-grow"], (toml::Value::Boolean(false), Layer::Profile("web".into())));
+		assert_eq!(web["jobs"].1, Layer::Config);
+	}
+
+	#[test]
+	fn lists_profiles_when_unknown() {
+		let config = Config::parse(CONFIG).unwrap();
+		let error = config.settings(Some("archive")).unwrap_err().to_string();
+		assert!(error.contains("`archive`") && error.contains("share, web"), "{}", error);
+
+		let error = Config::default().settings(Some("archive")).unwrap_err().to_string();
+		assert!(error.contains("no profiles"), "{}", error);
+	}
+
+	#[test]
+	fn command_line_overrides_settings() {
+		let command = Options::command();
+		let settings = Config::parse(CONFIG).unwrap().settings(Some("share")).unwrap();
+
+		let args = arguments(&command, &settings, |_| false).unwrap();
+		let args: Vec<_> = args.iter().map(|x| x.to_str().unwrap()).collect();
+		assert_eq!(args, ["--jobs=4", "--keep-metadata=icc", "--keep-metadata=exif", "--no-grow", "--units=decimal"]);
+
+		let args = arguments(&command, &settings, |x| x == "jobs" || x == "no_grow").unwrap();
+		let args: Vec<_> = args.iter().map(|x| x.to_str().unwrap()).collect();
+		assert_eq!(args, ["--keep-metadata=icc", "--keep-metadata=exif", "--units=decimal"]);
+
+		// a flag set to false in a profile stays unset
+		let settings = Config::parse(CONFIG).unwrap().settings(Some("web")).unwrap();
+		let args = arguments(&command, &settings, |_| false).unwrap();
+		assert!(!args.iter().any(|x| x == "--no-grow"));
+	}
+
+	#[test]
+	fn names_offending_keys() {
+		let command = Options::command();
+		let config = Config::parse("colour = true\nstats = 3\n[profile.x]\ntar = true\nprofile = \"y\"").unwrap();
+		let problems = arguments(&command, &config.settings(Some("x")).unwrap(), |_| false).unwrap_err();
+		assert_eq!(
+			problems,
+			[
+				"`colour`: unknown option",
+				"`profile.x.profile`: unknown option",
+				"`stats`: expected `true` or `false`",
+				"`profile.x.tar`: unknown option",
+			]
+		);
+
+		assert!(Config::parse("[profile]\nshare = 1").is_err());
+		assert!(Config::parse("profile = 1").is_err());
+		assert_eq!(Config::parse("jobs = ").unwrap_err().len(), 1);
+	}
+
+	#[test]
+	fn names_options_clap_rejects() {
+		let command = Options::command();
+		let error = |args: &[&str]| {
+			command.clone().try_get_matches_from(["shrink-ray"].iter().chain(args)).unwrap_err()
+		};
+		assert_eq!(offending(&error(&["--jobs=many", "a.jpg"])), Some("jobs"));
+		assert_eq!(offending(&error(&["--image-format=bmp", "a.jpg"])), Some("image-format"));
+		// either of the options in conflict
+		assert!(offending(&error(&["--auto-format", "--image-format=png", "a.jpg"])).is_some());
+	}
+
+	#[test]
+	fn hashes_conversion_settings_only() {
+		let command = Options::command();
+		let settings = |args: &[&str]| {
+			let matches = command.clone().get_matches_from(["shrink-ray"].iter().chain(args));
+			conversion_settings(&command, &matches)
+		};
+
+		assert_eq!(settings(&["a.jpg"]), []);
+		assert_eq!(settings(&["--jobs=4", "--no-grow", "a.jpg"]), []);
+		// the same as by default
+		assert_eq!(settings(&["--streams=best", "a.jpg"]), []);
+		assert_eq!(settings(&["--auto-format", "a.jpg"]), [("auto-format".to_string(), "true".to_string())]);
+		let streams = settings(&["--streams=all", "--keep-metadata=icc", "a.jpg"]);
+		assert_eq!(streams, [("keep-metadata".into(), "[\"icc\"]".into()), ("streams".into(), "\"all\"".into())]);
+	}
+}
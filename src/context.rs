@@ -1,51 +1,329 @@
-use std::ffi::OsStr;
-use std::process::Output;
-use std::{collections::HashMap, path::Path};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ffi::{OsStr, OsString};
+use std::process::{Output, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::collections::hash_map::Entry;
 use std::env;
 use std::path::PathBuf;
-use magic::{Cookie, CookieFlags};
 use tokio::fs::{self, OpenOptions};
 use tokio::io::AsyncReadExt;
 use tokio::process::Command;
-use tracing::{debug, trace};
+use tracing::{debug, error, trace, warn};
 
-use crate::options::OutputOptions;
+use crate::audit::AuditLog;
+use crate::batch::Batch;
+use crate::error::Stage;
+use crate::fsutil::{self, Claims, FsFamily};
+use crate::git::Repositories;
+use crate::identify::{self, Identify, Tier};
+use crate::journal::Journal;
+use crate::image::Formats;
+use crate::options::{MagicOptions, OnStall, OutputOptions};
+use crate::progress::Progress;
+use crate::rusage;
+use crate::qa::QaSamples;
+use crate::temp;
 use crate::terminal::Terminal;
+use crate::terminal::Activity;
+use crate::timing::{self, ActiveInstant, Mark};
+use crate::tools::Tools;
+
+/// A tool to run, which only [`Context`] makes and runs, so that it never
+/// inherits our standard streams: whatever it writes to the terminal would
+/// garble the progress shown there, all the more with several at once.
+#[derive(Debug)]
+pub struct ToolCommand(Command);
+
+impl ToolCommand {
+	pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+		self.0.arg(arg);
+		self
+	}
+
+	pub fn args(&mut self, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> &mut Self {
+		self.0.args(args);
+		self
+	}
+
+	pub fn as_std(&self) -> &std::process::Command {
+		self.0.as_std()
+	}
+
+	/// The command to spawn, with its output and error output piped, and its
+	/// input `stdin`.
+	fn piped(mut self, stdin: Stdio) -> Command {
+		self.0.stdin(stdin).stdout(Stdio::piped()).stderr(Stdio::piped());
+		self.0
+	}
+}
+
+/// Runs the system utility `program` with `args` to completion, e.g. to tell
+/// whether the system is idle, with its output captured like that of tools.
+pub fn query(program: &str, args: &[&str]) -> std::io::Result<Output> {
+	let mut command = std::process::Command::new(program);
+	command.args(args).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+	command.output()
+}
 
 pub struct Context {
-	binaries: HashMap<&'static str, PathBuf>,
-	cookie: Cookie,
+	binaries: HashMap<String, PathBuf>,
+	identify: Identify,
+	/// Time spent waiting for tools so far
+	tools: Duration,
+	/// CPU time the tools took so far, where it is known
+	cpu: Duration,
+	/// Most tools to run at once
+	jobs: usize,
+	/// Converters defined by the user
+	pub user_tools: Tools,
+	/// Git work trees the inputs are in
+	pub git: Repositories,
+	/// Where replaced files are logged, if anywhere; shared with the workers
+	pub audit: Option<Rc<RefCell<AuditLog>>>,
+	/// Where samples of converted files go for `--qa-samples`; shared with the
+	/// workers
+	pub qa: Option<Rc<RefCell<QaSamples>>>,
+	/// Where complete outputs are recorded with `--output-dir`; shared with the
+	/// workers
+	pub journal: Option<Rc<RefCell<Journal>>>,
+	/// Formats each image tool reads, once listed; unknown if it cannot list
+	/// them
+	pub image_formats: HashMap<&'static str, Option<Formats>>,
+	/// When to cancel whatever is running
+	pub deadline: Option<SystemTime>,
+	/// Variables of [`Context::CLEARED_ENV`] to pass on to the tools anyway
+	pub passthrough_env: Vec<OsString>,
+	/// How long tools may go without signs of life before they are considered
+	/// stalled
+	pub stall_timeout: Option<Duration>,
+	pub on_stall: OnStall,
+	/// Keep the scratch directories the tools run in, rather than deleting them
+	pub keep_temp: bool,
+	/// Convert images in one `gm batch` process, rather than in a gm process
+	/// each
+	pub gm_batch: bool,
+	/// The `gm batch` process, once started
+	batch: Option<Batch>,
+	/// Outputs handed out by [`Context::get_output_file`], which the tools
+	/// are to write even though they do not exist yet
+	outputs: HashSet<PathBuf>,
+	/// Input being converted, known to be there from its record, so that it is
+	/// not looked up again among the arguments of the tools
+	pub input: Option<PathBuf>,
+	/// Outputs handed out into `--output-dir` and the like, by input; shared
+	/// with the workers
+	claims: Rc<RefCell<Claims>>,
+	/// Set once one of the workers converting files alongside each other is
+	/// interrupted, so that the others do not start any more tools either
+	interrupted: Option<Rc<Cell<bool>>>,
+	/// Interrupts given in between the tools, once listened to with
+	/// [`Context::watch_interrupts`]
+	#[cfg(target_family = "unix")]
+	sigint: Option<tokio::signal::unix::Signal>,
 	pub terminal: Terminal,
-	pub output_options: OutputOptions
+	/// Pauses and resumes the running tool; listened to from the start, as
+	/// its default action would be to terminate us
+	#[cfg(target_family = "unix")]
+	pause: tokio::signal::unix::Signal,
 }
 
 impl Context {
-	pub async fn new(terminal: Terminal, output_options: OutputOptions) -> Result<Self, crate::Error> {
-		trace!("initializing libmagic");
-		let cookie = Cookie::open(CookieFlags::MIME_TYPE | CookieFlags::ERROR)?;
+	/// Prefix of the environment variables meant for us, which are not passed
+	/// on to the tools
+	const ENV_PREFIX: &'static str = "RAY_";
+	/// Environment variables which make the tools behave differently from one
+	/// machine to the next, or write reports wherever they run; a trailing `*`
+	/// stands for any suffix
+	const CLEARED_ENV: &'static [&'static str] = &["FFREPORT", "FFMPEG_DATADIR", "AVCONV_DATADIR", "MAGICK_*"];
+	/// Amount of standard error output of a tool kept for diagnosing failures
+	const ERR_LOG_SIZE: usize = 64 * 1024;
 
-		trace!("loading libmagic database");
-		// TODO: load databases manually using tokio
-		cookie.load::<&str>(&[])?;
+	pub async fn new(
+		mut terminal: Terminal, magic_options: &MagicOptions, user_tools: Tools, jobs: usize,
+	) -> Result<Self, crate::Error> {
+		let identify = Identify::new(magic_options);
+		match identify.tier() {
+			Tier::Database => {}
+			Tier::BuiltIn => terminal.write_note(
+				"identifying files with the libmagic database built into shrink-ray, which knows fewer formats",
+			),
+			Tier::Extension => terminal.write_note(
+				"identifying files by their extensions, as libmagic is of no use; they may not be what they are named",
+			),
+		}
 
 		let binaries = HashMap::new();
-		Ok(Self { binaries, cookie, terminal, output_options })
+		Ok(Self {
+			binaries,
+			identify,
+			tools: Duration::ZERO,
+			cpu: Duration::ZERO,
+			jobs,
+			user_tools,
+			git: Repositories::default(),
+			audit: None,
+			qa: None,
+			journal: None,
+			image_formats: HashMap::new(),
+			deadline: None,
+			passthrough_env: Vec::new(),
+			stall_timeout: None,
+			on_stall: OnStall::default(),
+			keep_temp: false,
+			gm_batch: false,
+			batch: None,
+			outputs: HashSet::new(),
+			input: None,
+			claims: Rc::default(),
+			interrupted: None,
+			#[cfg(target_family = "unix")]
+			sigint: None,
+			terminal,
+			#[cfg(target_family = "unix")]
+			pause: {
+				use tokio::signal::unix::{signal, SignalKind};
+				signal(SignalKind::user_defined1())?
+			},
+		})
+	}
+
+	/// A context for converting another file alongside those of this one,
+	/// with the same settings and logs, and a fork of its terminal; it stops
+	/// starting tools once `interrupted` is set, and sets it when interrupted
+	/// itself.
+	pub fn worker(&self, interrupted: Rc<Cell<bool>>) -> Result<Self, crate::Error> {
+		Ok(Self {
+			binaries: self.binaries.clone(),
+			identify: self.identify.clone(),
+			tools: Duration::ZERO,
+			cpu: Duration::ZERO,
+			jobs: self.jobs,
+			user_tools: self.user_tools.clone(),
+			git: Repositories::default(),
+			audit: self.audit.clone(),
+			qa: self.qa.clone(),
+			journal: self.journal.clone(),
+			image_formats: self.image_formats.clone(),
+			deadline: self.deadline,
+			passthrough_env: self.passthrough_env.clone(),
+			stall_timeout: self.stall_timeout,
+			on_stall: self.on_stall,
+			keep_temp: self.keep_temp,
+			gm_batch: self.gm_batch,
+			batch: None,
+			outputs: HashSet::new(),
+			input: None,
+			claims: self.claims.clone(),
+			interrupted: Some(interrupted),
+			#[cfg(target_family = "unix")]
+			sigint: None,
+			terminal: self.terminal.fork(),
+			#[cfg(target_family = "unix")]
+			pause: {
+				use tokio::signal::unix::{signal, SignalKind};
+				signal(SignalKind::user_defined1())?
+			},
+		})
+	}
+
+	/// Listens to interrupts given while no tools are running too, which
+	/// otherwise go unnoticed, for [`Context::check_interrupted`].
+	#[cfg(target_family = "unix")]
+	pub fn watch_interrupts(&mut self) -> Result<(), crate::Error> {
+		use tokio::signal::unix::{signal, SignalKind};
+
+		self.sigint = Some(signal(SignalKind::interrupt())?);
+		self.interrupted.get_or_insert_with(Rc::default);
+		Ok(())
+	}
+
+	/// Fails with [`crate::Error::Cancelled`] once interrupted, whether while
+	/// running a tool or in between, e.g. so that no output is left half done.
+	pub fn check_interrupted(&mut self) -> Result<(), crate::Error> {
+		match self.is_interrupted() {
+			true => Err(crate::Error::Cancelled),
+			false => Ok(()),
+		}
+	}
+
+	/// Whether this context or another worker was interrupted, see
+	/// [`Context::worker`] and [`Context::watch_interrupts`].
+	fn is_interrupted(&mut self) -> bool {
+		#[cfg(target_family = "unix")]
+		if let Some(sigint) = &mut self.sigint {
+			use futures_util::FutureExt;
+
+			if let Some(Some(())) = sigint.recv().now_or_never() {
+				debug!("interrupted in between the tools");
+				self.interrupt();
+			}
+		}
+
+		self.interrupted.as_ref().is_some_and(|x| x.get())
 	}
 
-	pub async fn get_output_file(&self, input: impl AsRef<Path>, suffix: impl AsRef<OsStr>) -> Result<PathBuf, crate::Error> {
-		let output = self.output_options.get(input, suffix);
+	fn interrupt(&self) {
+		if let Some(interrupted) = &self.interrupted {
+			interrupted.set(true);
+		}
+	}
+
+	pub async fn get_output_file(
+		&mut self, options: &OutputOptions, input: impl AsRef<Path>, suffix: impl AsRef<OsStr>,
+	) -> Result<PathBuf, crate::Error> {
+		let input = input.as_ref();
+		let suffix = suffix.as_ref();
+		let mut output = options.get(input, suffix);
+		// several inputs may come to the same name in the same directory, with
+		// one clobbering the other otherwise
+		if options.dir.is_some() {
+			let claimed = self.claims.borrow_mut().claim(&output, input, &fsutil::Disk);
+			claimed.map_err(|x| crate::Error::OutputClaimed(output.clone(), x))?;
+		}
+
+		if options.lands_on(input, suffix) {
+			output = temp::file(input, Some(suffix));
+			debug!("output is the input itself; writing `{}` to replace it", output.display());
+		}
+
+		// fail before converting rather than once done, both for the output and
+		// for where it ends up, which may get a longer extension than the input
+		fsutil::check_length(&output)?;
+		if let (true, Some(extension)) = (options.should_replace(), output.extension()) {
+			fsutil::check_length(&input.with_extension(fsutil::matching_extension(input, extension)))?;
+		}
+
 		if let Some(parent) = output.parent() {
 			if !parent.exists() {
 				fs::create_dir_all(parent).await?;
 			}
 		}
 
+		let family = FsFamily::detect(fsutil::parent_dir(&output)).unwrap_or_else(|x| {
+			debug!("unable to detect filesystem of `{}`: {}", output.display(), x);
+			FsFamily::Other
+		});
+		if let Some(Cow::Owned(name)) = output.file_name().map(|x| family.sanitize(x)) {
+			warn!(
+				"`{}` cannot be stored on a {:?} filesystem; writing to `{}` instead",
+				output.display(),
+				family,
+				name.to_string_lossy()
+			);
+			output.set_file_name(name);
+		}
+
+		self.outputs.insert(output.clone());
 		Ok(output)
 	}
 
-	pub fn command(&mut self, name: &'static str) -> Result<Command, crate::Error> {
-		let path = match self.binaries.entry(name) {
+	/// Command running the tool `name`, which only the context runs.
+	pub fn command(&mut self, name: &str) -> Result<ToolCommand, crate::Error> {
+		let path = match self.binaries.entry(name.to_string()) {
 			Entry::Occupied(x) => x.into_mut().as_path(),
 			Entry::Vacant(x) => {
 				let path = match Self::probe_env(name)? {
@@ -57,102 +335,709 @@ impl Context {
 			}
 		};
 
-		Ok(Command::new(path))
+		let mut command = Command::new(path);
+
+		// we parse the output of the tools, so it must not be localized
+		command.env("LC_ALL", "C").env("LANG", "C");
+		for (key, _) in env::vars_os() {
+			let name = key.to_string_lossy();
+			let cleared = Self::CLEARED_ENV.iter().any(|x| match x.strip_suffix('*') {
+				Some(prefix) => name.starts_with(prefix),
+				None => name == *x,
+			});
+
+			if name.starts_with(Self::ENV_PREFIX) || (cleared && !self.passthrough_env.contains(&key)) {
+				command.env_remove(key);
+			}
+		}
+
+		// scratch files of the tools go where ours do
+		command.env("TMPDIR", env::temp_dir());
+		Ok(ToolCommand(command))
+	}
+
+	pub fn tool_time(&self) -> Duration {
+		self.tools
+	}
+
+	pub fn cpu_time(&self) -> Duration {
+		self.cpu
+	}
+
+	pub fn mark(&self) -> Mark {
+		Mark::new(self.tools)
+	}
+
+	/// Runs `command` to completion without any progress reporting, e.g. to
+	/// probe a file, in a scratch directory like [`Context::run_all`].
+	pub async fn output(&mut self, command: ToolCommand) -> Result<Output, crate::Error> {
+		let program = PathBuf::from(command.as_std().get_program());
+		let dir = self.scratch_dir(&program).await?;
+		let command = self.isolate(&command, &env::current_dir()?, &dir);
+		let output = self.output_here(command).await;
+		self.remove_scratch_dir(&dir).await;
+		output
+	}
+
+	/// Like [`Context::output`], in our own working directory, for commands
+	/// given by the user, which may refer to files relative to it.
+	pub async fn output_here(&mut self, command: ToolCommand) -> Result<Output, crate::Error> {
+		debug!("running {:?}", command);
+		let start = ActiveInstant::now();
+		let output = command.piped(Stdio::null()).output().await;
+		self.tools += start.elapsed();
+		self.cpu += rusage::take();
+		Ok(output?)
+	}
+
+	/// Runs `command` to completion, showing its progress.
+	///
+	/// `SIGUSR1` pauses the tool by stopping it, and the next one (or a
+	/// `SIGCONT`) resumes it; the time in between does not count towards any
+	/// timings.
+	#[cfg(target_family = "unix")]
+	pub async fn run(
+		&mut self, name: &str, command: ToolCommand, input: impl AsRef<Path>,
+	) -> Result<Output, crate::Error> {
+		let mut outputs = self.run_commands(name, vec![command], input, None).await?;
+		Ok(outputs.remove(0))
+	}
+
+	/// Like [`Context::run`], for ffmpeg given [`Progress::ARGS`] along with
+	/// `progress`, showing how far it got rather than just that it is running;
+	/// the reports themselves are not shown.
+	#[cfg(target_family = "unix")]
+	pub async fn run_ffmpeg(
+		&mut self, command: ToolCommand, input: impl AsRef<Path>, progress: Option<Progress>,
+	) -> Result<Output, crate::Error> {
+		let mut outputs = self.run_commands("ffmpeg", vec![command], input, progress).await?;
+		Ok(outputs.remove(0))
+	}
+
+	/// Runs `commands` to completion, up to [`Context::jobs`] at once, showing
+	/// their progress as that of `input`. Once one of them fails, the others
+	/// are interrupted, and waited for.
+	///
+	/// Pausing and cancelling works like with [`Context::run`], for all of them
+	/// at once. With [`Context::stall_timeout`], they are shown as stalled
+	/// once none of them shows signs of life for that long, and interrupted if
+	/// [`Context::on_stall`] says so.
+	///
+	/// They run in a scratch directory, deleted once they are done unless
+	/// [`Context::keep_temp`], so that nothing they leave in their working
+	/// directory ends up in ours; the paths among their arguments are made
+	/// absolute for that.
+	#[cfg(target_family = "unix")]
+	pub async fn run_all(
+		&mut self, name: &str, commands: Vec<ToolCommand>, input: impl AsRef<Path>,
+	) -> Result<Vec<Output>, crate::Error> {
+		self.run_commands(name, commands, input, None).await
 	}
 
+	/// [`Context::run_all`], reading the progress reports of ffmpeg with
+	/// `reports`, if given.
 	#[cfg(target_family = "unix")]
-	pub async fn run(&mut self, mut command: Command, input: impl AsRef<Path>) -> Result<Output, crate::Error> {
-		use std::process::Stdio;
-		use std::time::Duration;
+	async fn run_commands(
+		&mut self, name: &str, commands: Vec<ToolCommand>, input: impl AsRef<Path>, reports: Option<Progress>,
+	) -> Result<Vec<Output>, crate::Error> {
 		use nix::sys::signal::{kill, Signal};
 		use nix::unistd::Pid;
-		use tokio::io::{AsyncBufReadExt, BufReader};
-		use tokio::signal;
-		use tokio::time::{self, interval};
+		use tokio::signal::unix::{signal, SignalKind};
+		use tokio::sync::mpsc;
+		use tokio::task::JoinSet;
+		use tokio::time::{self, interval, sleep_until};
+
+		use crate::stall::{self, Signs, Watchdog};
 
 		let input = input.as_ref();
-		command
-			.stdin(Stdio::null())
-			.stdout(Stdio::piped())
-			.stderr(Stdio::piped());
+		let count = commands.len();
+		// listen before spawning, otherwise an early SIGINT kills us instead of
+		// the children
+		let mut sigint = signal(SignalKind::interrupt())?;
+		let mut sigcont = signal(SignalKind::from_raw(Signal::SIGCONT as i32))?;
+		if self.is_interrupted() {
+			debug!("another file was interrupted; not running {}", name);
+			return Err(crate::Error::Cancelled);
+		}
 
-		debug!("spawning {:?}", command);
-		let mut child = command.spawn()?;
-		debug!("spawned {:?}", child);
+		let cwd = env::current_dir()?;
+		let dir = self.scratch_dir(input).await?;
+		let commands: Vec<_> = commands.iter().map(|x| self.isolate(x, &cwd, &dir)).collect();
+		let outputs: Vec<_> = commands.iter().map(|x| stall::output_of(x.as_std())).collect();
+		let mut pending = commands.into_iter().enumerate();
+		let mut statuses = vec![None; count];
 
-		let mut out_buffer = BufReader::new(child.stdout.take().unwrap());
-		let mut stdout = Vec::new();
+		let (sender, mut lines) = mpsc::unbounded_channel();
+		let mut children = JoinSet::new();
+		let mut running = HashMap::new();
+		let mut failure = None;
 
-		let mut err_buffer = BufReader::new(child.stderr.take().unwrap());
-		let mut stderr = Vec::new();
+		let deadline = self.deadline.map(|x| {
+			time::Instant::now() + x.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+		});
 
 		let mut progress = 0;
 		let mut cancel = false;
+		let mut expired = false;
+		let mut paused = false;
+		let mut stalled = false;
+		// interrupted after stalling, rather than cancelled
+		let mut gave_up = false;
+		let start = ActiveInstant::now();
 		self.terminal.start_processing(input);
 
-		let mut interval = interval(Duration::from_millis(100));
+		let spinner = self.terminal.spinner_interval();
+		let mut interval = interval(spinner.unwrap_or(Duration::from_secs(1)));
 		interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
+		let mut watchdog = self.stall_timeout.map(Watchdog::new);
+		let mut checks = time::interval(watchdog.as_ref().map_or(Duration::from_secs(1), Watchdog::period));
+		checks.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+		let mut output_bytes = 0;
+		let mut written = None;
+
+		// sends `signal` to all running children, the first error being the
+		// one that counts
+		let signal_all = |running: &HashMap<usize, Option<u32>>, signal: Signal| {
+			let mut result = Ok(());
+			for id in running.values().flatten() {
+				trace!("sending {} to child {}", signal, id);
+				match kill(Pid::from_raw(*id as i32), signal) {
+					Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+					Err(errno) => result = result.and(Err(errno)),
+				}
+			}
+
+			result
+		};
+
 		loop {
+			while failure.is_none() && !cancel && !paused && running.len() < self.jobs {
+				let Some((index, command)) = pending.next() else {
+					break;
+				};
+
+				debug!("spawning {:?}", command);
+				match command.piped(Stdio::null()).spawn() {
+					Ok(child) => {
+						debug!("spawned {:?}", child);
+						running.insert(index, child.id());
+						children.spawn(watch(index, child, sender.clone()));
+					}
+					Err(x) => {
+						failure = Some(crate::Error::from(x));
+						let _ = signal_all(&running, Signal::SIGINT);
+					}
+				}
+			}
+
+			if running.is_empty() {
+				break;
+			}
+
+			let activity = match (cancel, paused, stalled) {
+				(true, _, _) => Activity::Cancelling,
+				(false, true, _) => Activity::Paused,
+				(false, false, true) => Activity::Stalled,
+				(false, false, false) => Activity::Running,
+			};
+
 			tokio::select! {
-				status = child.wait() => {
-					let status = status?;
-					debug!("child process {}", status);
-					self.terminal.end_processing();
-					if cancel {
-						return Err(crate::Error::Cancelled)
+				Some(joined) = children.join_next() => {
+					let (index, status, err_log) = joined.map_err(std::io::Error::from)?;
+					running.remove(&index);
+					let error = match status {
+						Ok(status) => {
+							debug!("child process {}", status);
+							statuses[index] = Some(status);
+							(!status.success()).then(|| crate::Error::invocation(name, status, &err_log))
+						}
+						Err(x) => Some(crate::Error::from(x)),
+					};
+
+					if let Some(error) = error.filter(|_| failure.is_none() && !cancel) {
+						failure = Some(error);
+						if !running.is_empty() {
+							debug!("interrupting the other {} children", running.len());
+							let _ = signal_all(&running, Signal::SIGINT);
+							if paused {
+								let _ = signal_all(&running, Signal::SIGCONT);
+								paused = false;
+								timing::resume();
+							}
+						}
+					}
+				},
+
+				_ = interval.tick(), if spinner.is_some() => {
+					if !paused && !stalled {
+						progress += 1;
 					}
 
-					return Ok(Output { status, stdout, stderr });
+					self.terminal.update_processing(input, progress, activity);
 				},
 
-				_ = interval.tick() => {
-					progress += 1;
-					self.terminal.update_processing(input, progress, cancel);
+				Some(line) = lines.recv() => {
+					output_bytes += line.len() as u64;
+					match reports.filter(|_| Progress::is_report(&line)) {
+						Some(reports) => {
+							written = Progress::written(&line).or(written);
+							if let Some(done) = reports.done(&line) {
+								self.terminal.set_done(input, done, written);
+								self.terminal.update_processing(input, progress, activity);
+							}
+						}
+						None => self.terminal.write_processing(input, progress, activity, line),
+					}
+				},
+
+				_ = checks.tick(), if watchdog.is_some() && !paused && !cancel => {
+					let mut ids: Vec<_> = running.iter().collect();
+					ids.sort();
+					let signs = Signs {
+						output: output_bytes,
+						sizes: ids.iter().filter_map(|(x, _)| outputs[**x].as_deref()).map(stall::size).collect(),
+						cpu: ids.iter().map(|(_, x)| x.and_then(stall::cpu_time)).collect(),
+					};
+
+					let idle = watchdog.as_mut().and_then(|x| x.check(signs, start.elapsed()));
+					if idle.is_some() != stalled {
+						stalled = idle.is_some();
+						debug!("children {}", if stalled { "stalled" } else { "recovered" });
+						self.terminal.write_stall(input, progress, idle.and(self.stall_timeout));
+					}
+
+					if stalled && self.on_stall == OnStall::Fail {
+						debug!("interrupting {} stalled children", running.len());
+						(cancel, gave_up) = (true, true);
+						if let Err(errno) = signal_all(&running, Signal::SIGINT) {
+							failure.get_or_insert(crate::Error::from(errno));
+						}
+					}
+				},
+
+				_ = self.pause.recv() => {
+					let signal = if paused { Signal::SIGCONT } else { Signal::SIGSTOP };
+					if let Err(errno) = signal_all(&running, signal) {
+						failure.get_or_insert(crate::Error::from(errno));
+						continue;
+					}
+
+					paused = !paused;
+					if paused {
+						debug!("pausing");
+						timing::pause();
+					} else {
+						debug!("resuming");
+						timing::resume();
+					}
+
+					self.terminal.write_pause(input, progress, paused);
 				},
 
-				result = err_buffer.read_until(b'\n', &mut stderr) => {
-					let _ = result?;
-					let err = String::from_utf8_lossy(stderr.as_ref());
-					self.terminal.write_processing(input, progress, cancel, err);
-					stderr.clear();
+				_ = sigcont.recv(), if paused => {
+					trace!("resuming children after SIGCONT");
+					let _ = signal_all(&running, Signal::SIGCONT);
+					paused = false;
+					timing::resume();
+					self.terminal.write_pause(input, progress, paused);
 				},
 
-				result = out_buffer.read_until(b'\n', &mut stdout) => {
-					let _ = result?;
-					let out = String::from_utf8_lossy(stdout.as_ref());
-					self.terminal.write_processing(input, progress, cancel, out);
-					stdout.clear();
+				_ = sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() && !cancel => {
+					debug!("deadline reached; interrupting {} children", running.len());
+					(cancel, expired) = (true, true);
+					let result = signal_all(&running, Signal::SIGINT);
+					if paused {
+						let _ = signal_all(&running, Signal::SIGCONT);
+						paused = false;
+						timing::resume();
+					}
+
+					if let Err(errno) = result {
+						failure.get_or_insert(crate::Error::from(errno));
+					}
 				},
 
-				_ = signal::ctrl_c() => {
+				_ = sigint.recv() => {
 					trace!("forwarding SIGINT");
-					if let Some(id) = child.id() {
-						cancel = true;
-						let Err(errno) = kill(Pid::from_raw(id as i32), Signal::SIGINT) else {
-							continue;
-						};
-
-						if errno == nix::errno::Errno::ESRCH {
-							continue;
-						} else {
-							self.terminal.end_processing();
-							return Err(crate::Error::from(errno));
+					self.interrupt();
+					(cancel, gave_up) = (true, false);
+					let result = signal_all(&running, Signal::SIGINT);
+
+					// a stopped child only gets to handle the signal once it runs
+					if paused {
+						let _ = signal_all(&running, Signal::SIGCONT);
+						paused = false;
+						timing::resume();
+					}
+
+					if let Err(errno) = result {
+						failure.get_or_insert(crate::Error::from(errno));
+					}
+				}
+			}
+		}
+
+		if paused {
+			timing::resume();
+		}
+
+		self.tools += start.elapsed();
+		self.cpu += rusage::take();
+		self.terminal.end_processing();
+		self.remove_scratch_dir(&dir).await;
+		if expired {
+			return Err(crate::Error::DeadlineReached);
+		}
+
+		if let Some(timeout) = self.stall_timeout.filter(|_| gave_up) {
+			return Err(crate::Error::Stalled(timeout));
+		}
+
+		if cancel {
+			return Err(crate::Error::Cancelled);
+		}
+
+		if let Some(x) = failure {
+			return Err(x);
+		}
+
+		// the output has been shown, only the exit status is kept
+		let output = |status| Output { status, stdout: Vec::new(), stderr: Vec::new() };
+		Ok(statuses.into_iter().flatten().map(output).collect())
+	}
+
+	/// Runs `command`, a `gm` command, like [`Context::run`], but in the `gm
+	/// batch` process of [`Context::gm_batch`], started on first use.
+	///
+	/// It runs in a process of its own after all if its arguments cannot be
+	/// given to `gm batch`, or if the batch process is gone, whereupon the
+	/// other images get processes of their own too. Pausing, cancelling and the
+	/// stall watchdog act on the batch process; as it is interrupted, the next
+	/// image starts another one.
+	#[cfg(target_family = "unix")]
+	pub async fn run_batched(&mut self, command: ToolCommand, input: impl AsRef<Path>) -> Result<Output, crate::Error> {
+		use std::os::unix::process::ExitStatusExt;
+		use std::process::ExitStatus;
+		use nix::sys::signal::{kill, Signal};
+		use nix::unistd::Pid;
+		use tokio::signal::unix::{signal, SignalKind};
+		use tokio::time::{self, interval, sleep_until};
+
+		use crate::batch::Reply;
+		use crate::stall::{self, Signs, Watchdog};
+
+		let input = input.as_ref();
+		if !self.gm_batch {
+			return self.run("gm", command, input).await;
+		}
+
+		let mut sigint = signal(SignalKind::interrupt())?;
+		let mut sigcont = signal(SignalKind::from_raw(Signal::SIGCONT as i32))?;
+		if self.is_interrupted() {
+			debug!("another file was interrupted; not running gm");
+			return Err(crate::Error::Cancelled);
+		}
+
+		if self.batch.is_none() {
+			let gm = self.command("gm")?;
+			let dir = self.scratch_dir(Path::new("gm-batch")).await?;
+			match Batch::spawn(gm.piped(Stdio::piped()), dir.clone()) {
+				Ok(x) => self.batch = Some(x),
+				Err(x) => {
+					warn!("failed to start `gm batch`, converting images in a process each: {}", x);
+					self.gm_batch = false;
+					self.remove_scratch_dir(&dir).await;
+					return self.run("gm", command, input).await;
+				}
+			}
+		}
+
+		let dir = self.batch.as_ref().map(|x| x.dir.clone()).unwrap();
+		let isolated = self.isolate(&command, &env::current_dir()?, &dir);
+		let Some(line) = Batch::line(isolated.as_std().get_args()) else {
+			debug!("running {:?} on its own, as `gm batch` cannot be given its arguments", command);
+			return self.run("gm", command, input).await;
+		};
+
+		let batch = self.batch.as_mut().unwrap();
+		debug!("running {:?} in `gm batch`", command);
+		let id = batch.id();
+		let output = stall::output_of(isolated.as_std());
+		let deadline = self.deadline.map(|x| {
+			time::Instant::now() + x.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+		});
+
+		let mut progress = 0;
+		let mut err_log = Vec::new();
+		let mut failure = None;
+		let mut cancel = false;
+		let mut expired = false;
+		let mut paused = false;
+		let mut stalled = false;
+		let mut gave_up = false;
+		let start = ActiveInstant::now();
+		self.terminal.start_processing(input);
+
+		let spinner = self.terminal.spinner_interval();
+		let mut interval = interval(spinner.unwrap_or(Duration::from_secs(1)));
+		interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+		let mut watchdog = self.stall_timeout.map(Watchdog::new);
+		let mut checks = time::interval(watchdog.as_ref().map_or(Duration::from_secs(1), Watchdog::period));
+		checks.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+		let mut output_bytes = 0;
+
+		let send_signal = |signal: Signal| {
+			let Some(id) = id else {
+				return Ok(());
+			};
+
+			trace!("sending {} to `gm batch` {}", signal, id);
+			match kill(Pid::from_raw(id as i32), signal) {
+				Ok(()) | Err(nix::errno::Errno::ESRCH) => Ok(()),
+				Err(errno) => Err(errno),
+			}
+		};
+
+		// a batch process which cannot be written to is gone, which its replies
+		// tell as well
+		let sent = batch.send(&line).await.is_ok();
+		let reply = loop {
+			if !sent {
+				break None;
+			}
+
+			let activity = match (cancel, paused, stalled) {
+				(true, _, _) => Activity::Cancelling,
+				(false, true, _) => Activity::Paused,
+				(false, false, true) => Activity::Stalled,
+				(false, false, false) => Activity::Running,
+			};
+
+			tokio::select! {
+				reply = batch.recv() => match reply {
+					Some(Reply::Stdout(line)) => {
+						output_bytes += line.len() as u64;
+						self.terminal.write_processing(input, progress, activity, line);
+					}
+					Some(Reply::Stderr(line)) => {
+						output_bytes += line.len() as u64;
+						err_log.extend_from_slice(line.as_bytes());
+						if err_log.len() > Self::ERR_LOG_SIZE {
+							err_log.drain(..err_log.len() - Self::ERR_LOG_SIZE);
+						}
+
+						self.terminal.write_processing(input, progress, activity, line);
+					}
+					x => break x,
+				},
+
+				_ = interval.tick(), if spinner.is_some() => {
+					if !paused && !stalled {
+						progress += 1;
+					}
+
+					self.terminal.update_processing(input, progress, activity);
+				},
+
+				_ = checks.tick(), if watchdog.is_some() && !paused && !cancel => {
+					let signs = Signs {
+						output: output_bytes,
+						sizes: output.as_deref().map(stall::size).into_iter().collect(),
+						cpu: vec![id.and_then(stall::cpu_time)],
+					};
+
+					let idle = watchdog.as_mut().and_then(|x| x.check(signs, start.elapsed()));
+					if idle.is_some() != stalled {
+						stalled = idle.is_some();
+						debug!("`gm batch` {}", if stalled { "stalled" } else { "recovered" });
+						self.terminal.write_stall(input, progress, idle.and(self.stall_timeout));
+					}
+
+					if stalled && self.on_stall == OnStall::Fail {
+						debug!("interrupting stalled `gm batch`");
+						(cancel, gave_up) = (true, true);
+						if let Err(errno) = send_signal(Signal::SIGINT) {
+							failure.get_or_insert(crate::Error::from(errno));
 						}
 					}
+				},
+
+				_ = self.pause.recv() => {
+					if let Err(errno) = send_signal(if paused { Signal::SIGCONT } else { Signal::SIGSTOP }) {
+						failure.get_or_insert(crate::Error::from(errno));
+						continue;
+					}
+
+					paused = !paused;
+					if paused {
+						debug!("pausing");
+						timing::pause();
+					} else {
+						debug!("resuming");
+						timing::resume();
+					}
+
+					self.terminal.write_pause(input, progress, paused);
+				},
+
+				_ = sigcont.recv(), if paused => {
+					trace!("resuming `gm batch` after SIGCONT");
+					let _ = send_signal(Signal::SIGCONT);
+					paused = false;
+					timing::resume();
+					self.terminal.write_pause(input, progress, paused);
+				},
+
+				_ = sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() && !cancel => {
+					debug!("deadline reached; interrupting `gm batch`");
+					(cancel, expired) = (true, true);
+					if let Err(errno) = send_signal(Signal::SIGINT) {
+						failure.get_or_insert(crate::Error::from(errno));
+					}
+				},
+
+				_ = sigint.recv() => {
+					trace!("forwarding SIGINT");
+					if let Some(interrupted) = &self.interrupted {
+						interrupted.set(true);
+					}
+
+					(cancel, gave_up) = (true, false);
+					if let Err(errno) = send_signal(Signal::SIGINT) {
+						failure.get_or_insert(crate::Error::from(errno));
+					}
 				}
 			}
+
+			// a stopped process only gets to handle the signal once it runs
+			if cancel && paused {
+				let _ = send_signal(Signal::SIGCONT);
+				paused = false;
+				timing::resume();
+			}
+		};
+
+		if paused {
+			timing::resume();
+		}
+
+		self.tools += start.elapsed();
+		self.cpu += rusage::take();
+		self.terminal.end_processing();
+		if cancel || failure.is_some() || reply.is_none() {
+			// interrupted along with its command, or with a command it may be
+			// stuck on
+			if let Some(batch) = self.batch.take() {
+				let dir = batch.dir.clone();
+				batch.kill().await;
+				self.remove_scratch_dir(&dir).await;
+			}
+		}
+
+		if expired {
+			return Err(crate::Error::DeadlineReached);
+		}
+
+		if let Some(timeout) = self.stall_timeout.filter(|_| gave_up) {
+			return Err(crate::Error::Stalled(timeout));
+		}
+
+		if cancel {
+			return Err(crate::Error::Cancelled);
+		}
+
+		if let Some(x) = failure {
+			return Err(x);
+		}
+
+		match reply {
+			Some(Reply::Pass) => Ok(Output { status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() }),
+			// what the command would have exited with on its own
+			Some(_) => Err(crate::Error::invocation("gm", ExitStatus::from_raw(1 << 8), &err_log)),
+			None => {
+				warn!("`gm batch` exited unexpectedly; converting images in a process each from now on");
+				self.gm_batch = false;
+				self.run("gm", command, input).await
+			}
 		}
 	}
 
+	/// Lets the `gm batch` process of [`Context::run_batched`] finish, if it
+	/// has been started.
+	pub async fn end_batch(&mut self) {
+		if let Some(batch) = self.batch.take() {
+			let dir = batch.dir.clone();
+			batch.close().await;
+			self.remove_scratch_dir(&dir).await;
+		}
+	}
+
+	/// `command` set to run in `dir`, with the paths among its arguments, which
+	/// are relative to our working directory `cwd`, made absolute.
+	fn isolate(&self, command: &ToolCommand, cwd: &Path, dir: &Path) -> ToolCommand {
+		let command = command.as_std();
+		let mut isolated = Command::new(command.get_program());
+		for arg in command.get_args() {
+			let known = |x: &Path| self.outputs.contains(x) || self.input.as_deref() == Some(x);
+			match fsutil::absolute_arg(arg, cwd, known) {
+				Some(x) => isolated.arg(x),
+				None => isolated.arg(arg),
+			};
+		}
+
+		for (key, value) in command.get_envs() {
+			match value {
+				Some(x) => isolated.env(key, x),
+				None => isolated.env_remove(key),
+			};
+		}
+
+		isolated.current_dir(dir);
+		ToolCommand(isolated)
+	}
+
+	/// Creates a directory for the tools run for `input` to work in.
+	async fn scratch_dir(&self, input: &Path) -> Result<PathBuf, crate::Error> {
+		let dir = temp::scratch_file(input, None);
+		trace!("creating scratch directory `{}`", dir.display());
+		fs::create_dir(&dir).await?;
+		Ok(dir)
+	}
+
+	async fn remove_scratch_dir(&self, dir: &Path) {
+		if self.keep_temp {
+			debug!("keeping scratch directory `{}`", dir.display());
+			return;
+		}
+
+		trace!("deleting scratch directory `{}`", dir.display());
+		if let Err(x) = fs::remove_dir_all(dir).await {
+			error!("failed to delete scratch directory `{}`: {}", dir.display(), x);
+		}
+	}
+
+	/// Most tools run at once by [`Context::run_all`].
+	pub fn jobs(&self) -> usize {
+		self.jobs
+	}
+
 	pub async fn identify_file(&self, path: impl AsRef<Path>) -> Result<Option<String>, crate::Error> {
     	let path = path.as_ref();
     	trace!("identifying file `{}`", path.display());
 
     	let mut buffer = [0; 1024];
-    	let mut f = OpenOptions::new().read(true).open(path).await?;
-    	let count = f.read(&mut buffer).await?;
-    	let mime = self.identify_buffer(&buffer[..count])?;
+    	let mut f = OpenOptions::new()
+    		.read(true)
+    		.open(path)
+    		.await
+    		.map_err(crate::Error::input_io(Stage::Identify, path))?;
+    	let count = f.read(&mut buffer).await.map_err(crate::Error::input_io(Stage::Identify, path))?;
+    	let mime = match self.identify.tier() {
+    		Tier::Extension => identify::by_extension(path).map(String::from),
+    		_ => self.identify_buffer(&buffer[..count]).await?,
+    	};
 
     	if let Some(mime) = mime.as_deref() {
     		debug!("identified file `{}` as `{}`", path.display(), mime);
@@ -163,10 +1048,10 @@ impl Context {
     	Ok(mime)
 	}
 
-	fn identify_buffer(&self, buffer: impl AsRef<[u8]>) -> Result<Option<String>, crate::Error> {
+	async fn identify_buffer(&self, buffer: impl AsRef<[u8]>) -> Result<Option<String>, crate::Error> {
     	let buffer = buffer.as_ref();
     	trace!("identifying {} bytes using libmagic", buffer.len());
-    	let mime = self.cookie.buffer(buffer)?;
+    	let mime = self.identify.buffer(buffer.to_vec()).await?;
     	debug!("libmagic returned `{}`", mime);
 
     	if !mime.contains('/') {
@@ -180,8 +1065,8 @@ impl Context {
     	Ok(Some(mime))
 	}
 
-	fn probe_env(name: &'static str) -> Result<Option<PathBuf>, crate::Error> {
-		let var_name = format!("RAY_BIN_{}", name.to_ascii_uppercase());
+	fn probe_env(name: &str) -> Result<Option<PathBuf>, crate::Error> {
+		let var_name = format!("{}BIN_{}", Self::ENV_PREFIX, name.to_ascii_uppercase());
 		trace!("checking for binary `{}` in environment (`{}`)...", name, var_name);
 
 		let Some(path) = env::var_os(var_name.as_str()) else {
@@ -196,7 +1081,7 @@ impl Context {
 		Ok(Some(path))
 	}
 
-	fn probe_system(name: &'static str) -> Result<PathBuf, crate::Error> {
+	fn probe_system(name: &str) -> Result<PathBuf, crate::Error> {
 		trace!("probing for `{}` binary...", name);
 		match which::which(name) {
 			Ok(x) => {
@@ -205,8 +1090,54 @@ impl Context {
 				Ok(x)
 			}
 
-			Err(which::Error::CannotFindBinaryPath) => Err(crate::Error::BinaryNotFound(name)),
+			Err(which::Error::CannotFindBinaryPath) => Err(crate::Error::BinaryNotFound(name.to_string())),
 			Err(x) => Err(crate::Error::from(x)),
 		}
 	}
 }
+
+/// Forwards the output of `child` line by line until it exits, returning its
+/// exit status along with the end of its standard error output.
+#[cfg(target_family = "unix")]
+async fn watch(
+	index: usize, mut child: tokio::process::Child, lines: tokio::sync::mpsc::UnboundedSender<String>,
+) -> (usize, std::io::Result<std::process::ExitStatus>, Vec<u8>) {
+	use tokio::io::{AsyncBufReadExt, BufReader};
+
+	let mut out_buffer = BufReader::new(child.stdout.take().unwrap());
+	let mut stdout = Vec::new();
+	let mut err_buffer = BufReader::new(child.stderr.take().unwrap());
+	let mut stderr = Vec::new();
+	let mut err_log = Vec::new();
+	let mut out_done = false;
+	let mut err_done = false;
+
+	loop {
+		tokio::select! {
+			status = child.wait() => return (index, status, err_log),
+
+			result = err_buffer.read_until(b'\n', &mut stderr), if !err_done => {
+				match result {
+					Ok(0) | Err(_) => err_done = true,
+					Ok(_) => {
+						let _ = lines.send(String::from_utf8_lossy(&stderr).into_owned());
+						err_log.append(&mut stderr);
+						if err_log.len() > Context::ERR_LOG_SIZE {
+							err_log.drain(..err_log.len() - Context::ERR_LOG_SIZE);
+						}
+					}
+				}
+			},
+
+			result = out_buffer.read_until(b'\n', &mut stdout), if !out_done => {
+				match result {
+					Ok(0) | Err(_) => out_done = true,
+					Ok(_) => {
+						let _ = lines.send(String::from_utf8_lossy(&stdout).into_owned());
+						stdout.clear();
+					}
+				}
+			},
+		}
+	}
+}
@@ -0,0 +1,364 @@
+use std::cell::Cell;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use nix::sys::stat::{umask, Mode};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::{JoinSet, LocalSet};
+use tokio::time::{self, Instant};
+use tracing::{debug, error, trace, warn};
+
+use crate::context::Context;
+use crate::expand::Expansion;
+use crate::options::{DaemonOptions, Options};
+use crate::terminal::Terminal;
+use crate::{config, Run};
+
+/// What a client asks the daemon to do: a run, as if `args` were given in
+/// `cwd`
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+	args: Vec<String>,
+	cwd: PathBuf,
+	/// Interval to animate progress at, in milliseconds, if the client has a
+	/// terminal to show it on
+	spinner: Option<u64>,
+}
+
+/// What the daemon tells a client about its run, one on each line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+	/// What the run wrote to its terminal
+	Output(String),
+	/// What the run wrote to the standard error output, like fatal errors
+	Error(String),
+	/// The run is over, with this exit status
+	Exit(u8),
+}
+
+/// `shrink-ray.sock` in `$XDG_RUNTIME_DIR`, which only the user can get at,
+/// falling back to a name of their own in the temporary directory.
+fn default_socket() -> PathBuf {
+	match env::var_os("XDG_RUNTIME_DIR").filter(|x| !x.is_empty()) {
+		Some(x) => PathBuf::from(x).join("shrink-ray.sock"),
+		None => env::temp_dir().join(format!("shrink-ray-{}.sock", nix::unistd::getuid())),
+	}
+}
+
+/// Hands the run of `options` over to the daemon listening on `socket`,
+/// relaying what it tells; returns the exit status of the run.
+pub async fn hand_over(socket: Option<&Path>, options: &Options) -> Result<u8, crate::Error> {
+	let socket = socket.map(Path::to_path_buf).unwrap_or_else(default_socket);
+	let spinner = options.spinner().map(|x| x.as_millis() as u64);
+	let request = Request { args: args(&options.argv)?, cwd: env::current_dir()?, spinner };
+
+	trace!("handing the run over to the daemon at `{}`", socket.display());
+	let stream = UnixStream::connect(&socket).await.map_err(|x| crate::Error::DaemonUnreachable(socket.clone(), x))?;
+	let (reader, mut writer) = stream.into_split();
+	send(&mut writer, &request).await?;
+
+	let mut lines = BufReader::new(reader).lines();
+	let mut stdout = tokio::io::stdout();
+	while let Some(line) = lines.next_line().await? {
+		match serde_json::from_str(&line) {
+			Ok(Reply::Output(x)) => {
+				stdout.write_all(x.as_bytes()).await?;
+				stdout.flush().await?;
+			}
+			Ok(Reply::Error(x)) => eprintln!("{}", x),
+			Ok(Reply::Exit(x)) => return Ok(x),
+			Err(x) => return Err(crate::Error::DaemonReply(x.to_string())),
+		}
+	}
+
+	Err(crate::Error::DaemonReply("it hung up before the run was over".into()))
+}
+
+/// The arguments of the run, but for `--via-daemon`.
+fn args(argv: &[OsString]) -> Result<Vec<String>, crate::Error> {
+	let mut args = Vec::with_capacity(argv.len());
+	let mut options = true;
+	for arg in argv {
+		let Some(arg) = arg.to_str() else {
+			return Err(crate::Error::NotUnicode(arg.to_string_lossy().into_owned()));
+		};
+
+		options &= arg != "--";
+		if options && (arg == "--via-daemon" || arg.starts_with("--via-daemon=")) {
+			continue;
+		}
+
+		args.push(arg.to_string());
+	}
+
+	Ok(args)
+}
+
+async fn send<T: Serialize>(writer: &mut OwnedWriteHalf, message: &T) -> io::Result<()> {
+	let mut line = serde_json::to_string(message)?;
+	line.push('\n');
+	writer.write_all(line.as_bytes()).await
+}
+
+/// What the connections share
+struct Daemon {
+	/// Serves the runs one after the other, across which it keeps what it
+	/// learns about the tools, and the `gm batch` process
+	context: Mutex<Context>,
+	/// Working directory of the daemon, to go back to between runs
+	home: PathBuf,
+	/// Set once the daemon shuts down, to start no more runs, and no more
+	/// files of the runs going on
+	shutdown: Rc<Cell<bool>>,
+}
+
+/// Serves runs handed over with `--via-daemon` on the socket of `options`
+/// until no run has been handed over for `--idle-timeout`, or until SIGTERM
+/// or SIGINT, after which the files being converted are finished.
+pub async fn serve(options: &DaemonOptions, context: Context) -> Result<(), crate::Error> {
+	let socket = options.socket.clone().unwrap_or_else(default_socket);
+	let listener = listen(&socket).await?;
+	let daemon = Rc::new(Daemon { context: Mutex::new(context), home: env::current_dir()?, shutdown: Rc::default() });
+	daemon.context.lock().await.terminal.write_note(format!("listening on `{}`", socket.display()));
+
+	let local = LocalSet::new();
+	let result = local.run_until(accept(options, &listener, &daemon)).await;
+
+	trace!("deleting socket `{}`", socket.display());
+	if let Err(x) = std::fs::remove_file(&socket) {
+		error!("failed to delete socket `{}`: {}", socket.display(), x);
+	}
+
+	daemon.context.lock().await.end_batch().await;
+	result
+}
+
+/// Binds `socket`, for the user alone, in place of any left behind by a
+/// daemon which is gone.
+async fn listen(socket: &Path) -> Result<UnixListener, crate::Error> {
+	match std::fs::symlink_metadata(socket) {
+		Ok(x) if !x.file_type().is_socket() => return Err(crate::Error::SocketInTheWay(socket.to_path_buf())),
+		Ok(_) => {
+			if UnixStream::connect(socket).await.is_ok() {
+				return Err(crate::Error::DaemonRunning(socket.to_path_buf()));
+			}
+
+			debug!("replacing socket `{}` left behind", socket.display());
+			std::fs::remove_file(socket)?;
+		}
+		Err(x) if x.kind() == io::ErrorKind::NotFound => {}
+		Err(x) => return Err(x.into()),
+	}
+
+	// created without any permissions for others in the first place
+	let mask = umask(Mode::from_bits_truncate(0o177));
+	let listener = UnixListener::bind(socket);
+	umask(mask);
+	Ok(listener?)
+}
+
+async fn accept(options: &DaemonOptions, listener: &UnixListener, daemon: &Rc<Daemon>) -> Result<(), crate::Error> {
+	let mut terminate = signal(SignalKind::terminate())?;
+	let mut interrupt = signal(SignalKind::interrupt())?;
+	let mut clients = JoinSet::new();
+	let mut idle_since = Instant::now();
+	loop {
+		tokio::select! {
+			accepted = listener.accept() => {
+				let (stream, _) = match accepted {
+					Ok(x) => x,
+					Err(x) => {
+						warn!("failed to accept a connection: {}", x);
+						continue;
+					}
+				};
+
+				if clients.len() as u64 >= options.max_clients {
+					debug!("turning a client away");
+					let message = format!("the daemon is serving {} runs already", clients.len());
+					refuse(stream, message).await;
+					continue;
+				}
+
+				clients.spawn_local(client(stream, daemon.clone()));
+			}
+			Some(_) = clients.join_next() => {
+				if clients.is_empty() {
+					idle_since = Instant::now();
+				}
+			}
+			_ = time::sleep_until(idle_since + options.idle_timeout), if clients.is_empty() => {
+				let idle = humantime::format_duration(options.idle_timeout);
+				daemon.context.lock().await.terminal.write_note(format!("idle for {}; shutting down", idle));
+				return Ok(());
+			}
+			_ = terminate.recv() => break,
+			_ = interrupt.recv() => break,
+		}
+	}
+
+	daemon.shutdown.set(true);
+	if !clients.is_empty() {
+		let note = format!("shutting down once the files of {} runs are converted", clients.len());
+		daemon.context.lock().await.terminal.write_note(note);
+	}
+
+	while clients.join_next().await.is_some() {}
+	Ok(())
+}
+
+async fn refuse(stream: UnixStream, message: String) {
+	let (_, mut writer) = stream.into_split();
+	for reply in [Reply::Error(message), Reply::Exit(1)] {
+		if let Err(x) = send(&mut writer, &reply).await {
+			debug!("failed to turn a client away: {}", x);
+			return;
+		}
+	}
+}
+
+/// Serves the run a client hands over on `stream`.
+async fn client(stream: UnixStream, daemon: Rc<Daemon>) {
+	let (reader, mut writer) = stream.into_split();
+	let request = match BufReader::new(reader).lines().next_line().await {
+		Ok(Some(x)) => serde_json::from_str::<Request>(&x).map_err(|x| x.to_string()),
+		Ok(None) => return,
+		Err(x) => Err(x.to_string()),
+	};
+
+	let replies = match request {
+		Ok(request) => handle(request, &daemon, &mut writer).await,
+		Err(x) => vec![Reply::Error(format!("invalid request: {}", x)), Reply::Exit(2)],
+	};
+
+	for reply in replies {
+		if let Err(x) = send(&mut writer, &reply).await {
+			debug!("client went away: {}", x);
+			return;
+		}
+	}
+}
+
+/// Runs what `request` asks for, relaying the output of the run on `writer`
+/// as it goes; returns the replies left to end with.
+async fn handle(request: Request, daemon: &Daemon, writer: &mut OwnedWriteHalf) -> Vec<Reply> {
+	let args = request.args.into_iter().map(OsString::from).collect();
+	let options = match config::parse_merged(args) {
+		Ok(x) if x.command.is_some() || x.tar || x.via_daemon.is_some() => {
+			return vec![Reply::Error("only plain runs can be handed over to the daemon".into()), Reply::Exit(2)];
+		}
+		Ok(x) => x,
+		Err(x) => return vec![Reply::Error(x.to_string().trim_end().to_string()), Reply::Exit(2)],
+	};
+
+	let mut context = daemon.context.lock().await;
+	if daemon.shutdown.get() {
+		return vec![Reply::Error("the daemon is shutting down".into()), Reply::Exit(1)];
+	}
+
+	// the runs are served one at a time, so they can each have theirs
+	if let Err(x) = env::set_current_dir(&request.cwd) {
+		return vec![Reply::Error(format!("failed to enter `{}`: {}", request.cwd.display(), x)), Reply::Exit(1)];
+	}
+
+	debug!("serving a run in `{}`", request.cwd.display());
+	let (sender, mut receiver) = mpsc::unbounded_channel();
+	let mut terminal = Terminal::new(Relay(sender), request.spinner.map(Duration::from_millis), options.units);
+	terminal.set_path_style(options.path_style());
+	terminal.set_format(options.format);
+	let own = std::mem::replace(&mut context.terminal, terminal);
+
+	let work = async {
+		let result = run(&options, &mut context, daemon.shutdown.clone()).await;
+		// ending the relay
+		context.terminal = own;
+		context.qa = None;
+		context.audit = None;
+		context.journal = None;
+		result
+	};
+
+	let relay = async {
+		while let Some(mut output) = receiver.recv().await {
+			while let Ok(x) = receiver.try_recv() {
+				output.push_str(&x);
+			}
+
+			if let Err(x) = send(writer, &Reply::Output(output)).await {
+				// so that the run sees its output is closed
+				debug!("client went away: {}", x);
+				break;
+			}
+		}
+	};
+
+	let ((error, status), ()) = tokio::join!(work, relay);
+	if let Err(x) = env::set_current_dir(&daemon.home) {
+		warn!("failed to go back to `{}`: {}", daemon.home.display(), x);
+	}
+
+	error.map(Reply::Error).into_iter().chain([Reply::Exit(status)]).collect()
+}
+
+/// Converts the inputs of `options` like a run of its own would; returns the
+/// fatal error it stopped at, if any, and its exit status.
+async fn run(options: &Options, context: &mut Context, shutdown: Rc<Cell<bool>>) -> (Option<String>, u8) {
+	if let Err(x) = crate::configure(context, options) {
+		return (Some(x.to_string()), 1);
+	}
+
+	let Expansion { files: inputs, done, .. } = crate::expand_inputs(options, context).await;
+	let mut run = Run::new(options, &inputs);
+	run.done = options.write_done_markers.then_some(done);
+	run.shutdown = shutdown;
+	let flow = crate::convert(options, context, &mut run, &inputs).await;
+	run.finish(options, context).await;
+	(run.aborted.take().map(|(_, x)| x), run.exit_status(flow))
+}
+
+/// Passes what the terminal of a run writes on to the client
+struct Relay(mpsc::UnboundedSender<String>);
+
+impl Write for Relay {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let text = String::from_utf8_lossy(buf).into_owned();
+		self.0.send(text).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsString;
+
+	use super::{args, Reply};
+
+	#[test]
+	fn leaves_out_via_daemon() {
+		let argv = ["shrink-ray", "--via-daemon", "-s", "--via-daemon=/run/x.sock", "a.jpg", "--", "--via-daemon"];
+		let argv: Vec<_> = argv.iter().map(OsString::from).collect();
+		assert_eq!(args(&argv).unwrap(), ["shrink-ray", "-s", "a.jpg", "--", "--via-daemon"]);
+	}
+
+	#[test]
+	fn puts_replies_on_lines_of_json() {
+		let replies = [Reply::Output("Shrunk a.jpg\n".into()), Reply::Error("failed".into()), Reply::Exit(1)];
+		let lines: Vec<_> = replies.iter().map(|x| serde_json::to_string(x).unwrap()).collect();
+		assert_eq!(lines, [r#"{"output":"Shrunk a.jpg\n"}"#, r#"{"error":"failed"}"#, r#"{"exit":1}"#]);
+	}
+}
@@ -0,0 +1,117 @@
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+
+use crate::since;
+
+/// Parses the argument of `--max-runtime`, like `4h30m` or `90min`.
+pub fn parse_runtime(value: &str) -> Result<Duration, String> {
+	match humantime::parse_duration(value.trim()) {
+		Ok(x) if x.is_zero() => Err("the runtime must not be zero".into()),
+		Ok(x) => Ok(x),
+		Err(_) => Err(format!("`{}` is not a duration, like `4h30m`", value)),
+	}
+}
+
+/// Parses the argument of `--deadline`, which is either an absolute time
+/// (RFC 3339, or a local date/time without an offset), or a local time of day
+/// (`HH:MM`), the next time the clock shows it.
+pub fn parse(value: &str) -> Result<SystemTime, String> {
+	parse_at(value, Local::now())
+}
+
+fn parse_at(value: &str, now: DateTime<Local>) -> Result<SystemTime, String> {
+	let value = value.trim();
+	if let Ok(x) = DateTime::parse_from_rfc3339(value) {
+		return Ok(x.into());
+	}
+
+	if let Some(x) = since::local_time(value) {
+		return Ok(x);
+	}
+
+	let Some(time) = ["%H:%M", "%H:%M:%S"].iter().find_map(|x| NaiveTime::parse_from_str(value, x).ok()) else {
+		return Err("expected an RFC 3339 timestamp or a time of day (e.g. `07:00`)".into());
+	};
+
+	// later today, or else tomorrow; on a DST change the earlier of two
+	// instants is used, or the hour that is skipped is skipped as well
+	let mut day = now.date_naive();
+	for _ in 0..3 {
+		if let Some(x) = Local.from_local_datetime(&day.and_time(time)).earliest().filter(|x| *x > now) {
+			return Ok(x.into());
+		}
+
+		day = day.succ_opt().ok_or("the deadline is too far away")?;
+	}
+
+	Err(format!("`{}` does not occur on the next days", value))
+}
+
+/// Time to leave for one more file, given the average time taken by those
+/// processed so far, so that it can finish before the limit; files vary, so
+/// there is some slack on top.
+pub fn margin(average: Duration) -> Duration {
+	average.saturating_mul(3) / 2
+}
+
+/// Whether there is enough of `left` for another file, given the `average`
+/// time taken by those processed so far, if any.
+pub fn has_time_for_next(left: Duration, average: Option<Duration>) -> bool {
+	match average {
+		Some(x) => left > margin(x),
+		None => !left.is_zero(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, SystemTime};
+
+	use chrono::{Local, TimeZone};
+
+	use super::{has_time_for_next, margin, parse_at, parse_runtime};
+
+	#[test]
+	fn parses_runtimes() {
+		assert_eq!(parse_runtime("4h30m"), Ok(Duration::from_secs(4 * 3600 + 30 * 60)));
+		assert_eq!(parse_runtime("90min"), Ok(Duration::from_secs(90 * 60)));
+		assert_eq!(parse_runtime(" 20s "), Ok(Duration::from_secs(20)));
+		assert!(parse_runtime("0s").is_err());
+		assert!(parse_runtime("soon").is_err());
+	}
+
+	#[test]
+	fn parses_deadlines() {
+		let now = Local.with_ymd_and_hms(2024, 3, 12, 2, 0, 0).unwrap();
+		let at = |h, m| SystemTime::from(Local.with_ymd_and_hms(2024, 3, 12, h, m, 0).unwrap());
+		let tomorrow = |h, m| SystemTime::from(Local.with_ymd_and_hms(2024, 3, 13, h, m, 0).unwrap());
+
+		assert_eq!(parse_at("07:00", now), Ok(at(7, 0)));
+		assert_eq!(parse_at("07:00:30", now), Ok(at(7, 0) + Duration::from_secs(30)));
+		assert_eq!(parse_at("01:30", now), Ok(tomorrow(1, 30)));
+		// the time right now has passed already
+		assert_eq!(parse_at("02:00", now), Ok(tomorrow(2, 0)));
+		assert_eq!(parse_at("2024-03-12 06:45", now), Ok(at(6, 45)));
+
+		let utc = SystemTime::UNIX_EPOCH + Duration::from_secs(1_710_223_200);
+		assert_eq!(parse_at("2024-03-12T06:00:00Z", now), Ok(utc));
+
+		assert!(parse_at("25:00", now).is_err());
+		assert!(parse_at("tonight", now).is_err());
+	}
+
+	#[test]
+	fn leaves_margin_for_next_file() {
+		assert_eq!(margin(Duration::from_secs(60)), Duration::from_secs(90));
+
+		let minute = Some(Duration::from_secs(60));
+		assert!(has_time_for_next(Duration::from_secs(91), minute));
+		assert!(!has_time_for_next(Duration::from_secs(90), minute));
+		assert!(!has_time_for_next(Duration::from_secs(30), minute));
+
+		// nothing to go by before the first file
+		assert!(has_time_for_next(Duration::from_secs(1), None));
+		assert!(!has_time_for_next(Duration::ZERO, None));
+	}
+}
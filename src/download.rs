@@ -0,0 +1,281 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tracing::{error, trace, warn};
+
+#[cfg(feature = "http")]
+use crate::context::Context;
+#[cfg(feature = "http")]
+use crate::options::Units;
+
+/// Attempts at downloading a file, each resuming where the previous one
+/// stopped if the server allows it
+#[cfg(feature = "http")]
+const MAX_ATTEMPTS: usize = 3;
+/// MIME types which say nothing about what a file is
+const GENERIC_TYPES: &[&str] = &["application/octet-stream", "binary/octet-stream", "application/binary"];
+
+/// Whether `input` is a URL to download, rather than a path.
+pub fn is_url(input: &Path) -> bool {
+	let input = input.to_string_lossy();
+	["http://", "https://"].iter().any(|x| input.get(..x.len()).is_some_and(|y| y.eq_ignore_ascii_case(x)))
+}
+
+/// File downloaded into a directory of its own, so that it keeps its name
+#[derive(Debug)]
+pub struct Download {
+	dir: PathBuf,
+	pub path: PathBuf,
+	/// MIME type the server says the file has
+	pub content_type: Option<String>,
+}
+
+impl Download {
+	/// Warns if the server said the file is something other than `mime`, as
+	/// identified by libmagic, e.g. when it sent an error page.
+	pub fn check_type(&self, url: &str, mime: &str) {
+		let Some(claimed) = self.content_type.as_deref().filter(|x| !GENERIC_TYPES.contains(x)) else {
+			return;
+		};
+
+		if claimed != mime {
+			warn!("`{}` was served as {}, but it looks like {}", url, claimed, mime);
+		}
+	}
+
+	pub async fn remove(self) {
+		trace!("deleting download directory `{}`", self.dir.display());
+		if let Err(x) = fs::remove_dir_all(&self.dir).await {
+			error!("failed to delete download directory `{}`: {}", self.dir.display(), x);
+		}
+	}
+}
+
+/// Name to give the file downloaded from `url`, after the last segment of
+/// its path.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn file_name(url: &str) -> String {
+	let rest = url.split_once("://").map_or(url, |x| x.1);
+	let rest = rest.split(['?', '#']).next().unwrap_or_default();
+	let path = rest.split_once('/').map_or("", |x| x.1);
+	let segment = path.rsplit('/').find(|x| !x.is_empty()).unwrap_or_default();
+	let name = decode(segment).replace(['/', '\\', '\0'], "_");
+	match name.trim_matches('.') {
+		"" => "download".into(),
+		_ => name,
+	}
+}
+
+/// Decodes the `%XX` escapes of a segment of a URL.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn decode(segment: &str) -> String {
+	let mut bytes = Vec::with_capacity(segment.len());
+	let mut rest = segment.as_bytes();
+	while let Some((&byte, tail)) = rest.split_first() {
+		let escaped = tail.get(..2).and_then(|x| u8::from_str_radix(std::str::from_utf8(x).ok()?, 16).ok());
+		match escaped.filter(|_| byte == b'%') {
+			Some(x) => {
+				bytes.push(x);
+				rest = &tail[2..];
+			}
+			None => {
+				bytes.push(byte);
+				rest = tail;
+			}
+		}
+	}
+
+	String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Downloads `url` to the temporary directory, showing the progress, and
+/// failing if it is larger than `max_size`.
+#[cfg(feature = "http")]
+pub async fn fetch(context: &mut Context, url: &str, max_size: u64, units: Units) -> Result<Download, crate::Error> {
+	use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+	use std::sync::Arc;
+	use std::time::Duration;
+
+	use tokio::signal::unix::{signal, SignalKind};
+	use tokio::time::{self, interval};
+
+	use crate::temp;
+	use crate::terminal::Activity;
+
+	let dir = temp::scratch_file(Path::new("shrink-ray"), None);
+	fs::create_dir(&dir).await?;
+	let path = dir.join(file_name(url));
+
+	// listen before starting, otherwise an early SIGINT kills us
+	let mut sigint = signal(SignalKind::interrupt())?;
+	let received = Arc::new(AtomicU64::new(0));
+	let cancel = Arc::new(AtomicBool::new(false));
+	let mut task = {
+		let (url, path, received, cancel) = (url.to_string(), path.clone(), received.clone(), cancel.clone());
+		tokio::task::spawn_blocking(move || get(&url, &path, max_size, &received, &cancel))
+	};
+
+	let mut interval = interval(context.terminal.spinner_interval().unwrap_or(Duration::from_secs(1)));
+	interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+	let mut progress = 0;
+	context.terminal.start_processing(url);
+	let result = loop {
+		tokio::select! {
+			result = &mut task => break result.unwrap_or_else(|x| Err(std::io::Error::other(x).into())),
+			_ = interval.tick() => {
+				progress += 1;
+				let line = format!("downloaded {}", units.format(received.load(Ordering::Relaxed)));
+				context.terminal.write_processing(url, progress, Activity::Running, line);
+			}
+			_ = sigint.recv(), if !cancel.load(Ordering::Relaxed) => {
+				trace!("cancelling download of `{}`", url);
+				cancel.store(true, Ordering::Relaxed);
+				context.terminal.update_processing(url, progress, Activity::Cancelling);
+			}
+		}
+	};
+
+	context.terminal.end_processing();
+	match result {
+		Ok(content_type) => Ok(Download { dir, path, content_type }),
+		Err(x) => {
+			Download { dir, path, content_type: None }.remove().await;
+			Err(x)
+		}
+	}
+}
+
+#[cfg(not(feature = "http"))]
+pub async fn fetch(
+	_context: &mut crate::context::Context, url: &str, _max_size: u64, _units: crate::options::Units,
+) -> Result<Download, crate::Error> {
+	Err(crate::Error::Download(url.to_string(), "this build has no HTTP support".into()))
+}
+
+/// Downloads `url` to `path`, resuming after errors, and returns the MIME
+/// type the server gave.
+#[cfg(feature = "http")]
+fn get(
+	url: &str, path: &Path, max_size: u64, received: &std::sync::atomic::AtomicU64,
+	cancel: &std::sync::atomic::AtomicBool,
+) -> Result<Option<String>, crate::Error> {
+	use std::io::{self, Read, Seek, Write};
+	use std::sync::atomic::Ordering;
+	use std::time::Duration;
+
+	use tracing::debug;
+
+	let failed = |reason: String| crate::Error::Download(url.to_string(), reason);
+	let too_large = || failed(format!("the file is larger than {} bytes", max_size));
+	let agent = ureq::AgentBuilder::new()
+		.timeout_connect(Duration::from_secs(30))
+		.timeout_read(Duration::from_secs(60))
+		.build();
+
+	let mut file = std::fs::File::create(path)?;
+	let mut written = 0;
+	let mut attempt = 0;
+	loop {
+		attempt += 1;
+		let mut request = agent.get(url);
+		if written > 0 {
+			debug!("resuming download of `{}` after {} bytes", url, written);
+			request = request.set("Range", &format!("bytes={}-", written));
+		}
+
+		let response = match request.call() {
+			Ok(x) => x,
+			Err(ureq::Error::Status(code, _)) if code >= 500 && attempt < MAX_ATTEMPTS => {
+				debug!("downloading `{}` failed with status {}; retrying", url, code);
+				continue;
+			}
+			Err(ureq::Error::Status(code, x)) => {
+				return Err(failed(format!("the server responded with {} {}", code, x.status_text())));
+			}
+			Err(ureq::Error::Transport(x)) if attempt < MAX_ATTEMPTS => {
+				debug!("downloading `{}` failed: {}; retrying", url, x);
+				continue;
+			}
+			Err(ureq::Error::Transport(x)) => return Err(failed(x.to_string())),
+		};
+
+		if written > 0 && response.status() != 206 {
+			debug!("the server cannot resume `{}`; starting over", url);
+			file.set_len(0)?;
+			file.rewind()?;
+			written = 0;
+		}
+
+		let offset = written;
+		let length = response.header("Content-Length").and_then(|x| x.trim().parse::<u64>().ok());
+		if length.is_some_and(|x| offset + x > max_size) {
+			return Err(too_large());
+		}
+
+		let content_type = response.header("Content-Type").and_then(|x| x.split(';').next());
+		let content_type = content_type.map(|x| x.trim().to_ascii_lowercase()).filter(|x| !x.is_empty());
+		let mut reader = response.into_reader();
+		let mut buffer = vec![0; 64 * 1024];
+		let result = loop {
+			if cancel.load(Ordering::Relaxed) {
+				return Err(crate::Error::Cancelled);
+			}
+
+			match reader.read(&mut buffer) {
+				Ok(0) => break Ok(()),
+				Ok(x) => {
+					written += x as u64;
+					if written > max_size {
+						return Err(too_large());
+					}
+
+					file.write_all(&buffer[..x])?;
+					received.store(written, Ordering::Relaxed);
+				}
+				Err(x) if x.kind() == io::ErrorKind::Interrupted => {}
+				Err(x) => break Err(x),
+			}
+		};
+
+		let result = match result {
+			Ok(()) if length.is_some_and(|x| written < offset + x) => Err(io::ErrorKind::UnexpectedEof.into()),
+			x => x,
+		};
+
+		match result {
+			Ok(()) => {
+				debug!("downloaded {} bytes from `{}` to `{}`", written, url, path.display());
+				return Ok(content_type);
+			}
+			Err(x) if attempt < MAX_ATTEMPTS => debug!("downloading `{}` stopped after {} bytes: {}", url, written, x),
+			Err(x) => return Err(failed(x.to_string())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::{file_name, is_url};
+
+	#[test]
+	fn tells_urls_from_paths() {
+		assert!(is_url(Path::new("https://example.com/a.jpg")));
+		assert!(is_url(Path::new("HTTP://example.com/a.jpg")));
+		assert!(!is_url(Path::new("http/a.jpg")));
+		assert!(!is_url(Path::new("ftp://example.com/a.jpg")));
+		assert!(!is_url(Path::new("ü")));
+	}
+
+	#[test]
+	fn names_downloads_after_urls() {
+		assert_eq!(file_name("https://example.com/photos/IMG_0001.JPG"), "IMG_0001.JPG");
+		assert_eq!(file_name("https://example.com/photos/a%20b.jpg?size=large#top"), "a b.jpg");
+		assert_eq!(file_name("http://example.com/photos/"), "photos");
+		assert_eq!(file_name("http://example.com/a%2F..%2Fb.png"), "a_.._b.png");
+		assert_eq!(file_name("http://example.com/%zz.jpg"), "%zz.jpg");
+		assert_eq!(file_name("http://example.com"), "download");
+		assert_eq!(file_name("http://example.com/.."), "download");
+	}
+}
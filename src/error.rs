@@ -1,29 +1,116 @@
+use std::fmt;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
+use std::time::Duration;
 
-use crate::comment::Comment;
 
 #[derive(Debug, Error)]
 pub enum Error {
 	#[error("input file `{}` not found", .0.display())]
 	InputNotFound(PathBuf),
-	#[error("input file `{}` is a symlink", .0.display())]
-	InputIsSymlink(PathBuf),
+	#[error("failed to download `{}`: {}", .0, .1)]
+	Download(String, String),
+	#[error("`{}` is not writable; use `--output-dir` or `--auto-output-dir` to redirect outputs", .0.display())]
+	InputNotWritable(PathBuf),
+	#[error(
+		"input file `{}` changed while it was being converted{}",
+		.0.display(),
+		.1.as_ref().map(|x| format!("; its output is kept as `{}`", x.display())).unwrap_or_default()
+	)]
+	InputChanged(PathBuf, Option<PathBuf>),
 	#[error("output file `{}` already exists", .0.display())]
 	OutputExists(PathBuf),
-	#[error("input file `{}` could not be identified", .0.display())]
-	InputFormatUnknown(PathBuf),
+	#[error("output file `{}` is that of `{}` too", .0.display(), .1.display())]
+	OutputClaimed(PathBuf, PathBuf),
+	#[error("path `{}` is too long for the filesystem", .0.display())]
+	PathTooLong(PathBuf),
+	#[error("the extension of output file `{}` does not match the output format; expected {}", .0.display(), .1)]
+	OutputExtension(PathBuf, String),
+	#[error("no backup of `{}` found", .0.display())]
+	BackupNotFound(PathBuf),
+	#[error(
+		"several backups of `{}` found: {}",
+		.0.display(),
+		.1.iter().map(|x| format!("`{}`", x.display())).collect::<Vec<_>>().join(", ")
+	)]
+	BackupAmbiguous(PathBuf, Vec<PathBuf>),
+	#[error(
+		"failed to back up `{}` to `{}`: {}; the original is left untouched, and its conversion kept as `{}`",
+		.original.display(),
+		.backup.display(),
+		.source,
+		.output.display()
+	)]
+	BackupFailed { original: PathBuf, backup: PathBuf, output: PathBuf, source: Box<Error> },
+	#[error("no source of `{}` found", .0.display())]
+	SourceNotFound(PathBuf),
+	#[error(
+		"several sources of `{}` found: {}",
+		.0.display(),
+		.1.iter().map(|x| format!("`{}`", x.display())).collect::<Vec<_>>().join(", ")
+	)]
+	SourceAmbiguous(PathBuf, Vec<PathBuf>),
 	#[error("binary `{}` not found", .0)]
-	BinaryNotFound(&'static str),
+	BinaryNotFound(String),
 	#[error("binary `{}` not found", .0.display())]
 	BinaryInEnvNotFound(PathBuf),
 	#[error("{} invocation failed, {}", .0, .1)]
-	Invocation(&'static str, ExitStatus),
+	Invocation(String, ExitStatus, String),
+	#[error(
+		"gm lacks a {0} delegate; install GraphicsMagick with {0} support or install ffmpeg to let shrink-ray convert \
+		 these files with it"
+	)]
+	MissingDelegate(String),
+	#[error(
+		"unable to load tool definitions from `{}`:{}",
+		.0.display(),
+		.1.iter().map(|x| format!("\n  {}", x.replace('\n', "\n    "))).collect::<String>()
+	)]
+	ToolsFile(PathBuf, Vec<String>),
+	#[error(
+		"unable to load settings from `{}`:{}",
+		.0.display(),
+		.1.iter().map(|x| format!("\n  {}", x.replace('\n', "\n    "))).collect::<String>()
+	)]
+	ConfigFile(PathBuf, Vec<String>),
+	#[error(
+		"unknown profile `{}`; {}",
+		.0,
+		match .1.is_empty() {
+			true => "no profiles are defined".to_string(),
+			false => format!("available profiles: {}", .1.join(", ")),
+		}
+	)]
+	UnknownProfile(String, Vec<String>),
+	#[error("failed to create trace file `{}`: {}", .0.display(), .1)]
+	TraceFile(PathBuf, #[source] io::Error),
+	#[error("failed to write audit log `{}`: {}", .0.display(), .1)]
+	AuditLog(PathBuf, #[source] io::Error),
+	#[error("audit log `{}` is not intact: {}", .0.display(), .1)]
+	AuditChain(PathBuf, String),
+	#[error("failed to write journal `{}`: {}", .0.display(), .1)]
+	Journal(PathBuf, #[source] io::Error),
+	#[error("failed to open progress channel {}: {}", .0, .1)]
+	ProgressChannel(String, #[source] io::Error),
+	#[error("a daemon is listening on `{}` already", .0.display())]
+	DaemonRunning(PathBuf),
+	#[error("`{}` is in the way of the daemon socket, and not a socket", .0.display())]
+	SocketInTheWay(PathBuf),
+	#[error("failed to reach the daemon at `{}`: {}; is `shrink-ray daemon` running?", .0.display(), .1)]
+	DaemonUnreachable(PathBuf, #[source] io::Error),
+	#[error("unexpected reply from the daemon: {}", .0)]
+	DaemonReply(String),
+	#[error("argument `{}` is not valid UTF-8, which runs handed over to the daemon need", .0)]
+	NotUnicode(String),
 	#[error("cancelled")]
 	Cancelled,
-	#[error("file has already been converted")]
-	AlreadyConverted(Comment),
+	#[error("cancelled at the deadline")]
+	DeadlineReached,
+	#[error("stalled, without signs of life for {}", humantime::format_duration(*.0))]
+	Stalled(Duration),
+	#[error("failed to {} `{}`: {}", .stage, .path.display(), .source)]
+	InputIo { stage: Stage, path: PathBuf, source: io::Error },
 	#[error(transparent)]
 	Comment(#[from] crate::comment::CommentParseError),
 	#[error(transparent)]
@@ -36,3 +123,117 @@ pub enum Error {
 	#[error(transparent)]
 	Nix(#[from] nix::errno::Errno),
 }
+
+impl Error {
+	/// Whether the error only affects the file being processed, or the whole
+	/// run.
+	pub fn severity(&self) -> Severity {
+		match self {
+			Error::BinaryNotFound(_)
+			| Error::BinaryInEnvNotFound(_)
+			| Error::Magic(_)
+			| Error::Which(_)
+			| Error::ToolsFile(..)
+			| Error::ConfigFile(..)
+			| Error::UnknownProfile(..)
+			| Error::TraceFile(..)
+			| Error::AuditLog(..)
+			| Error::AuditChain(..)
+			| Error::Journal(..)
+			| Error::ProgressChannel(..)
+			| Error::Cancelled
+			| Error::DeadlineReached => Severity::Fatal,
+			#[cfg(target_family = "unix")]
+			Error::Nix(_) => Severity::Fatal,
+			_ => Severity::File,
+		}
+	}
+
+	/// Tag of the kind of error, as metrics label fatal ones with; a fixed
+	/// set, unlike the messages. Errors of single files are all `file`.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Error::BinaryNotFound(_) | Error::BinaryInEnvNotFound(_) | Error::Which(_) => "binary_not_found",
+			Error::Magic(_) => "magic",
+			Error::ToolsFile(..) => "tools_file",
+			Error::ConfigFile(..) => "config_file",
+			Error::UnknownProfile(..) => "unknown_profile",
+			Error::TraceFile(..) => "trace_file",
+			Error::AuditLog(..) => "audit_log",
+			Error::AuditChain(..) => "audit_chain",
+			Error::Journal(..) => "journal",
+			Error::ProgressChannel(..) => "progress_channel",
+			Error::Cancelled => "cancelled",
+			Error::DeadlineReached => "deadline_reached",
+			#[cfg(target_family = "unix")]
+			Error::Nix(_) => "system",
+			_ => "file",
+		}
+	}
+
+	/// Failed invocation of `tool`, keeping the end of what it wrote to the
+	/// standard error output.
+	pub fn invocation(tool: &str, status: ExitStatus, stderr: &[u8]) -> Self {
+		const TAIL: usize = 4096;
+
+		let stderr = &stderr[stderr.len().saturating_sub(TAIL)..];
+		Error::Invocation(tool.to_string(), status, String::from_utf8_lossy(stderr).into_owned())
+	}
+
+	/// Whether the error was caused by GraphicsMagick missing a library for
+	/// the format of the input.
+	pub fn is_missing_delegate(&self) -> bool {
+		let Error::Invocation(tool, _, stderr) = self else {
+			return false;
+		};
+
+		if tool != "gm" {
+			return false;
+		}
+
+		let stderr = stderr.to_ascii_lowercase();
+		stderr.contains("no decode delegate") || stderr.contains("no encode delegate")
+	}
+
+	/// Wraps an I/O error on the input file with the stage that produced it.
+	pub fn input_io(stage: Stage, path: impl AsRef<Path>) -> impl FnOnce(io::Error) -> Self {
+		let path = path.as_ref().to_path_buf();
+		move |source| Error::InputIo { stage, path, source }
+	}
+
+	/// Wraps an error writing the backup of `original` to `backup`, which left
+	/// the original untouched and `output` in place.
+	pub fn backup_failed(original: &Path, backup: &Path, output: &Path) -> impl FnOnce(Error) -> Self {
+		let (original, backup, output) = (original.to_path_buf(), backup.to_path_buf(), output.to_path_buf());
+		move |source| Error::BackupFailed { original, backup, output, source: Box::new(source) }
+	}
+
+	/// Whether the error was caused by the input file being deleted while it
+	/// was being processed.
+	pub fn is_disappeared(&self) -> bool {
+		matches!(self, Error::InputIo { source, .. } if source.kind() == io::ErrorKind::NotFound)
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Severity {
+	/// Only the current file failed, the remaining ones can still be processed
+	File,
+	/// The run cannot continue
+	Fatal,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Stage {
+	Identify,
+	Inspect,
+}
+
+impl fmt::Display for Stage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Stage::Identify => f.write_str("identify"),
+			Stage::Inspect => f.write_str("inspect"),
+		}
+	}
+}
@@ -0,0 +1,161 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+use serde_json::{json, Map, Value};
+use tracing::{debug, error};
+
+use crate::options::Options;
+
+/// Version of the events, raised whenever they change in a way readers may
+/// trip over, rather than just gaining fields.
+pub const VERSION: u32 = 1;
+
+/// Progress events written to a side channel for front-ends, whatever the
+/// terminal shows, as JSON objects a line each. Every one of them has the
+/// [`VERSION`] as `version`, and what happened as `event`:
+///
+/// - `file_started`, with the `path` of the input
+/// - `progress`, with the `path`, the `percent` done, the seconds left by the
+///   pace so far as `eta_s` and the bytes of the output written so far as
+///   `bytes_out`, both `null` unless known; only tools telling how far they
+///   got report it
+/// - `file_finished`, with the `path`, the `outcome` (`shrunk`, `grew`,
+///   `skipped`, `failed` or `cancelled`), the `delta` in size as
+///   `{"original_bytes", "new_bytes"}` once converted, `null` otherwise, and
+///   the `reason` it was skipped or failed, `null` otherwise
+/// - `run_finished`, with the `stats` of the run, like the `summary` of
+///   `--format json`, and whether it was `cancelled`
+pub struct Events {
+	out: Box<dyn Write>,
+	/// Whether the reading end went away, or writing failed otherwise
+	closed: bool,
+}
+
+impl Events {
+	pub fn new(out: impl Write + 'static) -> Self {
+		Events { out: Box::new(out), closed: false }
+	}
+
+	/// Opens the side channel `options` ask for, if any.
+	pub fn open(options: &Options) -> Result<Option<Self>, crate::Error> {
+		#[cfg(target_family = "unix")]
+		if let Some(fd) = options.progress_fd {
+			return Ok(Some(Events::new(inherited(fd)?)));
+		}
+
+		let Some(path) = &options.progress_pipe else {
+			return Ok(None);
+		};
+
+		// blocks until a named pipe has a reader
+		debug!("opening progress pipe `{}`", path.display());
+		let file = OpenOptions::new().append(true).create(true).open(path);
+		let file = file.map_err(|x| crate::Error::ProgressChannel(format!("`{}`", path.display()), x))?;
+		Ok(Some(Events::new(file)))
+	}
+
+	/// Writes an `event` with `fields`, which has to be an object.
+	pub fn write(&mut self, event: &str, fields: Value) {
+		if self.closed {
+			return;
+		}
+
+		let mut object = Map::new();
+		object.insert("version".into(), json!(VERSION));
+		object.insert("event".into(), json!(event));
+		if let Value::Object(fields) = fields {
+			object.extend(fields);
+		}
+
+		// readers act on every event as it comes
+		let result = writeln!(self.out, "{}", Value::Object(object)).and_then(|()| self.out.flush());
+		if let Err(x) = result {
+			match x.kind() {
+				io::ErrorKind::BrokenPipe => debug!("progress channel closed; not writing any more events"),
+				_ => error!("failed to write progress event, not writing any more: {}", x),
+			}
+
+			self.closed = true;
+		}
+	}
+}
+
+/// A copy of the file descriptor `fd`, inherited from whoever started us,
+/// which the tools we run do not inherit in turn.
+#[cfg(target_family = "unix")]
+fn inherited(fd: i32) -> Result<std::fs::File, crate::Error> {
+	use std::os::fd::FromRawFd;
+
+	use nix::fcntl::{fcntl, FcntlArg};
+
+	let error = |x| crate::Error::ProgressChannel(format!("file descriptor {}", fd), io::Error::from(x));
+	let copy = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(3)).map_err(error)?;
+	// SAFETY: the descriptor was just made, and is owned by nothing else
+	Ok(unsafe { std::fs::File::from_raw_fd(copy) })
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::io::{self, Write};
+	use std::rc::Rc;
+
+	use serde_json::json;
+
+	use super::Events;
+
+	#[derive(Clone, Default)]
+	struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.borrow_mut().write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	/// Fails like a pipe nobody reads anymore
+	struct Closed;
+
+	impl Write for Closed {
+		fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+			Err(io::ErrorKind::BrokenPipe.into())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn writes_versioned_events_a_line_each() {
+		let buffer = Buffer::default();
+		let mut events = Events::new(buffer.clone());
+		events.write("file_started", json!({"path": "a.jpg"}));
+		events.write("run_finished", json!({"cancelled": false}));
+
+		let written = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+		let lines: Vec<serde_json::Value> = written.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+		assert_eq!(lines[0], json!({"version": 1, "event": "file_started", "path": "a.jpg"}));
+		assert_eq!(lines[1], json!({"version": 1, "event": "run_finished", "cancelled": false}));
+		assert_eq!(lines.len(), 2);
+	}
+
+	#[test]
+	fn goes_quiet_once_closed() {
+		let mut events = Events::new(Closed);
+		events.write("file_started", json!({"path": "a.jpg"}));
+		assert!(events.closed);
+		events.write("file_started", json!({"path": "b.jpg"}));
+	}
+
+	#[cfg(target_family = "unix")]
+	#[test]
+	fn needs_open_file_descriptors() {
+		assert!(super::inherited(-1).is_err());
+		assert!(super::inherited(1).is_ok());
+	}
+}
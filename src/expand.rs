@@ -0,0 +1,406 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use chrono::{SecondsFormat, Utc};
+use tracing::{trace, warn};
+
+use crate::comment::Comment;
+use crate::download;
+use crate::inputs::FileId;
+
+/// Name of the file marking a directory done, with `--write-done-markers`
+pub const DONE_MARKER: &str = ".shrink-ray-done";
+
+/// Inputs expanded by [`expand`]
+#[derive(Default)]
+pub struct Expansion {
+	pub files: Vec<PathBuf>,
+	/// Directories skipped as marked done
+	pub skipped: Vec<PathBuf>,
+	pub done: Done,
+}
+
+/// The inputs, with the directories among them replaced by the regular files
+/// within them, recursively, in the order of their names.
+///
+/// Every file comes once: where it is given on its own rather than within a
+/// directory given as well, and where the first of overlapping directories
+/// reaches it; directories given on their own are walked where they are
+/// given, not within others. Files given are told by their identity as well,
+/// to leave out other links to them. Symlinks to directories within them are
+/// not followed, nor those to files unless `follow_symlinks`.
+///
+/// Directories marked done for the settings of `comment` are skipped, unless
+/// it is `None`.
+pub fn expand(inputs: &[PathBuf], follow_symlinks: bool, comment: Option<&Comment>) -> Expansion {
+	let mut walk = Walk { follow_symlinks, comment, ..Walk::default() };
+	for input in inputs.iter().filter(|x| !download::is_url(x)) {
+		if let Ok(canonical) = fs::canonicalize(input) {
+			walk.given.insert(canonical);
+		}
+
+		if let Some(id) = fs::metadata(input).ok().filter(|x| x.is_file()).and_then(|x| FileId::of(&x)) {
+			walk.ids.insert(id);
+		}
+	}
+
+	for input in inputs {
+		let is_dir = !download::is_url(input) && fs::symlink_metadata(input).is_ok_and(|x| x.is_dir());
+		match (is_dir, fs::canonicalize(input)) {
+			(true, Ok(canonical)) => walk.dir(input, canonical),
+			(_, canonical) => {
+				if let Ok(canonical) = canonical {
+					walk.expansion.done.pending.insert(input.clone(), canonical);
+				}
+
+				walk.expansion.files.push(input.clone());
+			}
+		}
+	}
+
+	walk.expansion
+}
+
+/// Directories walked, to mark done once every file within them is processed
+#[derive(Default)]
+pub struct Done {
+	/// Paths of the directories, with their canonical paths
+	dirs: Vec<(PathBuf, PathBuf)>,
+	/// Canonical paths of the files not yet processed, by their paths; those
+	/// of directories which could not be read stay here for good
+	pending: HashMap<PathBuf, PathBuf>,
+}
+
+impl Done {
+	/// Notes that `file` was processed without failing.
+	pub fn processed(&mut self, file: &Path) {
+		self.pending.remove(file);
+	}
+
+	/// The directories every file within which was processed.
+	pub fn finished(&self) -> impl Iterator<Item = &Path> {
+		let finished = |x: &&(PathBuf, PathBuf)| !self.pending.values().any(|y| y.starts_with(&x.1));
+		self.dirs.iter().filter(finished).map(|(x, _)| x.as_path())
+	}
+}
+
+/// Contents of the marker of a directory done as of now with the settings of
+/// `comment`.
+pub fn done_marker(comment: &Comment) -> String {
+	format!("{}\n{}\n", comment, Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// Whether `dir` is marked done for the settings of `comment`; markers made
+/// by hand, without settings, count as such.
+fn is_done(dir: &Path, comment: &Comment) -> bool {
+	let marker = dir.join(DONE_MARKER);
+	let contents = match fs::read_to_string(&marker) {
+		Ok(x) => x,
+		Err(x) if x.kind() == ErrorKind::NotFound => return false,
+		Err(x) => {
+			warn!("failed to read `{}`: {}", marker.display(), x);
+			return false;
+		}
+	};
+
+	match contents.lines().next().unwrap_or_default().parse::<Comment>() {
+		Ok(x) => x.has_settings(comment.settings.as_ref()),
+		Err(_) => true,
+	}
+}
+
+#[derive(Default)]
+struct Walk<'a> {
+	/// Canonical paths of the inputs, each to come where it is given
+	given: HashSet<PathBuf>,
+	/// Identities of the files given, where known
+	ids: HashSet<FileId>,
+	/// Canonical paths of the directories walked so far
+	walked: HashSet<PathBuf>,
+	expansion: Expansion,
+	/// Whether to take in symlinks to regular files, as `--follow-symlinks`
+	follow_symlinks: bool,
+	/// Comment of the outputs, to skip the directories marked done with
+	comment: Option<&'a Comment>,
+}
+
+impl Walk<'_> {
+	/// Walks `dir`, whose canonical path is `canonical`; the paths within it
+	/// are canonical as well, as no symlinks are followed.
+	fn dir(&mut self, dir: &Path, canonical: PathBuf) {
+		if !self.walked.insert(canonical.clone()) {
+			trace!("`{}` walked already", dir.display());
+			return;
+		}
+
+		if self.comment.is_some_and(|x| is_done(dir, x)) {
+			trace!("`{}` is marked done", dir.display());
+			self.expansion.skipped.push(dir.to_path_buf());
+			return;
+		}
+
+		trace!("walking `{}`", dir.display());
+		self.expansion.done.dirs.push((dir.to_path_buf(), canonical.clone()));
+		let mut entries = match fs::read_dir(dir).and_then(|x| x.collect::<Result<Vec<_>, _>>()) {
+			Ok(x) => x,
+			Err(x) => {
+				warn!("failed to read directory `{}`: {}", dir.display(), x);
+				self.expansion.done.pending.insert(dir.to_path_buf(), canonical);
+				return;
+			}
+		};
+
+		entries.sort_by_key(|x| x.file_name());
+		for entry in entries {
+			let path = entry.path();
+			let canonical = canonical.join(entry.file_name());
+			if self.given.contains(&canonical) {
+				trace!("`{}` is given on its own", path.display());
+				continue;
+			}
+
+			if entry.file_name() == DONE_MARKER {
+				continue;
+			}
+
+			match entry.file_type() {
+				Ok(x) if x.is_dir() => self.dir(&path, canonical),
+				Ok(x) if x.is_file() => self.file(path, canonical),
+				Ok(x) if x.is_symlink() && self.follow_symlinks && path.is_file() => self.file(path, canonical),
+				Ok(_) => trace!("`{}` is not a regular file", path.display()),
+				Err(x) => {
+					warn!("failed to inspect `{}`: {}", path.display(), x);
+					self.expansion.done.pending.insert(path, canonical);
+				}
+			}
+		}
+	}
+
+	/// Takes in the file at `path`, unless it is another link to one given on
+	/// its own, which is looked up only with files given.
+	fn file(&mut self, path: PathBuf, canonical: PathBuf) {
+		let id = || fs::metadata(&path).ok().and_then(|x| FileId::of(&x));
+		if !self.ids.is_empty() && id().is_some_and(|x| self.ids.contains(&x)) {
+			trace!("`{}` is a link to a file given on its own", path.display());
+			return;
+		}
+
+		self.expansion.done.pending.insert(path.clone(), canonical);
+		self.expansion.files.push(path);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::path::{Path, PathBuf};
+
+	use tempfile::TempDir;
+
+	use super::{done_marker, expand, DONE_MARKER};
+	use crate::comment::{Comment, SettingsHash};
+
+	/// A tree with the files of `paths`, and directories for those ending with
+	/// a slash.
+	fn tree(paths: &[&str]) -> TempDir {
+		let root = tempfile::tempdir().unwrap();
+		for path in paths {
+			let path = root.path().join(path);
+			match path.to_str().unwrap().ends_with('/') {
+				true => fs::create_dir_all(&path).unwrap(),
+				false => {
+					fs::create_dir_all(path.parent().unwrap()).unwrap();
+					fs::write(&path, "x").unwrap();
+				}
+			}
+		}
+
+		root
+	}
+
+	/// Expands `inputs` within `root`, relative to it.
+	fn expanded(root: &TempDir, inputs: &[&str]) -> Vec<String> {
+		expanded_following(root, inputs, false)
+	}
+
+	fn expanded_following(root: &TempDir, inputs: &[&str], follow_symlinks: bool) -> Vec<String> {
+		let inputs: Vec<PathBuf> = inputs.iter().map(|x| root.path().join(x)).collect();
+		expand(&inputs, follow_symlinks, None).files.iter().map(|x| relative(root, x)).collect()
+	}
+
+	fn relative(root: &TempDir, path: &Path) -> String {
+		path.strip_prefix(root.path()).unwrap().to_str().unwrap().to_string()
+	}
+
+	/// Comment of a run with `quality`.
+	fn comment(quality: &str) -> Comment {
+		Comment::new(Some(SettingsHash::of(&[("quality".to_string(), quality.to_string())])))
+	}
+
+	#[test]
+	fn walks_directories_in_order_of_names() {
+		let root = tree(&["photos/b.jpg", "photos/a.jpg", "photos/2021/c.jpg", "photos/empty/", "d.jpg"]);
+		let all = ["photos/2021/c.jpg", "photos/a.jpg", "photos/b.jpg", "d.jpg"];
+		assert_eq!(expanded(&root, &["photos", "d.jpg"]), all);
+		assert_eq!(expanded(&root, &["photos/empty"]), Vec::<String>::new());
+		// not there, left to fail like any other input
+		assert_eq!(expanded(&root, &["missing.jpg"]), ["missing.jpg"]);
+	}
+
+	#[test]
+	fn takes_files_given_on_their_own_where_they_are() {
+		let root = tree(&["photos/a.jpg", "photos/best.jpg", "photos/c.jpg"]);
+		let last = ["photos/a.jpg", "photos/c.jpg", "photos/best.jpg"];
+		assert_eq!(expanded(&root, &["photos", "photos/best.jpg"]), last);
+		let first = ["photos/best.jpg", "photos/a.jpg", "photos/c.jpg"];
+		assert_eq!(expanded(&root, &["photos/best.jpg", "photos"]), first);
+		// under another path
+		assert_eq!(expanded(&root, &["photos/../photos", "photos/best.jpg"]), [
+			"photos/../photos/a.jpg",
+			"photos/../photos/c.jpg",
+			"photos/best.jpg"
+		]);
+	}
+
+	#[test]
+	fn walks_overlapping_directories_once() {
+		let root = tree(&["photos/a.jpg", "photos/2021/b.jpg", "photos/2021/06/c.jpg", "photos/2022/d.jpg"]);
+		let all = ["photos/2021/06/c.jpg", "photos/2021/b.jpg", "photos/2022/d.jpg", "photos/a.jpg"];
+		assert_eq!(expanded(&root, &["photos", "photos"]), all);
+		assert_eq!(expanded(&root, &["photos", "photos/2021"]), [
+			"photos/2022/d.jpg",
+			"photos/a.jpg",
+			"photos/2021/06/c.jpg",
+			"photos/2021/b.jpg"
+		]);
+		assert_eq!(expanded(&root, &["photos/2021/06", "photos"]), [
+			"photos/2021/06/c.jpg",
+			"photos/2021/b.jpg",
+			"photos/2022/d.jpg",
+			"photos/a.jpg"
+		]);
+	}
+
+	#[test]
+	fn takes_every_file_once_whatever_the_inputs_and_their_order() {
+		let files = [
+			"photos/a.jpg",
+			"photos/best.jpg",
+			"photos/2021/b.jpg",
+			"photos/2021/06/c.jpg",
+			"photos/2022/d.jpg",
+		];
+		let root = tree(&files);
+		let mut files: Vec<&Path> = files.iter().map(Path::new).collect();
+		files.sort();
+
+		let given = [
+			"photos",
+			"photos/2021",
+			"photos/2021/06",
+			"photos/best.jpg",
+			"photos/2021/b.jpg",
+			"photos/2022/d.jpg",
+		];
+		for inputs in arrangements(&given) {
+			// each file comes with the nearest input it is within, or is
+			let nearest = |x: &Path| inputs.iter().filter(|y| x.starts_with(y)).max_by_key(|y| y.len()).copied();
+			let within = |input| files.iter().filter(move |x| nearest(x) == Some(input));
+			let expected: Vec<_> = inputs.iter().flat_map(|x| within(*x)).map(|x| x.to_str().unwrap()).collect();
+			assert_eq!(expanded(&root, &inputs), expected, "{:?}", inputs);
+		}
+	}
+
+	/// Every arrangement of every selection of `items`.
+	fn arrangements<'a>(items: &[&'a str]) -> Vec<Vec<&'a str>> {
+		let mut all = vec![Vec::new()];
+		for (i, item) in items.iter().enumerate() {
+			let rest: Vec<_> = items.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, x)| *x).collect();
+			all.extend(arrangements(&rest).into_iter().map(|x| [vec![*item], x].concat()));
+		}
+
+		all
+	}
+
+	#[cfg(target_family = "unix")]
+	#[test]
+	fn takes_files_given_under_other_paths_where_they_are() {
+		let root = tree(&["photos/a.jpg", "photos/best.jpg"]);
+		std::os::unix::fs::symlink(root.path().join("photos"), root.path().join("linked")).unwrap();
+		assert_eq!(expanded(&root, &["photos", "linked/best.jpg"]), ["photos/a.jpg", "linked/best.jpg"]);
+		assert_eq!(expanded(&root, &["linked/best.jpg", "photos"]), ["linked/best.jpg", "photos/a.jpg"]);
+	}
+
+	#[cfg(target_family = "unix")]
+	#[test]
+	fn takes_files_given_as_other_links_where_they_are() {
+		let root = tree(&["photos/a.jpg", "photos/best.jpg", "elsewhere/c.jpg"]);
+		fs::hard_link(root.path().join("photos/best.jpg"), root.path().join("photos/copy.jpg")).unwrap();
+		fs::hard_link(root.path().join("elsewhere/c.jpg"), root.path().join("photos/c.jpg")).unwrap();
+		let all = ["photos/a.jpg", "photos/best.jpg", "photos/c.jpg", "photos/copy.jpg"];
+		assert_eq!(expanded(&root, &["photos"]), all);
+		let expected = ["photos/a.jpg", "elsewhere/c.jpg", "photos/best.jpg"];
+		assert_eq!(expanded(&root, &["photos", "elsewhere/c.jpg", "photos/best.jpg"]), expected);
+	}
+
+	#[cfg(target_family = "unix")]
+	#[test]
+	fn does_not_follow_symlinks() {
+		let root = tree(&["photos/a.jpg", "elsewhere/b.jpg"]);
+		std::os::unix::fs::symlink(root.path().join("elsewhere"), root.path().join("photos/linked")).unwrap();
+		std::os::unix::fs::symlink(root.path().join("elsewhere/b.jpg"), root.path().join("photos/b.jpg")).unwrap();
+		assert_eq!(expanded(&root, &["photos"]), ["photos/a.jpg"]);
+		assert!(Path::new(&root.path().join("photos/linked/b.jpg")).exists());
+		assert_eq!(expanded_following(&root, &["photos"], true), ["photos/a.jpg", "photos/b.jpg"]);
+	}
+
+	#[test]
+	fn skips_directories_marked_done() {
+		let root = tree(&["photos/a.jpg", "photos/2021/b.jpg", "photos/2021/06/c.jpg", "photos/2022/d.jpg"]);
+		fs::write(root.path().join("photos/2021").join(DONE_MARKER), done_marker(&comment("80"))).unwrap();
+		fs::write(root.path().join("photos/2022").join(DONE_MARKER), done_marker(&comment("90"))).unwrap();
+		let inputs = [root.path().join("photos")];
+		let expansion = expand(&inputs, false, Some(&comment("80")));
+		let files: Vec<_> = expansion.files.iter().map(|x| relative(&root, x)).collect();
+		assert_eq!(files, ["photos/2022/d.jpg", "photos/a.jpg"]);
+		let skipped: Vec<_> = expansion.skipped.iter().map(|x| relative(&root, x)).collect();
+		assert_eq!(skipped, ["photos/2021"]);
+
+		// markers made by hand, and the directories given, the same
+		fs::write(root.path().join("photos").join(DONE_MARKER), "").unwrap();
+		assert_eq!(expand(&inputs, false, Some(&comment("90"))).files, Vec::<PathBuf>::new());
+		assert_eq!(expand(&inputs, false, None).files.len(), 4);
+	}
+
+	#[test]
+	fn finishes_directories_once_every_file_within_is_processed() {
+		let root = tree(&["photos/a.jpg", "photos/2021/b.jpg", "photos/2021/06/c.jpg", "photos/2022/d.jpg", "e.jpg"]);
+		let inputs = [root.path().join("photos"), root.path().join("e.jpg")];
+		let mut expansion = expand(&inputs, false, None);
+		let finished = |x: &super::Done| x.finished().map(|x| relative(&root, x)).collect::<Vec<_>>();
+		assert_eq!(finished(&expansion.done), Vec::<String>::new());
+
+		let file = |x: &str| root.path().join(x);
+		expansion.done.processed(&file("photos/2021/06/c.jpg"));
+		expansion.done.processed(&file("e.jpg"));
+		assert_eq!(finished(&expansion.done), ["photos/2021/06"]);
+		expansion.done.processed(&file("photos/2021/b.jpg"));
+		expansion.done.processed(&file("photos/2022/d.jpg"));
+		assert_eq!(finished(&expansion.done), ["photos/2021", "photos/2021/06", "photos/2022"]);
+		expansion.done.processed(&file("photos/a.jpg"));
+		assert_eq!(finished(&expansion.done), ["photos", "photos/2021", "photos/2021/06", "photos/2022"]);
+	}
+
+	#[test]
+	fn finishes_directories_with_files_given_on_their_own_once_they_are_processed() {
+		let root = tree(&["photos/a.jpg", "photos/2021/b.jpg"]);
+		let inputs = [root.path().join("photos/2021/b.jpg"), root.path().join("photos")];
+		let mut expansion = expand(&inputs, false, None);
+		expansion.done.processed(&root.path().join("photos/a.jpg"));
+		assert_eq!(expansion.done.finished().count(), 0);
+		expansion.done.processed(&root.path().join("photos/2021/b.jpg"));
+		assert_eq!(expansion.done.finished().count(), 2);
+	}
+}
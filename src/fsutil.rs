@@ -0,0 +1,937 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use tracing::{debug, error, trace};
+
+#[cfg(target_family = "unix")]
+pub fn is_writable(dir: impl AsRef<Path>) -> Result<bool, crate::Error> {
+	use nix::errno::Errno;
+	use nix::unistd::{access, AccessFlags};
+
+	let dir = dir.as_ref();
+	trace!("checking if `{}` is writable", dir.display());
+
+	// access(2) fails with EROFS on read-only mounts, so this covers both those
+	// and directories we lack permissions for
+	match access(dir, AccessFlags::W_OK) {
+		Ok(()) => Ok(true),
+		Err(Errno::EROFS | Errno::EACCES | Errno::EPERM) => Ok(false),
+		Err(x) => Err(crate::Error::from(x)),
+	}
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn is_writable(dir: impl AsRef<Path>) -> Result<bool, crate::Error> {
+	let dir = dir.as_ref();
+	trace!("checking if `{}` is writable", dir.display());
+
+	Ok(!std::fs::metadata(dir)?.permissions().readonly())
+}
+
+pub fn parent_dir(path: &Path) -> &Path {
+	match path.parent() {
+		Some(x) if !x.as_os_str().is_empty() => x,
+		_ => Path::new("."),
+	}
+}
+
+/// `arg` of a command made absolute, if it is a relative path to something in
+/// `cwd`, or one `known` to be there or to be written, which is not looked up,
+/// so that the command can run in another directory. A `FORMAT:` prefix, as
+/// given to GraphicsMagick, is kept in front.
+pub fn absolute_arg(arg: &OsStr, cwd: &Path, known: impl Fn(&Path) -> bool) -> Option<OsString> {
+	let (prefix, path) = match arg.to_str().and_then(|x| x.split_once(':')) {
+		Some((prefix, path)) if prefix.len() > 1 && prefix.bytes().all(|x| x.is_ascii_alphanumeric()) => {
+			(prefix, Path::new(path))
+		}
+		_ => ("", Path::new(arg)),
+	};
+
+	if path.as_os_str().is_empty() || path.is_absolute() || path.as_os_str().to_string_lossy().starts_with('-') {
+		return None;
+	}
+
+	let absolute = cwd.join(path);
+	if !known(path) && !absolute.exists() {
+		return None;
+	}
+
+	let mut arg = OsString::new();
+	if !prefix.is_empty() {
+		arg.push(prefix);
+		arg.push(":");
+	}
+
+	arg.push(extended(absolute));
+	Some(arg)
+}
+
+/// Canonical form of `path`, even once it is gone, e.g. replaced by an output
+/// with another extension: that of its directory, followed by its name, so
+/// that a symlink is not resolved either.
+pub fn canonical_path(path: &Path) -> PathBuf {
+	let canonical = match path.file_name() {
+		Some(name) => parent_dir(path).canonicalize().map(|x| x.join(name)),
+		None => path.canonicalize(),
+	};
+
+	canonical.or_else(|_| std::path::absolute(path)).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// `path` as seen from `base`, both absolute, going up with `..` out of
+/// `base` where needed.
+pub fn relative_path(path: &Path, base: &Path) -> PathBuf {
+	let mut components = path.components().peekable();
+	let mut base = base.components().peekable();
+	if components.peek() != base.peek() {
+		// on another drive
+		return path.to_path_buf();
+	}
+
+	while components.peek().is_some() && components.peek() == base.peek() {
+		components.next();
+		base.next();
+	}
+
+	let relative: PathBuf = base.map(|_| Component::ParentDir).chain(components).collect();
+	match relative.as_os_str().is_empty() {
+		true => PathBuf::from("."),
+		false => relative,
+	}
+}
+
+/// Points the symlink `link` at `output`, which replaced its canonical target
+/// `target`, by swapping the name of the target for that of the output in what
+/// the link holds, so that relative links stay so.
+pub fn retarget(link: &Path, target: &Path, output: &Path) -> io::Result<()> {
+	#[cfg(target_family = "unix")]
+	use std::os::unix::fs::symlink;
+	#[cfg(windows)]
+	use std::os::windows::fs::symlink_file as symlink;
+
+	let held = fs::read_link(link)?;
+	let dir = parent_dir(&parent_dir(link).join(&held)).canonicalize().ok();
+	let held = match output.file_name() {
+		Some(name) if held.file_name() == target.file_name() && dir.as_deref() == target.parent() => {
+			held.with_file_name(name)
+		}
+		// through other links
+		_ => output.to_path_buf(),
+	};
+
+	let temp = crate::temp::file(link, None);
+	symlink(&held, &temp)?;
+	fs::rename(&temp, link).inspect_err(|_| {
+		if let Err(x) = fs::remove_file(&temp) {
+			error!("failed to delete temporary link `{}`: {}", temp.display(), x);
+		}
+	})
+}
+
+/// Longest path the system takes, in bytes; on Windows, that of extended-length
+/// paths, see [`extended`]
+#[cfg(not(windows))]
+pub const PATH_MAX: usize = 4096;
+#[cfg(windows)]
+pub const PATH_MAX: usize = 32767;
+
+/// Checks that a file fits at `path`, within the limits of the filesystem on
+/// the length of its name and of its absolute path, so that writing it fails
+/// before anything has been converted rather than after.
+pub fn check_length(path: &Path) -> Result<(), crate::Error> {
+	let name = path.file_name().map_or(0, |x| x.len());
+	let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+	if name > crate::temp::NAME_MAX || absolute.as_os_str().len() >= PATH_MAX {
+		return Err(crate::Error::PathTooLong(path.to_path_buf()));
+	}
+
+	Ok(())
+}
+
+/// `path` as an extended-length path with the `\\?\` prefix, if it is long
+/// enough for Windows to refuse it otherwise, once suffixed for temporary
+/// files; as it is if short, and on other systems.
+#[cfg(windows)]
+pub fn extended(path: PathBuf) -> PathBuf {
+	// MAX_PATH, less the 12 characters Windows keeps for names in directories
+	const LIMIT: usize = 260 - 12 - 1 - crate::temp::LENGTH;
+
+	if path.as_os_str().len() < LIMIT {
+		return path;
+	}
+
+	let Some(absolute) = std::path::absolute(&path).ok().and_then(|x| x.to_str().map(str::to_string)) else {
+		return path;
+	};
+
+	match absolute.strip_prefix(r"\\") {
+		Some(x) if x.starts_with(r"?\") => path,
+		Some(x) => PathBuf::from(format!(r"\\?\UNC\{}", x)),
+		None => PathBuf::from(format!(r"\\?\{}", absolute)),
+	}
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: PathBuf) -> PathBuf {
+	path
+}
+
+/// `path` without the prefix of [`extended`], to show.
+#[cfg(windows)]
+pub fn unextended(path: &Path) -> Cow<'_, Path> {
+	let Some(text) = path.to_str() else {
+		return Cow::Borrowed(path);
+	};
+
+	match (text.strip_prefix(r"\\?\UNC\"), text.strip_prefix(r"\\?\")) {
+		(Some(x), _) => Cow::Owned(PathBuf::from(format!(r"\\{}", x))),
+		(None, Some(x)) => Cow::Borrowed(Path::new(x)),
+		(None, None) => Cow::Borrowed(path),
+	}
+}
+
+#[cfg(not(windows))]
+pub fn unextended(path: &Path) -> Cow<'_, Path> {
+	Cow::Borrowed(path)
+}
+
+/// Turns `path` into a relative path that can be joined onto another
+/// directory, keeping as much of its structure as possible.
+pub fn relative_structure(path: impl AsRef<Path>) -> Result<PathBuf, crate::Error> {
+	let path = path.as_ref();
+	let escapes = path.components().any(|x| matches!(x, Component::ParentDir));
+	let path = if escapes { path.canonicalize()? } else { path.to_path_buf() };
+
+	Ok(
+		path
+			.components()
+			.filter_map(|x| match x {
+				Component::Normal(x) => Some(x),
+				_ => None,
+			})
+			.collect(),
+	)
+}
+
+/// Spellings of an extension which all mean the same format
+const EXTENSION_ALIASES: &[&[&str]] = &[&["jpg", "jpeg", "jpe"], &["tif", "tiff"]];
+
+/// Extension to give the output of `input`, given the `extension` (without
+/// the dot) of its format: that of `input` as it is if the format stays the
+/// same, otherwise `extension` in upper case if that of `input` mostly is.
+pub fn matching_extension(input: &Path, extension: &OsStr) -> OsString {
+	let (Some(original), Some(new)) = (input.extension().and_then(OsStr::to_str), extension.to_str()) else {
+		return extension.to_os_string();
+	};
+
+	let same = original.eq_ignore_ascii_case(new)
+		|| EXTENSION_ALIASES.iter().any(|x| {
+			let spells = |y: &str| x.iter().any(|z| z.eq_ignore_ascii_case(y));
+			spells(original) && spells(new)
+		});
+	if same {
+		return OsString::from(original);
+	}
+
+	let upper = original.chars().filter(char::is_ascii_uppercase).count();
+	let lower = original.chars().filter(char::is_ascii_lowercase).count();
+	if upper > lower {
+		OsString::from(new.to_ascii_uppercase())
+	} else {
+		OsString::from(new)
+	}
+}
+
+/// How [`smart_copy`] copied a file
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CopyMethod {
+	/// Shares the data with the source until either is written to, on
+	/// copy-on-write filesystems like btrfs and XFS
+	Reflink,
+	/// Another name for the source
+	Hardlink,
+	/// Copies the data
+	Copy,
+}
+
+impl CopyMethod {
+	pub const ALL: [CopyMethod; 3] = [CopyMethod::Reflink, CopyMethod::Hardlink, CopyMethod::Copy];
+
+	fn copy(self, src: &Path, dst: &Path) -> io::Result<()> {
+		match self {
+			CopyMethod::Reflink => reflink(src, dst),
+			CopyMethod::Hardlink => fs::hard_link(src, dst),
+			CopyMethod::Copy => {
+				fs::copy(src, dst)?;
+				let metadata = fs::metadata(src)?;
+				filetime::set_file_mtime(dst, filetime::FileTime::from_last_modification_time(&metadata))
+			}
+		}
+	}
+}
+
+/// Methods found not to work from one mount to another, so that they are not
+/// tried again for every file
+fn unsupported() -> &'static Mutex<HashSet<(u64, u64, CopyMethod)>> {
+	static UNSUPPORTED: OnceLock<Mutex<HashSet<(u64, u64, CopyMethod)>>> = OnceLock::new();
+	UNSUPPORTED.get_or_init(Default::default)
+}
+
+/// Copies `src` to the new file `dst` as cheaply as their filesystems allow:
+/// by reflink or hardlink when they share one, by copying the data (and the
+/// modification time) otherwise. The source must not be modified in place
+/// afterwards, as a hardlink would see the change.
+pub async fn smart_copy(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<CopyMethod, crate::Error> {
+	let (src, dst) = (src.as_ref().to_path_buf(), dst.as_ref().to_path_buf());
+	tokio::task::spawn_blocking(move || copy_with(&src, &dst, &CopyMethod::ALL)).await.map_err(io::Error::from)?
+}
+
+/// Copies `src` to the new file `dst` like [`smart_copy`], but never by
+/// hardlink, so that either can be written to in place afterwards.
+pub async fn detached_copy(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<CopyMethod, crate::Error> {
+	const METHODS: [CopyMethod; 2] = [CopyMethod::Reflink, CopyMethod::Copy];
+
+	let (src, dst) = (src.as_ref().to_path_buf(), dst.as_ref().to_path_buf());
+	tokio::task::spawn_blocking(move || copy_with(&src, &dst, &METHODS)).await.map_err(io::Error::from)?
+}
+
+/// Moves `src` to `dst`, copying it with [`smart_copy`] if they are on
+/// different filesystems.
+pub async fn move_file(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<(), crate::Error> {
+	let (src, dst) = (src.as_ref(), dst.as_ref());
+	let renamed = tokio::fs::rename(src, dst).await;
+	finish_move(src, dst, renamed).await
+}
+
+/// Finishes moving `src` to `dst` once renaming it came to `renamed`, by
+/// copying it if that failed as they are on different filesystems. Until the
+/// copy is in place, and on disk, `src` is left as it was.
+async fn finish_move(src: &Path, dst: &Path, renamed: io::Result<()>) -> Result<(), crate::Error> {
+	match renamed {
+		Ok(()) => return Ok(()),
+		Err(x) if x.kind() == io::ErrorKind::CrossesDevices => {}
+		Err(x) => return Err(crate::Error::from(x)),
+	}
+
+	// copy beside the destination first, so that it is replaced atomically
+	// and never left half-written
+	let temp = crate::temp::file(dst, Some(OsStr::new(".tmp")));
+	let method = smart_copy(src, &temp).await?;
+	debug!("copied `{}` to `{}` by {:?} across filesystems", src.display(), temp.display(), method);
+	// on disk before it stands for the only copy of the data
+	let placed = async {
+		tokio::fs::File::open(&temp).await?.sync_all().await?;
+		tokio::fs::rename(&temp, dst).await
+	};
+	if let Err(x) = placed.await {
+		if let Err(x) = tokio::fs::remove_file(&temp).await {
+			error!("failed to delete temporary file `{}`: {}", temp.display(), x);
+		}
+
+		return Err(crate::Error::from(x));
+	}
+
+	tokio::fs::remove_file(src).await?;
+	Ok(())
+}
+
+/// Writes the contents of `src` over those of `dst` in place, so that `dst`
+/// keeps its inode, along with its hard links, permissions and owner.
+pub async fn overwrite(dst: impl AsRef<Path>, src: impl AsRef<Path>) -> io::Result<()> {
+	let (dst, src) = (dst.as_ref().to_path_buf(), src.as_ref().to_path_buf());
+	tokio::task::spawn_blocking(move || {
+		let mut reader = File::open(src)?;
+		let mut file = fs::OpenOptions::new().write(true).open(dst)?;
+		let length = io::copy(&mut reader, &mut file)?;
+		file.set_len(length)?;
+		file.sync_all()
+	})
+	.await?
+}
+
+/// Copies `src` to `dst` with the first of `methods` that works.
+fn copy_with(src: &Path, dst: &Path, methods: &[CopyMethod]) -> Result<CopyMethod, crate::Error> {
+	let mounts = (mount(src)?, mount(parent_dir(dst))?);
+	for (index, method) in methods.iter().copied().enumerate() {
+		let key = (mounts.0, mounts.1, method);
+		let last = index + 1 == methods.len();
+		if !last && unsupported().lock().unwrap().contains(&key) {
+			continue;
+		}
+
+		trace!("copying `{}` to `{}` by {:?}", src.display(), dst.display(), method);
+		match method.copy(src, dst) {
+			Ok(()) => return Ok(method),
+			Err(x) if !last && is_unsupported(&x) => {
+				debug!("cannot copy `{}` to `{}` by {:?}: {}", src.display(), dst.display(), method, x);
+				unsupported().lock().unwrap().insert(key);
+			}
+			Err(x) => return Err(crate::Error::from(x)),
+		}
+	}
+
+	unreachable!("the last method either works or fails")
+}
+
+/// Whether `error` means the copy method does not work between the two files,
+/// rather than that copying failed.
+fn is_unsupported(error: &io::Error) -> bool {
+	match error.kind() {
+		io::ErrorKind::CrossesDevices | io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied => true,
+		io::ErrorKind::TooManyLinks | io::ErrorKind::InvalidInput => true,
+		#[cfg(target_family = "unix")]
+		_ => error.raw_os_error() == Some(nix::errno::Errno::ENOTTY as i32),
+		#[cfg(not(target_family = "unix"))]
+		_ => false,
+	}
+}
+
+/// Whether `a` and `b` are on the same filesystem, so that one can be renamed
+/// to the other.
+pub fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+	Ok(mount(a)? == mount(b)?)
+}
+
+/// What telling colliding names apart asks of the filesystem, so that tests
+/// can fake a case-insensitive one whatever the platform
+pub trait Names {
+	fn exists(&self, path: &Path) -> bool;
+
+	/// Whether names in `dir` that differ only in case are the same file, as
+	/// on macOS and Windows by default, and on some mounts on Linux.
+	fn is_case_insensitive(&self, dir: &Path) -> bool;
+}
+
+/// The filesystems themselves, each directory probed for case-insensitivity
+/// once
+pub struct Disk;
+
+impl Names for Disk {
+	fn exists(&self, path: &Path) -> bool {
+		path.exists()
+	}
+
+	fn is_case_insensitive(&self, dir: &Path) -> bool {
+		static PROBED: OnceLock<Mutex<HashMap<PathBuf, bool>>> = OnceLock::new();
+		let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+		let mut probed = PROBED.get_or_init(Default::default).lock().unwrap();
+		*probed.entry(dir).or_insert_with_key(|dir| {
+			probe_case(dir).unwrap_or_else(|x| {
+				debug!("unable to probe `{}` for case-insensitivity: {}", dir.display(), x);
+				false
+			})
+		})
+	}
+}
+
+/// Whether `dir` is case-insensitive, by creating a file in it and looking for
+/// it under its name in upper case.
+fn probe_case(dir: &Path) -> io::Result<bool> {
+	let probe = dir.join(format!(".shrink-ray-case-{}", std::process::id()));
+	File::options().write(true).create_new(true).open(&probe)?;
+	let upper = probe.with_file_name(probe.file_name().unwrap().to_ascii_uppercase());
+	let insensitive = fs::symlink_metadata(upper).is_ok();
+	fs::remove_file(&probe)?;
+	trace!("`{}` is case-{}sensitive", dir.display(), if insensitive { "in" } else { "" });
+	Ok(insensitive)
+}
+
+/// What `path` is told apart from others by under `names`: itself, or in
+/// lower case in a case-insensitive directory.
+pub fn name_key(path: &Path, names: &impl Names) -> PathBuf {
+	if !names.is_case_insensitive(parent_dir(path)) {
+		return path.to_path_buf();
+	}
+
+	match path.to_str() {
+		Some(x) => PathBuf::from(x.to_lowercase()),
+		None => PathBuf::from(path.as_os_str().to_ascii_lowercase()),
+	}
+}
+
+/// Whether writing `destination` in place of `input` would clobber another
+/// file, rather than just renaming `input` on a case-insensitive filesystem.
+pub fn collides(input: &Path, destination: &Path, names: &impl Names) -> bool {
+	input != destination && names.exists(destination) && name_key(input, names) != name_key(destination, names)
+}
+
+/// Outputs handed out in a run, so that no two inputs write the same one,
+/// even under names differing only in case
+#[derive(Debug, Default)]
+pub struct Claims(HashMap<PathBuf, PathBuf>);
+
+impl Claims {
+	/// Claims `output` for `input`, failing with the input which claimed it
+	/// first if another did.
+	pub fn claim(&mut self, output: &Path, input: &Path, names: &impl Names) -> Result<(), PathBuf> {
+		let claimed = self.0.entry(name_key(output, names)).or_insert_with(|| input.to_path_buf());
+		match claimed == input {
+			true => Ok(()),
+			false => Err(claimed.clone()),
+		}
+	}
+}
+
+/// Room left on a filesystem
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Headroom {
+	/// Bytes unprivileged users may still write
+	pub bytes: u64,
+	/// Files they may still create
+	pub inodes: u64,
+}
+
+impl Headroom {
+	/// Checks there is room for a file of `size` bytes, failing the way
+	/// writing it would have otherwise.
+	pub fn check(self, size: u64) -> io::Result<()> {
+		if self.inodes == 0 {
+			return Err(io::Error::new(io::ErrorKind::StorageFull, "no inodes left"));
+		}
+
+		if self.bytes < size {
+			let message = format!("{} B needed, but only {} B left", size, self.bytes);
+			return Err(io::Error::new(io::ErrorKind::StorageFull, message));
+		}
+
+		Ok(())
+	}
+}
+
+/// Room left on the filesystem of `dir`, as far as it tells; network shares
+/// limited by quotas mostly report what is left of them.
+#[cfg(target_family = "unix")]
+pub fn headroom(dir: &Path) -> io::Result<Option<Headroom>> {
+	let stat = nix::sys::statvfs::statvfs(dir)?;
+	let bytes = (stat.blocks_available() as u64).saturating_mul(stat.fragment_size() as u64);
+	// filesystems allocating inodes as they go, like btrfs, report none at all
+	let inodes = match stat.files() {
+		0 => u64::MAX,
+		_ => stat.files_available() as u64,
+	};
+
+	Ok(Some(Headroom { bytes, inodes }))
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn headroom(_dir: &Path) -> io::Result<Option<Headroom>> {
+	Ok(None)
+}
+
+#[cfg(target_family = "unix")]
+fn mount(path: &Path) -> io::Result<u64> {
+	use std::os::unix::fs::MetadataExt;
+
+	Ok(fs::metadata(path)?.dev())
+}
+
+#[cfg(not(target_family = "unix"))]
+fn mount(_path: &Path) -> io::Result<u64> {
+	Ok(0)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+	use std::os::fd::AsRawFd;
+
+	nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+	let source = File::open(src)?;
+	let target = File::options().write(true).create_new(true).open(dst)?;
+	// SAFETY: both descriptors stay open for the duration of the call
+	if let Err(x) = unsafe { ficlone(target.as_raw_fd(), source.as_raw_fd() as _) } {
+		drop(target);
+		let _ = fs::remove_file(dst);
+		return Err(io::Error::from(x));
+	}
+
+	let metadata = source.metadata()?;
+	target.set_permissions(metadata.permissions())?;
+	filetime::set_file_handle_times(&target, None, Some(filetime::FileTime::from_last_modification_time(&metadata)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+	Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Filesystems we have to treat specially
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsFamily {
+	/// FAT and exFAT, as found on SD cards and USB sticks
+	Fat,
+	Other,
+}
+
+impl FsFamily {
+	const FAT_ILLEGAL: &'static [char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+	/// Detects the family of the filesystem `path` lives on.
+	#[cfg(target_os = "linux")]
+	pub fn detect(path: impl AsRef<Path>) -> Result<Self, crate::Error> {
+		let path = path.as_ref();
+		let stat = nix::sys::statfs::statfs(path)?;
+		let family = Self::from_magic(stat.filesystem_type().0 as u64);
+		trace!("`{}` is on a filesystem of type {:?}", path.display(), family);
+		Ok(family)
+	}
+
+	#[cfg(target_os = "macos")]
+	pub fn detect(path: impl AsRef<Path>) -> Result<Self, crate::Error> {
+		let path = path.as_ref();
+		let stat = nix::sys::statfs::statfs(path)?;
+		let family = Self::from_name(stat.filesystem_type_name());
+		trace!("`{}` is on a filesystem of type {:?}", path.display(), family);
+		Ok(family)
+	}
+
+	#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+	pub fn detect(_path: impl AsRef<Path>) -> Result<Self, crate::Error> {
+		Ok(FsFamily::Other)
+	}
+
+	/// Maps a `statfs(2)` magic number on Linux.
+	#[cfg(any(target_os = "linux", test))]
+	pub fn from_magic(magic: u64) -> Self {
+		const MSDOS_SUPER_MAGIC: u64 = 0x4d44;
+		const EXFAT_SUPER_MAGIC: u64 = 0x2011_bab0;
+
+		match magic {
+			MSDOS_SUPER_MAGIC | EXFAT_SUPER_MAGIC => FsFamily::Fat,
+			_ => FsFamily::Other,
+		}
+	}
+
+	/// Maps a filesystem type name, as reported by BSD-style `statfs(2)`.
+	#[cfg(any(target_os = "macos", test))]
+	pub fn from_name(name: &str) -> Self {
+		match name {
+			"msdos" | "vfat" | "exfat" => FsFamily::Fat,
+			_ => FsFamily::Other,
+		}
+	}
+
+	/// Replaces characters in `name` the filesystem cannot store.
+	pub fn sanitize(self, name: &OsStr) -> Cow<'_, OsStr> {
+		if self != FsFamily::Fat {
+			return Cow::Borrowed(name);
+		}
+
+		let Some(text) = name.to_str() else {
+			return Cow::Borrowed(name);
+		};
+
+		let legal = |x: char| !x.is_control() && !Self::FAT_ILLEGAL.contains(&x);
+		let trimmed = text.trim_end_matches(['.', ' ']);
+		if trimmed.len() == text.len() && text.chars().all(legal) {
+			return Cow::Borrowed(name);
+		}
+
+		// FAT silently drops trailing dots and spaces, so that the name would
+		// not round-trip
+		let mut sanitized: String = trimmed.chars().map(|x| if legal(x) { x } else { '_' }).collect();
+		sanitized.extend(std::iter::repeat_n('_', text.len() - trimmed.len()));
+		Cow::Owned(OsString::from(sanitized))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+	use std::ffi::OsStr;
+	use std::fs;
+	use std::io;
+	use std::path::{Path, PathBuf};
+
+	use super::{
+		absolute_arg, canonical_path, check_length, copy_with, finish_move, is_unsupported, matching_extension,
+		reflink, collides, name_key, relative_path, retarget, Claims, CopyMethod, Disk, FsFamily, Headroom, Names,
+		PATH_MAX,
+	};
+
+	#[test]
+	fn makes_paths_of_commands_absolute() {
+		let dir = tempfile::tempdir().unwrap();
+		let cwd = dir.path();
+		fs::write(cwd.join("a.jpg"), "").unwrap();
+		fs::create_dir(cwd.join("photos")).unwrap();
+		let outputs = HashSet::from([PathBuf::from("a-x.webp"), PathBuf::from("out")]);
+		let absolute = |arg: &str| absolute_arg(OsStr::new(arg), cwd, |x| outputs.contains(x));
+
+		assert_eq!(absolute("a.jpg"), Some(cwd.join("a.jpg").into()));
+		assert_eq!(absolute("photos"), Some(cwd.join("photos").into()));
+		assert_eq!(absolute("b.jpg"), None);
+		assert_eq!(absolute("libsvtav1"), None);
+		assert_eq!(absolute("-strip"), None);
+		assert_eq!(absolute("/tmp/a.jpg"), None);
+		assert_eq!(absolute("-"), None);
+		assert_eq!(absolute("pgm:-"), None);
+
+		// outputs do not exist yet
+		assert_eq!(absolute("a-x.webp"), Some(cwd.join("a-x.webp").into()));
+		assert_eq!(absolute("out"), Some(cwd.join("out").into()));
+		let mut prefixed = OsStr::new("webp:").to_os_string();
+		prefixed.push(cwd.join("a-x.webp"));
+		assert_eq!(absolute("webp:a-x.webp"), Some(prefixed));
+	}
+
+	#[test]
+	fn relates_paths_to_directories() {
+		let relative = |path: &str, base: &str| relative_path(Path::new(path), Path::new(base));
+		assert_eq!(relative("/home/me/photos/a.jpg", "/home/me"), Path::new("photos/a.jpg"));
+		assert_eq!(relative("/home/me/photos/a.jpg", "/home/me/videos"), Path::new("../photos/a.jpg"));
+		assert_eq!(relative("/srv/a.jpg", "/home/me"), Path::new("../../srv/a.jpg"));
+		assert_eq!(relative("/home/me", "/home/me"), Path::new("."));
+		assert_eq!(relative("/home/me", "/home/me/photos"), Path::new(".."));
+		// a common prefix of names does not count
+		assert_eq!(relative("/home/meg/a.jpg", "/home/me"), Path::new("../meg/a.jpg"));
+	}
+
+	#[test]
+	fn checks_lengths_of_paths() {
+		let dir = tempfile::tempdir().unwrap();
+		let deep: PathBuf = (0..6).map(|x| format!("{}{}", x, "d".repeat(49))).collect();
+		assert!(check_length(&dir.path().join(&deep).join(format!("{}.webp", "a".repeat(250)))).is_ok());
+		assert!(check_length(&dir.path().join(&deep).join(format!("{}.webp", "a".repeat(251)))).is_err());
+
+		let deepest: PathBuf = (0..=PATH_MAX / 50).map(|_| "d".repeat(49)).collect();
+		assert!(check_length(&dir.path().join(deepest).join("a.jpg")).is_err());
+		assert!(check_length(Path::new("a.jpg")).is_ok());
+	}
+
+	#[test]
+	fn canonicalizes_paths_to_display() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path().canonicalize().unwrap();
+		fs::create_dir(root.join("photos")).unwrap();
+		fs::write(root.join("photos/a.jpg"), "").unwrap();
+
+		let path = root.join("photos/../photos/./a.jpg");
+		assert_eq!(canonical_path(&path), root.join("photos/a.jpg"));
+		// gone, like an input replaced by an output of another format
+		assert_eq!(canonical_path(&root.join("photos/b.mp4")), root.join("photos/b.mp4"));
+		assert_eq!(canonical_path(&root.join("photos/..")), root);
+
+		#[cfg(target_family = "unix")]
+		{
+			std::os::unix::fs::symlink(root.join("photos/a.jpg"), root.join("link.jpg")).unwrap();
+			assert_eq!(canonical_path(&root.join("photos/../link.jpg")), root.join("link.jpg"));
+		}
+	}
+
+	/// Files of a filesystem case-insensitive in the directories given
+	struct Fake {
+		files: Vec<&'static str>,
+		insensitive: Vec<&'static str>,
+	}
+
+	impl Names for Fake {
+		fn exists(&self, path: &Path) -> bool {
+			let key = name_key(path, self);
+			self.files.iter().any(|x| name_key(Path::new(x), self) == key)
+		}
+
+		fn is_case_insensitive(&self, dir: &Path) -> bool {
+			self.insensitive.iter().any(|x| Path::new(x) == dir)
+		}
+	}
+
+	#[test]
+	fn tells_colliding_names_apart_by_case_where_it_matters() {
+		let names = Fake { files: vec!["mac/Photo.jpg", "linux/Photo.jpg"], insensitive: vec!["mac"] };
+		let collides = |input: &str, destination: &str| collides(Path::new(input), Path::new(destination), &names);
+		assert!(collides("mac/photo.png", "mac/photo.jpg"));
+		assert!(!collides("linux/photo.png", "linux/photo.jpg"));
+		assert!(collides("linux/Photo.png", "linux/Photo.jpg"));
+		// the input itself, under another name
+		assert!(!collides("mac/Photo.jpg", "mac/photo.jpg"));
+		assert!(!collides("mac/a.png", "mac/a.jpg"));
+	}
+
+	#[test]
+	fn claims_outputs_once_per_run() {
+		let names = Fake { files: vec![], insensitive: vec!["mac"] };
+		let mut claims = Claims::default();
+		let mut claim = |output: &str, input: &str| {
+			let claimed = claims.claim(Path::new(output), Path::new(input), &names);
+			claimed.map_err(|x| x.to_str().unwrap().to_string())
+		};
+		assert_eq!(claim("mac/Photo.jpg", "a/Photo.png"), Ok(()));
+		// again for the same input, e.g. with `--fallback`
+		assert_eq!(claim("mac/Photo.jpg", "a/Photo.png"), Ok(()));
+		assert_eq!(claim("mac/photo.jpg", "b/photo.png"), Err("a/Photo.png".into()));
+		assert_eq!(claim("linux/Photo.jpg", "a/Photo.png"), Ok(()));
+		assert_eq!(claim("linux/photo.jpg", "b/photo.png"), Ok(()));
+		assert_eq!(claim("linux/photo.jpg", "c/photo.png"), Err("b/photo.png".into()));
+	}
+
+	#[test]
+	fn probes_directories_for_case_insensitivity() {
+		let dir = tempfile::tempdir().unwrap();
+		fs::write(dir.path().join("a"), "").unwrap();
+		let expected = dir.path().join("A").exists();
+		assert_eq!(Disk.is_case_insensitive(dir.path()), expected);
+		// without leaving the probe behind
+		assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+	}
+
+	#[tokio::test]
+	async fn copies_across_filesystems() {
+		let dir = tempfile::tempdir().unwrap();
+		let (src, dst) = (dir.path().join("a.webp"), dir.path().join("b.webp"));
+		fs::write(&src, "converted").unwrap();
+		let modified = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+		filetime::set_file_mtime(&src, modified).unwrap();
+
+		// as if renaming failed because `dst` is on another filesystem
+		finish_move(&src, &dst, Err(io::ErrorKind::CrossesDevices.into())).await.unwrap();
+		assert!(!src.exists());
+		assert_eq!(fs::read_to_string(&dst).unwrap(), "converted");
+		assert_eq!(filetime::FileTime::from_last_modification_time(&fs::metadata(&dst).unwrap()), modified);
+		assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+		// failures of renaming otherwise leave everything alone
+		let denied = finish_move(&dst, &src, Err(io::ErrorKind::PermissionDenied.into())).await;
+		assert!(denied.is_err());
+		assert!(dst.exists() && !src.exists());
+	}
+
+	#[cfg(target_family = "unix")]
+	#[test]
+	fn points_symlinks_at_outputs() {
+		use std::os::unix::fs::symlink;
+
+		let dir = tempfile::tempdir().unwrap();
+		let root = &dir.path().canonicalize().unwrap();
+		fs::create_dir(root.join("archive")).unwrap();
+		fs::write(root.join("archive/b.webm"), "").unwrap();
+		symlink("archive/b.mp4", root.join("b.mp4")).unwrap();
+		retarget(&root.join("b.mp4"), &root.join("archive/b.mp4"), &root.join("archive/b.webm")).unwrap();
+		assert_eq!(fs::read_link(root.join("b.mp4")).unwrap(), Path::new("archive/b.webm"));
+
+		symlink("b.mp4", root.join("c.mp4")).unwrap();
+		retarget(&root.join("c.mp4"), &root.join("archive/b.mp4"), &root.join("archive/b.webm")).unwrap();
+		assert_eq!(fs::read_link(root.join("c.mp4")).unwrap(), root.join("archive/b.webm"));
+		assert_eq!(fs::read_dir(root).unwrap().count(), 3);
+	}
+
+	#[test]
+	fn detects_fat_families() {
+		assert_eq!(FsFamily::from_magic(0x4d44), FsFamily::Fat);
+		assert_eq!(FsFamily::from_magic(0x2011_bab0), FsFamily::Fat);
+		assert_eq!(FsFamily::from_magic(0xef53), FsFamily::Other);
+		assert_eq!(FsFamily::from_name("exfat"), FsFamily::Fat);
+		assert_eq!(FsFamily::from_name("apfs"), FsFamily::Other);
+	}
+
+	#[test]
+	fn sanitizes_only_on_fat() {
+		let name = OsStr::new("a: b?.jpg");
+		assert_eq!(FsFamily::Other.sanitize(name), name);
+		assert_eq!(FsFamily::Fat.sanitize(name), OsStr::new("a_ b_.jpg"));
+		assert_eq!(FsFamily::Fat.sanitize(OsStr::new("a.jpg")), OsStr::new("a.jpg"));
+		assert_eq!(FsFamily::Fat.sanitize(OsStr::new("a. ")), OsStr::new("a__"));
+	}
+
+	#[test]
+	fn keeps_extension_when_format_stays() {
+		let extension = |input: &str, new: &str| matching_extension(Path::new(input), OsStr::new(new));
+		assert_eq!(extension("PHOTO.JPG", "jpg"), "JPG");
+		assert_eq!(extension("photo.jpg", "jpg"), "jpg");
+		assert_eq!(extension("photo.Jpeg", "jpg"), "Jpeg");
+		assert_eq!(extension("photo.JPE", "jpg"), "JPE");
+		assert_eq!(extension("scan.TIF", "tiff"), "TIF");
+		assert_eq!(extension("dir.PNG/clip.webm", "webm"), "webm");
+	}
+
+	#[test]
+	fn changes_extension_in_case_of_input() {
+		let extension = |input: &str, new: &str| matching_extension(Path::new(input), OsStr::new(new));
+		assert_eq!(extension("PHOTO.PNG", "jpg"), "JPG");
+		assert_eq!(extension("IMG_0001.HEIC", "jpg"), "JPG");
+		assert_eq!(extension("clip.mov", "webm"), "webm");
+		assert_eq!(extension("CLIP.MOV", "webm"), "WEBM");
+		// mixed case goes by the letters there are more of
+		assert_eq!(extension("clip.Mov", "webm"), "webm");
+		assert_eq!(extension("clip.MoV", "webm"), "WEBM");
+		assert_eq!(extension("photo.Png", "jpg"), "jpg");
+		assert_eq!(extension("clip.MP4", "webm"), "WEBM");
+		assert_eq!(extension("clip.3GP", "webm"), "WEBM");
+		assert_eq!(extension("clip.3gp", "webm"), "webm");
+	}
+
+	#[test]
+	fn uses_extension_as_is_without_one() {
+		let extension = |input: &str, new: &str| matching_extension(Path::new(input), OsStr::new(new));
+		assert_eq!(extension("PHOTO", "jpg"), "jpg");
+		assert_eq!(extension(".HIDDEN", "jpg"), "jpg");
+		assert_eq!(extension("dir.D/PHOTO", "jpg"), "jpg");
+	}
+
+	#[test]
+	fn copies_data_and_modification_time() {
+		let dir = tempfile::tempdir().unwrap();
+		let (src, dst) = (dir.path().join("a.jpg"), dir.path().join("b.jpg"));
+		fs::write(&src, "original").unwrap();
+		filetime::set_file_mtime(&src, filetime::FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+
+		assert_eq!(copy_with(&src, &dst, &[CopyMethod::Copy]).unwrap(), CopyMethod::Copy);
+		assert_eq!(fs::read_to_string(&dst).unwrap(), "original");
+		assert_eq!(fs::metadata(&dst).unwrap().modified().unwrap(), fs::metadata(&src).unwrap().modified().unwrap());
+	}
+
+	#[test]
+	fn falls_back_to_methods_that_work() {
+		let dir = tempfile::tempdir().unwrap();
+		let src = dir.path().join("a.jpg");
+		fs::write(&src, "original").unwrap();
+
+		// reflinks only work on copy-on-write filesystems
+		let method = copy_with(&src, &dir.path().join("b.jpg"), &[CopyMethod::Reflink, CopyMethod::Copy]).unwrap();
+		assert_eq!(fs::read_to_string(dir.path().join("b.jpg")).unwrap(), "original");
+
+		// hardlinks are the next cheapest where reflinks are not supported
+		let expected = if method == CopyMethod::Reflink { CopyMethod::Reflink } else { CopyMethod::Hardlink };
+		assert_eq!(copy_with(&src, &dir.path().join("c.jpg"), &CopyMethod::ALL).unwrap(), expected);
+		assert_eq!(fs::read_to_string(dir.path().join("c.jpg")).unwrap(), "original");
+	}
+
+	#[test]
+	fn reflinks_where_supported() {
+		let dir = tempfile::tempdir().unwrap();
+		let (src, dst) = (dir.path().join("a.jpg"), dir.path().join("b.jpg"));
+		fs::write(&src, "original").unwrap();
+
+		match reflink(&src, &dst) {
+			Ok(()) => assert_eq!(fs::read_to_string(&dst).unwrap(), "original"),
+			Err(x) => {
+				assert!(is_unsupported(&x), "{}", x);
+				assert!(!dst.exists());
+			}
+		}
+	}
+
+	#[test]
+	fn checks_headroom() {
+		let headroom = Headroom { bytes: 1000, inodes: 2 };
+		assert!(headroom.check(1000).is_ok());
+		assert_eq!(headroom.check(1001).unwrap_err().kind(), io::ErrorKind::StorageFull);
+		let full = Headroom { inodes: 0, ..headroom };
+		assert_eq!(full.check(0).unwrap_err().kind(), io::ErrorKind::StorageFull);
+	}
+
+	#[test]
+	fn tells_headroom_of_directories() {
+		let dir = tempfile::tempdir().unwrap();
+		if let Some(headroom) = super::headroom(dir.path()).unwrap() {
+			assert!(headroom.inodes > 0);
+		}
+
+		assert!(super::headroom(&dir.path().join("missing")).is_err());
+	}
+}
@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::error::Stage;
+
+const IMAGE: u8 = 0x2c;
+const EXTENSION: u8 = 0x21;
+const TRAILER: u8 = 0x3b;
+
+/// Whether the GIF at `path` has more than one frame, and so is to be
+/// converted like a video; `None` for GIFs broken before that, which are of
+/// unknown format.
+pub async fn is_animated(path: &Path) -> Result<Option<bool>, crate::Error> {
+	let owned = path.to_path_buf();
+	let frames = tokio::task::spawn_blocking(move || frames(BufReader::new(File::open(owned)?), 2));
+	match frames.await.expect("failed to count the frames") {
+		Ok(x) => Ok(Some(x > 1)),
+		Err(x) if matches!(x.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => {
+			debug!("`{}` is broken: {}", path.display(), x);
+			Ok(None)
+		}
+		Err(x) => Err(crate::Error::input_io(Stage::Identify, path)(x)),
+	}
+}
+
+/// Frames of the GIF read from `reader`, counted up to `most`.
+pub fn frames(mut reader: impl Read, most: usize) -> io::Result<usize> {
+	// signature, version and logical screen descriptor
+	let mut header = [0; 13];
+	reader.read_exact(&mut header)?;
+	if !header.starts_with(b"GIF") {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "not a GIF"));
+	}
+
+	skip_color_table(&mut reader, header[10])?;
+	let mut frames = 0;
+	while frames < most {
+		match byte(&mut reader)? {
+			IMAGE => {
+				let mut descriptor = [0; 9];
+				reader.read_exact(&mut descriptor)?;
+				skip_color_table(&mut reader, descriptor[8])?;
+				// minimum code size of the image data
+				byte(&mut reader)?;
+				skip_sub_blocks(&mut reader)?;
+				frames += 1;
+			}
+			EXTENSION => {
+				// label
+				byte(&mut reader)?;
+				skip_sub_blocks(&mut reader)?;
+			}
+			TRAILER => break,
+			x => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown block {:#04x}", x))),
+		}
+	}
+
+	Ok(frames)
+}
+
+fn byte(reader: &mut impl Read) -> io::Result<u8> {
+	let mut byte = [0];
+	reader.read_exact(&mut byte)?;
+	Ok(byte[0])
+}
+
+fn skip(reader: &mut impl Read, length: u64) -> io::Result<()> {
+	match io::copy(&mut reader.take(length), &mut io::sink())? {
+		x if x < length => Err(io::ErrorKind::UnexpectedEof.into()),
+		_ => Ok(()),
+	}
+}
+
+/// Skips the color table following a descriptor with `flags`, if any.
+fn skip_color_table(reader: &mut impl Read, flags: u8) -> io::Result<()> {
+	match flags & 0x80 {
+		0 => Ok(()),
+		_ => skip(reader, 3 << ((flags & 0x07) + 1)),
+	}
+}
+
+/// Skips data sub-blocks, up to the empty one ending them.
+fn skip_sub_blocks(reader: &mut impl Read) -> io::Result<()> {
+	loop {
+		match byte(reader)? {
+			0 => return Ok(()),
+			x => skip(reader, x.into())?,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io;
+
+	use super::frames;
+
+	/// A GIF of `frames` frames, with a global color table of 4 colors and
+	/// a local one of 2 on every other frame.
+	fn gif(frames: usize) -> Vec<u8> {
+		let mut gif = b"GIF89a\x10\x00\x10\x00\x81\x00\x00".to_vec();
+		gif.extend([0; 12]);
+		// looping, like animations do
+		gif.extend(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+		for frame in 0..frames {
+			gif.extend(b"\x21\xf9\x04\x00\x0a\x00\x00\x00");
+			match frame % 2 {
+				0 => gif.extend(b"\x2c\x00\x00\x00\x00\x10\x00\x10\x00\x00"),
+				_ => gif.extend(b"\x2c\x00\x00\x00\x00\x10\x00\x10\x00\x80\x00\x00\x00\xff\xff\xff"),
+			}
+
+			gif.extend(b"\x02\xff");
+			gif.extend([0x2c; 255]);
+			gif.extend(b"\x03\x01\x02\x03\x00");
+		}
+
+		gif.push(0x3b);
+		gif
+	}
+
+	#[test]
+	fn counts_frames() {
+		assert_eq!(frames(&gif(0)[..], 2).unwrap(), 0);
+		assert_eq!(frames(&gif(1)[..], 2).unwrap(), 1);
+		assert_eq!(frames(&gif(2)[..], 2).unwrap(), 2);
+		assert_eq!(frames(&gif(5)[..], 10).unwrap(), 5);
+		// without reading the rest
+		assert_eq!(frames(&gif(5)[..], 2).unwrap(), 2);
+	}
+
+	#[test]
+	fn fails_on_broken_gifs() {
+		let kind = |x: &[u8]| frames(x, 2).unwrap_err().kind();
+		assert_eq!(kind(b""), io::ErrorKind::UnexpectedEof);
+		assert_eq!(kind(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"), io::ErrorKind::InvalidData);
+
+		let gif = gif(1);
+		assert_eq!(kind(&gif[..gif.len() - 20]), io::ErrorKind::UnexpectedEof);
+		let mut garbled = gif.clone();
+		garbled[13 + 12] = 0x42;
+		assert_eq!(kind(&garbled), io::ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tracing::{debug, trace};
+
+use crate::context::Context;
+use crate::fsutil;
+use crate::options::GitPolicy;
+use crate::skip::SkipReason;
+
+/// What is known about the git work trees inputs are in
+#[derive(Debug, Default)]
+pub struct Repositories {
+	/// Work tree of each directory seen so far, with the names of the files in
+	/// it that are tracked
+	dirs: HashMap<PathBuf, Option<Tracked>>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+	root: PathBuf,
+	files: HashSet<OsString>,
+}
+
+/// Applies `policy` to `input` if it is tracked in a git work tree, telling
+/// why to skip it if it is to be skipped.
+pub async fn check(context: &mut Context, policy: GitPolicy, input: &Path) -> Result<Option<SkipReason>, crate::Error> {
+	if policy == GitPolicy::Ignore {
+		return Ok(None);
+	}
+
+	let root = match tracked_by(context, input).await {
+		Ok(x) => x,
+		// only worth failing for when asked to leave tracked files alone
+		Err(x) if policy == GitPolicy::Warn => {
+			debug!("unable to tell whether `{}` is tracked by git: {}", input.display(), x);
+			None
+		}
+		Err(x) => return Err(x),
+	};
+
+	let Some(root) = root else {
+		return Ok(None);
+	};
+
+	match policy {
+		GitPolicy::SkipTracked => Ok(Some(SkipReason::TrackedByGit(root))),
+		_ => {
+			let message = format!(
+				"`{}` is tracked by the git repository at `{}`; use `--git skip-tracked` to leave tracked files alone",
+				input.display(),
+				root.display()
+			);
+			context.terminal.write_note_once("git", Some(&root), message);
+
+			Ok(None)
+		}
+	}
+}
+
+/// Root of the work tree tracking `input`, if any.
+async fn tracked_by(context: &mut Context, input: &Path) -> Result<Option<PathBuf>, crate::Error> {
+	let dir = fs::canonicalize(fsutil::parent_dir(input)).await?;
+	if !context.git.dirs.contains_key(&dir) {
+		let tracked = list_tracked(context, &dir).await?;
+		context.git.dirs.insert(dir.clone(), tracked);
+	}
+
+	let Some(tracked) = &context.git.dirs[&dir] else {
+		return Ok(None);
+	};
+
+	let name = input.file_name().unwrap_or(input.as_os_str());
+	Ok(tracked.files.contains(name).then(|| tracked.root.clone()))
+}
+
+/// Finds the work tree `dir` is in and the files in it git tracks, running
+/// git only for directories in a work tree.
+async fn list_tracked(context: &mut Context, dir: &Path) -> Result<Option<Tracked>, crate::Error> {
+	let mut root = None;
+	for ancestor in dir.ancestors() {
+		if fs::try_exists(ancestor.join(".git")).await? {
+			root = Some(ancestor.to_path_buf());
+			break;
+		}
+	}
+
+	let Some(root) = root else {
+		trace!("`{}` is not in a git work tree", dir.display());
+		return Ok(None);
+	};
+
+	let mut command = context.command("git")?;
+	command.arg("-C").arg(dir).args(["ls-files", "-z"]);
+
+	let output = context.output(command).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation("git", output.status, &output.stderr));
+	}
+
+	// only the files right in `dir`, not those in its subdirectories
+	let files: HashSet<_> = output
+		.stdout
+		.split(|x| *x == 0)
+		.filter(|x| !x.is_empty() && !x.contains(&b'/'))
+		.map(os_string)
+		.collect();
+
+	debug!("`{}` has {} files tracked by git in `{}`", dir.display(), files.len(), root.display());
+	Ok(Some(Tracked { root, files }))
+}
+
+#[cfg(target_family = "unix")]
+fn os_string(bytes: &[u8]) -> OsString {
+	std::os::unix::ffi::OsStringExt::from_vec(bytes.to_vec())
+}
+
+#[cfg(not(target_family = "unix"))]
+fn os_string(bytes: &[u8]) -> OsString {
+	OsString::from(String::from_utf8_lossy(bytes).into_owned())
+}
@@ -0,0 +1,111 @@
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use crate::context::Context;
+use crate::template::{CommandLine, Placeholder, Template, TemplateError};
+
+/// Command to run after each file, taken as a whole when run by a shell, and
+/// split on whitespace otherwise
+#[derive(Clone, Debug)]
+pub struct Hook {
+	line: CommandLine,
+	script: Template,
+}
+
+/// Parses a hook given on the command line.
+pub fn parse(value: &str) -> Result<Hook, String> {
+	let line = CommandLine::parse(value, Placeholder::HOOK).map_err(|x: TemplateError| x.to_string())?;
+	let script = Template::parse(value, Placeholder::HOOK).map_err(|x| x.to_string())?;
+	Ok(Hook { line, script })
+}
+
+/// What the placeholders of a hook stand for
+#[derive(Clone, Debug)]
+pub struct Values<'a> {
+	pub input: &'a Path,
+	pub output: Option<&'a Path>,
+	pub status: &'a str,
+	/// Negative if the file grew
+	pub saved_bytes: i64,
+	pub mime: &'a str,
+}
+
+impl Values<'_> {
+	fn get(&self, placeholder: Placeholder) -> OsString {
+		match placeholder {
+			Placeholder::Input => self.input.into(),
+			Placeholder::Output => self.output.map(OsString::from).unwrap_or_default(),
+			Placeholder::Status => self.status.into(),
+			Placeholder::SavedBytes => self.saved_bytes.to_string().into(),
+			Placeholder::Mime => self.mime.into(),
+			Placeholder::Quality | Placeholder::Comment => OsString::new(),
+		}
+	}
+}
+
+impl Hook {
+	/// Runs the hook, with `sh -c` if `shell`, failing if it does.
+	pub async fn run(&self, context: &mut Context, shell: bool, values: &Values<'_>) -> Result<(), crate::Error> {
+		let (program, mut command) = match shell {
+			true => ("sh", context.command("sh")?),
+			false => (self.line.program.as_str(), context.command(&self.line.program)?),
+		};
+
+		// quoted for the shell, which splits the script into words itself
+		let values: Vec<_> = Placeholder::HOOK
+			.iter()
+			.map(|x| (*x, values.get(*x)))
+			.map(|(x, value)| (x, if shell { quote(&value) } else { value }))
+			.collect();
+		let value = |x| values.iter().find(|(y, _)| *y == x).map_or(OsStr::new(""), |(_, x)| x.as_os_str());
+		if shell {
+			command.arg("-c").arg(self.script.expand(value));
+		} else {
+			command.args(self.line.args.iter().map(|x| x.expand(value)));
+		}
+
+		let output = context.output_here(command).await?;
+		if !output.status.success() {
+			return Err(crate::Error::invocation(program, output.status, &output.stderr));
+		}
+
+		Ok(())
+	}
+}
+
+/// Quotes `value` for a POSIX shell, so that it stays a single word.
+#[cfg(target_family = "unix")]
+fn quote(value: &OsStr) -> OsString {
+	use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+	let mut quoted = vec![b'\''];
+	for byte in value.as_bytes() {
+		match byte {
+			b'\'' => quoted.extend_from_slice(b"'\\''"),
+			x => quoted.push(*x),
+		}
+	}
+
+	quoted.push(b'\'');
+	OsString::from_vec(quoted)
+}
+
+#[cfg(not(target_family = "unix"))]
+fn quote(value: &OsStr) -> OsString {
+	format!("'{}'", value.to_string_lossy().replace('\'', "'\\''")).into()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsStr;
+
+	use super::quote;
+
+	#[test]
+	fn quotes_values_for_shells() {
+		assert_eq!(quote(OsStr::new("a.jpg")), "'a.jpg'");
+		assert_eq!(quote(OsStr::new("my photo.jpg")), "'my photo.jpg'");
+		assert_eq!(quote(OsStr::new("it's $HOME")), "'it'\\''s $HOME'");
+		assert_eq!(quote(OsStr::new("")), "''");
+	}
+}
@@ -0,0 +1,244 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use magic::Cookie;
+use tokio::sync::Semaphore;
+use tokio::task;
+use tracing::{trace, warn};
+
+use crate::options::MagicOptions;
+
+/// Magic database built in, covering the formats converted
+const BUILT_IN: &str = include_str!("media.magic");
+
+/// How files are identified, from the most accurate way down
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Tier {
+	/// By their contents, with the magic databases of the system or those given
+	Database,
+	/// By their contents, with the database built in
+	BuiltIn,
+	/// By their extensions, libmagic being of no use
+	Extension,
+}
+
+/// libmagic, run on the blocking threads so that identifying files does not
+/// hold up the async runtime and the progress shown, with a cookie for each
+/// file identified at once
+#[derive(Clone)]
+pub struct Identify {
+	/// Cookies not in use, one for each permit
+	idle: Arc<Mutex<Vec<Loaded>>>,
+	permits: Arc<Semaphore>,
+	tier: Tier,
+}
+
+/// A cookie with its databases loaded
+struct Loaded(Cookie);
+
+// SAFETY: libmagic cookies may be used from any thread, as long as only one
+// thread uses a cookie at a time; each is taken out of `Identify::idle` while
+// it is used
+unsafe impl Send for Loaded {}
+
+impl Identify {
+	/// Opens a cookie for each of `options.jobs` files to identify at once,
+	/// falling back on the database built in if the others fail to load, and
+	/// on extensions if that fails too.
+	pub fn new(options: &MagicOptions) -> Self {
+		match Self::with_databases(options) {
+			Ok(x) => return x,
+			Err(x) => warn!("failed to load libmagic database: {}", x),
+		}
+
+		match Self::built_in(options) {
+			Ok(x) => return x,
+			Err(x) => warn!("failed to load the built-in libmagic database: {}", x),
+		}
+
+		Identify { idle: Arc::default(), permits: Arc::new(Semaphore::new(0)), tier: Tier::Extension }
+	}
+
+	fn with_databases(options: &MagicOptions) -> Result<Self, crate::Error> {
+		if options.databases.is_empty() {
+			trace!("loading default libmagic database");
+		} else {
+			trace!("loading libmagic databases {:?}", options.databases);
+		}
+
+		// TODO: load databases manually using tokio
+		Self::open(options, Tier::Database, |x| Ok(x.load(&options.databases)?))
+	}
+
+	fn built_in(options: &MagicOptions) -> Result<Self, crate::Error> {
+		// libmagic only loads sources from files, and keeps them in memory
+		let path = std::env::temp_dir().join(format!("shrink-ray-{}.magic", std::process::id()));
+		trace!("loading built-in libmagic database from `{}`", path.display());
+		std::fs::write(&path, BUILT_IN)?;
+		let result = Self::open(options, Tier::BuiltIn, |x| Ok(x.load(&[&path])?));
+		let _ = std::fs::remove_file(&path);
+		result
+	}
+
+	fn open(
+		options: &MagicOptions, tier: Tier, load: impl Fn(&Cookie) -> Result<(), crate::Error>,
+	) -> Result<Self, crate::Error> {
+		let flags = options.flags();
+		let mut cookies = Vec::new();
+		for _ in 0..options.jobs.max(1) {
+			trace!("initializing libmagic with {:?}", flags);
+			let cookie = Cookie::open(flags)?;
+			load(&cookie)?;
+			cookies.push(Loaded(cookie));
+		}
+
+		let permits = Arc::new(Semaphore::new(cookies.len()));
+		Ok(Identify { idle: Arc::new(Mutex::new(cookies)), permits, tier })
+	}
+
+	pub fn tier(&self) -> Tier {
+		self.tier
+	}
+
+	/// What libmagic makes of `buffer`, once one of the cookies is free; not
+	/// to be used with [`Tier::Extension`].
+	pub async fn buffer(&self, buffer: Vec<u8>) -> Result<String, crate::Error> {
+		// never closed
+		let _permit = self.permits.acquire().await.unwrap();
+		let idle = self.idle.clone();
+		let result = task::spawn_blocking(move || {
+			let cookie = idle.lock().unwrap().pop().expect("a cookie for every permit");
+			let result = cookie.0.buffer(&buffer);
+			idle.lock().unwrap().push(cookie);
+			result
+		})
+		.await;
+
+		match result {
+			Ok(x) => Ok(x?),
+			Err(x) => Err(crate::Error::from(std::io::Error::other(x))),
+		}
+	}
+}
+
+/// MIME type of the formats converted, by the extension of `path`.
+pub fn by_extension(path: &Path) -> Option<&'static str> {
+	let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+	let mime = match extension.as_str() {
+		"jpg" | "jpeg" | "jpe" => "image/jpeg",
+		"png" => "image/png",
+		"gif" => "image/gif",
+		"webp" => "image/webp",
+		"tif" | "tiff" => "image/tiff",
+		"heic" => "image/heic",
+		"heif" => "image/heif",
+		"avif" => "image/avif",
+		"jxl" => "image/jxl",
+		"mp4" | "m4v" => "video/mp4",
+		"mov" => "video/quicktime",
+		"mkv" => "video/x-matroska",
+		"webm" => "video/webm",
+		"avi" => "video/x-msvideo",
+		"ts" | "mts" | "m2ts" => "video/mp2t",
+		_ => return None,
+	};
+
+	Some(mime)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::{Path, PathBuf};
+	use std::time::{Duration, Instant};
+
+	use tokio::task::JoinSet;
+
+	use super::{by_extension, Identify, Tier};
+	use crate::options::MagicOptions;
+
+	fn identify(jobs: u64) -> Identify {
+		Identify::new(&options(Vec::new(), jobs))
+	}
+
+	fn options(databases: Vec<PathBuf>, jobs: u64) -> MagicOptions {
+		MagicOptions { databases, flags: Vec::new(), jobs }
+	}
+
+	#[tokio::test]
+	async fn identifies_buffers() {
+		let identify = identify(2);
+		assert_eq!(identify.buffer(b"\xff\xd8\xff\xe0\x00\x10JFIF\x00".to_vec()).await.unwrap(), "image/jpeg");
+		assert_eq!(identify.buffer(b"GIF89a\x10\x00\x10\x00".to_vec()).await.unwrap(), "image/gif");
+	}
+
+	#[tokio::test]
+	async fn keeps_the_runtime_responsive() {
+		let identify = identify(4);
+		let mut files = JoinSet::new();
+		for index in 0..256 {
+			let identify = identify.clone();
+			let mut buffer = b"%PDF-1.4\n".to_vec();
+			buffer.resize(1024, index as u8);
+			files.spawn(async move { identify.buffer(buffer).await });
+		}
+
+		// how late the ticks of a progress bar would come meanwhile
+		let mut latest = Duration::ZERO;
+		let mut tick = Instant::now();
+		while !files.is_empty() {
+			tokio::select! {
+				Some(result) = files.join_next() => {
+					result.unwrap().unwrap();
+				}
+				_ = tokio::time::sleep(Duration::from_millis(1)) => {}
+			}
+
+			latest = latest.max(tick.elapsed());
+			tick = Instant::now();
+		}
+
+		assert!(latest < Duration::from_millis(20), "{:?}", latest);
+	}
+
+	#[tokio::test]
+	async fn falls_back_on_built_in_database() {
+		let identify = Identify::new(&options(vec!["/nonexistent/magic.mgc".into()], 1));
+		assert_eq!(identify.tier(), Tier::BuiltIn);
+
+		let samples: &[(&[u8], &str)] = &[
+			(b"\xff\xd8\xff\xe0\x00\x10JFIF\x00", "image/jpeg"),
+			(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR", "image/png"),
+			(b"GIF89a\x10\x00\x10\x00", "image/gif"),
+			(b"RIFF\x24\x10\x00\x00WEBPVP8 ", "image/webp"),
+			(b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00", "image/heic"),
+			(b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00isomiso2", "video/mp4"),
+			(b"\x00\x00\x00\x14ftypqt  \x00\x00\x00\x00", "video/quicktime"),
+			(b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x84webm", "video/webm"),
+			(b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x88matroska", "video/x-matroska"),
+		];
+		for (buffer, mime) in samples {
+			assert_eq!(identify.buffer(buffer.to_vec()).await.unwrap(), *mime);
+		}
+
+		// told by libmagic itself
+		assert_eq!(identify.buffer(b"plain text".to_vec()).await.unwrap(), "text/plain");
+	}
+
+	#[tokio::test]
+	async fn loads_databases_given() {
+		let database = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/custom.magic");
+		let identify = Identify::new(&options(vec![database], 1));
+		assert_eq!(identify.tier(), Tier::Database);
+		let buffer = b"SHRINKRAYTEST\x00\x01".to_vec();
+		assert_eq!(identify.buffer(buffer).await.unwrap(), "application/x-shrink-ray-test");
+	}
+
+	#[test]
+	fn identifies_by_extension() {
+		assert_eq!(by_extension(Path::new("a/b.JPG")), Some("image/jpeg"));
+		assert_eq!(by_extension(Path::new("b.webm")), Some("video/webm"));
+		assert_eq!(by_extension(Path::new("capture.M2TS")), Some("video/mp2t"));
+		assert_eq!(by_extension(Path::new("b.txt")), None);
+		assert_eq!(by_extension(Path::new("jpg")), None);
+	}
+}
@@ -0,0 +1,286 @@
+use std::pin::pin;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::time;
+use tracing::{debug, trace};
+
+use crate::context;
+use crate::terminal::Terminal;
+
+/// Tells whether the system is otherwise idle
+pub trait Detector {
+	/// Whether the system is idle, or nothing if it cannot tell.
+	fn is_idle(&mut self) -> Option<bool>;
+}
+
+/// Idle while the load average over the last minute stays below `threshold`
+pub struct LoadAverage {
+	pub threshold: f64,
+}
+
+impl Detector for LoadAverage {
+	fn is_idle(&mut self) -> Option<bool> {
+		let load = load_average()?;
+		trace!("load average is {:.2}", load);
+		Some(load < self.threshold)
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn load_average() -> Option<f64> {
+	let text = std::fs::read_to_string("/proc/loadavg").ok()?;
+	text.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn load_average() -> Option<f64> {
+	// like `{ 1.23 1.10 0.98 }`
+	let output = context::query("sysctl", &["-n", "vm.loadavg"]).ok()?;
+	String::from_utf8_lossy(&output.stdout).split_whitespace().find_map(|x| x.parse().ok())
+}
+
+/// Idle while logind says nobody uses the session shrink-ray runs in
+#[cfg(target_os = "linux")]
+pub struct Logind {
+	session: String,
+}
+
+#[cfg(target_os = "linux")]
+impl Logind {
+	/// Watches the session of `XDG_SESSION_ID`, unless not running in one,
+	/// e.g. from a service.
+	pub fn new() -> Option<Self> {
+		let session = std::env::var("XDG_SESSION_ID").ok().filter(|x| !x.is_empty())?;
+		Some(Logind { session })
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl Detector for Logind {
+	fn is_idle(&mut self) -> Option<bool> {
+		let output = context::query("loginctl", &["show-session", &self.session, "--property=IdleHint", "--value"])
+			.ok()
+			.filter(|x| x.status.success())?;
+		match String::from_utf8_lossy(&output.stdout).trim() {
+			"yes" => Some(true),
+			"no" => Some(false),
+			_ => None,
+		}
+	}
+}
+
+/// Idle once there was no input from the keyboard or the mouse for `after`
+#[cfg(target_os = "macos")]
+pub struct HidIdleTime {
+	pub after: Duration,
+}
+
+#[cfg(target_os = "macos")]
+impl Detector for HidIdleTime {
+	fn is_idle(&mut self) -> Option<bool> {
+		let output = context::query("ioreg", &["-c", "IOHIDSystem", "-d", "4"]).ok()?;
+		let output = String::from_utf8_lossy(&output.stdout);
+		// like `"HIDIdleTime" = 1234567890`, in nanoseconds
+		let line = output.lines().find(|x| x.contains("\"HIDIdleTime\""))?;
+		let idle = Duration::from_nanos(line.split('=').nth(1)?.trim().parse().ok()?);
+		trace!("no input for {:?}", idle);
+		Some(idle >= self.after)
+	}
+}
+
+/// Idle unless one of `detectors` says otherwise; it cannot tell if none of
+/// them can
+pub struct All(pub Vec<Box<dyn Detector>>);
+
+impl Detector for All {
+	fn is_idle(&mut self) -> Option<bool> {
+		let mut known = None;
+		for detector in &mut self.0 {
+			match detector.is_idle() {
+				Some(false) => return Some(false),
+				Some(true) => known = Some(true),
+				None => {}
+			}
+		}
+
+		known
+	}
+}
+
+/// Whether anybody uses the system, where the platform tells, and the load
+/// average otherwise.
+pub fn system(load: f64) -> Box<dyn Detector> {
+	#[allow(unused_mut)]
+	let mut detectors: Vec<Box<dyn Detector>> = Vec::new();
+	#[cfg(target_os = "linux")]
+	if let Some(logind) = Logind::new() {
+		detectors.push(Box::new(logind));
+	}
+
+	#[cfg(target_os = "macos")]
+	detectors.push(Box::new(HidIdleTime { after: Duration::from_secs(5 * 60) }));
+
+	detectors.push(Box::new(LoadAverage { threshold: load }));
+	Box::new(All(detectors))
+}
+
+/// Holds off starting files while the system is used, asking `detector`
+/// every `poll`
+pub struct Idle {
+	detector: Box<dyn Detector>,
+	poll: Duration,
+	/// Progress shown of the wait, while waiting or once a wait was cut short
+	waiting: Option<usize>,
+}
+
+impl Idle {
+	pub const POLL: Duration = Duration::from_secs(15);
+
+	pub fn new(detector: Box<dyn Detector>, poll: Duration) -> Self {
+		Idle { detector, poll, waiting: None }
+	}
+
+	/// Waits for the system to be idle, or for `deadline`, showing so; systems
+	/// which cannot tell count as idle. A wait cut short goes on where it was
+	/// the next time.
+	pub async fn wait(&mut self, terminal: &mut Terminal, deadline: Option<SystemTime>) -> Result<(), crate::Error> {
+		if self.detector.is_idle() != Some(false) {
+			if self.waiting.take().is_some() {
+				terminal.end_processing();
+			}
+
+			return Ok(());
+		}
+
+		let tick = terminal.spinner_interval().unwrap_or(self.poll).min(self.poll);
+		let mut interrupt = pin!(tokio::signal::ctrl_c());
+		let mut polled = Instant::now();
+		let mut progress = match self.waiting {
+			Some(x) => x,
+			None => {
+				debug!("system in use; waiting for it to be idle");
+				terminal.write_waiting(0);
+				0
+			}
+		};
+		self.waiting = Some(progress);
+		let result = loop {
+			let left = deadline.map(|x| x.duration_since(SystemTime::now()).unwrap_or_default());
+			tokio::select! {
+				_ = &mut interrupt => break Err(crate::Error::Cancelled),
+				_ = time::sleep(left.map_or(tick, |x| x.min(tick))) => {}
+			}
+
+			if deadline.is_some_and(|x| SystemTime::now() >= x) {
+				break Ok(());
+			}
+
+			if polled.elapsed() >= self.poll {
+				polled = Instant::now();
+				if self.detector.is_idle() != Some(false) {
+					debug!("system idle; going on");
+					break Ok(());
+				}
+			}
+
+			progress += 1;
+			self.waiting = Some(progress);
+			terminal.write_waiting(progress);
+		};
+
+		self.waiting = None;
+		terminal.end_processing();
+		result
+	}
+
+	/// Whether a wait was cut short, its progress still shown.
+	pub fn is_waiting(&self) -> bool {
+		self.waiting.is_some()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+	use std::collections::VecDeque;
+	use std::io;
+	use std::rc::Rc;
+	use std::time::{Duration, SystemTime};
+
+	use super::{All, Detector, Idle};
+	use crate::options::Units;
+	use crate::terminal::Terminal;
+
+	/// Goes through `states`, then stays idle, counting how often it is asked
+	struct Fake {
+		states: VecDeque<Option<bool>>,
+		polls: Rc<Cell<usize>>,
+	}
+
+	impl Detector for Fake {
+		fn is_idle(&mut self) -> Option<bool> {
+			self.polls.set(self.polls.get() + 1);
+			self.states.pop_front().unwrap_or(Some(true))
+		}
+	}
+
+	fn fake(states: &[Option<bool>]) -> (Box<Fake>, Rc<Cell<usize>>) {
+		let polls = Rc::new(Cell::new(0));
+		(Box::new(Fake { states: states.iter().copied().collect(), polls: polls.clone() }), polls)
+	}
+
+	fn terminal() -> Terminal {
+		Terminal::new(io::sink(), None, Units::default())
+	}
+
+	#[tokio::test]
+	async fn waits_whenever_in_use() {
+		let states = [Some(true), Some(false), Some(true), Some(false), Some(false), Some(true)];
+		let (detector, polls) = fake(&states);
+		let mut idle = Idle::new(detector, Duration::from_millis(1));
+		let mut waited = Vec::new();
+		for _ in 0..3 {
+			let before = polls.get();
+			idle.wait(&mut terminal(), None).await.unwrap();
+			waited.push(polls.get() - before);
+		}
+
+		assert_eq!(waited, [1, 2, 3]);
+	}
+
+	#[tokio::test]
+	async fn goes_on_with_waits_cut_short() {
+		let (detector, polls) = fake(&[Some(false), Some(false), Some(true)]);
+		let mut idle = Idle::new(detector, Duration::from_millis(1));
+		let mut terminal = terminal();
+		assert!(tokio::time::timeout(Duration::ZERO, idle.wait(&mut terminal, None)).await.is_err());
+		assert!(idle.is_waiting());
+		assert_eq!(polls.get(), 1);
+
+		idle.wait(&mut terminal, None).await.unwrap();
+		assert!(!idle.is_waiting());
+		assert_eq!(polls.get(), 3);
+	}
+
+	#[tokio::test]
+	async fn counts_unknown_as_idle() {
+		let mut idle = Idle::new(fake(&[None]).0, Duration::from_secs(3600));
+		idle.wait(&mut terminal(), None).await.unwrap();
+
+		let mut all = All(vec![fake(&[None]).0, fake(&[Some(false)]).0]);
+		assert_eq!(all.is_idle(), Some(false));
+		let mut all = All(vec![fake(&[None]).0, fake(&[Some(true)]).0]);
+		assert_eq!(all.is_idle(), Some(true));
+		assert_eq!(All(vec![fake(&[None]).0]).is_idle(), None);
+	}
+
+	#[tokio::test]
+	async fn stops_waiting_at_deadline() {
+		let (detector, polls) = fake(&[Some(false); 1000]);
+		let mut idle = Idle::new(detector, Duration::from_secs(3600));
+		let deadline = SystemTime::now() + Duration::from_millis(20);
+		let mut terminal = Terminal::new(io::sink(), Some(Duration::from_millis(1)), Units::default());
+		idle.wait(&mut terminal, Some(deadline)).await.unwrap();
+		assert_eq!(polls.get(), 1);
+	}
+}
@@ -1,51 +1,694 @@
-use std::{ffi::OsString, path::{Path, PathBuf}};
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
 use tokio::fs;
-use tracing::{error, trace};
+use tracing::{debug, error, trace, warn};
+
+use crate::classify::{self, Content, Features, Thumbnail};
+use crate::comment::Comment;
+use crate::context::{Context, ToolCommand};
+use crate::options::{ImageFormat, ImageOptions, Metadata, OutputOptions};
+use crate::skip::SkipReason;
+use crate::temp;
+
+/// Well-known locations of an sRGB profile, used when converting images with
+/// an embedded profile
+const SRGB_PROFILES: &[&str] = &[
+	"/usr/share/color/icc/sRGB.icc",
+	"/usr/share/color/icc/colord/sRGB.icc",
+	"/usr/share/color/icc/ghostscript/srgb.icc",
+	"/usr/share/color/icc/OpenICC/sRGB.icc",
+	"/Library/ColorSync/Profiles/sRGB Profile.icc",
+	"/System/Library/ColorSync/Profiles/sRGB Profile.icc",
+];
+
+/// Way of converting images, in the order they are tried with `--fallback`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+	Gm,
+	ImageMagick,
+	/// Decoding with ffmpeg and encoding with gm
+	Ffmpeg,
+}
+
+impl Backend {
+	pub const CHAIN: [Backend; 3] = [Backend::Gm, Backend::ImageMagick, Backend::Ffmpeg];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Backend::Gm => "GraphicsMagick",
+			Backend::ImageMagick => "ImageMagick",
+			Backend::Ffmpeg => "ffmpeg",
+		}
+	}
+}
+
+/// Formats an image tool can read, as it lists them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Formats(BTreeSet<String>);
+
+impl Formats {
+	/// Parses what `-list format` prints: a table of the names, modes (like
+	/// `rw+`) and descriptions of the formats, with another column in between,
+	/// the loader for GraphicsMagick and the module for ImageMagick, which also
+	/// marks some names with `*` and wraps long descriptions.
+	pub fn parse(listing: &str) -> Self {
+		let is_mode = |x: &str| {
+			let x = x.as_bytes();
+			x.len() == 3 && b"r-".contains(&x[0]) && b"w-".contains(&x[1]) && b"+-".contains(&x[2])
+		};
+
+		let mut formats = BTreeSet::new();
+		for line in listing.lines() {
+			let words: Vec<_> = line.split_whitespace().take(4).collect();
+			let Some((name, rest)) = words.split_first() else {
+				continue;
+			};
+
+			if rest.iter().find(|x| is_mode(x)).is_some_and(|x| x.starts_with('r')) {
+				formats.insert(name.trim_end_matches('*').to_ascii_uppercase());
+			}
+		}
+
+		Formats(formats)
+	}
+
+	pub fn reads(&self, name: &str) -> bool {
+		self.0.contains(name)
+	}
+}
+
+/// Names tools give the formats of images of `mime`, for those which builds
+/// are known to lack support for
+fn format_names(mime: &str) -> &'static [&'static str] {
+	match mime {
+		"image/jxl" => &["JXL"],
+		"image/avif" => &["AVIF"],
+		"image/heic" | "image/heif" => &["HEIC", "HEIF"],
+		"image/jp2" | "image/jpx" => &["JP2"],
+		"image/x-canon-cr2" => &["CR2"],
+		"image/x-canon-cr3" => &["CR3"],
+		"image/x-canon-crw" => &["CRW"],
+		"image/x-nikon-nef" => &["NEF"],
+		"image/x-olympus-orf" => &["ORF"],
+		"image/x-fuji-raf" => &["RAF"],
+		"image/x-panasonic-rw2" => &["RW2"],
+		"image/x-sony-arw" => &["ARW"],
+		"image/x-adobe-dng" => &["DNG"],
+		"image/webp" => &["WEBP"],
+		_ => &[],
+	}
+}
+
+/// Whether `encoder` reads any of the formats `names`, as it lists them once
+/// and for all; unknown if it cannot list them.
+async fn reads(context: &mut Context, encoder: Encoder, names: &[&str]) -> Option<bool> {
+	if !context.image_formats.contains_key(encoder.binary()) {
+		let formats = list_formats(context, encoder).await;
+		if let Err(x) = &formats {
+			debug!("unable to list formats of {}: {}", encoder.binary(), x);
+		}
+
+		context.image_formats.insert(encoder.binary(), formats.ok());
+	}
+
+	let formats = context.image_formats.get(encoder.binary())?.as_ref()?;
+	Some(names.iter().any(|x| formats.reads(x)))
+}
+
+async fn list_formats(context: &mut Context, encoder: Encoder) -> Result<Formats, crate::Error> {
+	let mut command = encoder.command(context)?;
+	command.args(["-list", "format"]);
+
+	let output = context.output(command).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation(encoder.binary(), output.status, &output.stderr));
+	}
+
+	let formats = Formats::parse(&String::from_utf8_lossy(&output.stdout));
+	trace!("{} reads {:?}", encoder.binary(), formats);
+	Ok(formats)
+}
+
+/// Backend to convert the image at `path` of `mime` with: GraphicsMagick,
+/// unless it lists its formats and that of `mime` is not among them, in which
+/// case ImageMagick or ffmpeg if they can read it instead; otherwise why to
+/// skip the image.
+pub async fn backend_for(context: &mut Context, path: &Path, mime: &str) -> Result<Backend, SkipReason> {
+	let names = format_names(mime);
+	let Some(name) = names.first() else {
+		return Ok(Backend::Gm);
+	};
+
+	if reads(context, Encoder::Gm, names).await != Some(false) {
+		return Ok(Backend::Gm);
+	}
+
+	debug!("gm cannot read {} images; looking for another backend", name);
+	if reads(context, Encoder::Magick, names).await == Some(true) {
+		return Ok(Backend::ImageMagick);
+	}
+
+	if decodes_with_ffmpeg(context, path).await {
+		return Ok(Backend::Ffmpeg);
+	}
+
+	Err(SkipReason::FormatUnsupported("gm", name))
+}
+
+/// Whether ffprobe finds the image at `path` readable.
+async fn decodes_with_ffmpeg(context: &mut Context, path: &Path) -> bool {
+	let Ok(mut ffprobe) = context.command("ffprobe") else {
+		return false;
+	};
+
+	ffprobe.args(["-v", "error", "-show_entries", "stream=codec_type", "-of", "csv=p=0"]).arg(path);
+	match context.output(ffprobe).await {
+		Ok(x) => x.status.success() && String::from_utf8_lossy(&x.stdout).contains("video"),
+		Err(x) => {
+			debug!("unable to probe `{}` with ffprobe: {}", path.display(), x);
+			false
+		}
+	}
+}
+
+/// Format images are encoded to, JPEG unless `--output-file` or
+/// `--image-format` asks for another or `--auto-format` picks one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+	Jpeg,
+	/// Losslessly, with the strongest compression
+	Png,
+	WebP,
+	WebPLossless,
+	/// Lossy, which only ImageMagick encodes
+	Avif,
+}
+
+impl From<ImageFormat> for Format {
+	fn from(format: ImageFormat) -> Self {
+		match format {
+			ImageFormat::Jpeg => Format::Jpeg,
+			ImageFormat::Webp => Format::WebP,
+			ImageFormat::Png => Format::Png,
+			ImageFormat::Avif => Format::Avif,
+		}
+	}
+}
+
+impl Format {
+	/// Extensions of `--output-file` images can be written as
+	pub const EXTENSIONS: &'static [&'static str] = &["jpg", "jpeg", "png", "webp", "avif"];
+
+	pub fn from_extension(extension: &str) -> Option<Self> {
+		match extension.to_ascii_lowercase().as_str() {
+			"jpg" | "jpeg" => Some(Format::Jpeg),
+			"png" => Some(Format::Png),
+			"webp" => Some(Format::WebP),
+			"avif" => Some(Format::Avif),
+			_ => None,
+		}
+	}
+
+	pub fn of(options: &OutputOptions, image_options: &ImageOptions) -> Result<Self, crate::Error> {
+		let (Some(file), Some(extension)) = (&options.file, options.file_extension()) else {
+			return Ok(image_options.image_format.map_or(Format::Jpeg, Format::from));
+		};
+
+		Self::from_extension(&extension).ok_or_else(|| {
+			let expected = Self::EXTENSIONS.iter().map(|x| format!("`.{}`", x)).collect::<Vec<_>>().join(", ");
+			crate::Error::OutputExtension(file.clone(), format!("one of {}", expected))
+		})
+	}
+
+	/// Format to encode images of the given content to.
+	pub fn suited_to(content: Content, alpha: bool) -> Self {
+		match content {
+			Content::Photo => Format::Jpeg.keeping(alpha),
+			Content::Graphic => Format::WebPLossless,
+		}
+	}
+
+	/// The format itself, unless it would flatten the transparency of an
+	/// image with `alpha`, which lossy WebP keeps.
+	pub fn keeping(self, alpha: bool) -> Self {
+		match self {
+			Format::Jpeg if alpha => Format::WebP,
+			x => x,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Format::Jpeg => "jpeg",
+			Format::Png => "png",
+			Format::WebP => "webp",
+			Format::WebPLossless => "webp-lossless",
+			Format::Avif => "avif",
+		}
+	}
+
+	fn suffix(self) -> &'static str {
+		match self {
+			Format::Jpeg => ".jpg",
+			Format::Png => ".png",
+			Format::WebP | Format::WebPLossless => ".webp",
+			Format::Avif => ".avif",
+		}
+	}
+
+	/// Prefix of the output file argument telling gm the format to write.
+	fn coder(self) -> &'static str {
+		match self {
+			Format::Jpeg => "jpeg:",
+			Format::Png => "png:",
+			Format::WebP | Format::WebPLossless => "webp:",
+			Format::Avif => "avif:",
+		}
+	}
+
+	/// Whether the format trades quality for size, as `-quality` sets.
+	fn is_lossy(self) -> bool {
+		matches!(self, Format::Jpeg | Format::WebP | Format::Avif)
+	}
+
+	/// Whether only ImageMagick encodes the format, as GraphicsMagick lacks
+	/// AVIF.
+	fn needs_magick(self) -> bool {
+		self == Format::Avif
+	}
+
+	fn args(self) -> &'static [&'static str] {
+		match self {
+			// zlib level 9 with adaptive filtering
+			Format::Png => &["-quality", "95"],
+			Format::WebPLossless => &["-define", "webp:lossless=true"],
+			Format::Jpeg | Format::WebP | Format::Avif => &[],
+		}
+	}
+}
+
+/// Program encoding the output
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Encoder {
+	Gm,
+	Magick,
+}
+
+impl Encoder {
+	fn binary(self) -> &'static str {
+		match self {
+			Encoder::Gm => "gm",
+			Encoder::Magick => "magick",
+		}
+	}
+
+	/// ToolCommand to convert images with, which only needs its arguments.
+	fn command(self, context: &mut Context) -> Result<ToolCommand, crate::Error> {
+		let mut command = context.command(self.binary())?;
+		if self == Encoder::Gm {
+			command.arg("convert");
+		}
+
+		Ok(command)
+	}
+}
+
+/// Preprocessing applied to images before they are encoded
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Filter {
+	/// Straightening scans skewed by more than this threshold, in percent
+	Deskew(f64),
+	/// Cutting off borders of the same color as the corners
+	TrimBorders,
+	/// Stretching the levels to the full range
+	AutoLevel,
+}
+
+impl Filter {
+	/// Filters asked for, in the order they apply: straightening leaves
+	/// borders in the corners, which trimming then cuts off, before the
+	/// levels are taken of what is left.
+	fn of(options: &ImageOptions) -> Vec<Filter> {
+		let deskew = options.deskew.map(Filter::Deskew);
+		let trim = options.trim_borders.then_some(Filter::TrimBorders);
+		let level = options.auto_level.then_some(Filter::AutoLevel);
+		[deskew, trim, level].into_iter().flatten().collect()
+	}
+
+	/// Whether only ImageMagick has the filter, and so encodes the image.
+	fn needs_magick(self) -> bool {
+		matches!(self, Filter::Deskew(_))
+	}
+
+	fn args(self, encoder: Encoder) -> Vec<OsString> {
+		let args = match (self, encoder) {
+			(Filter::Deskew(x), _) => return vec!["-deskew".into(), format!("{}%", x).into()],
+			// GraphicsMagick keeps no virtual canvas to reset
+			(Filter::TrimBorders, Encoder::Gm) => &["-trim"][..],
+			(Filter::TrimBorders, Encoder::Magick) => &["-trim", "+repage"],
+			(Filter::AutoLevel, Encoder::Gm) => &["-normalize"],
+			(Filter::AutoLevel, Encoder::Magick) => &["-auto-level"],
+		};
+
+		args.iter().map(OsString::from).collect()
+	}
+}
+
+/// Arguments converting an image, between its source and the output, in stages:
+/// normalizing its colors, the filters, then encoding it with its comment
+#[derive(Clone, Debug)]
+struct Stages<'a> {
+	/// sRGB profile to convert the colors to
+	srgb: Option<&'a Path>,
+	filters: Vec<Filter>,
+	/// Color profile to embed in the output
+	profile: Option<&'a Path>,
+	format: Format,
+	quality: Option<u8>,
+	comment: String,
+}
+
+impl Stages<'_> {
+	fn args(&self, encoder: Encoder) -> Vec<OsString> {
+		let mut args = Vec::new();
+		if let Some(srgb) = self.srgb {
+			args.extend(["-intent".into(), "perceptual".into(), "-profile".into(), srgb.into()]);
+		}
+
+		for filter in &self.filters {
+			args.extend(filter.args(encoder));
+		}
+
+		// `-strip` drops the embedded profile, so the pixels have to be converted
+		// to sRGB first or the profile has to be put back afterwards, otherwise
+		// the colors shift
+		args.push("-strip".into());
+		if let Some(profile) = self.profile {
+			args.extend(["-profile".into(), profile.into()]);
+		}
+
+		if let Some(quality) = self.quality {
+			args.extend(["-quality".into(), quality.to_string().into()]);
+		}
+
+		args.extend(self.format.args().iter().map(OsString::from));
+		args.extend(["-comment".into(), self.comment.as_str().into()]);
+		args
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImageInfo {
+	comment: Option<String>,
+	pub icc_profile: bool,
+	/// Whether the image has an alpha channel, which JPEG would flatten
+	pub alpha: bool,
+	pub dimensions: Option<Dimensions>,
+	/// What the image depicts and the format picked for it, with
+	/// `--auto-format`
+	pub classification: Option<Classification>,
+}
+
+/// Format picked for an image by what it depicts
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Classification {
+	pub content: Content,
+	pub format: Format,
+}
+
+impl fmt::Display for Classification {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "classified as {} → {}", self.content, self.format.name())
+	}
+}
 
-use crate::{comment::Comment, context::Context};
+/// Size of an image, in pixels
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Dimensions {
+	pub width: u32,
+	pub height: u32,
+}
 
-pub async fn get_comment(context: &mut Context, path: impl AsRef<Path>) -> Result<Option<Comment>, crate::Error> {
+impl Dimensions {
+	/// Parses geometry like `4032x3024`, ignoring any offsets after it.
+	fn parse(geometry: &str) -> Option<Self> {
+		let size = geometry.trim().split(['+', '-']).next()?;
+		let (width, height) = size.split_once('x')?;
+		Some(Dimensions { width: width.parse().ok()?, height: height.parse().ok()? })
+	}
+}
+
+impl fmt::Display for Dimensions {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}×{}", self.width, self.height)
+	}
+}
+
+impl ImageInfo {
+	pub fn comment(&self) -> Result<Option<Comment>, crate::Error> {
+		match &self.comment {
+			Some(x) => x.parse().map(Some).map_err(crate::Error::from),
+			None => Ok(None),
+		}
+	}
+
+	/// Comment of the image, as is
+	pub fn raw_comment(&self) -> Option<&str> {
+		self.comment.as_deref()
+	}
+}
+
+/// Probes the image at `path` with the tool of `backend`, GraphicsMagick
+/// unless it is ImageMagick.
+pub async fn probe(context: &mut Context, path: impl AsRef<Path>, backend: Backend) -> Result<ImageInfo, crate::Error> {
 	let path = path.as_ref();
-    let mut gm = context.command("gm")?;
+	let binary = match backend {
+		Backend::ImageMagick => Encoder::Magick.binary(),
+		Backend::Gm | Backend::Ffmpeg => Encoder::Gm.binary(),
+	};
+
+    let mut gm = context.command(binary)?;
 	gm
 		.args(["identify", "-verbose"])
 		.arg(path);
 
-	let output = gm.output().await?;
+	let output = context.output(gm).await?;
 	if !output.status.success() {
-		return Err(crate::Error::Invocation("gm", output.status))
+		return Err(crate::Error::invocation(binary, output.status, &output.stderr))
 	}
 
 	let output = String::from_utf8_lossy(output.stdout.as_ref());
-	let Some(index) = output.find("Comment:") else {
-		return Ok(None)
-	};
+	let mut info = ImageInfo::default();
+	for line in output.lines().map(str::trim) {
+		if let Some(comment) = line.strip_prefix("Comment:").filter(|_| info.comment.is_none()) {
+			info.comment = Some(comment.trim().to_string());
+		}
 
-	let Some(comment) = output[index..].lines().next().map(str::trim) else {
+		if let Some(geometry) = line.strip_prefix("Geometry:").filter(|_| info.dimensions.is_none()) {
+			info.dimensions = Dimensions::parse(geometry);
+		}
+
+		// GraphicsMagick calls it `Profile-color`, ImageMagick `Profile-icc`
+		let line = line.to_ascii_lowercase();
+		// like `TrueColorMatte` of GraphicsMagick or `PaletteAlpha` of ImageMagick
+		if let Some(kind) = line.strip_prefix("type:") {
+			info.alpha |= kind.contains("matte") || kind.contains("alpha");
+		}
+
+		if ["profile-color:", "profile-icc:", "profile-icm:"].iter().any(|x| line.starts_with(x)) {
+			info.icc_profile = true;
+		}
+	}
+
+	debug!("probed image `{}`: {:?}", path.display(), info);
+	Ok(info)
+}
+
+/// Measures the image at `path`, e.g. a converted one.
+pub async fn dimensions(context: &mut Context, path: impl AsRef<Path>) -> Result<Option<Dimensions>, crate::Error> {
+	let path = path.as_ref();
+	let mut gm = context.command("gm")?;
+	gm.args(["identify", "-format", "%wx%h"]).arg(path);
+
+	let output = context.output(gm).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation("gm", output.status, &output.stderr));
+	}
+
+	// multi-frame images print one per frame
+	let output = String::from_utf8_lossy(&output.stdout);
+	Ok(output.lines().next().and_then(Dimensions::parse))
+}
+
+/// Tells what the image at `path` depicts, from its number of colors, alpha
+/// channel and a grayscale thumbnail, unless gm gives an unreadable one.
+pub async fn classify(context: &mut Context, path: impl AsRef<Path>) -> Result<Option<Classification>, crate::Error> {
+	let path = path.as_ref();
+	let mut gm = context.command("gm")?;
+	gm.args(["identify", "-format", "%k %A\\n"]).arg(path);
+
+	let output = context.output(gm).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation("gm", output.status, &output.stderr));
+	}
+
+	// multi-frame images print one line per frame
+	let output = String::from_utf8_lossy(&output.stdout);
+	let mut fields = output.lines().next().unwrap_or_default().split_whitespace();
+	let colors = fields.next().and_then(|x| x.parse().ok()).unwrap_or(u64::MAX);
+	let alpha = fields.next().map(str::to_ascii_lowercase);
+	let alpha = alpha.is_some_and(|x| !["false", "undefined", "none"].contains(&x.as_str()));
+
+	let mut gm = context.command("gm")?;
+	gm.arg("convert").arg(path).args(["-resize", "64x64!", "-colorspace", "Gray", "-depth", "8", "pgm:-"]);
+
+	let output = context.output(gm).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation("gm", output.status, &output.stderr));
+	}
+
+	let Some(thumbnail) = Thumbnail::parse_pgm(&output.stdout) else {
+		debug!("unable to read thumbnail of `{}`", path.display());
 		return Ok(None);
 	};
 
-	comment.parse().map(Some).map_err(crate::Error::from)
+	let features = Features::measure(colors, alpha, &thumbnail);
+	let content = classify::classify(&features);
+	debug!("classified `{}` as {} from {:?}", path.display(), content, features);
+	Ok(Some(Classification { content, format: Format::suited_to(content, alpha) }))
+}
+
+pub async fn convert(
+	context: &mut Context, options: &OutputOptions, image_options: &ImageOptions, info: &ImageInfo, comment: Comment,
+	input: impl AsRef<Path>, backend: Backend,
+) -> Result<PathBuf, crate::Error> {
+	let input = input.as_ref();
+	match backend {
+		Backend::Gm => encode(context, options, image_options, info, comment, input, input, Encoder::Gm).await,
+		Backend::ImageMagick => {
+			encode(context, options, image_options, info, comment, input, input, Encoder::Magick).await
+		}
+		Backend::Ffmpeg => convert_frame(context, options, image_options, comment, input).await,
+	}
 }
 
-pub async fn convert(context: &mut Context, comment: Comment, input: impl AsRef<Path>) -> Result<PathBuf, crate::Error> {
+/// Converts an image GraphicsMagick has no delegate for, by having ffmpeg
+/// decode it into a lossless intermediate first.
+pub async fn convert_with_ffmpeg(
+	context: &mut Context, options: &OutputOptions, image_options: &ImageOptions, comment: Comment,
+	input: impl AsRef<Path>, mime: &str,
+) -> Result<PathBuf, crate::Error> {
 	let input = input.as_ref();
-	let output = context.get_output_file(input, ".jpg").await?;
-	let comment = comment.to_string();
+	let format = format_name(mime);
+	match context.command("ffmpeg") {
+		Ok(_) => {}
+		Err(crate::Error::BinaryNotFound(_)) => return Err(crate::Error::MissingDelegate(format)),
+		Err(x) => return Err(x),
+	}
 
-	let mut output_arg = OsString::from("jpeg:");
-	output_arg.push(&output);
+	let message = format!("gm lacks a {} delegate; decoding `{}` with ffmpeg instead", format, input.display());
+	context.terminal.warn_once(&format!("delegate {}", format), None, message);
+	convert_frame(context, options, image_options, comment, input).await
+}
 
-	let mut gm = context.command("gm")?;
-	gm
-		.arg("convert")
+/// Converts the first frame of `input` (which may as well be a video),
+/// decoded by ffmpeg into a lossless intermediate.
+pub async fn convert_frame(
+	context: &mut Context, options: &OutputOptions, image_options: &ImageOptions, comment: Comment,
+	input: impl AsRef<Path>,
+) -> Result<PathBuf, crate::Error> {
+	let input = input.as_ref();
+	let decoded = temp::scratch_file(input, Some(OsStr::new(".png")));
+	let mut ffmpeg = context.command("ffmpeg")?;
+	ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
 		.arg(input)
-		.arg("-strip")
-		.arg("-comment")
-		.arg(comment)
-		.arg(output_arg);
+		.args(["-frames:v", "1", "-c:v", "png", "-f", "image2"])
+		.arg(&decoded);
+
+	let result = match context.run("ffmpeg", ffmpeg, input).await {
+		Ok(_) => {
+			let info = ImageInfo::default();
+			encode(context, options, image_options, &info, comment, &decoded, input, Encoder::Gm).await
+		}
+		Err(x) => Err(x),
+	};
+
+	if decoded.exists() {
+		trace!("deleting decoded image `{}`", decoded.display());
+		if let Err(x) = fs::remove_file(&decoded).await {
+			error!("failed to delete decoded image `{}`: {}", decoded.display(), x);
+		}
+	}
+
+	result
+}
 
-	match context.run(gm, input).await {
+/// Encodes `source` into the output for `input`.
+#[allow(clippy::too_many_arguments)]
+async fn encode(
+	context: &mut Context, options: &OutputOptions, image_options: &ImageOptions, info: &ImageInfo, comment: Comment,
+	source: &Path, input: &Path, encoder: Encoder,
+) -> Result<PathBuf, crate::Error> {
+	let asked = options.file.is_some() || image_options.image_format.is_some();
+	let format = match (info.classification, asked) {
+		(Some(x), _) => x.format,
+		(None, true) => Format::of(options, image_options)?,
+		(None, false) => Format::of(options, image_options)?.keeping(info.alpha),
+	};
+	if info.alpha && format == Format::Jpeg {
+		warn!("`{}` has transparency, which JPEG flattens", input.display());
+	}
+
+	let output = context.get_output_file(options, input, format.suffix()).await?;
+	let quality = image_options.image_quality.filter(|_| format.is_lossy());
+	let comment = comment.with_quality(quality).to_string();
+	let filters = Filter::of(image_options);
+	let encoder = match format.needs_magick() || filters.iter().any(|x| x.needs_magick()) {
+		true => Encoder::Magick,
+		false => encoder,
+	};
+
+	let mut output_arg = OsString::from(format.coder());
+	output_arg.push(&output);
+
+	let mut profile = None;
+	let mut srgb = None;
+	if info.icc_profile {
+		srgb = image_options.srgb_profile.clone().or_else(find_srgb_profile);
+		if image_options.keep_metadata.contains(&Metadata::Icc) || srgb.is_none() {
+			if srgb.is_none() {
+				let message = format!("no sRGB profile found; keeping the color profile of `{}`", input.display());
+				context.terminal.warn_once("srgb", None, message);
+			}
+
+			srgb = None;
+			profile = Some(extract_profile(context, source, encoder).await?);
+		}
+	}
+
+	if let Some(srgb) = &srgb {
+		debug!("converting `{}` to sRGB using `{}`", input.display(), srgb.display());
+	}
+
+	let stages = Stages { srgb: srgb.as_deref(), filters, profile: profile.as_deref(), format, quality, comment };
+	let mut gm = encoder.command(context)?;
+	gm.arg(source).args(stages.args(encoder)).arg(output_arg);
+
+	let result = match encoder {
+		Encoder::Gm => context.run_batched(gm, input).await,
+		Encoder::Magick => context.run(encoder.binary(), gm, input).await,
+	};
+	if let Some(profile) = profile {
+		trace!("deleting extracted profile `{}`", profile.display());
+		if let Err(x) = fs::remove_file(&profile).await {
+			error!("failed to delete extracted profile `{}`: {}", profile.display(), x);
+		}
+	}
+
+	match result {
 		Ok(_) => Ok(output),
 		Err(x) => {
 			if output.exists() {
@@ -59,3 +702,166 @@ pub async fn convert(context: &mut Context, comment: Comment, input: impl AsRef<
 		}
 	}
 }
+
+/// Human readable name of an image format, as used in delegate names.
+fn format_name(mime: &str) -> String {
+	let subtype = mime.strip_prefix("image/").unwrap_or(mime);
+	match subtype {
+		"webp" => "WebP".to_string(),
+		"heic" | "heif" => "HEIC".to_string(),
+		"avif" => "AVIF".to_string(),
+		"jxl" => "JPEG XL".to_string(),
+		x => x.trim_start_matches("x-").to_ascii_uppercase(),
+	}
+}
+
+fn find_srgb_profile() -> Option<PathBuf> {
+	SRGB_PROFILES.iter().map(PathBuf::from).find(|x| x.exists())
+}
+
+/// Extracts the color profile of `input` into a scratch file, with `encoder`
+/// the way it is encoded afterwards, batched with `--gm-batch` like it.
+async fn extract_profile(context: &mut Context, input: &Path, encoder: Encoder) -> Result<PathBuf, crate::Error> {
+	let profile = temp::scratch_file(input, Some(OsStr::new(".icc")));
+	trace!("extracting color profile of `{}` to `{}`", input.display(), profile.display());
+
+	let mut profile_arg = OsString::from("icc:");
+	profile_arg.push(&profile);
+
+	let mut gm = encoder.command(context)?;
+	gm.arg(input).arg(profile_arg);
+
+	let result = match encoder {
+		Encoder::Gm => context.run_batched(gm, input).await,
+		Encoder::Magick => context.run(encoder.binary(), gm, input).await,
+	};
+	if let Err(x) = result {
+		if profile.exists() {
+			if let Err(x) = fs::remove_file(&profile).await {
+				error!("failed to delete extracted profile `{}`: {}", profile.display(), x);
+			}
+		}
+
+		return Err(x);
+	}
+
+	Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsString;
+	use std::path::Path;
+
+	use super::{Encoder, Filter, Format, Formats, Stages};
+
+	fn stages(filters: Vec<Filter>) -> Stages<'static> {
+		Stages {
+			srgb: Some(Path::new("sRGB.icc")),
+			filters,
+			profile: None,
+			format: Format::Jpeg,
+			quality: Some(85),
+			comment: "shrunk".into(),
+		}
+	}
+
+	fn joined(args: Vec<OsString>) -> String {
+		args.iter().map(|x| x.to_str().unwrap()).collect::<Vec<_>>().join(" ")
+	}
+
+	const GM_FORMATS: &str = "\
+   Format L  Mode  Description
+--------------------------------------------------------------------------------
+      3FR P  r--   Hasselblad Photo RAW
+      ART *  rw-   PFS: 1st Publisher
+     JPEG *  rw-   Joint Photographic Experts Group JFIF format
+      PNG *  rw-   Portable Network Graphics
+      PS2 *  -w+   Adobe Level II PostScript
+     WEBP *  rw-   Google WebP Image Format
+";
+
+	const MAGICK_FORMATS: &str = "\
+   Format  Module    Mode  Description
+-------------------------------------------------------------------------------
+      3FR  DNG       r--   Hasselblad CFV/H3D39II Raw Format (0.21.2-Release)
+     AVIF  HEIC      rw+   AV1 Image File Format (1.17.6)
+     HEIC  HEIC      rw+   High Efficiency Image Format (1.17.6)
+     JPEG* JPEG      rw-   Joint Photographic Experts Group JFIF format (libjpeg-turbo 3.0.2)
+      JXL  JXL       rw-   JPEG XL (ISO/IEC 18181) (libjxl 0.10.2)
+      MPG  VIDEO     rw+   MPEG Video Stream
+                           See https://imagemagick.org/script/formats.php#video
+   SVGZ  SVG       rw+   Compressed Scalable Vector Graphics (RSVG 2.58.0)
+     WEBP* WEBP      rw+   WebP Image Format (libwebp 1.4.0 [020F])
+
+* native blob support
+r read support
+w write support
++ support for multiple images
+";
+
+	#[test]
+	fn parses_formats_of_graphicsmagick() {
+		let formats = Formats::parse(GM_FORMATS);
+		for name in ["3FR", "ART", "JPEG", "PNG", "WEBP"] {
+			assert!(formats.reads(name), "{}", name);
+		}
+
+		// write-only, or missing a delegate
+		assert!(!formats.reads("PS2"));
+		assert!(!formats.reads("JXL"));
+		// the header
+		assert!(!formats.reads("FORMAT"));
+	}
+
+	#[test]
+	fn parses_formats_of_imagemagick() {
+		let formats = Formats::parse(MAGICK_FORMATS);
+		for name in ["3FR", "AVIF", "HEIC", "JPEG", "JXL", "MPG", "SVGZ", "WEBP"] {
+			assert!(formats.reads(name), "{}", name);
+		}
+
+		// wrapped descriptions and the legend
+		assert!(!formats.reads("SEE"));
+		assert!(!formats.reads("R"));
+		assert!(!formats.reads("*"));
+		assert!(!formats.reads("PNG"));
+	}
+
+	#[test]
+	fn filters_images_between_normalizing_and_encoding() {
+		let filters = vec![Filter::Deskew(40.0), Filter::TrimBorders, Filter::AutoLevel];
+		assert_eq!(
+			joined(stages(filters).args(Encoder::Magick)),
+			"-intent perceptual -profile sRGB.icc -deskew 40% -trim +repage -auto-level \
+			-strip -quality 85 -comment shrunk",
+		);
+
+		assert_eq!(
+			joined(stages(vec![Filter::TrimBorders, Filter::AutoLevel]).args(Encoder::Gm)),
+			"-intent perceptual -profile sRGB.icc -trim -normalize -strip -quality 85 -comment shrunk",
+		);
+	}
+
+	#[test]
+	fn encodes_unfiltered_images_as_before() {
+		let stages = Stages { srgb: None, profile: Some(Path::new("a.icc")), format: Format::Png, ..stages(vec![]) };
+		assert_eq!(joined(stages.args(Encoder::Gm)), "-strip -profile a.icc -quality 85 -quality 95 -comment shrunk");
+	}
+
+	#[test]
+	fn keeps_transparency() {
+		assert_eq!(Format::Jpeg.keeping(true), Format::WebP);
+		assert_eq!(Format::Jpeg.keeping(false), Format::Jpeg);
+		for format in [Format::Png, Format::WebP, Format::WebPLossless] {
+			assert_eq!(format.keeping(true), format);
+		}
+	}
+
+	#[test]
+	fn needs_imagemagick_only_to_deskew() {
+		assert!(Filter::Deskew(0.0).needs_magick());
+		assert!(!Filter::TrimBorders.needs_magick());
+		assert!(!Filter::AutoLevel.needs_magick());
+	}
+}
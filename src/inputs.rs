@@ -0,0 +1,200 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::task::{self, JoinHandle};
+use tracing::trace;
+
+use crate::fsutil;
+use crate::shard::Shard;
+
+pub struct Input {
+	pub path: PathBuf,
+	/// Metadata of the input itself (not following symlinks), as of when it
+	/// was prefetched
+	pub metadata: io::Result<Metadata>,
+}
+
+/// Pulls inputs lazily from `paths`, while fetching the metadata of up to
+/// `depth` upcoming ones in the background, and telling which belong to
+/// `shard` if given.
+///
+/// Nothing here needs to know about all inputs up front, so the memory used
+/// stays bounded by `depth` however many inputs there are.
+pub struct Inputs<I> {
+	paths: I,
+	/// Upcoming inputs, with their metadata, or `None` for those of other
+	/// shards
+	pending: VecDeque<(PathBuf, JoinHandle<Option<io::Result<Metadata>>>)>,
+	depth: usize,
+	shard: Option<Shard>,
+}
+
+impl<I: Iterator<Item = PathBuf>> Inputs<I> {
+	pub fn new(paths: I, depth: usize, shard: Option<Shard>) -> Self {
+		Inputs { paths, pending: VecDeque::with_capacity(depth), depth: depth.max(1), shard }
+	}
+
+	/// The next input; cancelling this loses none.
+	pub async fn next(&mut self) -> Option<Input> {
+		loop {
+			self.fill();
+			let (_, metadata) = self.pending.front_mut()?;
+			let metadata = match metadata.await {
+				Ok(x) => x,
+				Err(x) => Some(Err(io::Error::other(x))),
+			};
+
+			let (path, _) = self.pending.pop_front().unwrap();
+			self.fill();
+			match metadata {
+				Some(metadata) => return Some(Input { path, metadata }),
+				None => trace!("`{}` belongs to another shard", path.display()),
+			}
+		}
+	}
+
+	fn fill(&mut self) {
+		while self.pending.len() < self.depth {
+			let Some(path) = self.paths.next() else {
+				break;
+			};
+
+			trace!("prefetching metadata of `{}`", path.display());
+			let metadata = {
+				let (path, shard) = (path.clone(), self.shard);
+				task::spawn_blocking(move || {
+					let canonical = || fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+					match shard {
+						Some(x) if !x.contains(&canonical()) => None,
+						_ => Some(fs::symlink_metadata(&path)),
+					}
+				})
+			};
+			self.pending.push_back((path, metadata));
+		}
+	}
+}
+
+/// Identity of a file, the same whatever path leads to it, e.g. through a bind
+/// mount or a hard link
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FileId {
+	dev: u64,
+	ino: u64,
+}
+
+impl FileId {
+	#[cfg(target_family = "unix")]
+	pub fn of(metadata: &Metadata) -> Option<Self> {
+		use std::os::unix::fs::MetadataExt;
+
+		Some(FileId { dev: metadata.dev(), ino: metadata.ino() })
+	}
+
+	/// Unknown where the standard library does not tell.
+	#[cfg(not(target_family = "unix"))]
+	pub fn of(_metadata: &Metadata) -> Option<Self> {
+		None
+	}
+}
+
+/// Inputs seen so far, to tell those given more than once: by their identity
+/// where known, which canonical paths miss for other mount points of the same
+/// files, and else by their canonical paths
+#[derive(Debug, Default)]
+pub struct Seen {
+	ids: HashMap<FileId, PathBuf>,
+	paths: HashMap<PathBuf, PathBuf>,
+}
+
+impl Seen {
+	/// Records `path`, of the file with `id` if known, returning the path the
+	/// same file was seen as before, if it was.
+	pub fn insert(&mut self, path: &Path, id: Option<FileId>) -> Option<PathBuf> {
+		let canonical = fsutil::canonical_path(path);
+		if let Some(first) = id.and_then(|x| self.ids.get(&x)).or_else(|| self.paths.get(&canonical)) {
+			return Some(first.clone());
+		}
+
+		if let Some(id) = id {
+			self.ids.insert(id, path.to_path_buf());
+		}
+
+		self.paths.insert(canonical, path.to_path_buf());
+		None
+	}
+
+	/// Records that `path` is the file with `id` now, e.g. once it has been
+	/// replaced by its output.
+	pub fn update(&mut self, path: &Path, id: FileId) {
+		self.ids.entry(id).or_insert_with(|| path.to_path_buf());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::{Path, PathBuf};
+	use std::time::Duration;
+
+	use super::{FileId, Inputs, Seen};
+	use crate::shard;
+
+	/// Paths of everything `inputs` yields.
+	async fn paths<I: Iterator<Item = PathBuf>>(mut inputs: Inputs<I>) -> Vec<PathBuf> {
+		let mut paths = Vec::new();
+		while let Some(input) = inputs.next().await {
+			paths.push(input.path);
+		}
+
+		paths
+	}
+
+	#[tokio::test]
+	async fn loses_no_input_when_cut_short() {
+		let given: Vec<_> = (0..5).map(|x| PathBuf::from(format!("{}.jpg", x))).collect();
+		let mut inputs = Inputs::new(given.clone().into_iter(), 2, None);
+		let first = match tokio::time::timeout(Duration::ZERO, inputs.next()).await {
+			Ok(x) => x,
+			Err(_) => inputs.next().await,
+		};
+		assert_eq!(first.unwrap().path, given[0]);
+		assert_eq!(paths(inputs).await, given[1..]);
+	}
+
+	#[tokio::test]
+	async fn yields_inputs_of_the_shard_given() {
+		let given: Vec<_> = (0..20).map(|x| PathBuf::from(format!("{}.jpg", x))).collect();
+		let share = |x| paths(Inputs::new(given.clone().into_iter(), 4, Some(shard::parse(x).unwrap())));
+		let (first, second) = (share("1/2").await, share("2/2").await);
+		assert!(!first.is_empty() && !second.is_empty());
+		let mut all = [first, second].concat();
+		all.sort_by_key(|x| x.to_str().unwrap().trim_end_matches(".jpg").parse::<u32>().unwrap());
+		assert_eq!(all, given);
+	}
+
+	#[test]
+	fn tells_files_seen_under_other_paths() {
+		let id = |ino| Some(FileId { dev: 1, ino });
+		let mut seen = Seen::default();
+		assert_eq!(seen.insert(Path::new("/srv/media/a.jpg"), id(10)), None);
+		assert_eq!(seen.insert(Path::new("/srv/media/b.jpg"), id(11)), None);
+		// the same inode, which canonical paths do not tell for bind mounts
+		assert_eq!(seen.insert(Path::new("/home/me/media/a.jpg"), id(10)).unwrap(), Path::new("/srv/media/a.jpg"));
+		// the same inode number on another device
+		assert_eq!(seen.insert(Path::new("/mnt/a.jpg"), Some(FileId { dev: 2, ino: 10 })), None);
+
+		// replaced by its output, which the other path then leads to as well
+		seen.update(Path::new("/srv/media/b.jpg"), FileId { dev: 1, ino: 12 });
+		assert_eq!(seen.insert(Path::new("/home/me/media/b.jpg"), id(12)).unwrap(), Path::new("/srv/media/b.jpg"));
+	}
+
+	#[test]
+	fn tells_files_by_path_without_identities() {
+		let mut seen = Seen::default();
+		assert_eq!(seen.insert(Path::new("/srv/media/a.jpg"), None), None);
+		assert_eq!(seen.insert(Path::new("/srv/media/./a.jpg"), None).unwrap(), Path::new("/srv/media/a.jpg"));
+		assert_eq!(seen.insert(Path::new("/srv/media/b.jpg"), None), None);
+	}
+}
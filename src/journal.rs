@@ -0,0 +1,100 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::fsutil;
+
+/// Name of the journal in the output directory
+pub const FILE_NAME: &str = ".shrink-ray-journal";
+
+/// Record of an output complete in every way, a line of JSON in the journal
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+	/// When the output was completed, in RFC 3339
+	pub time: String,
+	/// Canonical path of the input
+	pub source: PathBuf,
+	/// Path of the output, relative to the output directory
+	pub output: PathBuf,
+	pub size: u64,
+}
+
+/// Journal of the outputs of `--output-dir` runs: those it lists are complete,
+/// and a run interrupted leaves no others behind. It is opened on the first
+/// output, so that runs converting nothing leave the directory alone.
+#[derive(Debug)]
+pub struct Journal {
+	dir: PathBuf,
+	file: Option<File>,
+}
+
+impl Journal {
+	pub fn new(dir: &Path) -> Self {
+		Journal { dir: dir.to_path_buf(), file: None }
+	}
+
+	pub fn path(&self) -> PathBuf {
+		self.dir.join(FILE_NAME)
+	}
+
+	/// Records that `output`, converted from `source`, is complete, and
+	/// makes sure of it on disk. The entry is written at once, so that an
+	/// entry cut off by a crash is no more than a partial last line.
+	pub fn record(&mut self, source: &Path, output: &Path, size: u64) -> Result<(), crate::Error> {
+		let entry = Entry {
+			time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+			source: fsutil::canonical_path(source),
+			output: output.strip_prefix(&self.dir).unwrap_or(output).to_path_buf(),
+			size,
+		};
+
+		debug!("journaling output `{}`", output.display());
+		self.append(&entry).map_err(|x| crate::Error::Journal(self.path(), x))
+	}
+
+	fn append(&mut self, entry: &Entry) -> io::Result<()> {
+		let line = format!("{}\n", serde_json::to_string(entry)?);
+		let file = match &mut self.file {
+			Some(x) => x,
+			None => {
+				fs::create_dir_all(&self.dir)?;
+				self.file.insert(OpenOptions::new().create(true).append(true).open(self.path())?)
+			}
+		};
+
+		file.write_all(line.as_bytes())?;
+		file.sync_data()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::path::Path;
+
+	use super::{Entry, Journal};
+
+	#[test]
+	fn appends_entries_relative_to_dir() {
+		let dir = tempfile::tempdir().unwrap();
+		let out = dir.path().join("out");
+		fs::write(dir.path().join("a.jpg"), "a").unwrap();
+		let mut journal = Journal::new(&out);
+		assert!(!out.exists());
+
+		journal.record(&dir.path().join("a.jpg"), &out.join("a.webp"), 12).unwrap();
+		journal.record(&dir.path().join("a.jpg"), &out.join("sub/b.webp"), 34).unwrap();
+		let contents = fs::read_to_string(journal.path()).unwrap();
+		let entries: Vec<Entry> = contents.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].output, Path::new("a.webp"));
+		assert_eq!(entries[0].size, 12);
+		assert_eq!(entries[1].output, Path::new("sub/b.webp"));
+		assert!(entries[1].source.is_absolute() && entries[1].source.ends_with("a.jpg"));
+	}
+}
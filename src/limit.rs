@@ -0,0 +1,111 @@
+/// Quality images are taken to be encoded at unless given, the default of
+/// GraphicsMagick and libwebp
+pub const IMAGE_QUALITY: u8 = 75;
+/// Quality images are lowered by for each attempt
+const IMAGE_QUALITY_STEP: u8 = 15;
+/// Lowest quality to take images down to, below which they are not worth
+/// keeping however small
+const MIN_IMAGE_QUALITY: u8 = 10;
+/// Steps of the constant rate factor that halve the bitrate, by the rule of
+/// thumb for x265, which roughly holds for VP9 too
+const CRF_HALVING: f64 = 6.0;
+
+/// Attempts at converting a file into an output of at most `--max-output-size`
+/// bytes
+#[derive(Clone, Debug)]
+pub struct Attempts {
+	limit: Option<u64>,
+	left: u64,
+	/// Bytes of the latest output, to tell when lowering the quality stops
+	/// helping, as it does with lossless formats
+	last: Option<u64>,
+}
+
+impl Attempts {
+	/// Up to `max` attempts at meeting `limit`, if there is any.
+	pub fn new(limit: Option<u64>, max: u64) -> Self {
+		Attempts { limit, left: max, last: None }
+	}
+
+	/// Whether to try again, at a lower quality, after an attempt came out
+	/// at `size` bytes; the output is left to the caller to judge otherwise.
+	pub fn retry(&mut self, size: u64) -> bool {
+		let Some(limit) = self.limit else {
+			return false;
+		};
+
+		self.left = self.left.saturating_sub(1);
+		let shrinking = self.last.is_none_or(|x| size < x);
+		self.last = Some(size);
+		size > limit && self.left > 0 && shrinking
+	}
+
+	/// Most bytes outputs may take, if limited.
+	pub fn limit(&self) -> Option<u64> {
+		self.limit
+	}
+}
+
+/// Quality to retry an image at after `quality` made it too large, unless it
+/// cannot go any lower.
+pub fn image_quality(quality: u8) -> Option<u8> {
+	(quality > MIN_IMAGE_QUALITY).then(|| quality.saturating_sub(IMAGE_QUALITY_STEP).max(MIN_IMAGE_QUALITY))
+}
+
+/// Constant rate factor, up to `max`, to retry a video at after `crf` made it
+/// `size` bytes, estimated to get it to `limit` in one go, unless it cannot
+/// go any higher.
+pub fn crf(crf: u8, max: u8, size: u64, limit: u64) -> Option<u8> {
+	if crf >= max {
+		return None;
+	}
+
+	// the step rounded up, so that the estimate errs on the smaller side
+	let step = (CRF_HALVING * (size as f64 / limit.max(1) as f64).log2()).ceil().max(1.0);
+	Some((crf as f64 + step).min(max as f64) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Attempts;
+
+	#[test]
+	fn retries_until_under_limit() {
+		let mut attempts = Attempts::new(Some(100), 3);
+		assert!(attempts.retry(400));
+		assert!(!attempts.retry(90));
+
+		let mut attempts = Attempts::new(Some(100), 3);
+		assert!(attempts.retry(400));
+		assert!(attempts.retry(200));
+		// out of attempts
+		assert!(!attempts.retry(150));
+	}
+
+	#[test]
+	fn stops_once_quality_does_not_matter() {
+		let mut attempts = Attempts::new(Some(100), 5);
+		assert!(attempts.retry(400));
+		assert!(!attempts.retry(400));
+		assert!(!Attempts::new(None, 5).retry(400));
+	}
+
+	#[test]
+	fn lowers_image_quality_in_steps() {
+		assert_eq!(super::image_quality(75), Some(60));
+		assert_eq!(super::image_quality(20), Some(10));
+		assert_eq!(super::image_quality(12), Some(10));
+		assert_eq!(super::image_quality(10), None);
+	}
+
+	#[test]
+	fn estimates_rate_factor_meeting_limit() {
+		// twice the size, one halving of the bitrate away
+		assert_eq!(super::crf(30, 63, 200, 100), Some(36));
+		assert_eq!(super::crf(30, 63, 400, 100), Some(42));
+		// barely over still takes a step
+		assert_eq!(super::crf(30, 63, 101, 100), Some(31));
+		assert_eq!(super::crf(40, 51, 1000, 100), Some(51));
+		assert_eq!(super::crf(51, 51, 1000, 100), None);
+	}
+}
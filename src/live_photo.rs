@@ -0,0 +1,102 @@
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tokio::fs;
+use tracing::{debug, trace};
+
+use crate::backup::Backup;
+use crate::fsutil;
+
+/// Extensions of the still images of Live Photos, as cameras write them and as
+/// they are named once converted
+const STILL_EXTENSIONS: &[&str] = &["HEIC", "heic", "HEIF", "heif", "JPG", "jpg", "JPEG", "jpeg"];
+/// Longest video of a Live Photo, in seconds; they are about 3 seconds long
+const MAX_DURATION: f64 = 5.0;
+/// Furthest the still and the video of a Live Photo are modified apart
+const MAX_APART: Duration = Duration::from_secs(60);
+
+/// Finds the still image `video` is the motion part of, if it is short enough
+/// to be one.
+pub async fn find_still(video: &Path, metadata: &Metadata, duration: Option<f64>) -> Option<PathBuf> {
+	if !duration.is_some_and(|x| x <= MAX_DURATION) {
+		return None;
+	}
+
+	let modified = metadata.modified().ok()?;
+	for still in candidates(video) {
+		let Ok(still_metadata) = fs::metadata(&still).await else {
+			continue;
+		};
+
+		trace!("`{}` might be the still of `{}`", still.display(), video.display());
+		if still_metadata.is_file() && still_metadata.modified().is_ok_and(|x| is_close(modified, x)) {
+			debug!("`{}` is the video of the Live Photo `{}`", video.display(), still.display());
+			return Some(still);
+		}
+	}
+
+	None
+}
+
+/// Paths the still image of a Live Photo with the video `video` would have.
+fn candidates(video: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+	STILL_EXTENSIONS.iter().map(|x| video.with_extension(x)).filter(move |x| x != video)
+}
+
+/// Whether files modified at `a` and `b` were taken together.
+fn is_close(a: SystemTime, b: SystemTime) -> bool {
+	let apart = a.duration_since(b).or_else(|_| b.duration_since(a)).unwrap_or(Duration::MAX);
+	apart <= MAX_APART
+}
+
+/// Gets rid of the video of a Live Photo, moving it to `backup` if given.
+pub async fn drop_video(video: &Path, backup: Option<&Backup>) -> Result<(), crate::Error> {
+	let Some(backup) = backup else {
+		trace!("deleting Live Photo video `{}`", video.display());
+		return Ok(fs::remove_file(video).await?);
+	};
+
+	let path = backup.path(video)?;
+	if path.exists() {
+		return Err(crate::Error::OutputExists(path));
+	}
+
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent).await?;
+	}
+
+	trace!("moving Live Photo video `{}` to `{}`", video.display(), path.display());
+	fsutil::move_file(video, &path).await
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::{Path, PathBuf};
+	use std::time::{Duration, SystemTime};
+
+	use super::{candidates, is_close};
+
+	#[test]
+	fn looks_for_stills_with_same_stem() {
+		let found: Vec<_> = candidates(Path::new("DCIM/IMG_0001.MOV")).collect();
+		assert!(found.contains(&PathBuf::from("DCIM/IMG_0001.HEIC")));
+		assert!(found.contains(&PathBuf::from("DCIM/IMG_0001.jpg")));
+		assert!(found.iter().all(|x| x.file_stem().unwrap() == "IMG_0001"));
+
+		// a video named like a still is not its own pair
+		let found: Vec<_> = candidates(Path::new("IMG_0002.jpg")).collect();
+		assert!(!found.contains(&PathBuf::from("IMG_0002.jpg")));
+		assert!(found.contains(&PathBuf::from("IMG_0002.HEIC")));
+	}
+
+	#[test]
+	fn pairs_files_modified_together() {
+		let taken = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		assert!(is_close(taken, taken));
+		assert!(is_close(taken, taken + Duration::from_secs(2)));
+		assert!(is_close(taken + Duration::from_secs(2), taken));
+		assert!(!is_close(taken, taken + Duration::from_secs(3600)));
+		assert!(!is_close(taken - Duration::from_secs(86_400), taken));
+	}
+}
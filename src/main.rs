@@ -1,37 +1,119 @@
-use std::path::Path;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::SystemTime;
 
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, ValueEnum};
+use advisor::Utilization;
+use audit::{AuditLog, Digest};
+use backup::Backup;
 use comment::Comment;
 use context::Context;
-use error::Error;
-use options::Options;
-use terminal::Terminal;
+use error::{Error, Severity, Stage};
+use events::Events;
+use expand::{Done, Expansion};
+use idle::Idle;
+use image::ImageInfo;
+use inputs::{FileId, Input, Inputs, Seen};
+use journal::Journal;
+use provenance::Marker;
+use qa::QaSamples;
+use ratios::Ratios;
+use record::InputRecord;
+use options::{
+	BrokenPipe, Command, Format, HookErrors, ImageOptions, InputChange, LivePhotos, Measure, Options, Outcome,
+	OutputOptions, Verify, VideoFormat, VideoOptions,
+};
+use sequences::Sequences;
+use skip::SkipReason;
+use terminal::{Details, Terminal};
 use stats::{Delta, Statistics};
+use summary::Summary;
+use template::{CommandLine, Placeholder};
+use timing::{ActiveInstant, Phase, Timings};
+use top::Top;
+use tools::Tools;
 use tokio::fs;
-use tracing::{debug, trace, warn};
+use tracing::{debug, debug_span, error, field, trace, warn, Instrument, Span};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
+mod advisor;
+mod audit;
+mod backup;
+mod batch;
+mod bytes;
+mod classify;
+mod deadline;
+mod download;
 mod error;
+mod events;
+mod expand;
+mod fsutil;
+mod gif;
+mod git;
+mod hook;
+mod identify;
+mod idle;
+mod journal;
+mod limit;
+mod live_photo;
 mod options;
+mod perceptual;
+mod progress;
+mod order;
+mod parallel;
+mod provenance;
+mod prune;
+mod qa;
+mod ratios;
+mod record;
+mod repeats;
+mod rusage;
 mod terminal;
 mod stats;
 mod temp;
+mod sequences;
+mod shard;
+mod since;
+mod skip;
+mod stall;
+mod summary;
+mod tar;
 mod image;
+mod inputs;
+mod metrics;
+mod template;
+mod timing;
+mod top;
+mod tools;
 mod video;
 mod context;
+#[cfg(target_family = "unix")]
+mod daemon;
 mod comment;
+mod config;
 
 #[macro_use]
 extern crate thiserror;
 
 #[tokio::main]
 async fn main() -> ExitCode {
-	tracing_subscriber::fmt()
-		.with_env_filter(EnvFilter::from_default_env())
-		.init();
+	let options = config::parse_options();
+	if let Err(x) = init_tracing(options.trace_file.as_deref()) {
+		eprintln!("{}", x);
+		return ExitCode::FAILURE;
+	}
 
-	let options = Options::parse();
 	if options.inputs.len() > 1 && options.output.file.is_some() {
 		Options::command()
 			.error(
@@ -41,10 +123,59 @@ async fn main() -> ExitCode {
 			.exit();
 	}
 
+	if let Some(conflict) = options.format_conflict() {
+		Options::command().error(clap::error::ErrorKind::ArgumentConflict, conflict).exit();
+	}
+
+	let has_output = options.output.dir.is_some() || options.output.file.is_some();
+	if !has_output && options.inputs.iter().any(|x| download::is_url(x)) {
+		Options::command()
+			.error(
+				clap::error::ErrorKind::MissingRequiredArgument,
+				"inputs given as URLs need '--output-dir <PATH>' or '--output-file <PATH>'",
+			)
+			.exit();
+	}
+
 	debug!("arguments: {:?}", options);
 
-	let terminal = Terminal::new();
-	let mut context = match Context::new(terminal, options.output.clone()).await {
+	let mut terminal = if options.terminal_to_stderr() {
+		Terminal::new(io::stderr().lock(), options.spinner(), options.units)
+	} else {
+		Terminal::new(io::stdout().lock(), options.spinner(), options.units)
+	};
+	terminal.set_path_style(options.path_style());
+	terminal.set_format(options.format);
+	if let Some(Command::Restore(restore)) = &options.command {
+		let backup = Backup::new(&restore.backup).unwrap_or(Backup::Suffix(Backup::DEFAULT_SUFFIX.into()));
+		return match backup::restore(&mut terminal, &backup, &restore.files).await {
+			true => ExitCode::SUCCESS,
+			false => ExitCode::FAILURE,
+		};
+	}
+
+	if let Some(Command::Tools(tools)) = &options.command {
+		return match tools::run(&mut terminal, tools).await {
+			true => ExitCode::SUCCESS,
+			false => ExitCode::FAILURE,
+		};
+	}
+
+	if let Some(Command::Audit(audit)) = &options.command {
+		return match audit::run(&mut terminal, audit) {
+			true => ExitCode::SUCCESS,
+			false => ExitCode::FAILURE,
+		};
+	}
+
+	if let Some(Command::Prune(prune)) = &options.command {
+		return match prune::run(&mut terminal, prune).await {
+			true => ExitCode::SUCCESS,
+			false => ExitCode::FAILURE,
+		};
+	}
+
+	let user_tools = match Tools::load(options.tools_file.as_deref(), false).await {
 		Ok(x) => x,
 		Err(x) => {
 			eprintln!("{}", x);
@@ -52,165 +183,1673 @@ async fn main() -> ExitCode {
 		}
 	};
 
-	let mut cancel = false;
-	let mut stats = Statistics::default();
-	for input in &options.inputs {
-		match run_input(input, &options, &mut context).await {
-			Ok(delta) if delta.is_smaller() => {
-				context.terminal.write_shrink(input, delta);
-				stats.shrink(delta);
-			}
-			Ok(delta) => {
-				context.terminal.write_grow(input, delta);
-				stats.grow(delta);
+	if let Some(extension) = options.output.file_extension() {
+		let videos = VideoFormat::value_variants().iter().map(|x| x.extension());
+		let mut known = image::Format::EXTENSIONS.iter().copied().chain(videos).chain(user_tools.extensions());
+		if !known.any(|x| x.eq_ignore_ascii_case(&extension)) {
+			Options::command()
+				.error(
+					clap::error::ErrorKind::ValueValidation,
+					format!("no conversion produces `.{}` files for '--output-file <PATH>'", extension),
+				)
+				.exit();
+		}
+	}
+
+	#[cfg(target_family = "unix")]
+	if let Some(socket) = &options.via_daemon {
+		// the output is that of the daemon
+		drop(terminal);
+		return match daemon::hand_over(socket.as_deref(), &options).await {
+			Ok(x) => ExitCode::from(x),
+			Err(x) => {
+				eprintln!("{}", x);
+				ExitCode::FAILURE
 			}
-			Err(Error::InputFormatUnknown(_)) => {
-				context.terminal.write_skip(input, "unknown file format");
-				stats.skip();
+		};
+	}
+
+	let mut context = match Context::new(terminal, &options.magic, user_tools, options.jobs()).await {
+		Ok(x) => x,
+		Err(x) => {
+			eprintln!("{}", x);
+			return ExitCode::FAILURE;
+		}
+	};
+
+	#[cfg(target_family = "unix")]
+	if let Some(Command::Daemon(daemon)) = &options.command {
+		return match daemon::serve(daemon, context).await {
+			Ok(()) => ExitCode::SUCCESS,
+			Err(x) => {
+				eprintln!("{}", x);
+				ExitCode::FAILURE
 			}
-			Err(Error::AlreadyConverted(_)) => {
-				context.terminal.write_skip(input, "file already converted");
-				stats.skip();
+		};
+	}
+
+	if let Err(x) = configure(&mut context, &options) {
+		eprintln!("{}", x);
+		return ExitCode::FAILURE;
+	}
+
+	#[cfg(target_family = "unix")]
+	if let Err(x) = context.watch_interrupts() {
+		eprintln!("{}", x);
+		return ExitCode::FAILURE;
+	}
+
+	let Expansion { files: inputs, done, .. } = expand_inputs(&options, &mut context).await;
+	let mut run = Run::new(&options, &inputs);
+	run.done = options.write_done_markers.then_some(done);
+	let flow = convert(&options, &mut context, &mut run, &inputs).await;
+	context.end_batch().await;
+	run.finish(&options, &mut context).await;
+	ExitCode::from(run.exit_status(flow))
+}
+
+/// Applies the options of a run to `context`.
+fn configure(context: &mut Context, options: &Options) -> Result<(), Error> {
+	context.deadline = options.deadline;
+	context.passthrough_env = options.passthrough_env.clone();
+	context.stall_timeout = options.stall_timeout;
+	context.on_stall = options.on_stall;
+	context.keep_temp = options.keep_temp;
+	context.gm_batch = options.gm_batch;
+	let qa = options.qa_samples.as_deref().map(|x| QaSamples::new(x, options.qa_rate));
+	context.qa = qa.map(|x| Rc::new(RefCell::new(x)));
+	let audit = options.audit_log.as_deref().map(AuditLog::open).transpose()?;
+	context.audit = audit.map(|x| Rc::new(RefCell::new(x)));
+	context.journal = options.output.dir.as_deref().map(|x| Rc::new(RefCell::new(Journal::new(x))));
+	if let Some(events) = Events::open(options)? {
+		context.terminal.set_events(events);
+	}
+	if options.verbose && options.measure == Measure::Allocated && !Measure::ALLOCATED_KNOWN {
+		context.terminal.write_note("allocated sizes are not known on this platform; measuring apparent sizes");
+	}
+
+	Ok(())
+}
+
+/// The inputs, with the directories among them walked with `--recursive`.
+async fn expand_inputs(options: &Options, context: &mut Context) -> Expansion {
+	if !options.recursive {
+		return Expansion { files: options.inputs.clone(), ..Expansion::default() };
+	}
+
+	let inputs = options.inputs.clone();
+	let follow_symlinks = options.follow_symlinks;
+	let comment = (!options.ignore_done_markers).then(|| Comment::new(options.settings_hash.clone()));
+	let expand = move || expand::expand(&inputs, follow_symlinks, comment.as_ref());
+	let expansion = tokio::task::spawn_blocking(expand).await.expect("failed to walk the inputs");
+	if options.verbose {
+		for dir in &expansion.skipped {
+			context.terminal.write_note(format!("skipping `{}`, marked done", dir.display()));
+		}
+	}
+
+	expansion
+}
+
+/// Converts `inputs`, or the files of the tar archive read from the standard
+/// input with `--tar`.
+async fn convert(options: &Options, context: &mut Context, run: &mut Run, inputs: &[PathBuf]) -> Flow {
+	if options.tar {
+		return match tar::run(options, context, run).await {
+			Ok(x) => x,
+			Err(x) => run.abort(x),
+		};
+	}
+
+	let paths = order::sort(options.sort, inputs).await;
+	let paths = paths.into_iter().map(|x| if download::is_url(&x) { x } else { fsutil::extended(x) });
+	let mut inputs = Inputs::new(paths, options.pipeline_depth as usize, options.shard);
+	let mut idle = options.only_when_idle.then(|| Idle::new(idle::system(options.idle_load), Idle::POLL));
+	if options.files_at_once() > 1 {
+		return parallel::convert(options, context, run, &mut inputs, &mut idle).await;
+	}
+
+	let mut flow = Flow::Continue;
+	while flow == Flow::Continue {
+		let next = next_input(options, context, run, &mut inputs, &mut idle).await;
+		let Some((Input { path, metadata }, duplicate)) = next else {
+			break;
+		};
+
+		let span = input_span(&path);
+		let timings = run.stats.timings_mut();
+		let result = process(&path, metadata, duplicate, options, context, timings, &span).await;
+		flow = complete(&path, result, options, context, run, span).await;
+	}
+
+	flow
+}
+
+/// The next input to process, once the run may start another, along with
+/// where it was processed already if it is a duplicate; `None` once the run
+/// is not to start any more. Cancelling this, even while waiting for idle,
+/// loses no input.
+async fn next_input<I: Iterator<Item = PathBuf>>(
+	options: &Options, context: &mut Context, run: &mut Run, inputs: &mut Inputs<I>, idle: &mut Option<Idle>,
+) -> Option<(Input, Option<PathBuf>)> {
+	if run.shutdown.get() {
+		context.terminal.write_note("the daemon is shutting down; not starting any more files");
+		run.cancel = true;
+		return None;
+	}
+
+	if let Some(idle) = idle {
+		if let Err(x) = idle.wait(&mut context.terminal, options.deadline).await {
+			debug!("stopped waiting for idle: {}", x);
+			run.cancel = true;
+			return None;
+		}
+	}
+
+	if run.out_of_time(options, context) {
+		return None;
+	}
+
+	if context.check_interrupted().is_err() {
+		debug!("interrupted; not starting any more files");
+		run.cancel = true;
+		return None;
+	}
+
+	let input = inputs.next().await?;
+	let duplicate = match download::is_url(&input.path) {
+		true => None,
+		false => run.seen.insert(&input.path, input.metadata.as_ref().ok().and_then(FileId::of)),
+	};
+
+	Some((input, duplicate))
+}
+
+/// Processes `path`, first seen as `duplicate` if it was seen already, along
+/// with the hooks.
+async fn process(
+	path: &Path, metadata: io::Result<Metadata>, duplicate: Option<PathBuf>, options: &Options, context: &mut Context,
+	timings: &mut Timings, span: &Span,
+) -> Result<Processed, Error> {
+	context.terminal.start_file(path);
+	let (cpu, tools) = (context.cpu_time(), context.tool_time());
+	let result = match (download::is_url(path), duplicate) {
+		(_, Some(first)) => Ok(Processed::Skipped(SkipReason::Duplicate(first))),
+		(true, None) => run_url(path, options, context, timings).instrument(span.clone()).await,
+		(false, None) => run_input(path, metadata, options, context, timings).instrument(span.clone()).await,
+	};
+
+	let result = match (result, &options.post_command) {
+		(Ok(Processed::Converted(x)), Some(command)) => {
+			run_post_command(context, command, path, x, options).await.map(Processed::Converted)
+		}
+		(x, _) => x,
+	};
+
+	let result = run_hooks(context, options, path, result).instrument(span.clone()).await;
+	let cpu = context.cpu_time().saturating_sub(cpu);
+	span.record("cpu_ms", cpu.as_millis() as u64);
+	timings.spend_cpu(cpu);
+	result.map(|mut x| {
+		if let (Processed::Converted(x), true) = (&mut x, options.verbose) {
+			x.details.utilization = Utilization::new(cpu, context.tool_time().saturating_sub(tools));
+		}
+
+		x
+	})
+}
+
+/// Accounts for the `result` of processing `path` and reports it.
+async fn complete(
+	path: &Path, result: Result<Processed, Error>, options: &Options, context: &mut Context, run: &mut Run,
+	span: Span,
+) -> Flow {
+	// other paths to the input lead to its output now
+	if let Ok(Processed::Converted(Conversion { reclaimed: true, output, .. })) = &result {
+		if let Some(id) = fs::symlink_metadata(output).await.ok().and_then(|x| FileId::of(&x)) {
+			run.seen.update(path, id);
+		}
+	}
+
+	run.report(path, result, options, context).instrument(span).await
+}
+
+/// Span of everything that happens to `input`, its fields filled in as it
+/// goes.
+fn input_span(input: &Path) -> Span {
+	debug_span!(
+		"input",
+		path = %input.display(),
+		mime = field::Empty,
+		tool = field::Empty,
+		outcome = field::Empty,
+		bytes_before = field::Empty,
+		bytes_after = field::Empty,
+		cpu_ms = field::Empty,
+	)
+}
+
+/// Fills in how processing the current input went on its span.
+fn record_outcome(outcome: &'static str, delta: Option<Delta>) {
+	let span = Span::current();
+	span.record("outcome", outcome);
+	if let Some(delta) = delta {
+		span.record("bytes_before", delta.original);
+		span.record("bytes_after", delta.new);
+	}
+}
+
+/// Logs to the standard error as `RUST_LOG` says, and everything worth
+/// analysing afterwards to `trace_file` as JSON.
+fn init_tracing(trace_file: Option<&Path>) -> Result<(), Error> {
+	let stderr = tracing_subscriber::fmt::layer().with_writer(io::stderr).with_filter(EnvFilter::from_default_env());
+	let trace_file = match trace_file {
+		Some(path) => {
+			let file = std::fs::File::create(path).map_err(|x| Error::TraceFile(path.to_path_buf(), x))?;
+			let layer = tracing_subscriber::fmt::layer()
+				.json()
+				.with_span_list(false)
+				.with_span_events(FmtSpan::CLOSE)
+				.with_writer(Mutex::new(file))
+				.with_filter(LevelFilter::DEBUG);
+			Some(layer)
+		}
+		None => None,
+	};
+
+	tracing_subscriber::registry().with(stderr).with(trace_file).init();
+	Ok(())
+}
+
+/// What to do after an input has been processed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Flow {
+	Continue,
+	/// Skip the remaining inputs
+	Stop,
+	/// Exit right away, the error has been reported already
+	Abort,
+}
+
+/// Progress of the whole run
+struct Run {
+	start: ActiveInstant,
+	cancel: bool,
+	/// Whether the run stopped at `--max-runtime` or `--deadline`
+	out_of_time: bool,
+	/// Whether the run stopped because nobody reads the output anymore
+	broken_pipe: bool,
+	/// Fatal error the run stopped at, if any, by its kind and message
+	aborted: Option<(&'static str, String)>,
+	stats: Statistics,
+	mime_stats: BTreeMap<String, Statistics>,
+	/// Inputs reported together, with the statistics and number of files
+	/// seen so far of each sequence
+	sequences: Sequences,
+	sequence_stats: BTreeMap<usize, (Statistics, usize)>,
+	/// Files to list at the end
+	summary: Summary,
+	/// Files which changed the most, to list at the end
+	top: Top,
+	/// Savings of each file shrunk, for the statistics
+	ratios: Ratios,
+	/// Inputs processed so far, to skip those given again under another path
+	seen: Seen,
+	/// Directories walked, to mark done with `--write-done-markers`
+	done: Option<Done>,
+	/// Set once the daemon the run was handed over to shuts down, to stop
+	/// starting new files
+	shutdown: Rc<Cell<bool>>,
+}
+
+impl Run {
+	fn new(options: &Options, inputs: &[PathBuf]) -> Self {
+		let mut run = Run {
+			start: ActiveInstant::now(),
+			cancel: false,
+			out_of_time: false,
+			broken_pipe: false,
+			aborted: None,
+			stats: Statistics::default(),
+			mime_stats: BTreeMap::new(),
+			sequences: Sequences::default(),
+			sequence_stats: BTreeMap::new(),
+			summary: Summary::new(&options.summary_list, options.summary_limit),
+			top: Top::new(options.top.unwrap_or_default()),
+			ratios: Ratios::new(options.ratio_samples as usize),
+			seen: Seen::default(),
+			done: None,
+			shutdown: Rc::default(),
+		};
+
+		if options.collapse_sequences {
+			run.sequences = Sequences::detect(inputs);
+		}
+
+		run
+	}
+
+	/// Exit status of the run, which ended with `flow`.
+	fn exit_status(&self, flow: Flow) -> u8 {
+		if flow == Flow::Abort {
+			1
+		} else if self.broken_pipe {
+			// what a shell reports for processes killed by SIGPIPE
+			128 + 13
+		} else if self.stats.failed_files() > 0 {
+			1
+		} else if self.cancel {
+			// this will stop tools like `xargs`
+			u8::MAX
+		} else {
+			0
+		}
+	}
+
+	/// Reports the outcome of processing `input` and accounts for it.
+	async fn report(
+		&mut self, input: &Path, result: Result<Processed, Error>, options: &Options, context: &mut Context,
+	) -> Flow {
+		if let Ok(Processed::Converted(Conversion { redirected: true, output, .. })) = &result {
+			context.terminal.write_redirect(input, output);
+		}
+
+		if let (Ok(_), Some(done)) = (&result, &mut self.done) {
+			done.processed(&fsutil::unextended(input));
+		}
+
+		if let Ok(Processed::Converted(Conversion { reclaimed: true, delta, mime, .. })) = &result {
+			let delta = *delta;
+			self.account(self.sequences.get(input), |x| x.reclaim(delta));
+			self.mime_stats.entry(mime.clone()).or_default().reclaim(delta);
+		}
+
+		let sequence = self.sequences.get(input);
+		let flow = match result {
+			Ok(Processed::Converted(Conversion { delta, mime, details, .. })) if delta.is_smaller() => {
+				record_outcome("shrunk", Some(delta));
+				if sequence.is_none() && !self.hide(options, delta) {
+					context.terminal.write_shrink(input, delta, &details);
+				}
+
+				// whether their results were shown or not
+				self.top.add(input, delta);
+				self.ratios.add(delta);
+				self.account(sequence, |x| x.shrink(delta));
+				self.mime_stats.entry(mime).or_default().shrink(delta);
+				Flow::Continue
 			}
-			Err(Error::Invocation(_, status)) => {
-				context.terminal.write_fail(input, status);
-				stats.fail();
+			Ok(Processed::Converted(Conversion { delta, mime, details, .. })) => {
+				record_outcome("grew", Some(delta));
+				if sequence.is_none() && !self.hide(options, delta) {
+					context.terminal.write_grow(input, delta, &details);
+				}
 
-				if !options.keep_going {
-					break;
+				self.top.add(input, delta);
+				self.account(sequence, |x| x.grow(delta));
+				self.summary.add(Outcome::Grew, input, || {
+					format!("+{}, +{}", options.units.format(delta.difference()), delta.percent())
+				});
+				self.mime_stats.entry(mime).or_default().grow(delta);
+				Flow::Continue
+			}
+			Ok(Processed::Skipped(reason)) => {
+				if options.verbose || !reason.is_quiet() {
+					context.terminal.write_skip(input, &reason);
 				}
+
+				self.skip(sequence, input, &reason);
+				Flow::Continue
 			}
 			Err(Error::Cancelled) => {
+				record_outcome("cancelled", None);
+				context.terminal.write_cancel(input);
+				self.cancel = true;
+				return Flow::Stop;
+			}
+			Err(Error::DeadlineReached) => {
+				record_outcome("cancelled", None);
 				context.terminal.write_cancel(input);
-				cancel = true;
-				break;
+				context.terminal.write_note("deadline reached; stopping");
+				self.out_of_time = true;
+				return Flow::Stop;
+			}
+			Err(x) if x.severity() == Severity::File => {
+				record_outcome("failed", None);
+				debug!(error = %x, "failed");
+				self.summary.add(Outcome::Failed, input, || x.to_string());
+				context.terminal.write_fail(input, x);
+				self.account(sequence, Statistics::fail);
+
+				if options.keep_going {
+					Flow::Continue
+				} else {
+					Flow::Stop
+				}
 			}
 			Err(x) => {
-				eprintln!("{}", x);
-				return ExitCode::FAILURE;
+				record_outcome("aborted", None);
+				return self.abort(x);
+			}
+		};
+
+		if let Some(index) = sequence {
+			let (stats, seen) = self.sequence_stats.entry(index).or_default();
+			*seen += 1;
+
+			let sequence = self.sequences.sequence(index);
+			if *seen == sequence.len() {
+				context.terminal.write_sequence(sequence, *seen, stats);
+				self.sequence_stats.remove(&index);
 			}
 		}
+
+		self.write_metrics(options).await;
+		if flow == Flow::Continue && context.terminal.is_closed() && options.on_broken_pipe == BrokenPipe::Quit {
+			debug!("output closed; stopping");
+			self.broken_pipe = true;
+			return Flow::Stop;
+		}
+
+		flow
 	}
 
-	if options.stats {
-		println!();
-		context.terminal.write_stats(stats);
-		println!();
+	/// Stops the run at the fatal `error`, which is reported right away.
+	fn abort(&mut self, error: Error) -> Flow {
+		eprintln!("{}", error);
+		self.aborted = Some((error.kind(), error.to_string()));
+		Flow::Abort
 	}
 
-	if stats.failed_files() > 0 {
-		ExitCode::FAILURE
-	} else if cancel {
-		// this will stop tools like `xargs`
-		ExitCode::from(u8::MAX)
-	} else {
-		ExitCode::SUCCESS
+	/// Reports everything known about the run, however it ended, so that
+	/// nothing processed is left out of the statistics, the summary or the
+	/// metrics, even after a fatal error.
+	async fn finish(&mut self, options: &Options, context: &mut Context) {
+		// like that of `gm batch`, which outlives the files it converts
+		self.stats.timings_mut().spend_cpu(rusage::take());
+		self.flush_sequences(context);
+		if self.stats.hidden_files() > 0 {
+			context.terminal.write_hidden(self.stats.hidden_files());
+		}
+
+		context.terminal.write_suppressed();
+		context.terminal.end_run(&self.stats, options.output.should_replace(), self.cancel);
+
+		self.write_done_markers(options).await;
+		self.write_metrics(options).await;
+		// the statistics end the JSON however the run went
+		if options.stats || options.format == Format::Json {
+			context.terminal.write_newline();
+			context.terminal.write_stats(&self.stats, options.output.should_replace());
+			context.terminal.write_ratios(&self.ratios);
+			if options.verbose {
+				context.terminal.write_newline();
+				context.terminal.write_timings(self.stats.timings());
+			}
+
+			context.terminal.write_newline();
+			if self.aborted.is_some() {
+				context.terminal.write_note("stopped by a fatal error; only the files before it are counted");
+				context.terminal.write_newline();
+			}
+		}
+
+		if !self.top.is_empty() {
+			if !options.stats {
+				context.terminal.write_newline();
+			}
+
+			context.terminal.write_top(&self.top);
+			if !self.summary.is_empty() {
+				context.terminal.write_newline();
+			}
+		}
+
+		if !self.summary.is_empty() {
+			if !options.stats && self.top.is_empty() {
+				context.terminal.write_newline();
+			}
+
+			context.terminal.write_summary(&self.summary);
+		}
+	}
+
+	fn account(&mut self, sequence: Option<usize>, update: impl Fn(&mut Statistics)) {
+		update(&mut self.stats);
+		if let Some(index) = sequence {
+			update(&mut self.sequence_stats.entry(index).or_default().0);
+		}
+	}
+
+	/// Whether to hide the result, accounting for it if so.
+	fn hide(&mut self, options: &Options, delta: Delta) -> bool {
+		let hide = options.hide_below.is_some_and(|x| x.covers(delta));
+		if hide {
+			self.stats.hide();
+		}
+
+		hide
+	}
+
+	/// Whether to stop starting new files because of `--max-runtime` or
+	/// `--deadline`, telling why the first time.
+	fn out_of_time(&mut self, options: &Options, context: &mut Context) -> bool {
+		if self.out_of_time {
+			return true;
+		}
+
+		let timings = self.stats.timings().total();
+		let average = (timings.files > 0).then(|| timings.per_file());
+		let reason = if options.deadline.is_some_and(|x| SystemTime::now() >= x) {
+			"deadline reached"
+		} else if options.max_runtime.is_some_and(|x| {
+			!deadline::has_time_for_next(x.saturating_sub(self.start.elapsed()), average)
+		}) {
+			"maximum runtime nearly reached"
+		} else {
+			return false;
+		};
+
+		context.terminal.write_note(format!("{}; not starting any more files", reason));
+		self.out_of_time = true;
+		true
+	}
+
+	fn skip(&mut self, sequence: Option<usize>, input: &Path, reason: &SkipReason) {
+		record_outcome("skipped", None);
+		debug!(reason = %reason, skip_reason = reason.tag(), "skipped");
+		self.account(sequence, |x| x.skip(reason));
+		self.summary.add(Outcome::Skipped, input, || reason.to_string());
+	}
+
+	/// Reports the sequences the run stopped in the middle of.
+	fn flush_sequences(&mut self, context: &mut Context) {
+		for (index, (stats, seen)) in std::mem::take(&mut self.sequence_stats) {
+			context.terminal.write_sequence(self.sequences.sequence(index), seen, &stats);
+		}
+	}
+
+	/// Marks the directories every file within which was processed done.
+	async fn write_done_markers(&self, options: &Options) {
+		let Some(done) = &self.done else {
+			return;
+		};
+
+		let marker = expand::done_marker(&Comment::new(options.settings_hash.clone()));
+		for dir in done.finished() {
+			let path = dir.join(expand::DONE_MARKER);
+			match fs::write(&path, &marker).await {
+				Ok(()) => debug!("marked `{}` done", dir.display()),
+				Err(x) => error!("failed to write `{}`: {}", path.display(), x),
+			}
+		}
+	}
+
+	async fn write_metrics(&self, options: &Options) {
+		let Some(path) = &options.metrics_file else {
+			return;
+		};
+
+		let metrics = metrics::render(
+			&options.metrics_prefix,
+			&self.stats,
+			&self.mime_stats,
+			self.aborted.as_ref().map(|(kind, _)| *kind),
+			self.start.elapsed(),
+			SystemTime::now(),
+		);
+		if let Err(x) = metrics::write(path, metrics).await {
+			error!("failed to write metrics to `{}`: {}", path.display(), x);
+		}
 	}
 }
 
+struct Conversion {
+	mime: String,
+	delta: Delta,
+	/// Where the result ended up; the input itself if the output was discarded
+	output: PathBuf,
+	/// Whether the output went elsewhere because it could not replace the input
+	redirected: bool,
+	/// What else to tell about the conversion
+	details: Details<'static>,
+	/// Whether the original is gone, replaced by the output
+	reclaimed: bool,
+}
+
+/// What processing an input came to, short of failing
+enum Processed {
+	Converted(Conversion),
+	Skipped(SkipReason),
+}
+
+/// Processes the file `input_file`, converting it unless it is to be skipped;
+/// files deleted along the way are skipped rather than failed.
 async fn run_input(
-	input_file: impl AsRef<Path>, args: &Options, context: &mut Context,
-) -> Result<Delta, Error> {
-	let input_file = input_file.as_ref();
-	if !input_file.exists() {
-		return Err(Error::InputNotFound(input_file.to_path_buf()));
-	}
-
-	if input_file.is_symlink() {
-		// TODO: handle symlinks
-		return Err(Error::InputIsSymlink(input_file.to_path_buf()));
-	}
-
-	let Some(mime) = context.identify_file(input_file).await? else {
-		return Err(Error::InputFormatUnknown(input_file.to_path_buf()));
-	};
-
-	let output_file = if mime == "image/gif" {
-		// TODO: check if GIF is single- or multi-frame
-		warn!("GIF files are currently not supported");
-		return Err(Error::InputFormatUnknown(input_file.to_path_buf()));
-	} else if mime.starts_with("image/") {
-		match image::get_comment(context, input_file).await {
-			Ok(Some(x)) => {
-				debug!("comment found: {}", x);
-				return Err(Error::AlreadyConverted(x))
-			},
-			Ok(None) => {},
-			Err(crate::Error::Comment(x)) => debug!("unable to parse comment: {}", x),
-			Err(x) => return Err(x)
-		};
-
-		image::convert(context, Comment::default(), input_file).await?
-	} else if mime.starts_with("video/") {
-		match video::get_comment(context, input_file).await {
-			Ok(Some(x)) => {
-				debug!("comment found: {}", x);
-				return Err(Error::AlreadyConverted(x))
-			},
-			Ok(None) => {},
-			Err(crate::Error::Comment(x)) => debug!("unable to parse comment: {}", x),
-			Err(x) => return Err(x)
-		};
-
-		video::convert(context, Comment::default(), input_file).await?
+	input_file: &Path, metadata: io::Result<Metadata>, args: &Options, context: &mut Context, timings: &mut Timings,
+) -> Result<Processed, Error> {
+	let result = match convert_input(input_file, metadata, args, context, timings).await {
+		Err(x @ Error::Invocation(..)) => Err(vanished(input_file, x).await),
+		x => x,
+	};
+
+	match result {
+		Err(x) if x.is_disappeared() => Ok(Processed::Skipped(SkipReason::Disappeared)),
+		x => x,
+	}
+}
+
+/// `error` of a tool run on `input`, unless the input is gone, which tools
+/// fail on when it is deleted under them.
+async fn vanished(input: &Path, error: Error) -> Error {
+	match fs::symlink_metadata(input).await {
+		Err(x) if x.kind() == io::ErrorKind::NotFound => {
+			debug!("`{}` disappeared while converting it: {}", input.display(), error);
+			Error::input_io(Stage::Inspect, input)(x)
+		}
+		_ => error,
+	}
+}
+
+/// Converts the file `input_file`, unless it is to be skipped.
+async fn convert_input(
+	input_file: &Path, metadata: io::Result<Metadata>, args: &Options, context: &mut Context, timings: &mut Timings,
+) -> Result<Processed, Error> {
+	let metadata = match metadata {
+		Ok(x) => x,
+		Err(x) if x.kind() == io::ErrorKind::NotFound => {
+			return Err(Error::InputNotFound(input_file.to_path_buf()));
+		}
+		Err(x) => return Err(Error::input_io(Stage::Inspect, input_file)(x)),
+	};
+
+	if metadata.is_symlink() {
+		return follow_symlink(input_file, args, context, timings).await;
+	}
+
+	let mut record = InputRecord::new(input_file, metadata);
+	context.input = Some(input_file.to_path_buf());
+
+	if record.metadata.is_dir() {
+		return Ok(Processed::Skipped(SkipReason::Directory(input_file.to_path_buf())));
+	}
+
+	if let Some(since) = args.since {
+		let modified = record.metadata.modified()?;
+		if modified < since {
+			return Ok(Processed::Skipped(SkipReason::NotModifiedSince));
+		}
+	}
+
+	if args.min_size.is_some_and(|x| record.metadata.len() < x) {
+		return Ok(Processed::Skipped(SkipReason::TooSmall));
+	}
+
+	let mut output_options = Cow::Borrowed(&args.output);
+	if args.output.should_replace() {
+		let parent = fsutil::parent_dir(input_file);
+		if !fsutil::is_writable(parent)? {
+			let Some(auto_dir) = &args.output.auto_dir else {
+				return Err(Error::InputNotWritable(parent.to_path_buf()));
+			};
+
+			let dir = auto_dir.join(fsutil::relative_structure(parent)?);
+			debug!("`{}` is not writable; redirecting output to `{}`", parent.display(), dir.display());
+			output_options = Cow::Owned(OutputOptions::redirected(dir));
+			record.redirected = true;
+		}
+	}
+
+	if !record.redirected && output_options.should_replace() {
+		if let Some(reason) = git::check(context, args.git, input_file).await? {
+			return Ok(Processed::Skipped(reason));
+		}
+	}
+
+	let mark = context.mark();
+	let mime = context.identify_file(input_file).await;
+	timings.record(Phase::Identify, mark, context.tool_time());
+	let Some(mime) = mime? else {
+		return Ok(Processed::Skipped(SkipReason::UnknownFormat));
+	};
+
+	let mime = record.mime.insert(mime);
+	Span::current().record("mime", mime.as_str());
+	let user_tool = context.user_tools.find(mime).cloned();
+	// animations are converted like videos, unless a tool of the user is
+	let animated = if user_tool.is_none() && mime == "image/gif" {
+		let mark = context.mark();
+		let animated = gif::is_animated(input_file).await;
+		timings.record(Phase::Probe, mark, context.tool_time());
+		let Some(animated) = animated? else {
+			return Ok(Processed::Skipped(SkipReason::UnknownFormat));
+		};
+
+		animated
 	} else {
-		warn!("unsupported file format: {}", mime);
-		return Err(Error::InputFormatUnknown(input_file.to_path_buf()));
+		false
 	};
 
-	let input_meta = fs::metadata(input_file).await?;
-	let output_meta = fs::metadata(&output_file).await?;
+	let (output_file, backend) = if let Some(tool) = user_tool {
+		let mark = context.mark();
+		let comment = tool.get_comment(context, input_file).await;
+		timings.record(Phase::Probe, mark, context.tool_time());
+		if let Some(reason) = check_comment(comment, args)? {
+			return Ok(Processed::Skipped(reason));
+		}
+
+		let mark = context.mark();
+		Span::current().record("tool", tool.name.as_str());
+		record.tool = Some(tool.name.clone());
+		let output = tool.convert(context, &output_options, Comment::new(args.settings_hash.clone()), input_file).await;
+		timings.record(Phase::Convert, mark, context.tool_time());
+		(output?, None)
+	} else if mime.starts_with("image/") && !animated {
+		let mark = context.mark();
+		let backend = image::backend_for(context, input_file, mime).await;
+		timings.record(Phase::Probe, mark, context.tool_time());
+		let backend = match backend {
+			Ok(x) => x,
+			Err(x) => return Ok(Processed::Skipped(x)),
+		};
+		record.tool = Some(backend.name().into());
+		let output = if backend == image::Backend::Ffmpeg {
+			None
+		} else {
+			let mark = context.mark();
+			let info = image::probe(context, input_file, backend).await;
+			timings.record(Phase::Probe, mark, context.tool_time());
 
-	let input_size = input_meta.len();
-	let output_size = output_meta.len();
+			Some(match info {
+				Ok(info) => {
+					let info = record.image.insert(info);
+					if let Some(reason) = check_comment(info.comment(), args)? {
+						return Ok(Processed::Skipped(reason));
+					}
+
+					if let Some(reason) = check_provenance(info, &args.respect_markers, input_file).await? {
+						return Ok(Processed::Skipped(reason));
+					}
+
+					if args.image.auto_format && output_options.file.is_none() {
+						let mark = context.mark();
+						match image::classify(context, input_file).await {
+							Ok(x) => info.classification = x,
+							Err(x) => debug!("unable to classify `{}`: {}", input_file.display(), x),
+						}
+						timings.record(Phase::Probe, mark, context.tool_time());
+					}
+
+					let mark = context.mark();
+					let output = convert_image(context, &output_options, args, info, input_file, backend).await;
+					timings.record(Phase::Convert, mark, context.tool_time());
+					output
+				}
+				Err(x) => Err(x),
+			})
+		};
+
+		match output {
+			None => {
+				debug!("only ffmpeg reads `{}`", input_file.display());
+				record.tool = Some("ffmpeg".into());
+				decode_with_ffmpeg(context, &output_options, args, input_file, mime, timings).await?
+			}
+			Some(Err(x)) if x.is_missing_delegate() => {
+				debug!("gm is missing a delegate for `{}`: {}", input_file.display(), x);
+				record.tool = Some("ffmpeg".into());
+				decode_with_ffmpeg(context, &output_options, args, input_file, mime, timings).await?
+			}
+			Some(x) => x?,
+		}
+	} else if mime.starts_with("video/") || animated {
+		let mark = context.mark();
+		let comment = video::get_comment(context, input_file).await;
+		let streams = match check_comment(comment, args) {
+			Ok(None) => video::probe_streams(context, input_file).await.map(Ok),
+			Ok(Some(x)) => Ok(Err(x)),
+			Err(x) => Err(x),
+		};
+		timings.record(Phase::Probe, mark, context.tool_time());
+		let streams = match streams? {
+			Ok(x) => record.streams.insert(x),
+			Err(x) => return Ok(Processed::Skipped(x)),
+		};
+		if args.live_photos != LivePhotos::Convert {
+			let duration = video::duration(streams);
+			if let Some(still) = live_photo::find_still(input_file, &record.metadata, duration).await {
+				if args.live_photos == LivePhotos::DropVideo && !record.redirected && output_options.should_replace() {
+					let mark = context.mark();
+					let result = drop_video(record, args).await;
+					timings.record(Phase::Replace, mark, context.tool_time());
+					return result.map(Processed::Converted);
+				}
+
+				return Ok(Processed::Skipped(SkipReason::LivePhotoVideo(still)));
+			}
+		}
+
+		let mark = context.mark();
+		let container = video::probe_container(context, input_file).await;
+		timings.record(Phase::Probe, mark, context.tool_time());
+		if container? == video::MPEG_TS {
+			let mark = context.mark();
+			record.tool = Some("ffmpeg".into());
+			let remuxed = video::remux(context, input_file).await;
+			timings.record(Phase::Convert, mark, context.tool_time());
+			let remuxed = remuxed?;
+			let output = convert_video_file(context, &output_options, args, &mut record, input_file, &remuxed, timings);
+			let output = output.await;
+			video::remove_remux(&remuxed).await;
+			output?
+		} else {
+			convert_video_file(context, &output_options, args, &mut record, input_file, input_file, timings).await?
+		}
+	} else {
+		let message = format!("unsupported file format: {}", mime);
+		context.terminal.warn_once(&format!("format {}", mime), None, message);
+		return Ok(Processed::Skipped(SkipReason::UnknownFormat));
+	};
+
+	check_interrupted(context, &output_file).await?;
+	if let Some(drift) = record.drift.filter(|x| args.video.fail_on_drift.is_some_and(|y| x.percent > y)) {
+		trace!("output drifted too far, removing `{}`", output_file.display());
+		fs::remove_file(&output_file).await?;
+		return Ok(Processed::Skipped(SkipReason::Drifted(drift)));
+	}
+
+	if let Some(limit) = args.max_output_size {
+		if fs::metadata(&output_file).await?.len() > limit {
+			trace!("output is still over the size limit, removing `{}`", output_file.display());
+			fs::remove_file(&output_file).await?;
+			return Ok(Processed::Skipped(SkipReason::SizeLimit(args.units.format(limit))));
+		}
+	}
+
+	if args.verify == Some(Verify::Perceptual) {
+		let mark = context.mark();
+		let result = check_likeness(context, args, &record, input_file, &output_file).await;
+		timings.record(Phase::Probe, mark, context.tool_time());
+		if let Some(reason) = result? {
+			return Ok(Processed::Skipped(reason));
+		}
+
+		check_interrupted(context, &output_file).await?;
+	}
+
+	qa::sample(context, input_file, &output_file, record.mime.as_deref().unwrap_or_default()).await;
+
+	let mark = context.mark();
+	record.backend = backend;
+	if let Some(x) = backend {
+		record.tool = Some(x.into());
+	}
+
+	if args.verbose && record.image.as_ref().is_some_and(|x| x.dimensions.is_some()) {
+		match image::dimensions(context, &output_file).await {
+			Ok(x) => record.output_dimensions = x,
+			Err(x) => debug!("unable to measure output file `{}`: {}", output_file.display(), x),
+		}
+	}
+
+	let result = finish(context, record, &output_file, args).await;
+	timings.record(Phase::Replace, mark, context.tool_time());
+	result.map(Processed::Converted)
+}
+
+/// Converts the target of the symlink `link` with `--follow-symlinks`,
+/// replacing the target rather than the link, which is pointed at the output
+/// if it took another name.
+async fn follow_symlink(
+	link: &Path, args: &Options, context: &mut Context, timings: &mut Timings,
+) -> Result<Processed, Error> {
+	let target = match fs::canonicalize(link).await {
+		Ok(x) => x,
+		Err(x) if x.kind() == io::ErrorKind::NotFound => return Err(Error::InputNotFound(link.to_path_buf())),
+		Err(x) => return Err(Error::input_io(Stage::Inspect, link)(x)),
+	};
+
+	if !args.follow_symlinks {
+		return Ok(Processed::Skipped(SkipReason::Symlink(link.to_path_buf())));
+	}
+
+	debug!("following `{}` to `{}`", link.display(), target.display());
+	let metadata = fs::symlink_metadata(&target).await;
+	let processed = Box::pin(convert_input(&target, metadata, args, context, timings)).await?;
+	if let Processed::Converted(conversion) = &processed {
+		if conversion.reclaimed && conversion.output != target {
+			debug!("pointing `{}` at `{}`", link.display(), conversion.output.display());
+			fsutil::retarget(link, &target, &conversion.output)?;
+		}
+	}
+
+	Ok(processed)
+}
+
+/// Fails once the run is interrupted, deleting `output_file` first, so that
+/// no output is left behind before it is complete.
+async fn check_interrupted(context: &mut Context, output_file: &Path) -> Result<(), Error> {
+	let result = context.check_interrupted();
+	if result.is_err() && output_file.exists() {
+		trace!("interrupted; deleting output file `{}`...", output_file.display());
+		if let Err(x) = fs::remove_file(output_file).await {
+			error!("failed to delete output file `{}`: {}", output_file.display(), x);
+		}
+	}
+
+	result
+}
+
+/// Downloads the input at `url`, to convert it into the output directory.
+async fn run_url(
+	url: &Path, args: &Options, context: &mut Context, timings: &mut Timings,
+) -> Result<Processed, Error> {
+	let url = url.to_string_lossy();
+	let download = download::fetch(context, &url, args.max_download_size, args.units).await?;
+	if let Ok(Some(mime)) = context.identify_file(&download.path).await {
+		download.check_type(&url, &mime);
+	}
+
+	let metadata = std::fs::symlink_metadata(&download.path);
+	let result = run_input(&download.path, metadata, args, context, timings).await;
+	download.remove().await;
+	result
+}
+
+/// Runs `--post-command` on the output of `conversion` of `input`, if it was
+/// kept.
+async fn run_post_command(
+	context: &mut Context, command: &CommandLine, input: &Path, conversion: Conversion, args: &Options,
+) -> Result<Conversion, Error> {
+	let discarded = args.no_grow && !conversion.delta.is_smaller();
+	if discarded || !conversion.output.exists() {
+		return Ok(conversion);
+	}
+
+	let output = conversion.output.as_os_str();
+	let mut process = context.command(&command.program)?;
+	for arg in &command.args {
+		process.arg(arg.expand(|x| match x {
+			Placeholder::Input => input.as_os_str(),
+			Placeholder::Output => output,
+			_ => OsStr::new(""),
+		}));
+	}
+
+	let result = context.output_here(process).await?;
+	if !result.status.success() {
+		return Err(Error::invocation(&command.program, result.status, &result.stderr));
+	}
+
+	Ok(conversion)
+}
+
+/// Runs `--on-success` or `--on-failure` for what processing `input` came to.
+async fn run_hooks(
+	context: &mut Context, args: &Options, input: &Path, result: Result<Processed, Error>,
+) -> Result<Processed, Error> {
+	let (hook, values) = match &result {
+		Ok(Processed::Converted(x)) => {
+			let Some(hook) = &args.on_success else {
+				return result;
+			};
+
+			let status = if x.delta.is_smaller() { "shrunk" } else { "grew" };
+			let saved_bytes = x.delta.original as i64 - x.delta.new as i64;
+			let values = hook::Values { input, output: Some(&x.output), status, saved_bytes, mime: &x.mime };
+			(hook, values)
+		}
+		Err(x) if x.severity() == Severity::File => {
+			let Some(hook) = &args.on_failure else {
+				return result;
+			};
+
+			(hook, hook::Values { input, output: None, status: "failed", saved_bytes: 0, mime: "" })
+		}
+		Ok(Processed::Skipped(_)) | Err(_) => return result,
+	};
+
+	let Err(error) = hook.run(context, args.hook_shell, &values).await else {
+		return result;
+	};
+
+	match (args.hook_errors, result) {
+		(_, Ok(_)) if error.severity() == Severity::Fatal => Err(error),
+		(HookErrors::Fail, Ok(_)) => Err(error),
+		(_, result) => {
+			context.terminal.write_note(format_args!("hook for `{}` failed: {}", input.display(), error));
+			result
+		}
+	}
+}
+
+/// Most conversions attempted for a single file with `--fallback`
+const MAX_ATTEMPTS: usize = 3;
+
+/// Backends of `chain` to try, in order.
+fn fallback_chain<T>(chain: &[T], fallback: bool) -> &[T] {
+	let len = if fallback { MAX_ATTEMPTS } else { 1 };
+	&chain[..len.min(chain.len())]
+}
+
+/// Outcome of the conversion attempt number `attempt`, unless the next
+/// backend is to be tried; the error of the latest failed invocation is kept
+/// in `error` for when none succeeds.
+fn attempt_outcome(
+	result: Result<PathBuf, Error>, attempt: usize, backend: &'static str, error: &mut Option<Error>,
+) -> Option<Result<(PathBuf, Option<&'static str>), Error>> {
+	match result {
+		Ok(x) => Some(Ok((x, (attempt > 0).then_some(backend)))),
+		Err(Error::BinaryNotFound(x)) if attempt > 0 => {
+			debug!("cannot fall back to {}, binary `{}` not found", backend, x);
+			None
+		}
+		Err(x @ Error::Invocation(..)) => {
+			debug!("conversion with {} failed: {}", backend, x);
+			*error = Some(x);
+			None
+		}
+		Err(x) => Some(Err(x)),
+	}
+}
+
+/// Converts an image like [`convert_image_with`], and with
+/// `--max-output-size` again at lower qualities while the output is larger.
+async fn convert_image(
+	context: &mut Context, output_options: &OutputOptions, args: &Options, info: &ImageInfo, input_file: &Path,
+	preferred: image::Backend,
+) -> Result<(PathBuf, Option<&'static str>), Error> {
+	let mut image_options = Cow::Borrowed(&args.image);
+	let mut attempts = limit::Attempts::new(args.max_output_size, args.max_attempts);
+	loop {
+		let output = convert_image_with(context, output_options, args, &image_options, info, input_file, preferred);
+		let output = output.await?;
+		let quality = image_options.image_quality.unwrap_or(limit::IMAGE_QUALITY);
+		let quality = match limit::image_quality(quality) {
+			Some(x) if attempts.retry(fs::metadata(&output.0).await?.len()) => x,
+			_ => return Ok(output),
+		};
+
+		debug!("`{}` came out too large; converting it again at quality {}", input_file.display(), quality);
+		fs::remove_file(&output.0).await?;
+		image_options.to_mut().image_quality = Some(quality);
+	}
+}
+
+/// Converts an image with `preferred`, or with `--fallback` with the next
+/// backends of [`image::Backend::CHAIN`] until one succeeds. Returns the
+/// backend used if it was not GraphicsMagick.
+async fn convert_image_with(
+	context: &mut Context, output_options: &OutputOptions, args: &Options, image_options: &ImageOptions,
+	info: &ImageInfo, input_file: &Path, preferred: image::Backend,
+) -> Result<(PathBuf, Option<&'static str>), Error> {
+	let start = image::Backend::CHAIN.iter().position(|x| *x == preferred).unwrap_or_default();
+	let mut error = None;
+	for (attempt, backend) in fallback_chain(&image::Backend::CHAIN[start..], args.fallback).iter().enumerate() {
+		if attempt > 0 {
+			warn!("converting `{}` failed; falling back to {}", input_file.display(), backend.name());
+		}
+
+		Span::current().record("tool", backend.name());
+		let comment = Comment::new(args.settings_hash.clone());
+		let result = image::convert(context, output_options, image_options, info, comment, input_file, *backend).await;
+		if let Some(x) = attempt_outcome(result, start + attempt, backend.name(), &mut error) {
+			return x;
+		}
+	}
+
+	Err(error.expect("the preferred backend is always attempted"))
+}
+
+/// Converts an image which gm cannot read by having ffmpeg decode it.
+async fn decode_with_ffmpeg(
+	context: &mut Context, output_options: &OutputOptions, args: &Options, input_file: &Path, mime: &str,
+	timings: &mut Timings,
+) -> Result<(PathBuf, Option<&'static str>), Error> {
+	let mark = context.mark();
+	Span::current().record("tool", "ffmpeg");
+	let comment = Comment::new(args.settings_hash.clone());
+	let output = image::convert_with_ffmpeg(context, output_options, &args.image, comment, input_file, mime).await;
+	timings.record(Phase::Convert, mark, context.tool_time());
+	Ok((output?, None))
+}
+
+/// Converts the video `input_file`, read from `source`: the file itself, or a
+/// remux of it, whose streams then replace those probed of the file. Like
+/// [`convert_image`], it tries the variants of [`video::Variant::CHAIN`], and
+/// higher rate factors with `--max-output-size`.
+async fn convert_video_file(
+	context: &mut Context, output_options: &OutputOptions, args: &Options, record: &mut InputRecord<'_>,
+	input_file: &Path, source: &Path, timings: &mut Timings,
+) -> Result<(PathBuf, Option<&'static str>), Error> {
+	if source != input_file {
+		let mark = context.mark();
+		let streams = video::probe_streams(context, source).await;
+		timings.record(Phase::Probe, mark, context.tool_time());
+		record.streams = Some(streams?);
+	}
+
+	let streams = record.streams.clone().unwrap_or_default();
+	let mark = context.mark();
+	record.tool = Some("ffmpeg".into());
+	let output = if video::is_still(&streams) {
+		debug!("`{}` is a single frame; converting it as an image", input_file.display());
+		Span::current().record("tool", "ffmpeg");
+		let comment = Comment::new(args.settings_hash.clone());
+		let output = image::convert_frame(context, output_options, &args.image, comment, input_file);
+		output.await.map(|x| (x, None))
+	} else {
+		let size = record.metadata.len();
+		convert_video(context, output_options, args, &streams, input_file, source, size).await
+	};
+	timings.record(Phase::Convert, mark, context.tool_time());
+	let output = output?;
+	if !video::is_still(&streams) {
+		let mark = context.mark();
+		let drift = check_drift(context, &streams, input_file, &output.0).await;
+		timings.record(Phase::Probe, mark, context.tool_time());
+		record.drift = drift?;
+	}
+
+	Ok(output)
+}
+
+/// Converts the video `input_file` of `size` bytes, read from `source`, like
+/// [`convert_video_with`], and with `--max-output-size` again at a rate
+/// factor estimated from how far over the output came out.
+async fn convert_video(
+	context: &mut Context, output_options: &OutputOptions, args: &Options, streams: &[video::Stream],
+	input_file: &Path, source: &Path, size: u64,
+) -> Result<(PathBuf, Option<&'static str>), Error> {
+	let mut video_options = Cow::Borrowed(&args.video);
+	let mut attempts = limit::Attempts::new(args.max_output_size, args.max_attempts);
+	loop {
+		let output =
+			convert_video_with(context, output_options, args, &video_options, streams, input_file, source, size);
+		let output = output.await?;
+		let format = video_options.video_format;
+		let crf = video_options.crf.unwrap_or(format.default_crf());
+		let written = fs::metadata(&output.0).await?.len();
+		let crf = match attempts.limit() {
+			Some(limit) if attempts.retry(written) => limit::crf(crf, format.max_crf(), written, limit),
+			_ => None,
+		};
+		let Some(crf) = crf else {
+			return Ok(output);
+		};
+
+		debug!("`{}` came out too large; converting it again at rate factor {}", input_file.display(), crf);
+		fs::remove_file(&output.0).await?;
+		video_options.to_mut().crf = Some(crf);
+	}
+}
+
+/// Converts the video with the first variant of [`video::Variant::CHAIN`],
+/// or with `--fallback` with the next ones until one succeeds.
+#[allow(clippy::too_many_arguments)]
+async fn convert_video_with(
+	context: &mut Context, output_options: &OutputOptions, args: &Options, video_options: &VideoOptions,
+	streams: &[video::Stream], input_file: &Path, source: &Path, size: u64,
+) -> Result<(PathBuf, Option<&'static str>), Error> {
+	let mut error = None;
+	for (attempt, variant) in fallback_chain(&video::Variant::CHAIN, args.fallback).iter().enumerate() {
+		let name = variant.name(video_options.video_format);
+		if attempt > 0 {
+			warn!("converting `{}` failed; falling back to {}", input_file.display(), name);
+		}
+
+		Span::current().record("tool", name);
+		let comment = Comment::new(args.settings_hash.clone());
+		let result = video::convert(
+			context,
+			output_options,
+			video_options,
+			streams,
+			comment,
+			input_file,
+			source,
+			*variant,
+			video_options.effort(size),
+		);
+		let result = result.await;
+		if let Some(x) = attempt_outcome(result, attempt, name, &mut error) {
+			return x;
+		}
+	}
+
+	Err(error.expect("the preferred variant is always attempted"))
+}
+
+/// Compares the converted video `output_file` with its source, telling how
+/// far it strays if it does.
+async fn check_drift(
+	context: &mut Context, streams: &[video::Stream], input_file: &Path, output_file: &Path,
+) -> Result<Option<video::Drift>, Error> {
+	let output_streams = match video::probe_streams(context, output_file).await {
+		Ok(x) => x,
+		Err(x) if x.severity() == Severity::Fatal => {
+			trace!("error raised; deleting output file `{}`...", output_file.display());
+			if let Err(x) = fs::remove_file(output_file).await {
+				error!("failed to delete output file `{}`: {}", output_file.display(), x);
+			}
+
+			return Err(x);
+		}
+		Err(x) => {
+			debug!("unable to probe output file `{}`: {}", output_file.display(), x);
+			return Ok(None);
+		}
+	};
+
+	let Some(drift) = video::drift(streams, &output_streams) else {
+		return Ok(None);
+	};
+
+	warn!("`{}` drifted from the source: {}", input_file.display(), drift);
+	Ok(Some(drift))
+}
+
+/// Compares the look of `output_file` with its source with `--verify
+/// perceptual`, deleting it if they are too far apart, telling why to skip
+/// the file then, or if they cannot be compared.
+async fn check_likeness(
+	context: &mut Context, args: &Options, record: &InputRecord<'_>, input_file: &Path, output_file: &Path,
+) -> Result<Option<SkipReason>, Error> {
+	let duration = match &record.streams {
+		Some(x) if video::is_still(x) => Some(0.0),
+		Some(x) => Some(video::duration(x).unwrap_or_default()),
+		None => None,
+	};
+
+	let result = match perceptual::compare(context, input_file, output_file, duration).await {
+		Ok(x) if x > args.verify_distance => Ok(Some(SkipReason::Unlike(x))),
+		x => x.map(|_| None),
+	};
+
+	if !matches!(result, Ok(None)) {
+		trace!("output unlike the source or not comparable; deleting `{}`", output_file.display());
+		if let Err(x) = fs::remove_file(output_file).await {
+			error!("failed to delete output file `{}`: {}", output_file.display(), x);
+		}
+	}
+
+	result
+}
+
+/// Puts the output of a conversion into place.
+async fn finish(
+	context: &mut Context, record: InputRecord<'_>, output_file: &Path, args: &Options,
+) -> Result<Conversion, Error> {
+	let input_file = record.path;
+	match record.check_unchanged().await {
+		Ok(()) => {}
+		Err(Error::InputChanged(input, None)) if args.on_input_change == InputChange::Keep => {
+			debug!("input file changed; keeping output file `{}`", output_file.display());
+			return Err(Error::InputChanged(input, Some(output_file.to_path_buf())));
+		}
+		Err(x) => {
+			trace!("input file vanished or changed; deleting output file `{}`...", output_file.display());
+			if let Err(x) = fs::remove_file(output_file).await {
+				error!("failed to delete output file `{}`: {}", output_file.display(), x);
+			}
+
+			return Err(x);
+		}
+	}
+
+	let output_meta = fs::metadata(output_file).await?;
+	let input_size = args.measure.size(&record.metadata);
+	let output_size = args.measure.size(&output_meta);
 	filetime::set_file_mtime(
-		&output_file,
-		filetime::FileTime::from_last_modification_time(&input_meta),
+		output_file,
+		filetime::FileTime::from_last_modification_time(&record.metadata),
 	)?;
 
+	let mime = record.mime.unwrap_or_default();
+	let redirected = record.redirected;
+	let source = record.image.as_ref().and_then(|x| x.dimensions);
+	let resize = source.zip(record.output_dimensions);
+	let classified = record.image.as_ref().and_then(|x| x.classification).filter(|_| args.verbose);
+	let drift = record.drift;
+	let details = Details { backend: record.backend, resize, classified, drift, ..Details::default() };
 	let delta = Delta::new(input_size, output_size);
 	if args.no_grow && !delta.is_smaller() {
 		trace!("conversion grew file, removing `{}`", output_file.display());
 		fs::remove_file(output_file).await?;
-		return Ok(delta);
+		let output = input_file.to_path_buf();
+		return Ok(Conversion { mime, delta, output, redirected: false, details, reclaimed: false });
 	}
 
-	// TODO: rotate files when output is explicitly given, but it coincides with
-	// input
-	if args.output.should_replace() {
-		replace(input_file, output_file).await?;
+	if redirected {
+		check_interrupted(context, output_file).await?;
+		let output = output_file.to_path_buf();
+		return Ok(Conversion { mime, delta, output, redirected, details, reclaimed: false });
+	}
+
+	let lands_on_input = args.output.lands_on(input_file, output_file.extension().unwrap_or_default());
+	if !args.output.should_replace() && !lands_on_input {
+		// complete now, as far as an interrupt goes
+		check_interrupted(context, output_file).await?;
+		if let Some(journal) = &context.journal {
+			let recorded = journal.borrow_mut().record(input_file, output_file, output_meta.len());
+			if let Err(x) = recorded {
+				trace!("unable to journal output; deleting output file `{}`...", output_file.display());
+				if let Err(x) = fs::remove_file(output_file).await {
+					error!("failed to delete output file `{}`: {}", output_file.display(), x);
+				}
+
+				return Err(x);
+			}
+		}
+
+		let output = output_file.to_path_buf();
+		return Ok(Conversion { mime, delta, output, redirected, details, reclaimed: false });
+	}
+
+	let digests = match context.audit.is_some() {
+		true => match (Digest::of(input_file).await, Digest::of(output_file).await) {
+			(Ok(original), Ok(new)) => Some((original, new)),
+			(Err(x), _) | (_, Err(x)) => {
+				trace!("unable to hash files to log; deleting output file `{}`...", output_file.display());
+				if let Err(x) = fs::remove_file(output_file).await {
+					error!("failed to delete output file `{}`: {}", output_file.display(), x);
+				}
+
+				return Err(x.into());
+			}
+		},
+		false => None,
+	};
+
+	check_interrupted(context, output_file).await?;
+	let backup = Backup::new(&args.backup);
+	let reclaimed = backup.is_none();
+	match replace(input_file, output_file, backup.as_ref(), args.preserve_inode, &backup::Files).await {
+		Ok(output) => {
+			if let Some((original, mut new)) = digests {
+				new.path = fsutil::canonical_path(&output);
+				let tool = record.tool.as_deref().unwrap_or_default();
+				audit::record(context, original, new, tool, &args.settings).await?;
+			}
+
+			Ok(Conversion { mime, delta, output, redirected, details, reclaimed })
+		}
+		// the conversion is all that is left of the work done
+		Err(x @ Error::BackupFailed { .. }) => Err(x),
+		Err(x) => {
+			if output_file.exists() {
+				trace!("error raised; deleting output file `{}`...", output_file.display());
+				if let Err(x) = fs::remove_file(output_file).await {
+					error!("failed to delete output file `{}`: {}", output_file.display(), x);
+				}
+			}
+
+			Err(x)
+		}
+	}
+}
+
+/// Gets rid of the video of a Live Photo as if it was converted to nothing.
+async fn drop_video(record: InputRecord<'_>, args: &Options) -> Result<Conversion, Error> {
+	let backup = Backup::new(&args.backup);
+	live_photo::drop_video(record.path, backup.as_ref()).await?;
+
+	let mime = record.mime.unwrap_or_default();
+	let delta = Delta::new(args.measure.size(&record.metadata), 0);
+	let output = record.path.to_path_buf();
+	let details = Details { note: Some("Live Photo video dropped"), ..Details::default() };
+	Ok(Conversion { mime, delta, output, redirected: false, details, reclaimed: backup.is_none() })
+}
+
+/// Skips files converted before, unless with other settings than those of
+/// `args`, telling why.
+fn check_comment(comment: Result<Option<Comment>, Error>, args: &Options) -> Result<Option<SkipReason>, Error> {
+	if !args.respect_markers.contains(&Marker::ShrinkRay) {
+		return Ok(None);
+	}
+
+	match comment {
+		Ok(Some(x)) if !args.ignore_marker_settings && !x.has_settings(args.settings_hash.as_ref()) => {
+			debug!("comment found, but of other settings: {}", x);
+			Ok(None)
+		}
+		Ok(Some(x)) if args.force => {
+			debug!("comment found, but converting anyway: {}", x);
+			Ok(None)
+		}
+		Ok(Some(x)) => {
+			debug!("comment found: {}", x);
+			Ok(Some(SkipReason::AlreadyConverted { version: x.version }))
+		}
+		Ok(None) => Ok(None),
+		Err(Error::Comment(x)) => {
+			debug!("unable to parse comment: {}", x);
+			Ok(None)
+		}
+		Err(x) => Err(x),
+	}
+}
+
+/// Skips images which the other tools of `markers` look to have optimized,
+/// telling why.
+async fn check_provenance(
+	info: &ImageInfo, markers: &[Marker], input_file: &Path,
+) -> Result<Option<SkipReason>, Error> {
+	if markers.iter().all(|x| *x == Marker::ShrinkRay) {
+		return Ok(None);
 	}
 
-	Ok(delta)
+	let head = provenance::read_head(input_file).await.map_err(Error::input_io(Stage::Inspect, input_file))?;
+	match provenance::detect(markers, info.raw_comment(), &head) {
+		Some(x) => {
+			debug!("`{}` looks optimized by {}", input_file.display(), x);
+			Ok(Some(SkipReason::OptimizedBy(x)))
+		}
+		None => Ok(None),
+	}
 }
 
-async fn replace(input: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<(), Error> {
+/// Replaces `input` with `output`, returning where the output ended up. The
+/// original is moved to `backup` if given, instead of being deleted, by
+/// `writer`; with `preserve_inode`, see [`overwrite`]. If the backup cannot be
+/// written, the original is left as it was and the output kept.
+async fn replace(
+	input: impl AsRef<Path>, output: impl AsRef<Path>, backup: Option<&Backup>, preserve_inode: bool,
+	writer: &impl backup::Writer,
+) -> Result<PathBuf, Error> {
 	let input = input.as_ref();
 	let output = output.as_ref();
-	let destination = input.with_extension(output.extension().unwrap());
+	let destination = input.with_extension(fsutil::matching_extension(input, output.extension().unwrap()));
 	debug!(
 		"replacing `{}` with `{}` (as `{}`)",
 		input.display(),
 		output.display(),
 		destination.display()
 	);
-	if input != destination && destination.exists() {
+	if fsutil::collides(input, &destination, &fsutil::Disk) {
 		return Err(Error::OutputExists(destination));
 	}
 
-	let temp = temp::file(input, input.extension());
+	let backup = match backup {
+		Some(x) => Some(x.path(input)?),
+		None => None,
+	};
+
+	if let Some(backup) = &backup {
+		if backup.exists() {
+			return Err(Error::OutputExists(backup.clone()));
+		}
+
+		let prepared = async {
+			if let Some(parent) = backup.parent() {
+				fs::create_dir_all(parent).await?;
+			}
+
+			backup::check_headroom(input, backup, preserve_inode)
+		};
+		prepared.await.map_err(Error::backup_failed(input, backup, output))?;
+	}
+
+	let keep = backup.is_some();
+	let suffix = input.extension().map(|x| {
+		let mut suffix = OsString::from(".");
+		suffix.push(x);
+		suffix
+	});
+	let temp = backup.unwrap_or_else(|| temp::file(input, suffix.as_deref()));
+	if preserve_inode {
+		return overwrite(input, output, &destination, &temp, keep, writer).await.map(|()| destination);
+	}
+
 	trace!("renaming original file `{}` to `{}`", input.display(), temp.display());
-	fs::rename(input, &temp).await?;
+	match keep {
+		true => writer.write(input, &temp, false).await.map_err(Error::backup_failed(input, &temp, output))?,
+		false => fs::rename(input, &temp).await?,
+	}
 
 	trace!(
 		"renaming new file `{}` to `{}`",
 		output.display(),
 		destination.display()
 	);
-	fs::rename(output, destination).await?;
+	// the original stays at `temp` until the output is in place, even when
+	// it has to be copied there from another filesystem
+	if let Err(x) = fsutil::move_file(output, &destination).await {
+		trace!("error raised; restoring original file `{}`", input.display());
+		if let Err(x) = fsutil::move_file(&temp, input).await {
+			error!("failed to restore original file `{}` from `{}`: {}", input.display(), temp.display(), x);
+		}
 
-	trace!("deleting original file `{}`", temp.display());
-	fs::remove_file(temp).await?;
+		return Err(x);
+	}
+
+	if !keep {
+		trace!("deleting original file `{}`", temp.display());
+		fs::remove_file(temp).await?;
+	}
+
+	Ok(destination)
+}
+
+/// Replaces `input` with `output` like [`replace`], but by writing `output`
+/// over it, renamed to `destination` afterwards, so that it keeps its inode.
+/// As the writing may fail halfway, the original is copied to `copy` first,
+/// and restored from there if it does; the copy is kept as a backup, written
+/// by `writer`, if `keep`.
+async fn overwrite(
+	input: &Path, output: &Path, destination: &Path, copy: &Path, keep: bool, writer: &impl backup::Writer,
+) -> Result<(), Error> {
+	trace!("copying original file `{}` to `{}`", input.display(), copy.display());
+	match keep {
+		true => writer.write(input, copy, true).await.map_err(Error::backup_failed(input, copy, output))?,
+		false => {
+			fsutil::detached_copy(input, copy).await?;
+		}
+	}
+	// the output bears the time the original was modified at by now
+	let modified = filetime::FileTime::from_last_modification_time(&fs::metadata(output).await?);
+
+	trace!("writing new file `{}` over `{}`", output.display(), input.display());
+	let written = fsutil::overwrite(input, output).await.and_then(|()| filetime::set_file_mtime(input, modified));
+	let renamed = match written {
+		Ok(()) if input != destination => {
+			trace!("renaming `{}` to `{}`", input.display(), destination.display());
+			fs::rename(input, destination).await
+		}
+		x => x,
+	};
+
+	if let Err(x) = renamed {
+		trace!("error raised; restoring original file `{}`", input.display());
+		match fsutil::overwrite(input, copy).await {
+			Ok(()) if !keep => fs::remove_file(copy).await?,
+			Ok(()) => {}
+			Err(x) => error!("failed to restore original file `{}` from `{}`: {}", input.display(), copy.display(), x),
+		}
+
+		return Err(Error::from(x));
+	}
+
+	trace!("deleting new file `{}`", output.display());
+	fs::remove_file(output).await?;
+	if !keep {
+		trace!("deleting copy of original file `{}`", copy.display());
+		fs::remove_file(copy).await?;
+	}
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::io;
+	use std::path::Path;
+
+	use crate::backup::{self, Backup, Writer};
+	use crate::Error;
+
+	/// Fails like a share over its quota
+	struct OverQuota;
+
+	impl Writer for OverQuota {
+		async fn write(&self, _original: &Path, _backup: &Path, _copy: bool) -> Result<(), Error> {
+			Err(io::Error::from(io::ErrorKind::QuotaExceeded).into())
+		}
+	}
+
+	#[tokio::test]
+	async fn keeps_original_and_output_if_backup_fails() {
+		for preserve_inode in [false, true] {
+			let dir = tempfile::tempdir().unwrap();
+			let (input, output) = (dir.path().join("a.jpg"), dir.path().join("a-x.webp"));
+			fs::write(&input, "original").unwrap();
+			fs::write(&output, "converted").unwrap();
+
+			let backup = Backup::Dir(dir.path().join("backups"));
+			let result = super::replace(&input, &output, Some(&backup), preserve_inode, &OverQuota).await;
+			let Err(Error::BackupFailed { original, output: kept, source, .. }) = result else {
+				panic!("{:?}", result);
+			};
+
+			assert!(matches!(*source, Error::Io(ref x) if x.kind() == io::ErrorKind::QuotaExceeded), "{}", source);
+			assert_eq!((original, kept), (input.clone(), output.clone()));
+			assert_eq!(fs::read_to_string(&input).unwrap(), "original");
+			assert_eq!(fs::read_to_string(&output).unwrap(), "converted");
+			assert!(!dir.path().join("a.webp").exists());
+		}
+	}
+
+	#[tokio::test]
+	async fn tells_where_files_are_if_backup_fails() {
+		let dir = tempfile::tempdir().unwrap();
+		let (input, output) = (dir.path().join("a.jpg"), dir.path().join("a-x.webp"));
+		fs::write(&input, "original").unwrap();
+		fs::write(&output, "converted").unwrap();
+
+		let backup = Backup::Suffix(".orig".into());
+		let error = super::replace(&input, &output, Some(&backup), false, &OverQuota).await.unwrap_err();
+		let message = error.to_string();
+		let start = format!("failed to back up `{}` to `{}.orig`: ", input.display(), input.display());
+		assert!(message.starts_with(&start), "{}", message);
+		assert!(message.ends_with(&format!("kept as `{}`", output.display())), "{}", message);
+	}
+
+	#[tokio::test]
+	async fn backs_up_originals_replaced() {
+		for preserve_inode in [false, true] {
+			let dir = tempfile::tempdir().unwrap();
+			let (input, output) = (dir.path().join("a.jpg"), dir.path().join("a-x.webp"));
+			fs::write(&input, "original").unwrap();
+			fs::write(&output, "converted").unwrap();
+
+			let backup = Backup::Suffix(".orig".into());
+			let replaced = super::replace(&input, &output, Some(&backup), preserve_inode, &backup::Files).await;
+			let replaced = replaced.unwrap();
+			assert_eq!(replaced, dir.path().join("a.webp"));
+			assert_eq!(fs::read_to_string(&replaced).unwrap(), "converted");
+			assert_eq!(fs::read_to_string(dir.path().join("a.jpg.orig")).unwrap(), "original");
+			assert!(!input.exists() && !output.exists());
+		}
+	}
+}
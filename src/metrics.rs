@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::trace;
+
+use crate::stats::Statistics;
+use crate::temp;
+
+/// Renders the statistics of a run in the Prometheus text exposition format,
+/// as consumed by node_exporter's textfile collector; `aborted` is the
+/// [kind](crate::Error::kind) of the fatal error the run stopped at, if any.
+pub fn render(
+	prefix: &str, stats: &Statistics, by_mime: &BTreeMap<String, Statistics>, aborted: Option<&str>, duration: Duration,
+	timestamp: SystemTime,
+) -> String {
+	let mut out = String::new();
+
+	header(&mut out, prefix, "files", "Number of files by outcome in the last run.");
+	for (outcome, count) in outcomes(stats) {
+		let _ = writeln!(out, "{}_files{{outcome=\"{}\"}} {}", prefix, outcome, count);
+	}
+
+	header(&mut out, prefix, "bytes", "Number of bytes processed, saved, wasted and reclaimed in the last run.");
+	for (kind, bytes) in sizes(stats) {
+		let _ = writeln!(out, "{}_bytes{{kind=\"{}\"}} {}", prefix, kind, bytes);
+	}
+
+	if !by_mime.is_empty() {
+		header(&mut out, prefix, "mime_files", "Number of converted files by MIME type and outcome in the last run.");
+		for (mime, stats) in by_mime {
+			// only conversions are tracked per MIME type
+			for (outcome, count) in outcomes(stats).into_iter().take(2) {
+				let _ = writeln!(out, "{}_mime_files{{mime=\"{}\",outcome=\"{}\"}} {}", prefix, escape(mime), outcome, count);
+			}
+		}
+
+		header(
+			&mut out,
+			prefix,
+			"mime_bytes",
+			"Number of bytes processed, saved, wasted and reclaimed by MIME type in the last run.",
+		);
+		for (mime, stats) in by_mime {
+			for (kind, bytes) in sizes(stats) {
+				let _ = writeln!(out, "{}_mime_bytes{{mime=\"{}\",kind=\"{}\"}} {}", prefix, escape(mime), kind, bytes);
+			}
+		}
+	}
+
+	header(&mut out, prefix, "stage_files", "Number of files that went through each stage in the last run.");
+	for (phase, timing) in stats.timings().iter() {
+		let _ = writeln!(out, "{}_stage_files{{stage=\"{}\"}} {}", prefix, phase, timing.files);
+	}
+
+	header(&mut out, prefix, "stage_seconds", "Time spent in tools and in between them by stage in the last run.");
+	for (phase, timing) in stats.timings().iter() {
+		for (kind, duration) in [("tools", timing.tools), ("overhead", timing.overhead())] {
+			let _ = writeln!(
+				out,
+				"{}_stage_seconds{{stage=\"{}\",kind=\"{}\"}} {:.6}",
+				prefix,
+				phase,
+				kind,
+				duration.as_secs_f64()
+			);
+		}
+	}
+
+	header(&mut out, prefix, "tools_cpu_seconds", "CPU time the tools took in the last run.");
+	let _ = writeln!(out, "{}_tools_cpu_seconds {:.6}", prefix, stats.timings().cpu().as_secs_f64());
+
+	let help = "Whether the last run stopped at a fatal error, its kind given as a label.";
+	header(&mut out, prefix, "run_aborted", help);
+	match aborted {
+		Some(reason) => {
+			let _ = writeln!(out, "{}_run_aborted{{reason=\"{}\"}} 1", prefix, reason);
+		}
+		None => {
+			let _ = writeln!(out, "{}_run_aborted 0", prefix);
+		}
+	}
+
+	header(&mut out, prefix, "run_duration_seconds", "Duration of the last run.");
+	let _ = writeln!(out, "{}_run_duration_seconds {:.3}", prefix, duration.as_secs_f64());
+
+	let timestamp = timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+	header(&mut out, prefix, "last_run_timestamp_seconds", "Time the last run was last updated.");
+	let _ = writeln!(out, "{}_last_run_timestamp_seconds {}", prefix, timestamp.as_secs());
+
+	out
+}
+
+/// Replaces `path` with `contents` atomically, so the collector never picks
+/// up a partially written file.
+pub async fn write(path: impl AsRef<Path>, contents: String) -> Result<(), crate::Error> {
+	let path = path.as_ref();
+	let temp = temp::file(path, Some(OsStr::new(".tmp")));
+	trace!("writing metrics to `{}`", temp.display());
+	let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&temp).await?;
+	let written = file.write_all(contents.as_bytes()).await;
+	if let Err(x) = written.and(file.flush().await) {
+		let _ = fs::remove_file(&temp).await;
+		return Err(crate::Error::from(x));
+	}
+
+	drop(file);
+
+	trace!("renaming metrics file `{}` to `{}`", temp.display(), path.display());
+	if let Err(x) = fs::rename(&temp, path).await {
+		let _ = fs::remove_file(&temp).await;
+		return Err(crate::Error::from(x));
+	}
+
+	Ok(())
+}
+
+fn header(out: &mut String, prefix: &str, name: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {}_{} {}", prefix, name, help);
+	let _ = writeln!(out, "# TYPE {}_{} gauge", prefix, name);
+}
+
+fn outcomes(stats: &Statistics) -> [(&'static str, usize); 4] {
+	[
+		("shrunk", stats.shrunk_files()),
+		("grew", stats.grew_files()),
+		("skipped", stats.skipped_files()),
+		("failed", stats.failed_files()),
+	]
+}
+
+/// Bytes by kind; outputs are smaller by `saved` bytes, but only `reclaimed`
+/// ones are actually freed, as originals are kept unless replaced
+fn sizes(stats: &Statistics) -> [(&'static str, u64); 4] {
+	[
+		("processed", stats.processed_bytes()),
+		("saved", stats.saved_bytes()),
+		("wasted", stats.wasted_bytes()),
+		("reclaimed", stats.reclaimed_bytes()),
+	]
+}
+
+fn escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use std::time::{Duration, SystemTime};
+
+	use super::{render, write};
+	use crate::skip::SkipReason;
+	use crate::stats::{Delta, Statistics};
+
+	fn golden(aborted: Option<&str>) -> String {
+		let mut stats = Statistics::default();
+		let (shrunk, grew) = (Delta::new(4000, 1000), Delta::new(500, 600));
+		stats.shrink(shrunk);
+		stats.reclaim(shrunk);
+		stats.grow(grew);
+		stats.skip(&SkipReason::TooSmall);
+		stats.fail();
+		stats.timings_mut().spend_cpu(Duration::from_millis(1500));
+
+		let mut by_mime = BTreeMap::new();
+		by_mime.entry("image/jpeg".to_string()).or_insert_with(Statistics::default).shrink(shrunk);
+		by_mime.entry("video/\"odd\"".to_string()).or_insert_with(Statistics::default).grow(grew);
+
+		let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		render("shrink_ray", &stats, &by_mime, aborted, Duration::from_millis(12_345), timestamp)
+	}
+
+	#[test]
+	fn renders_statistics() {
+		let expected = include_str!("../tests/fixtures/metrics.prom");
+		assert_eq!(golden(None), expected);
+
+		let aborted = golden(Some("binary_not_found"));
+		let label = "shrink_ray_run_aborted{reason=\"binary_not_found\"} 1";
+		let expected = expected.replace("shrink_ray_run_aborted 0", label);
+		assert_eq!(aborted, expected);
+	}
+
+	#[tokio::test]
+	async fn replaces_files_whole() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("shrink-ray.prom");
+		std::fs::write(&path, "stale").unwrap();
+		write(&path, "fresh\n".to_string()).await.unwrap();
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "fresh\n");
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+	}
+}
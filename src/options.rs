@@ -1,29 +1,463 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::io::{stderr, stdout, IsTerminal};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use clap::Parser;
+use magic::CookieFlags;
+use size::{Base, Size};
 use tracing::{debug, trace};
 
-use crate::temp;
+use crate::backup::Backup;
+use crate::comment::SettingsHash;
+use crate::order::Order;
+use crate::provenance::Marker;
+use crate::ratios::Ratios;
+use crate::shard::{self, Shard};
+use crate::stats::Delta;
+use crate::hook::{self, Hook};
+use crate::image;
+use crate::template::{CommandLine, Placeholder};
+use crate::terminal::PathStyle;
+use crate::video::Effort;
+use crate::{bytes, deadline, fsutil, since, temp};
 
 #[derive(Debug, Parser)]
-#[command(author, version, about)]
+#[command(
+	author,
+	version,
+	about,
+	args_conflicts_with_subcommands = true,
+	subcommand_negates_reqs = true,
+	after_help = "Send SIGUSR1 to pause the running tool, and again to resume it."
+)]
 pub struct Options {
-	/// Files to convert
-	#[arg(required = true)]
+	#[command(subcommand)]
+	pub command: Option<Command>,
+	/// Files to convert, or `http(s)://` URLs of files to download and
+	/// convert into `--output-dir`
+	#[arg(required_unless_present_any = ["tar", "print_config"])]
 	pub inputs: Vec<PathBuf>,
+	/// Convert the files within the directories among the inputs, and within
+	/// their subdirectories, in the order of their names; symlinks within them
+	/// are not followed, unless to files with `--follow-symlinks`
+	#[arg(short = 'r', long, conflicts_with_all = ["tar", "file"])]
+	pub recursive: bool,
+	/// Convert the targets of symlinks among the inputs, replacing them and
+	/// pointing the symlinks at the outputs, rather than skipping them
+	#[arg(long, conflicts_with = "tar")]
+	pub follow_symlinks: bool,
+	/// Mark each directory walked done once every file within it was processed
+	/// without failing, with a `.shrink-ray-done` file recording when and the
+	/// settings it was converted with; later runs with the same image and
+	/// video options skip it
+	#[arg(long, requires = "recursive")]
+	pub write_done_markers: bool,
+	/// Walk directories marked done as well
+	#[arg(long, requires = "recursive")]
+	pub ignore_done_markers: bool,
+	/// Convert the files of a tar archive read from the standard input, writing
+	/// the results as a tar archive to the standard output
+	#[arg(long, conflicts_with_all = ["inputs", "OutputOptions"])]
+	pub tar: bool,
+	/// Hand the run over to a `shrink-ray daemon` listening on `SOCKET`, or on
+	/// its default socket, rather than starting up anew; the output, and the
+	/// exit status, are those of the run
+	#[cfg(target_family = "unix")]
+	#[arg(
+		long,
+		value_name = "SOCKET",
+		num_args = 0..=1,
+		require_equals = true,
+		conflicts_with = "tar"
+	)]
+	pub via_daemon: Option<Option<PathBuf>>,
 	/// Output options
 	#[command(flatten)]
 	pub output: OutputOptions,
+	/// Backup options
+	#[command(flatten)]
+	pub backup: BackupOptions,
+	/// Write outputs over the originals they replace in place, so that hard
+	/// links to them and tools tracking them by inode see the new contents;
+	/// this is not atomic like swapping the files, so the originals are
+	/// copied aside first, to be restored should the writing fail
+	#[arg(long)]
+	pub preserve_inode: bool,
+	/// File identification options
+	#[command(flatten)]
+	pub magic: MagicOptions,
+	/// Image conversion options
+	#[command(flatten)]
+	pub image: ImageOptions,
+	/// Video conversion options
+	#[command(flatten)]
+	pub video: VideoOptions,
 	/// Discard output file if it ended up being bigger than the input file
 	#[arg(short = 'G', long)]
 	pub no_grow: bool,
+	/// Convert files again at lower qualities while their output is larger
+	/// than this (e.g. `25M`), keeping the original if it never fits
+	#[arg(long, value_name = "SIZE", value_parser = bytes::parse)]
+	pub max_output_size: Option<u64>,
+	/// Most conversions to attempt for a single file with `--max-output-size`
+	#[arg(
+		long,
+		value_name = "N",
+		default_value_t = 3,
+		value_parser = clap::value_parser!(u64).range(1..),
+		requires = "max_output_size"
+	)]
+	pub max_attempts: u64,
 	/// Do not stop when an input fails to process
 	#[arg(short, long)]
 	pub keep_going: bool,
+	/// When a tool fails on a file, retry it with the next capable backend
+	/// (ImageMagick or ffmpeg for images, other encoder settings for videos)
+	#[arg(long)]
+	pub fallback: bool,
 	/// Show statistics once all files are processed
 	#[arg(short, long)]
 	pub stats: bool,
+	/// Once all files are processed, list those with these outcomes
+	#[arg(long, value_name = "OUTCOMES", value_delimiter = ',')]
+	pub summary_list: Vec<Outcome>,
+	/// Most files to list of each outcome of `--summary-list`
+	#[arg(long, value_name = "N", default_value_t = 20, requires = "summary_list")]
+	pub summary_limit: usize,
+	/// Once all files are processed, list the N which shrunk the most, and
+	/// the N which grew the most
+	#[arg(long, value_name = "N")]
+	pub top: Option<usize>,
+	/// Most savings of single files to keep for the percentiles of
+	/// `--stats`, which are estimated from a sample of them beyond that
+	#[arg(
+		long,
+		value_name = "N",
+		default_value_t = Ratios::DEFAULT_CAP as u64,
+		value_parser = clap::value_parser!(u64).range(1..)
+	)]
+	pub ratio_samples: u64,
+	/// Do not show results changing files by less than a size and/or percentage
+	/// (e.g. `4K`, `1%` or `4K,1%`); they still count towards the statistics
+	#[arg(long, value_name = "LIMITS", value_parser = parse_negligible)]
+	pub hide_below: Option<Negligible>,
+	/// Most files to convert at once, and most tools to run at once for each,
+	/// e.g. for `--segment-encode`; unless given, files are converted one at a
+	/// time, with as many tools at once as there are CPUs
+	#[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+	pub jobs: Option<u64>,
+	/// Order to process the inputs in; any but `given` looks up the sizes of
+	/// all of them first, rather than only those within `--pipeline-depth`
+	#[arg(long, value_name = "ORDER", default_value = "given", conflicts_with_all = ["tar", "collapse_sequences"])]
+	pub sort: Order,
+	/// Number of upcoming inputs to inspect ahead of time; the inputs
+	/// themselves, and the files within the directories walked with
+	/// `--recursive`, are all listed up front
+	#[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+	pub pipeline_depth: u64,
+	/// Report runs of consecutively numbered files (like `IMG_0001.jpg`,
+	/// `IMG_0002.jpg`, …) as a single line
+	#[arg(long)]
+	pub collapse_sequences: bool,
+	/// Display the paths of files in canonical form, rather than as given
+	#[arg(long, conflicts_with_all = ["tar", "relative_to"])]
+	pub absolute_paths: bool,
+	/// Display the paths of files relative to this directory, rather than as
+	/// given
+	#[arg(long, value_name = "DIR", value_parser = parse_dir, conflicts_with = "tar")]
+	pub relative_to: Option<PathBuf>,
+	/// Write what happens to each file, as JSON lines of tracing spans and
+	/// events, to this file, whatever `RUST_LOG` says
+	#[arg(long, value_name = "PATH")]
+	pub trace_file: Option<PathBuf>,
+	/// Append an entry for every replaced file to this log, with the hashes of
+	/// the original and of the output, each entry chained to the one before so
+	/// that changes to the log show; check it with `shrink-ray audit verify`
+	#[arg(long, value_name = "PATH", conflicts_with = "tar")]
+	pub audit_log: Option<PathBuf>,
+	/// Write side-by-side crops of the originals and the outputs of some of the
+	/// converted files to this directory, at 1:1 scale, to check their quality
+	/// by eye; videos are compared at their middle frame
+	#[arg(long, value_name = "DIR")]
+	pub qa_samples: Option<PathBuf>,
+	/// Sample every Nth converted file for `--qa-samples`, starting with the
+	/// first
+	#[arg(
+		long,
+		value_name = "N",
+		default_value_t = 10,
+		requires = "qa_samples",
+		value_parser = clap::value_parser!(u64).range(1..)
+	)]
+	pub qa_rate: u64,
+	/// Write Prometheus metrics about the run to this file
+	#[arg(long, value_name = "PATH")]
+	pub metrics_file: Option<PathBuf>,
+	/// Prefix of the metric names written to `--metrics-file`
+	#[arg(long, value_name = "PREFIX", default_value = "shrink_ray", requires = "metrics_file")]
+	pub metrics_prefix: String,
+	/// Only process files modified after the given RFC 3339 time, duration ago
+	/// (e.g. `7d`), or modification time of `@PATH`
+	#[arg(long, value_name = "WHEN", value_parser = since::parse)]
+	pub since: Option<SystemTime>,
+	/// Skip files smaller than this (e.g. `100K`), which take longer to
+	/// convert than what little they would save is worth
+	#[arg(long, value_name = "SIZE", value_parser = bytes::parse)]
+	pub min_size: Option<u64>,
+	/// Only process share K of N (e.g. `2/3`) of the inputs, picked by a hash
+	/// of their canonical paths, so that several hosts can split a batch
+	/// between them; the other inputs are left out silently
+	#[arg(long, value_name = "K/N", value_parser = shard::parse, conflicts_with = "tar")]
+	pub shard: Option<Shard>,
+	/// Stop starting new files once the run has taken about this long (e.g.
+	/// `4h30m`), leaving time for the last one to finish; pauses do not count
+	#[arg(long, value_name = "DURATION", value_parser = deadline::parse_runtime)]
+	pub max_runtime: Option<Duration>,
+	/// Stop at the given RFC 3339 time or time of day (e.g. `07:00`),
+	/// cancelling the file being processed then
+	#[arg(long, value_name = "WHEN", value_parser = deadline::parse)]
+	pub deadline: Option<SystemTime>,
+	/// Point out tools which show no signs of life (output, growth of the file
+	/// they write, or CPU time where known) for this long (e.g. `5min`)
+	#[arg(long, value_name = "DURATION", value_parser = deadline::parse_runtime)]
+	pub stall_timeout: Option<Duration>,
+	/// What to do about tools stalled for `--stall-timeout`
+	#[arg(long, value_name = "ACTION", default_value = "warn", requires = "stall_timeout")]
+	pub on_stall: OnStall,
+	/// Only start files while the system is otherwise idle: nobody uses it,
+	/// where that is known, and the load average is below `--idle-load`
+	#[arg(long, conflicts_with = "tar")]
+	pub only_when_idle: bool,
+	/// Load average over the last minute below which the system is idle
+	#[arg(long, value_name = "LOAD", default_value_t = 1.0, requires = "only_when_idle")]
+	pub idle_load: f64,
+	/// Compare the converted files with their sources this way, keeping the
+	/// sources of those which differ too much
+	#[arg(long, value_name = "MODE")]
+	pub verify: Option<Verify>,
+	/// Most bits out of 64 the perceptual hashes of a converted file and its
+	/// source may differ in with `--verify perceptual`
+	#[arg(long, value_name = "BITS", default_value_t = 10, value_parser = clap::value_parser!(u32).range(0..=64))]
+	pub verify_distance: u32,
+	/// What to do with the output of a file that something else wrote to while
+	/// it was being converted, e.g. a sync tool; the file fails either way
+	#[arg(long, value_name = "ACTION", default_value = "keep")]
+	pub on_input_change: InputChange,
+	/// What to do once nobody reads the output anymore, e.g. after piping it
+	/// into `head`
+	#[arg(long, value_name = "ACTION", default_value = "quit")]
+	pub on_broken_pipe: BrokenPipe,
+	/// Keep the scratch directories the tools run in, instead of deleting them
+	/// once they are done, e.g. to look into what they left there
+	#[arg(long)]
+	pub keep_temp: bool,
+	/// Convert images in a single `gm batch` process fed one command after the
+	/// other, rather than in a gm process each, which adds up for many small
+	/// images; files it cannot take still get a process of their own
+	#[arg(long)]
+	pub gm_batch: bool,
+	/// Tool definitions to use instead of `~/.config/shrink-ray/tools.toml`
+	#[arg(long, value_name = "PATH")]
+	pub tools_file: Option<PathBuf>,
+	/// Pass this environment variable on to the tools even though it is one of
+	/// those cleared to make them behave the same everywhere: FFREPORT,
+	/// FFMPEG_DATADIR, AVCONV_DATADIR and MAGICK_*
+	#[arg(long, value_name = "VAR")]
+	pub passthrough_env: Vec<OsString>,
+	/// Largest file to download, for inputs given as URLs
+	#[arg(long, value_name = "SIZE", default_value = "1G", value_parser = bytes::parse)]
+	pub max_download_size: u64,
+	/// Run this command after each conversion whose output is kept, e.g. to
+	/// upload it; `{input}` and `{output}` stand for the paths (or URL) of the
+	/// input and of the output, and arguments are split on whitespace
+	#[arg(long, value_name = "CMD", value_parser = parse_post_command, conflicts_with = "tar")]
+	pub post_command: Option<CommandLine>,
+	/// Run this command after each file converted, whether it shrunk or grew;
+	/// `{input}`, `{output}`, `{status}` (`shrunk` or `grew`), `{saved_bytes}`
+	/// and `{mime}` stand for what happened to it
+	#[arg(long, value_name = "CMD", value_parser = hook::parse, conflicts_with = "tar")]
+	pub on_success: Option<Hook>,
+	/// Like `--on-success`, after each file that failed, with `{status}` being
+	/// `failed`
+	#[arg(long, value_name = "CMD", value_parser = hook::parse, conflicts_with = "tar")]
+	pub on_failure: Option<Hook>,
+	/// Run hooks with `sh -c`, the values of placeholders quoted, rather than
+	/// splitting them on whitespace
+	#[arg(long)]
+	pub hook_shell: bool,
+	/// What a failing hook means for the file
+	#[arg(long, value_name = "POLICY", default_value = "ignore")]
+	pub hook_errors: HookErrors,
+	/// Configuration file to use instead of `~/.config/shrink-ray/config.toml`,
+	/// whose settings the command line overrides
+	#[arg(long, value_name = "PATH")]
+	pub config: Option<PathBuf>,
+	/// Use the settings of the profile `[profile.NAME]` of the configuration
+	/// file, on top of its global ones
+	#[arg(long, value_name = "NAME")]
+	pub profile: Option<String>,
+	/// Print the effective settings, and where each comes from, then exit
+	#[arg(long)]
+	pub print_config: bool,
+	/// What to do about files tracked in a git work tree when replacing them
+	#[arg(long, value_name = "POLICY", default_value = "warn")]
+	pub git: GitPolicy,
+	/// Skip files bearing the marks of these tools, as already optimized, as a
+	/// comma-separated list
+	#[arg(long, value_name = "MARKERS", value_delimiter = ',', default_value = "shrink-ray")]
+	pub respect_markers: Vec<Marker>,
+	/// Skip files bearing the mark of shrink-ray even if they were converted
+	/// with other image and video options than those of this run
+	#[arg(long)]
+	pub ignore_marker_settings: bool,
+	/// Convert files bearing the mark of shrink-ray anyway, e.g. once a flaw
+	/// of an earlier version is fixed, marking them anew
+	#[arg(long)]
+	pub force: bool,
+	/// What to do about the short videos paired with an image of the same name,
+	/// like the motion part of Live Photos
+	#[arg(long, value_name = "ACTION", default_value = "convert", conflicts_with = "tar")]
+	pub live_photos: LivePhotos,
+	/// How to measure file sizes when comparing inputs and outputs: the
+	/// `apparent` length, or the space `allocated` on disk, which differs
+	/// for sparse files or on filesystems with compression
+	#[arg(long, value_name = "HOW", default_value = "apparent")]
+	pub measure: Measure,
+	/// Unit system to show sizes in
+	#[arg(long, value_name = "UNITS", default_value = "binary")]
+	pub units: Units,
+	/// Progress animation interval, in milliseconds
+	#[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(10..))]
+	pub progress_interval: Option<u64>,
+	/// Do not animate progress or echo tool output
+	#[arg(long, conflicts_with = "progress_interval")]
+	pub no_spinner: bool,
+	/// Show more details about each processed file
+	#[arg(short, long)]
+	pub verbose: bool,
+	/// How to report the results: as lines for `human` readers, or as `json`,
+	/// an object per line for each input, and one for the statistics at the
+	/// end, however the run ends
+	#[arg(
+		long,
+		value_name = "FORMAT",
+		default_value = "human",
+		conflicts_with_all = ["collapse_sequences", "hide_below"]
+	)]
+	pub format: Format,
+	/// Write progress events, as JSON objects a line each, to the file
+	/// descriptor `N` inherited from whoever started shrink-ray, e.g. a
+	/// front-end, whatever the terminal shows
+	#[cfg(target_family = "unix")]
+	#[arg(long, value_name = "N", conflicts_with_all = ["progress_pipe", "via_daemon"])]
+	pub progress_fd: Option<i32>,
+	/// Write progress events like `--progress-fd` to the named pipe (or file)
+	/// at `PATH`, waiting for a reader to open it
+	#[arg(long, value_name = "PATH")]
+	pub progress_pipe: Option<PathBuf>,
+	/// Effective value of every option that can go in the configuration file,
+	/// by its long name, as `--print-config` shows it
+	#[arg(skip)]
+	pub settings: Vec<(String, String)>,
+	/// Hash of the image and video options of this run, to mark outputs with
+	#[arg(skip)]
+	pub settings_hash: Option<SettingsHash>,
+	/// Arguments the options were parsed from, settings of the configuration
+	/// file included, to hand the run over to the daemon with
+	#[arg(skip)]
+	pub argv: Vec<OsString>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+	/// Put the backups of converted files back in place of them
+	Restore(RestoreOptions),
+	/// Inspect the tool definitions of the user
+	Tools(ToolsOptions),
+	/// Inspect audit logs written with `--audit-log`
+	Audit(AuditOptions),
+	/// Delete outputs in an output directory which are larger than the
+	/// sources they were converted from
+	Prune(PruneOptions),
+	/// Serve the runs of `--via-daemon` over a unix socket, sparing each of
+	/// them the start-up
+	#[cfg(target_family = "unix")]
+	Daemon(DaemonOptions),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RestoreOptions {
+	/// Converted files to restore
+	#[arg(required = true)]
+	pub files: Vec<PathBuf>,
+	/// Where the backups were kept
+	#[command(flatten)]
+	pub backup: BackupOptions,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ToolsOptions {
+	#[command(subcommand)]
+	pub command: ToolsCommand,
+	/// Tool definitions to use instead of `~/.config/shrink-ray/tools.toml`
+	#[arg(long, value_name = "PATH", global = true)]
+	pub tools_file: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct PruneOptions {
+	/// Output directory to prune
+	#[arg(short = 'd', long = "output-dir", value_name = "DIR")]
+	pub output_dir: PathBuf,
+	/// Directory of the sources, mirrored by the output directory; outputs
+	/// are paired with the source of the same name in the same place, of the
+	/// same format if there are several
+	#[arg(long, value_name = "DIR")]
+	pub against: PathBuf,
+	/// Only show which outputs would be deleted
+	#[arg(short = 'n', long)]
+	pub dry_run: bool,
+}
+
+#[cfg(target_family = "unix")]
+#[derive(Debug, clap::Args)]
+pub struct DaemonOptions {
+	/// Socket to listen on; `shrink-ray.sock` in `$XDG_RUNTIME_DIR` by default,
+	/// or `shrink-ray-UID.sock` in the temporary directory
+	#[arg(long, value_name = "PATH")]
+	pub socket: Option<PathBuf>,
+	/// Exit once no run has been handed over for this long
+	#[arg(long, value_name = "DURATION", default_value = "10min", value_parser = deadline::parse_runtime)]
+	pub idle_timeout: Duration,
+	/// Most runs to take at once; they are served one after the other, and any
+	/// beyond these are turned away
+	#[arg(long, value_name = "N", default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+	pub max_clients: u64,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct AuditOptions {
+	#[command(subcommand)]
+	pub command: AuditCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum AuditCommand {
+	/// Check that no entry of an audit log was changed or removed, except at
+	/// the end
+	Verify {
+		/// Audit log to check
+		path: PathBuf,
+	},
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ToolsCommand {
+	/// List the defined tools, in the order they are tried
+	List,
+	/// Check the tool definitions for mistakes
+	Validate,
 }
 
 #[derive(Clone, Debug, clap::Args)]
@@ -35,11 +469,138 @@ pub struct OutputOptions {
 	/// Output directory
 	#[arg(short, long = "output-dir", value_name = "PATH")]
 	pub dir: Option<PathBuf>,
+	/// Output directory for inputs that cannot be replaced in place (e.g. on
+	/// read-only filesystems)
+	#[arg(long = "auto-output-dir", value_name = "PATH")]
+	pub auto_dir: Option<PathBuf>,
+}
+
+impl Options {
+	const PROGRESS_INTERVAL: u64 = 100;
+
+	pub fn jobs(&self) -> usize {
+		match self.jobs {
+			Some(x) => x as usize,
+			None => std::thread::available_parallelism().map_or(1, |x| x.get()),
+		}
+	}
+
+	/// Most files to convert at once.
+	pub fn files_at_once(&self) -> usize {
+		self.jobs.map_or(1, |x| x as usize)
+	}
+
+	/// Progress animation interval, unless disabled explicitly or because the
+	/// output is not a terminal.
+	pub fn spinner(&self) -> Option<Duration> {
+		if self.no_spinner || self.format == Format::Json {
+			return None;
+		}
+
+		match self.progress_interval {
+			Some(x) => Some(Duration::from_millis(x)),
+			None if self.terminal_is_tty() => Some(Duration::from_millis(Self::PROGRESS_INTERVAL)),
+			None => None,
+		}
+	}
+
+	pub fn path_style(&self) -> PathStyle {
+		match &self.relative_to {
+			Some(dir) => PathStyle::RelativeTo(dir.clone()),
+			None if self.absolute_paths => PathStyle::Absolute,
+			None => PathStyle::AsGiven,
+		}
+	}
+
+	/// Whether the human readable output goes to the standard error output,
+	/// as the standard output carries data.
+	pub fn terminal_to_stderr(&self) -> bool {
+		self.tar
+	}
+
+	/// What is wrong with the formats asked for, if they cannot go together.
+	pub fn format_conflict(&self) -> Option<String> {
+		let extension = self.output.file_extension();
+		if let (Some(format), Some(extension)) = (self.image.image_format, &extension) {
+			let asked = image::Format::from(format);
+			if image::Format::from_extension(extension).is_some_and(|x| x != asked) {
+				return Some(format!(
+					"'--image-format {}' does not write `.{}` files for '--output-file'",
+					asked.name(),
+					extension
+				));
+			}
+		}
+
+		let format = self.video.video_format;
+		let other = |x: &&str| VideoFormat::from_extension(x).is_some_and(|x| x != format);
+		if let Some(extension) = extension.as_deref().filter(other) {
+			return Some(format!("'--output-file <PATH>' with `.{}` needs '--video-format {}'", extension, extension));
+		}
+
+		match self.video.crf {
+			Some(crf) if crf > format.max_crf() => Some(format!(
+				"'--crf {}' is above {}, the highest '--video-format {}' takes",
+				crf,
+				format.max_crf(),
+				format.extension()
+			)),
+			_ => None,
+		}
+	}
+
+	fn terminal_is_tty(&self) -> bool {
+		if self.terminal_to_stderr() {
+			stderr().is_terminal()
+		} else {
+			stdout().is_terminal()
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, clap::Args)]
+#[group(required = false, multiple = false)]
+pub struct BackupOptions {
+	/// Keep the originals of replaced files beside them, with SUFFIX appended
+	/// to their names
+	#[arg(
+		long,
+		value_name = "SUFFIX",
+		num_args = 0..=1,
+		require_equals = true,
+		default_missing_value = Backup::DEFAULT_SUFFIX
+	)]
+	pub backup: Option<String>,
+	/// Keep the originals of replaced files in this directory, mirroring their
+	/// absolute paths
+	#[arg(long, value_name = "PATH")]
+	pub backup_dir: Option<PathBuf>,
 }
 
 impl OutputOptions {
+	pub fn redirected(dir: PathBuf) -> Self {
+		OutputOptions { file: None, dir: Some(dir), auto_dir: None }
+	}
+
+	/// Extension of `--output-file`, in lower case.
+	pub fn file_extension(&self) -> Option<String> {
+		let extension = self.file.as_deref()?.extension()?;
+		Some(extension.to_string_lossy().to_ascii_lowercase())
+	}
+
+	/// Checks that the extension of `--output-file`, if any, matches the
+	/// `extension` (without the dot) of what the conversion produces.
+	pub fn check_extension(&self, extension: &str) -> Result<(), crate::Error> {
+		match (&self.file, self.file_extension()) {
+			(Some(file), Some(x)) if !x.eq_ignore_ascii_case(extension) => {
+				Err(crate::Error::OutputExtension(file.clone(), format!("`.{}`", extension)))
+			}
+			_ => Ok(()),
+		}
+	}
+
 	pub fn should_replace(&self) -> bool {
-		matches!(self, OutputOptions { file: None, dir: None })
+		matches!(self, OutputOptions { file: None, dir: None, .. })
 	}
 
 	pub fn get(&self, input: impl AsRef<Path>, suffix: impl AsRef<OsStr>) -> PathBuf {
@@ -48,7 +609,9 @@ impl OutputOptions {
 		}
 
 		if let Some(dir) = &self.dir {
-			return dir.join(input.as_ref().file_name().unwrap()).with_extension(suffix);
+			let suffix = suffix.as_ref().to_string_lossy();
+			let extension = fsutil::matching_extension(input.as_ref(), OsStr::new(suffix.trim_start_matches('.')));
+			return dir.join(input.as_ref().file_name().unwrap()).with_extension(extension);
 		}
 
 		trace!("no output file given; choosing random temporary file");
@@ -56,4 +619,472 @@ impl OutputOptions {
 		debug!("chose a temporary output file `{}`", name.display());
 		name
 	}
+
+	/// Whether the output of `input` given or put into a directory is `input`
+	/// itself, e.g. with `-o` naming it, which has to replace it then rather
+	/// than be written over it by tools still reading it.
+	pub fn lands_on(&self, input: &Path, suffix: impl AsRef<OsStr>) -> bool {
+		if self.should_replace() {
+			return false;
+		}
+
+		let key = |x: &Path| fsutil::name_key(&fsutil::canonical_path(x), &fsutil::Disk);
+		key(&self.get(input, suffix)) == key(input)
+	}
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct MagicOptions {
+	/// Magic database to use instead of the system default (can be repeated)
+	#[arg(long = "magic-db", value_name = "PATH")]
+	pub databases: Vec<PathBuf>,
+	/// Additional libmagic flags, as a comma-separated list
+	#[arg(long = "magic-flags", value_name = "FLAGS", value_delimiter = ',', value_parser = parse_magic_flag)]
+	pub flags: Vec<CookieFlags>,
+	/// Most files to identify at once, each with a libmagic instance of its own
+	#[arg(
+		id = "magic_jobs",
+		long = "magic-jobs",
+		value_name = "N",
+		default_value_t = 2,
+		value_parser = clap::value_parser!(u64).range(1..)
+	)]
+	pub jobs: u64,
+}
+
+impl MagicOptions {
+	// flags changing what libmagic returns are left out, since we expect a MIME
+	// type back
+	const FLAGS: &'static [&'static str] = &[
+		"debug",
+		"symlink",
+		"compress",
+		"devices",
+		"check",
+		"preserve_atime",
+		"raw",
+		"compress_transp",
+		"no_check_compress",
+		"no_check_tar",
+		"no_check_soft",
+		"no_check_apptype",
+		"no_check_elf",
+		"no_check_text",
+		"no_check_cdf",
+		"no_check_csv",
+		"no_check_tokens",
+		"no_check_encoding",
+		"no_check_json",
+		"no_check_builtin",
+	];
+
+	pub fn flags(&self) -> CookieFlags {
+		self.flags.iter().fold(CookieFlags::MIME_TYPE | CookieFlags::ERROR, |acc, x| acc | *x)
+	}
+}
+
+/// Parses a directory into its canonical form, failing if it does not exist.
+fn parse_dir(value: &str) -> Result<PathBuf, String> {
+	match Path::new(value).canonicalize() {
+		Ok(x) if x.is_dir() => Ok(x),
+		Ok(_) => Err(format!("`{}` is not a directory", value)),
+		Err(x) => Err(x.to_string()),
+	}
+}
+
+fn parse_magic_flag(value: &str) -> Result<CookieFlags, String> {
+	let value = value.trim().to_ascii_lowercase();
+	let name = value.strip_prefix("magic_").unwrap_or(&value);
+	if MagicOptions::FLAGS.contains(&name) {
+		if let Some(flag) = CookieFlags::from_name(&name.to_ascii_uppercase()) {
+			return Ok(flag);
+		}
+	}
+
+	Err(format!("unknown libmagic flag (valid flags: {})", MagicOptions::FLAGS.join(", ")))
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct ImageOptions {
+	/// Metadata to keep in converted images, as a comma-separated list
+	#[arg(long, value_name = "KINDS", value_delimiter = ',')]
+	pub keep_metadata: Vec<Metadata>,
+	/// sRGB profile to convert images with an embedded color profile to
+	#[arg(long, value_name = "PATH")]
+	pub srgb_profile: Option<PathBuf>,
+	/// Encode photos as JPEG (or lossy WebP if they are transparent), and
+	/// screenshots, diagrams and the like losslessly as WebP, unless
+	/// `--output-file` asks for a format
+	#[arg(long)]
+	pub auto_format: bool,
+	/// Format to encode images to, unless `--output-file` asks for one; JPEG
+	/// unless given, or lossy WebP for transparent images
+	#[arg(long, value_name = "FORMAT", conflicts_with = "auto_format")]
+	pub image_format: Option<ImageFormat>,
+	/// Quality to encode JPEG and lossy WebP images with, from 1 to 100; left
+	/// to the encoder unless given
+	#[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+	pub image_quality: Option<u8>,
+	/// Straighten scans skewed by more than this threshold, in percent (e.g.
+	/// `40`); encodes images with ImageMagick, as GraphicsMagick lacks it
+	#[arg(long, value_name = "THRESHOLD", value_parser = parse_percent)]
+	pub deskew: Option<f64>,
+	/// Cut off the borders of images, of the same color as their corners
+	#[arg(long)]
+	pub trim_borders: bool,
+	/// Stretch the levels of images to the full range, brightening dark photos
+	#[arg(long)]
+	pub auto_level: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum ImageFormat {
+	/// JPEG, flattening any transparency
+	Jpeg,
+	/// Lossy WebP
+	Webp,
+	/// PNG, losslessly with the strongest compression
+	Png,
+	/// Lossy AVIF, which only ImageMagick encodes
+	Avif,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Format {
+	/// Colored lines, with the progress animated on terminals
+	#[default]
+	Human,
+	/// JSON objects, one per line
+	Json,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum BrokenPipe {
+	/// Stop once the current file is done
+	Quit,
+	/// Keep processing the files without reporting anything
+	ContinueSilent,
+}
+
+/// Change in size too small to be worth showing, that is below all of the
+/// given limits
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Negligible {
+	bytes: Option<u64>,
+	/// In hundredths of a percent
+	hundredths: Option<u128>,
+}
+
+impl Negligible {
+	pub fn covers(&self, delta: Delta) -> bool {
+		self.bytes.is_none_or(|x| delta.difference() < x)
+			&& self.hundredths.is_none_or(|x| delta.percent().hundredths() < x)
+	}
+}
+
+fn parse_negligible(value: &str) -> Result<Negligible, String> {
+	let mut negligible = Negligible::default();
+	for limit in value.split(',').map(str::trim) {
+		if let Some(percent) = limit.strip_suffix('%') {
+			let percent: f64 = percent.trim().parse().map_err(|_| format!("`{}` is not a percentage", limit))?;
+			if negligible.hundredths.replace((percent.max(0.0) * 100.0).round() as u128).is_some() {
+				return Err("more than one percentage given".into());
+			}
+		} else if negligible.bytes.replace(bytes::parse(limit)?).is_some() {
+			return Err("more than one size given".into());
+		}
+	}
+
+	Ok(negligible)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, clap::ValueEnum)]
+pub enum Outcome {
+	/// Files that failed to process
+	Failed,
+	/// Files that were skipped
+	Skipped,
+	/// Files whose conversion ended up bigger
+	Grew,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum GitPolicy {
+	/// Convert them like any other file
+	Ignore,
+	/// Leave them alone
+	SkipTracked,
+	/// Convert them, but point out once per repository that they are tracked
+	Warn,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum HookErrors {
+	/// Point them out, the file being processed as if nothing happened
+	Ignore,
+	/// Fail the file, even though its conversion went through
+	Fail,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum OnStall {
+	/// Show them as possibly stalled, and keep waiting
+	#[default]
+	Warn,
+	/// Interrupt them, failing the file
+	Fail,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Verify {
+	/// Compare difference hashes of a thumbnail of images, and of a few frames
+	/// of videos, which re-encoding leaves alone but which catch rotated,
+	/// mirrored or mangled outputs
+	Perceptual,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum InputChange {
+	/// Leave the output where it was written, next to the newer input, so
+	/// that nothing is lost
+	Keep,
+	/// Delete the output, as it may have been made from half-written data
+	Discard,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum LivePhotos {
+	/// Leave the videos alone, so that they stay paired
+	Keep,
+	/// Convert the videos like any other
+	Convert,
+	/// Delete the videos (or move them to the backup), counting their size as
+	/// saved; only when replacing inputs, otherwise they are kept
+	DropVideo,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Measure {
+	/// Length of the contents
+	#[default]
+	Apparent,
+	/// Blocks taken up on disk
+	Allocated,
+}
+
+impl Measure {
+	/// Whether allocated sizes are known on this platform; apparent sizes are
+	/// used otherwise.
+	pub const ALLOCATED_KNOWN: bool = cfg!(target_family = "unix");
+
+	pub fn size(self, metadata: &std::fs::Metadata) -> u64 {
+		match self {
+			Measure::Apparent => metadata.len(),
+			#[cfg(target_family = "unix")]
+			Measure::Allocated => std::os::unix::fs::MetadataExt::blocks(metadata) * 512,
+			#[cfg(not(target_family = "unix"))]
+			Measure::Allocated => metadata.len(),
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Units {
+	/// Powers of 1024, e.g. `KiB`
+	#[default]
+	Binary,
+	/// Powers of 1000, e.g. `KB`
+	Decimal,
+}
+
+impl Units {
+	pub fn format(self, bytes: u64) -> String {
+		let base = match self {
+			Units::Binary => Base::Base2,
+			Units::Decimal => Base::Base10,
+		};
+
+		Size::from_bytes(bytes).format().with_base(base).to_string()
+	}
+
+	/// Bytes in a megabyte, and its symbol.
+	pub fn megabyte(self) -> (u64, &'static str) {
+		match self {
+			Units::Binary => (1 << 20, "MiB"),
+			Units::Decimal => (1_000_000, "MB"),
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Metadata {
+	/// Embedded ICC color profile
+	Icc,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct VideoOptions {
+	/// Which streams to keep: `best` video and audio stream, `all` audio
+	/// streams, or `map:SPEC[,SPEC...]` to pass raw `-map` specifiers to
+	/// ffmpeg
+	#[arg(long, value_name = "STREAMS", default_value = "best", value_parser = parse_streams)]
+	pub streams: Streams,
+	/// Split videos at keyframes and encode the parts in parallel, with the
+	/// audio encoded separately over the whole file
+	#[arg(long)]
+	pub segment_encode: bool,
+	/// Container and codecs to encode videos to
+	#[arg(long, value_name = "FORMAT", default_value = "webm")]
+	pub video_format: VideoFormat,
+	/// Constant rate factor to encode videos with, from 0 for the best quality
+	/// to 63 for the smallest files (51 with `--video-format mp4`); left to
+	/// ffmpeg unless given
+	#[arg(
+		long,
+		visible_alias = "video-quality",
+		value_name = "CRF",
+		value_parser = clap::value_parser!(u8).range(..=63)
+	)]
+	pub crf: Option<u8>,
+	/// Keep the original when the duration or frame count of the output
+	/// strays from the source by more than this share, in percent
+	#[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+	pub fail_on_drift: Option<f64>,
+	/// Encode videos smaller than `--efficiency-threshold` in a single pass
+	/// with faster settings, as what little they would save at best is not
+	/// worth the CPU time of two
+	#[arg(long)]
+	pub efficiency: bool,
+	/// Size from which `--efficiency` still encodes videos in two passes
+	#[arg(long, value_name = "SIZE", default_value = "64M", value_parser = bytes::parse)]
+	pub efficiency_threshold: u64,
+}
+
+impl VideoOptions {
+	/// How much to put into encoding a video of `size` bytes.
+	pub fn effort(&self, size: u64) -> Effort {
+		match self.efficiency && size < self.efficiency_threshold {
+			true => Effort::Quick,
+			false => Effort::Full,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum VideoFormat {
+	/// VP9 and Opus in WebM
+	#[default]
+	Webm,
+	/// HEVC and AAC in MP4, which plays on more devices
+	Mp4,
+	/// VP9 and Opus in Matroska
+	Mkv,
+}
+
+impl VideoFormat {
+	/// Highest constant rate factor the encoder takes.
+	pub fn max_crf(self) -> u8 {
+		match self {
+			VideoFormat::Webm | VideoFormat::Mkv => 63,
+			VideoFormat::Mp4 => 51,
+		}
+	}
+
+	/// Constant rate factor to take videos to be encoded at unless given: the
+	/// default of x265, and the one commonly suggested for VP9 at 1080p.
+	pub fn default_crf(self) -> u8 {
+		match self {
+			VideoFormat::Webm | VideoFormat::Mkv => 31,
+			VideoFormat::Mp4 => 28,
+		}
+	}
+
+	pub fn from_extension(extension: &str) -> Option<Self> {
+		match extension.to_ascii_lowercase().as_str() {
+			"webm" => Some(VideoFormat::Webm),
+			"mp4" => Some(VideoFormat::Mp4),
+			"mkv" => Some(VideoFormat::Mkv),
+			_ => None,
+		}
+	}
+
+	pub fn extension(self) -> &'static str {
+		match self {
+			VideoFormat::Webm => "webm",
+			VideoFormat::Mp4 => "mp4",
+			VideoFormat::Mkv => "mkv",
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub enum Streams {
+	Best,
+	All,
+	Map(Vec<String>),
+}
+
+fn parse_post_command(value: &str) -> Result<CommandLine, String> {
+	const PLACEHOLDERS: &[Placeholder] = &[Placeholder::Input, Placeholder::Output];
+
+	CommandLine::parse(value, PLACEHOLDERS).map_err(|x| x.to_string())
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+	match value.trim().trim_end_matches('%').parse::<f64>() {
+		Ok(x) if x.is_finite() && x >= 0.0 => Ok(x),
+		_ => Err(format!("`{}` is not a percentage, like `2.5`", value)),
+	}
+}
+
+fn parse_streams(value: &str) -> Result<Streams, String> {
+	match value {
+		"best" => Ok(Streams::Best),
+		"all" => Ok(Streams::All),
+		_ => {
+			let Some(specs) = value.strip_prefix("map:") else {
+				return Err("expected `best`, `all` or `map:SPEC`".into());
+			};
+
+			let specs: Vec<_> = specs.split(',').map(str::trim).filter(|x| !x.is_empty()).map(String::from).collect();
+			if specs.is_empty() {
+				return Err("expected at least one stream specifier after `map:`".into());
+			}
+
+			Ok(Streams::Map(specs))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use clap::Parser;
+
+	use super::{Format, Options};
+
+	fn options(args: &[&str]) -> Options {
+		Options::try_parse_from(["shrink-ray"].iter().chain(args)).unwrap()
+	}
+
+	#[test]
+	fn animates_progress_as_asked() {
+		let given = options(&["--progress-interval", "250", "a.jpg"]);
+		assert_eq!(given.spinner(), Some(Duration::from_millis(250)));
+		assert_eq!(options(&["--no-spinner", "a.jpg"]).spinner(), None);
+		assert_eq!(options(&["--format", "json", "--progress-interval", "250", "a.jpg"]).spinner(), None);
+
+		assert!(Options::try_parse_from(["shrink-ray", "--progress-interval", "5", "a.jpg"]).is_err());
+		let both = ["shrink-ray", "--no-spinner", "--progress-interval", "250", "a.jpg"];
+		assert!(Options::try_parse_from(both).is_err());
+	}
+
+	#[test]
+	fn animates_progress_only_on_terminals_by_default() {
+		let default = options(&["a.jpg"]);
+		let expected = default.terminal_is_tty().then_some(Duration::from_millis(Options::PROGRESS_INTERVAL));
+		assert_eq!(default.spinner(), expected);
+		assert_eq!(default.format, Format::Human);
+	}
 }
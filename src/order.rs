@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use tokio::fs;
+use tracing::trace;
+
+use crate::download;
+
+/// Order to process the inputs in
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Order {
+	/// As given
+	#[default]
+	Given,
+	/// Largest first, which gets most of the savings early
+	Largest,
+	/// Smallest first, which gets most of the files done early
+	Smallest,
+	/// Big and small files interleaved, so that the bytes processed grow
+	/// steadily along with the files
+	Balanced,
+}
+
+/// Puts `inputs` in `order`, looking up the sizes of all of them first unless
+/// they stay as given; those whose size is unknown, like URLs, count as empty.
+pub async fn sort(order: Order, inputs: &[PathBuf]) -> Vec<PathBuf> {
+	if order == Order::Given {
+		return inputs.to_vec();
+	}
+
+	let mut files = Vec::with_capacity(inputs.len());
+	for input in inputs {
+		trace!("fetching size of `{}` to sort inputs", input.display());
+		let size = match download::is_url(input) {
+			true => 0,
+			false => fs::symlink_metadata(input).await.map_or(0, |x| x.len()),
+		};
+
+		files.push((input.clone(), size));
+	}
+
+	match order {
+		Order::Given => unreachable!(),
+		Order::Largest => {
+			files.sort_by(|(_, x), (_, y)| y.cmp(x));
+			files.into_iter().map(|(x, _)| x).collect()
+		}
+		Order::Smallest => {
+			files.sort_by_key(|(_, x)| *x);
+			files.into_iter().map(|(x, _)| x).collect()
+		}
+		Order::Balanced => balance(files),
+	}
+}
+
+/// Orders `files` so that their cumulative size grows about linearly with
+/// their number: each next one is the smallest or the largest left, whichever
+/// keeps the sum closer to its share of the total, the smallest on a tie.
+pub fn balance(mut files: Vec<(PathBuf, u64)>) -> Vec<PathBuf> {
+	// stable, so that ties stay as given
+	files.sort_by_key(|(_, x)| *x);
+	let total: u128 = files.iter().map(|(_, x)| u128::from(*x)).sum();
+	let count = files.len() as u128;
+	let mut files = VecDeque::from(files);
+	let mut order = Vec::with_capacity(files.len());
+	let mut sum = 0;
+	while let (Some((_, smallest)), Some((_, largest))) = (files.front(), files.back()) {
+		let target = total * (order.len() as u128 + 1) / count;
+		let (smallest, largest) = (sum + u128::from(*smallest), sum + u128::from(*largest));
+		let (path, size) = match largest.abs_diff(target) < smallest.abs_diff(target) {
+			true => files.pop_back(),
+			false => files.pop_front(),
+		}
+		.expect("there is a file left");
+
+		sum += u128::from(size);
+		order.push(path);
+	}
+
+	order
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::balance;
+
+	/// `sizes` in balanced order, by their indexes.
+	fn balanced(sizes: &[u64]) -> Vec<usize> {
+		let files = sizes.iter().enumerate().map(|(i, x)| (PathBuf::from(i.to_string()), *x)).collect();
+		balance(files).iter().map(|x| x.to_str().unwrap().parse().unwrap()).collect()
+	}
+
+	/// Furthest the cumulative size of `sizes` in `order` strays from a
+	/// straight line.
+	fn deviation(sizes: &[u64], order: &[usize]) -> u64 {
+		let total: u64 = sizes.iter().sum();
+		let mut sum = 0;
+		let mut furthest = 0;
+		for (i, index) in order.iter().enumerate() {
+			sum += sizes[*index];
+			let target = total * (i as u64 + 1) / sizes.len() as u64;
+			furthest = furthest.max(sum.abs_diff(target));
+		}
+
+		furthest
+	}
+
+	#[test]
+	fn interleaves_big_and_small_files() {
+		let sizes = [1, 1, 1, 1, 1, 1, 10, 10, 100, 100, 1000, 5000];
+		let order = balanced(&sizes);
+		let mut sorted = order.clone();
+		sorted.sort();
+		assert_eq!(sorted, (0..sizes.len()).collect::<Vec<_>>());
+
+		// neither all the big files first, nor all the small ones
+		assert_ne!(order[..2], [11, 10]);
+		assert!(order[..6].iter().any(|x| *x >= 10), "{:?}", order);
+		assert!(order[..6].iter().any(|x| *x < 6), "{:?}", order);
+		assert!(deviation(&sizes, &order) <= *sizes.iter().max().unwrap(), "{:?}", order);
+	}
+
+	#[test]
+	fn stays_close_to_linear_progress() {
+		// sizes of a photo library with the odd video, spread deterministically
+		let size = |x: u64| if x % 50 == 7 { 200_000_000 + x } else { 2_000_000 + (x * 7919) % 3_000_000 };
+		let sizes: Vec<u64> = (0..500).map(size).collect();
+		let order = balanced(&sizes);
+		assert_eq!(order.len(), sizes.len());
+
+		let total: u64 = sizes.iter().sum();
+		let largest = *sizes.iter().max().unwrap();
+		let furthest = deviation(&sizes, &order);
+		assert!(furthest <= largest, "{} of {}", furthest, total);
+
+		// largest first strays much further
+		let mut by_size: Vec<_> = (0..sizes.len()).collect();
+		by_size.sort_by_key(|x| std::cmp::Reverse(sizes[*x]));
+		assert!(deviation(&sizes, &by_size) > 4 * furthest);
+	}
+
+	#[test]
+	fn keeps_order_of_equal_sizes() {
+		assert_eq!(balanced(&[5, 5, 5, 5]), [0, 1, 2, 3]);
+		assert_eq!(balanced(&[0, 0, 0]), [0, 1, 2]);
+		assert_eq!(balanced(&[7]), [0]);
+		assert_eq!(balanced(&[]), Vec::<usize>::new());
+	}
+}
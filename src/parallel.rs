@@ -0,0 +1,92 @@
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::rc::Rc;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tracing::debug;
+
+use crate::context::Context;
+use crate::idle::Idle;
+use crate::inputs::{Input, Inputs};
+use crate::options::Options;
+use crate::timing::Timings;
+use crate::{complete, input_span, next_input, process, Flow, Run};
+
+/// Converts up to [`Options::files_at_once`] files at once, each with a
+/// worker context of its own, reporting them in the order they complete.
+///
+/// Once a file ends the run, no more are started, but those under way are
+/// waited for and reported. An interrupt reaches the tools of all of them,
+/// and keeps the others from starting any more tools.
+pub async fn convert<I: Iterator<Item = PathBuf>>(
+	options: &Options, context: &mut Context, run: &mut Run, inputs: &mut Inputs<I>, idle: &mut Option<Idle>,
+) -> Flow {
+	let interrupted = Rc::new(Cell::new(false));
+	let mut ctrl_c = pin!(tokio::signal::ctrl_c());
+	let mut workers: Vec<Context> = Vec::new();
+	let mut running = FuturesUnordered::new();
+	let mut flow = Flow::Continue;
+	let mut exhausted = false;
+	loop {
+		let start = flow == Flow::Continue && !exhausted && !interrupted.get();
+		let start = start && running.len() < options.files_at_once();
+		if !start && running.is_empty() {
+			break;
+		}
+
+		// the files under way are waited for along with the next one
+		tokio::select! {
+			next = next_input(options, context, run, inputs, idle), if start => {
+				let Some((Input { path, metadata }, duplicate)) = next else {
+					exhausted = true;
+					continue;
+				};
+
+				let mut worker = match workers.pop() {
+					Some(x) => x,
+					None => match context.worker(interrupted.clone()) {
+						Ok(x) => x,
+						Err(x) => {
+							flow = run.abort(x);
+							continue;
+						}
+					},
+				};
+
+				running.push(async move {
+					let span = input_span(&path);
+					let mut timings = Timings::default();
+					let result = process(&path, metadata, duplicate, options, &mut worker, &mut timings, &span).await;
+					(path, span, result, worker, timings)
+				});
+			},
+
+			Some((path, span, result, worker, timings)) = running.next() => {
+				if idle.as_ref().is_some_and(Idle::is_waiting) {
+					context.terminal.end_processing();
+				}
+
+				run.stats.timings_mut().add(&timings);
+				workers.push(worker);
+				match complete(&path, result, options, context, run, span).await {
+					Flow::Continue => {}
+					_ if flow == Flow::Abort => {}
+					x => flow = x,
+				}
+			},
+
+			_ = &mut ctrl_c, if !interrupted.get() => {
+				debug!("interrupted; not starting any more files");
+				interrupted.set(true);
+				run.cancel = true;
+			},
+		}
+	}
+
+	for mut worker in workers {
+		worker.end_batch().await;
+	}
+
+	flow
+}
@@ -0,0 +1,210 @@
+use std::path::Path;
+
+use tracing::{debug, trace};
+
+use crate::context::{Context, ToolCommand};
+
+/// Side of the grayscale thumbnails the tools decode files into, which are
+/// scaled down further here
+const SIDE: usize = 32;
+/// Where in a video to take the frames compared, as shares of its duration
+const FRAMES: &[f64] = &[0.25, 0.5, 0.75];
+
+/// Difference hash of a grayscale image of `width` by `height` pixels: a bit
+/// for each pair of neighbouring pixels of it scaled down to 9 by 8, set if
+/// the left one is brighter. It stays the same through re-encoding, resizing
+/// or small changes of brightness, but not through rotation, mirroring or
+/// mangled colors.
+pub fn dhash(pixels: &[u8], width: usize, height: usize) -> u64 {
+	let small = downscale(pixels, width, height, 9, 8);
+	let mut hash = 0;
+	for row in small.chunks(9) {
+		for pair in row.windows(2) {
+			hash = (hash << 1) | u64::from(pair[0] > pair[1]);
+		}
+	}
+
+	hash
+}
+
+/// Bits in which hashes `a` and `b` differ, out of 64.
+pub fn distance(a: u64, b: u64) -> u32 {
+	(a ^ b).count_ones()
+}
+
+/// Averages the pixels of each box of the image covering a pixel of the one
+/// of `width` by `height` returned.
+fn downscale(pixels: &[u8], from_width: usize, from_height: usize, width: usize, height: usize) -> Vec<u16> {
+	let mut small = Vec::with_capacity(width * height);
+	for y in 0..height {
+		let rows = y * from_height / height..((y + 1) * from_height / height).max(y * from_height / height + 1);
+		for x in 0..width {
+			let columns = x * from_width / width..((x + 1) * from_width / width).max(x * from_width / width + 1);
+			let mut sum = 0;
+			for row in rows.clone() {
+				sum += pixels[row * from_width + columns.start..row * from_width + columns.end]
+					.iter()
+					.map(|&x| u32::from(x))
+					.sum::<u32>();
+			}
+
+			// scaled up for precision
+			small.push((sum * 16 / (rows.len() * columns.len()) as u32) as u16);
+		}
+	}
+
+	small
+}
+
+/// Bits the hashes of `input` and its conversion `output` are apart; for
+/// videos of `duration` seconds, the most of those of the frames compared.
+pub async fn compare(
+	context: &mut Context, input: &Path, output: &Path, duration: Option<f64>,
+) -> Result<u32, crate::Error> {
+	let times = match duration {
+		Some(duration) => FRAMES.iter().map(|x| Some(duration * x)).collect(),
+		None => vec![None],
+	};
+
+	let mut farthest = 0;
+	for time in times {
+		let original = thumbnail(context, input, time).await?;
+		let converted = thumbnail(context, output, time).await?;
+		trace!("hashes {:016x} and {:016x} at {:?}", original, converted, time);
+		farthest = farthest.max(distance(original, converted));
+	}
+
+	debug!("`{}` and `{}` are {} bits apart", input.display(), output.display(), farthest);
+	Ok(farthest)
+}
+
+/// Hash of the frame of the video at `path` at `time`, in seconds, or of the
+/// image at `path`; images which gm cannot read are left to ffmpeg.
+async fn thumbnail(context: &mut Context, path: &Path, time: Option<f64>) -> Result<u64, crate::Error> {
+	if time.is_none() {
+		let gm = image(context, path)?;
+		match hash(context, "gm", gm).await {
+			Err(x @ crate::Error::Invocation(..)) => debug!("gm cannot decode `{}`: {}", path.display(), x),
+			x => return x,
+		}
+	}
+
+	let ffmpeg = frame(context, path, time.unwrap_or_default())?;
+	hash(context, "ffmpeg", ffmpeg).await
+}
+
+/// Decodes the image at `path` into a grayscale thumbnail.
+fn image(context: &mut Context, path: &Path) -> Result<ToolCommand, crate::Error> {
+	let mut gm = context.command("gm")?;
+	let size = format!("{}x{}!", SIDE, SIDE);
+	gm.arg("convert").arg(path).args(["-colorspace", "Gray", "-resize", &size, "-depth", "8", "gray:-"]);
+	Ok(gm)
+}
+
+/// Decodes the frame of the video at `path` at `time`, in seconds, into a
+/// grayscale thumbnail.
+fn frame(context: &mut Context, path: &Path, time: f64) -> Result<ToolCommand, crate::Error> {
+	let mut ffmpeg = context.command("ffmpeg")?;
+	ffmpeg
+		.args(["-hide_banner", "-loglevel", "error", "-ss"])
+		.arg(format!("{:.3}", time))
+		.arg("-i")
+		.arg(path)
+		.args(["-frames:v", "1", "-vf"])
+		.arg(format!("scale={}:{},format=gray", SIDE, SIDE))
+		.args(["-f", "rawvideo", "-"]);
+	Ok(ffmpeg)
+}
+
+async fn hash(context: &mut Context, name: &str, command: ToolCommand) -> Result<u64, crate::Error> {
+	let output = context.output(command).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation(name, output.status, &output.stderr));
+	}
+
+	let Some(pixels) = output.stdout.get(..SIDE * SIDE) else {
+		let message = format!("decoded {} bytes rather than a {}x{} thumbnail", output.stdout.len(), SIDE, SIDE);
+		return Err(crate::Error::invocation(name, output.status, message.as_bytes()));
+	};
+
+	Ok(dhash(pixels, SIDE, SIDE))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{dhash, distance};
+
+	const SIDE: usize = 64;
+
+	/// Some photo: soft blobs of light over a gradient.
+	fn photo() -> Vec<u8> {
+		let mut pixels = Vec::with_capacity(SIDE * SIDE);
+		for y in 0..SIDE {
+			for x in 0..SIDE {
+				let (fx, fy) = (x as f64 / SIDE as f64, y as f64 / SIDE as f64);
+				let blob = |cx: f64, cy: f64, r: f64| (-((fx - cx).powi(2) + (fy - cy).powi(2)) / r).exp();
+				let value = 60.0 * fx + 120.0 * blob(0.3, 0.4, 0.02) + 90.0 * blob(0.7, 0.6, 0.05) + 20.0 * fy;
+				pixels.push(value.min(255.0) as u8);
+			}
+		}
+
+		pixels
+	}
+
+	/// Like a lossy encoder: pixels off by a little, in blocks.
+	fn reencoded(pixels: &[u8]) -> Vec<u8> {
+		let mut noise = 12345u32;
+		pixels
+			.iter()
+			.enumerate()
+			.map(|(index, &x)| {
+				noise = noise.wrapping_mul(1103515245).wrapping_add(12345);
+				let block = ((index / SIDE / 8) + (index % SIDE / 8)) % 3;
+				(x as i32 + (noise >> 16) as i32 % 5 - 2 + block as i32 - 1).clamp(0, 255) as u8
+			})
+			.collect()
+	}
+
+	fn hash(pixels: &[u8]) -> u64 {
+		dhash(pixels, SIDE, SIDE)
+	}
+
+	#[test]
+	fn keeps_close_through_reencoding() {
+		let photo = photo();
+		assert_eq!(distance(hash(&photo), hash(&photo)), 0);
+		assert!(distance(hash(&photo), hash(&reencoded(&photo))) <= 4);
+
+		let brighter: Vec<_> = photo.iter().map(|x| x.saturating_add(10)).collect();
+		assert!(distance(hash(&photo), hash(&brighter)) <= 4);
+
+		// scaled down to half, like with `--max-size`
+		let half: Vec<_> = (0..SIDE / 2)
+			.flat_map(|y| (0..SIDE / 2).map(move |x| (y, x)))
+			.map(|(y, x)| photo[y * 2 * SIDE + x * 2])
+			.collect();
+		assert!(distance(hash(&photo), dhash(&half, SIDE / 2, SIDE / 2)) <= 6);
+	}
+
+	#[test]
+	fn tells_mangled_outputs_apart() {
+		let photo = photo();
+		let far = |other: &[u8]| distance(hash(&photo), hash(other)) > 16;
+
+		let upside_down: Vec<_> = photo.iter().rev().copied().collect();
+		assert!(far(&upside_down));
+
+		// by a quarter turn
+		let rotated: Vec<_> = (0..SIDE)
+			.flat_map(|y| (0..SIDE).map(move |x| (y, x)))
+			.map(|(y, x)| photo[(SIDE - 1 - x) * SIDE + y])
+			.collect();
+		assert!(far(&rotated));
+
+		let inverted: Vec<_> = photo.iter().map(|x| 255 - x).collect();
+		assert!(far(&inverted));
+
+		let other: Vec<_> = (0..SIDE * SIDE).map(|x| (((x % SIDE) * 4) ^ ((x / SIDE) * 3)) as u8).collect();
+		assert!(far(&other));
+	}
+}
@@ -0,0 +1,115 @@
+/// How far ffmpeg got through a file, from the lines it writes to the
+/// standard output given `-progress pipe:1`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Progress {
+	/// Length of the file, in seconds
+	duration: f64,
+	/// Share of the whole conversion done before this run of ffmpeg
+	offset: f64,
+	/// Share of the whole conversion this run of ffmpeg stands for
+	span: f64,
+}
+
+impl Progress {
+	/// Arguments making ffmpeg report its progress the way it is read here.
+	pub const ARGS: [&'static str; 3] = ["-progress", "pipe:1", "-nostats"];
+
+	/// [`Progress::ARGS`] if there is any `progress` to read, nothing
+	/// otherwise.
+	pub fn args(progress: Option<Self>) -> &'static [&'static str] {
+		progress.map_or(&[], |_| &Self::ARGS)
+	}
+
+	/// Progress through a file of `duration` seconds, unless its duration is
+	/// unknown; the whole conversion is a single run of ffmpeg.
+	pub fn new(duration: Option<f64>) -> Option<Self> {
+		let duration = duration.filter(|x| x.is_finite() && *x > 0.0)?;
+		Some(Progress { duration, offset: 0.0, span: 1.0 })
+	}
+
+	/// The same for pass `pass` out of `passes` equal ones, counted from 0.
+	pub fn pass(self, pass: usize, passes: usize) -> Self {
+		let span = self.span / passes as f64;
+		Progress { offset: self.offset + span * pass as f64, span, ..self }
+	}
+
+	/// Whether `line` is one of the `key=value` lines ffmpeg reports its
+	/// progress with, rather than anything worth showing.
+	pub fn is_report(line: &str) -> bool {
+		let Some((key, value)) = line.trim_end().split_once('=') else {
+			return false;
+		};
+
+		!key.is_empty()
+			&& key.bytes().all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == b'_')
+			&& !value.contains(char::is_whitespace)
+	}
+
+	/// Bytes of the output ffmpeg wrote so far, if `line` tells.
+	pub fn written(line: &str) -> Option<u64> {
+		line.trim_end().strip_prefix("total_size=")?.parse().ok()
+	}
+
+	/// Share of the whole conversion done, if `line` tells how far into the
+	/// file ffmpeg got.
+	pub fn done(&self, line: &str) -> Option<f64> {
+		// despite its name, in microseconds, like `out_time_us`
+		let micros: u64 = line.trim_end().strip_prefix("out_time_ms=")?.parse().ok()?;
+		let share = (micros as f64 / 1e6 / self.duration).clamp(0.0, 1.0);
+		Some(self.offset + share * self.span)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Progress;
+
+	#[test]
+	fn reads_time_reported() {
+		let progress = Progress::new(Some(10.0)).unwrap();
+		assert_eq!(progress.done("out_time_ms=2500000\n"), Some(0.25));
+		assert_eq!(progress.done("out_time_ms=0"), Some(0.0));
+		// overshooting the duration probed, as the last frame may
+		assert_eq!(progress.done("out_time_ms=10040000"), Some(1.0));
+		assert_eq!(progress.done("out_time_ms=N/A"), None);
+		assert_eq!(progress.done("out_time=00:00:02.500000"), None);
+		assert_eq!(progress.done("frame=62"), None);
+	}
+
+	#[test]
+	fn reads_size_reported() {
+		assert_eq!(Progress::written("total_size=48312\n"), Some(48312));
+		assert_eq!(Progress::written("total_size=N/A"), None);
+		assert_eq!(Progress::written("out_time_ms=2500000"), None);
+	}
+
+	#[test]
+	fn splits_passes() {
+		let progress = Progress::new(Some(10.0)).unwrap();
+		let first = progress.pass(0, 2);
+		let second = progress.pass(1, 2);
+		assert_eq!(first.done("out_time_ms=5000000"), Some(0.25));
+		assert_eq!(first.done("out_time_ms=10000000"), Some(0.5));
+		assert_eq!(second.done("out_time_ms=0"), Some(0.5));
+		assert_eq!(second.done("out_time_ms=5000000"), Some(0.75));
+		assert_eq!(second.done("out_time_ms=10000000"), Some(1.0));
+	}
+
+	#[test]
+	fn needs_known_duration() {
+		assert_eq!(Progress::new(None), None);
+		assert_eq!(Progress::new(Some(0.0)), None);
+		assert_eq!(Progress::new(Some(f64::NAN)), None);
+	}
+
+	#[test]
+	fn tells_reports_apart() {
+		for line in ["frame=62\n", "fps=24.00", "out_time_ms=N/A", "progress=continue\n", "bitrate=N/A"] {
+			assert!(Progress::is_report(line), "{}", line);
+		}
+
+		for line in ["[vp9 @ 0x55d0] some warning", "Error while decoding stream #0:0", "a = b", "=x", "\n"] {
+			assert!(!Progress::is_report(line), "{}", line);
+		}
+	}
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Most of the start of an image read to look for fingerprints; in a JPEG,
+/// the metadata before the first scan can be large
+const HEAD_SIZE: u64 = 1 << 20;
+
+/// Tools whose outputs are left alone, as already optimized
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Marker {
+	/// The comment shrink-ray leaves in its outputs
+	ShrinkRay,
+	/// A comment naming jpegoptim; it does not add one itself, so only the
+	/// outputs of scripts commenting what they ran are recognised
+	Jpegoptim,
+	/// The layout cwebp and mozjpeg give their outputs by default: lossy WebP
+	/// without any metadata, and progressive JPEG whose first scan holds all of
+	/// the DC coefficients
+	Generic,
+}
+
+/// Tool an image looks to have been optimized with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fingerprint {
+	Jpegoptim,
+	Cwebp,
+	Mozjpeg,
+}
+
+impl fmt::Display for Fingerprint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Fingerprint::Jpegoptim => "jpegoptim",
+			Fingerprint::Cwebp => "cwebp",
+			Fingerprint::Mozjpeg => "mozjpeg",
+		})
+	}
+}
+
+/// Reads the start of the image at `path`, where the fingerprints are.
+pub async fn read_head(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+	let mut head = Vec::new();
+	File::open(path).await?.take(HEAD_SIZE).read_to_end(&mut head).await?;
+	Ok(head)
+}
+
+/// Looks for the fingerprints of the tools of `markers` in an image with
+/// `comment`, whose file starts with `head`.
+///
+/// False negatives are fine, the image only gets converted again, but false
+/// positives leave an image unoptimized. Those known are:
+///
+/// - images whose comment happens to mention jpegoptim;
+/// - lossy WebP images written without metadata by something else than
+///   cwebp, e.g. GraphicsMagick or a browser, which are as lossy already;
+/// - progressive JPEG images written with a custom scan script that also
+///   gets the DC coefficients in one go, which libjpeg does in two.
+pub fn detect(markers: &[Marker], comment: Option<&str>, head: &[u8]) -> Option<Fingerprint> {
+	if markers.contains(&Marker::Jpegoptim) && comment.is_some_and(|x| x.to_ascii_lowercase().contains("jpegoptim")) {
+		return Some(Fingerprint::Jpegoptim);
+	}
+
+	if markers.contains(&Marker::Generic) {
+		if is_plain_webp(head) {
+			return Some(Fingerprint::Cwebp);
+		}
+
+		if is_mozjpeg(head) {
+			return Some(Fingerprint::Mozjpeg);
+		}
+	}
+
+	None
+}
+
+/// Whether `head` starts a lossy WebP in the simple format, which cwebp writes
+/// unless told to keep metadata; anything else needs the extended format.
+fn is_plain_webp(head: &[u8]) -> bool {
+	head.get(..4) == Some(b"RIFF") && head.get(8..12) == Some(b"WEBP") && head.get(12..16) == Some(b"VP8 ")
+}
+
+/// Whether `head` starts a progressive JPEG scanned like mozjpeg does:
+/// libjpeg refines the DC coefficients in a later scan (`Al` = 1), while mozjpeg
+/// sends them whole in the first.
+fn is_mozjpeg(head: &[u8]) -> bool {
+	let Some((frame, scan)) = first_scan(head) else {
+		return false;
+	};
+
+	// SOF2 is progressive with Huffman coding
+	let [ss, se, approximation] = scan;
+	frame == 0xc2 && ss == 0 && se == 0 && approximation == 0
+}
+
+/// Marker of the frame of the JPEG starting with `head`, and the spectral
+/// selection and successive approximation of its first scan.
+fn first_scan(head: &[u8]) -> Option<(u8, [u8; 3])> {
+	if head.get(..2) != Some(b"\xff\xd8") {
+		return None;
+	}
+
+	let mut frame = None;
+	let mut rest = &head[2..];
+	loop {
+		// markers may be padded with any number of 0xff
+		let start = rest.iter().position(|x| *x != 0xff).filter(|x| *x > 0)?;
+		let marker = rest[start];
+		let length = usize::from(u16::from_be_bytes(rest.get(start + 1..start + 3)?.try_into().ok()?));
+		let segment = rest.get(start + 3..start + 1 + length)?;
+		match marker {
+			0xc0..=0xcf if ![0xc4, 0xc8, 0xcc].contains(&marker) => frame = Some(marker),
+			0xda => {
+				let components = usize::from(*segment.first()?);
+				let scan = segment.get(1 + 2 * components..4 + 2 * components)?;
+				return Some((frame?, scan.try_into().ok()?));
+			}
+			0xd9 => return None,
+			_ => {}
+		}
+
+		rest = &rest[start + 1 + length..];
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{detect, Fingerprint, Marker};
+
+	const ALL: &[Marker] = &[Marker::ShrinkRay, Marker::Jpegoptim, Marker::Generic];
+
+	fn segment(marker: u8, contents: &[u8]) -> Vec<u8> {
+		let mut segment = vec![0xff, marker];
+		segment.extend_from_slice(&(contents.len() as u16 + 2).to_be_bytes());
+		segment.extend_from_slice(contents);
+		segment
+	}
+
+	/// Start of a YCbCr JPEG encoded with `frame`, whose first scan covers
+	/// `components` with the spectral selection and successive approximation
+	/// of `scan`.
+	fn jpeg(frame: u8, components: &[u8], scan: [u8; 3]) -> Vec<u8> {
+		let mut head = b"\xff\xd8".to_vec();
+		head.extend(segment(0xe0, b"JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00"));
+		head.extend(segment(0xdb, &[0; 65]));
+		head.extend(segment(frame, b"\x08\x00\x10\x00\x10\x03\x01\x22\x00\x02\x11\x01\x03\x11\x01"));
+		head.extend(segment(0xc4, &[0; 29]));
+
+		let mut sos = vec![components.len() as u8];
+		for component in components {
+			sos.extend([*component, 0x00]);
+		}
+		sos.extend(scan);
+		// some padding before the marker, then the entropy-coded data
+		head.push(0xff);
+		head.extend(segment(0xda, &sos));
+		head.extend([0x12, 0xff, 0x00, 0x34]);
+		head
+	}
+
+	#[test]
+	fn recognizes_jpegoptim_comments() {
+		assert_eq!(detect(ALL, Some("Optimized with JPEGoptim 1.5.5"), b""), Some(Fingerprint::Jpegoptim));
+		assert_eq!(detect(&[Marker::Generic], Some("jpegoptim"), b""), None);
+		assert_eq!(detect(ALL, Some("Holidays 2023"), b""), None);
+		assert_eq!(detect(ALL, None, b""), None);
+	}
+
+	#[test]
+	fn recognizes_cwebp_outputs() {
+		let lossy = b"RIFF\x24\x10\x00\x00WEBPVP8 \x18\x10\x00\x00\x30\x01\x00\x9d\x01\x2a";
+		assert_eq!(detect(ALL, None, lossy), Some(Fingerprint::Cwebp));
+		assert_eq!(detect(&[Marker::ShrinkRay, Marker::Jpegoptim], None, lossy), None);
+
+		// lossless, or with metadata or transparency
+		assert_eq!(detect(ALL, None, b"RIFF\x24\x10\x00\x00WEBPVP8L\x18\x10\x00\x00\x2f"), None);
+		assert_eq!(detect(ALL, None, b"RIFF\x24\x10\x00\x00WEBPVP8X\x0a\x00\x00\x00\x08"), None);
+		assert_eq!(detect(ALL, None, b"RIFF\x24\x10"), None);
+	}
+
+	#[test]
+	fn recognizes_mozjpeg_outputs() {
+		let mozjpeg = jpeg(0xc2, &[1, 2, 3], [0, 0, 0x00]);
+		assert_eq!(detect(ALL, None, &mozjpeg), Some(Fingerprint::Mozjpeg));
+		assert_eq!(detect(&[Marker::ShrinkRay], None, &mozjpeg), None);
+
+		// the scan script of libjpeg, starting with a coarse DC scan
+		assert_eq!(detect(ALL, None, &jpeg(0xc2, &[1, 2, 3], [0, 0, 0x01])), None);
+		// baseline, which is never split into scans like this
+		assert_eq!(detect(ALL, None, &jpeg(0xc0, &[1, 2, 3], [0, 63, 0x00])), None);
+		// AC coefficients first, which is not valid, but not mozjpeg either
+		assert_eq!(detect(ALL, None, &jpeg(0xc2, &[1], [1, 5, 0x00])), None);
+
+		// cut off before the first scan
+		let truncated = &mozjpeg[..mozjpeg.len() - 16];
+		assert_eq!(detect(ALL, None, truncated), None);
+		assert_eq!(detect(ALL, None, b"\xff\xd8\xff\xd9"), None);
+		assert_eq!(detect(ALL, None, b"GIF89a"), None);
+	}
+}
@@ -0,0 +1,199 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tracing::{debug, trace};
+
+use crate::fsutil;
+use crate::journal;
+use crate::options::PruneOptions;
+use crate::skip::SkipReason;
+use crate::stats::{Delta, Statistics};
+use crate::terminal::Terminal;
+
+/// Deletes the outputs in the output directory of `options` which are larger
+/// than their sources, returning whether all of them could be looked at.
+pub async fn run(terminal: &mut Terminal, options: &PruneOptions) -> bool {
+	let outputs = match outputs(&options.output_dir).await {
+		Ok(x) => x,
+		Err(x) => {
+			terminal.write_fail(&options.output_dir, x);
+			return false;
+		}
+	};
+
+	let mut stats = Statistics::default();
+	let mut success = true;
+	for output in outputs {
+		let source = match source_of(&output, &options.output_dir, &options.against).await {
+			Ok(x) => x,
+			Err(x @ (crate::Error::SourceNotFound(_) | crate::Error::SourceAmbiguous(..))) => {
+				let reason = SkipReason::NoSource(x.to_string());
+				terminal.write_skip(&output, &reason);
+				stats.skip(&reason);
+				continue;
+			}
+			Err(x) => {
+				terminal.write_fail(&output, x);
+				stats.fail();
+				success = false;
+				continue;
+			}
+		};
+
+		match prune(&output, &source, options.dry_run).await {
+			Ok(delta) if delta.is_smaller() => stats.shrink(delta),
+			Ok(delta) => {
+				terminal.write_prune(&output, &source, delta, options.dry_run);
+				stats.grow(delta);
+				// the output goes in favour of nothing
+				stats.reclaim(Delta::new(delta.new, 0));
+			}
+			Err(x) => {
+				terminal.write_fail(&output, x);
+				stats.fail();
+				success = false;
+			}
+		}
+	}
+
+	terminal.write_newline();
+	terminal.write_prune_stats(stats, options.dry_run);
+	success
+}
+
+/// Deletes `output` if it is larger than `source`, unless `dry_run`, returning
+/// both their sizes.
+async fn prune(output: &Path, source: &Path, dry_run: bool) -> Result<Delta, crate::Error> {
+	let delta = Delta::new(fs::metadata(source).await?.len(), fs::metadata(output).await?.len());
+	if !delta.is_smaller() && !dry_run {
+		debug!("deleting `{}`, larger than its source `{}`", output.display(), source.display());
+		fs::remove_file(output).await?;
+	}
+
+	Ok(delta)
+}
+
+/// Regular files under `dir`, sorted, but for the journal of the runs writing
+/// them; symlinks are never followed, nor pruned.
+async fn outputs(dir: &Path) -> Result<Vec<PathBuf>, crate::Error> {
+	let mut files = Vec::new();
+	let mut dirs = vec![dir.to_path_buf()];
+	while let Some(dir) = dirs.pop() {
+		trace!("listing outputs in `{}`", dir.display());
+		let mut entries = fs::read_dir(&dir).await?;
+		while let Some(entry) = entries.next_entry().await? {
+			let kind = entry.file_type().await?;
+			if kind.is_dir() {
+				dirs.push(entry.path());
+			} else if kind.is_file() && entry.file_name() != journal::FILE_NAME {
+				files.push(entry.path());
+			}
+		}
+	}
+
+	files.sort();
+	Ok(files)
+}
+
+/// Source of `output` in `sources`, at the same place as the output is in
+/// `outputs`: see [`pick`].
+async fn source_of(output: &Path, outputs: &Path, sources: &Path) -> Result<PathBuf, crate::Error> {
+	let relative = output.strip_prefix(outputs).unwrap_or(output);
+	let dir = sources.join(relative.parent().unwrap_or(Path::new("")));
+	let stem = output.file_stem().unwrap_or_default();
+	let mut candidates = Vec::new();
+	match fs::read_dir(&dir).await {
+		Ok(mut entries) => {
+			while let Some(entry) = entries.next_entry().await? {
+				let path = entry.path();
+				if path.file_stem() == Some(stem) && entry.file_type().await?.is_file() {
+					candidates.push(path);
+				}
+			}
+		}
+		Err(x) if x.kind() == std::io::ErrorKind::NotFound => {}
+		Err(x) => return Err(crate::Error::from(x)),
+	}
+
+	candidates.sort();
+	pick(output, candidates)
+}
+
+/// Which of the files named like `output` but for the extension it was
+/// converted from: that of the same format, as formats usually stay, or else
+/// the only one, as videos for one get another extension.
+fn pick(output: &Path, mut candidates: Vec<PathBuf>) -> Result<PathBuf, crate::Error> {
+	let extension = output.extension().unwrap_or_default();
+	let same_format = |x: &PathBuf| {
+		!extension.is_empty() && Some(fsutil::matching_extension(x, extension).as_os_str()) == x.extension()
+	};
+
+	if let Some(index) = candidates.iter().position(same_format) {
+		return Ok(candidates.swap_remove(index));
+	}
+
+	match candidates.len() {
+		0 => Err(crate::Error::SourceNotFound(output.to_path_buf())),
+		1 => Ok(candidates.remove(0)),
+		_ => Err(crate::Error::SourceAmbiguous(output.to_path_buf(), candidates)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::path::{Path, PathBuf};
+
+	use super::{pick, source_of};
+
+	fn pick_of(output: &str, candidates: &[&str]) -> Result<PathBuf, crate::Error> {
+		pick(Path::new(output), candidates.iter().map(PathBuf::from).collect())
+	}
+
+	#[test]
+	fn picks_sources_of_the_same_format() {
+		assert_eq!(pick_of("out/a.jpg", &["src/a.jpg"]).unwrap(), Path::new("src/a.jpg"));
+		assert_eq!(pick_of("out/a.jpg", &["src/a.png", "src/a.jpg"]).unwrap(), Path::new("src/a.jpg"));
+		// spelled differently, or in another case, as outputs keep the
+		// extension of their source
+		assert_eq!(pick_of("out/a.jpg", &["src/a.jpeg", "src/a.png"]).unwrap(), Path::new("src/a.jpeg"));
+		assert_eq!(pick_of("out/A.JPG", &["src/A.MP4", "src/A.JPG"]).unwrap(), Path::new("src/A.JPG"));
+	}
+
+	#[test]
+	fn picks_sources_of_another_format() {
+		assert_eq!(pick_of("out/a.webm", &["src/a.mp4"]).unwrap(), Path::new("src/a.mp4"));
+		assert_eq!(pick_of("out/a.webm", &["src/a.mov"]).unwrap(), Path::new("src/a.mov"));
+		assert_eq!(pick_of("out/a", &["src/a.jpg"]).unwrap(), Path::new("src/a.jpg"));
+
+		// never guesses between several
+		let error = pick_of("out/a.webm", &["src/a.mov", "src/a.mp4"]).unwrap_err();
+		assert!(matches!(error, crate::Error::SourceAmbiguous(_, ref x) if x.len() == 2), "{}", error);
+		let error = pick_of("out/a.jpg", &[]).unwrap_err();
+		assert!(matches!(error, crate::Error::SourceNotFound(_)), "{}", error);
+	}
+
+	#[tokio::test]
+	async fn finds_sources_in_the_same_place() {
+		let dir = tempfile::tempdir().unwrap();
+		let (outputs, sources) = (dir.path().join("out"), dir.path().join("src"));
+		fs::create_dir_all(outputs.join("2024")).unwrap();
+		fs::create_dir_all(sources.join("2024")).unwrap();
+		for name in ["a.jpg", "2024/b.mp4", "2024/b.mp4.bak", "2024/ab.jpg", "c.jpg"] {
+			fs::write(sources.join(name), "").unwrap();
+		}
+
+		let (outputs, sources) = (&outputs, &sources);
+		let source = |x: &str| {
+			let output = outputs.join(x);
+			async move { source_of(&output, outputs, sources).await }
+		};
+		assert_eq!(source("a.jpg").await.unwrap(), sources.join("a.jpg"));
+		// not `b.mp4.bak`, whose stem is `b.mp4`
+		assert_eq!(source("2024/b.webm").await.unwrap(), sources.join("2024/b.mp4"));
+		// not in the same place
+		assert!(matches!(source("2024/a.jpg").await, Err(crate::Error::SourceNotFound(_))));
+		assert!(matches!(source("d.jpg").await, Err(crate::Error::SourceNotFound(_))));
+		assert!(matches!(source("nowhere/a.jpg").await, Err(crate::Error::SourceNotFound(_))));
+	}
+}
@@ -0,0 +1,184 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+use tokio::fs;
+use tracing::{debug, error, trace};
+
+use crate::context::{Context, ToolCommand};
+use crate::temp;
+use crate::video;
+
+/// Where to write side-by-side crops of the originals and the outputs of some
+/// of the converted files, to check their quality by eye
+#[derive(Debug)]
+pub struct QaSamples {
+	dir: PathBuf,
+	/// Sample every `rate`th converted file, starting with the first
+	rate: u64,
+	/// Files converted so far
+	converted: u64,
+}
+
+impl QaSamples {
+	/// Size of the crops, taken from the middle at 1:1 scale
+	const CROP: &'static str = "256x256+0+0";
+
+	pub fn new(dir: &Path, rate: u64) -> Self {
+		// the tools run elsewhere
+		let dir = std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+		QaSamples { dir, rate: rate.max(1), converted: 0 }
+	}
+
+	/// Whether to sample the next converted file, which is counted.
+	fn next(&mut self) -> bool {
+		let sample = self.converted.is_multiple_of(self.rate);
+		self.converted += 1;
+		sample
+	}
+
+	/// Sample of `input`, named after its path so that files of the same name
+	/// in different directories do not overwrite each other's.
+	fn path(&self, input: &Path) -> PathBuf {
+		let mut name = OsString::new();
+		for component in input.components() {
+			if let Component::Normal(x) = component {
+				if !name.is_empty() {
+					name.push("_");
+				}
+
+				name.push(x);
+			}
+		}
+
+		name.push(".qa.png");
+		self.dir.join(name)
+	}
+}
+
+/// Writes a sample of `input`, converted into `output`, if it is its turn;
+/// failing to is only warned about, never failing the conversion.
+pub async fn sample(context: &mut Context, input: &Path, output: &Path, mime: &str) {
+	let Some(qa) = context.qa.clone() else {
+		return;
+	};
+
+	if !qa.borrow_mut().next() {
+		return;
+	}
+
+	let sample = qa.borrow().path(input);
+	match write(context, input, output, mime, &sample).await {
+		Ok(()) => debug!("wrote QA sample `{}`", sample.display()),
+		Err(x) => {
+			let message = format!("failed to write QA sample of `{}`: {}", input.display(), x);
+			context.terminal.warn_once("qa", None, message);
+		}
+	}
+}
+
+async fn write(
+	context: &mut Context, input: &Path, output: &Path, mime: &str, sample: &Path,
+) -> Result<(), crate::Error> {
+	if let Some(parent) = sample.parent() {
+		fs::create_dir_all(parent).await?;
+	}
+
+	let mut scratch = Vec::new();
+	let result = async {
+		let (original, converted) = match mime.starts_with("video/") {
+			true => {
+				let streams = video::probe_streams(context, input).await?;
+				let middle = video::duration(&streams).unwrap_or_default() / 2.0;
+				let original = frame(context, input, middle, &mut scratch).await?;
+				(original, frame(context, output, middle, &mut scratch).await?)
+			}
+			false => (input.to_path_buf(), output.to_path_buf()),
+		};
+
+		let left = crop(context, &original, &mut scratch).await?;
+		let right = crop(context, &converted, &mut scratch).await?;
+		let mut gm = context.command("gm")?;
+		gm.arg("convert").arg(&left).arg(&right).arg("+append").arg(coded("png:", sample));
+		run(context, "gm", gm).await
+	}
+	.await;
+
+	for file in scratch {
+		trace!("deleting QA scratch file `{}`", file.display());
+		if let Err(x) = fs::remove_file(&file).await {
+			error!("failed to delete QA scratch file `{}`: {}", file.display(), x);
+		}
+	}
+
+	result
+}
+
+/// Extracts the frame of the video at `path` at `time`, in seconds.
+async fn frame(
+	context: &mut Context, path: &Path, time: f64, scratch: &mut Vec<PathBuf>,
+) -> Result<PathBuf, crate::Error> {
+	let frame = temp::scratch_file(path, Some(OsStr::new(".png")));
+	let mut ffmpeg = context.command("ffmpeg")?;
+	ffmpeg
+		.args(["-hide_banner", "-loglevel", "error", "-y", "-ss"])
+		.arg(format!("{:.3}", time))
+		.arg("-i")
+		.arg(path)
+		.args(["-frames:v", "1", "-c:v", "png", "-f", "image2"])
+		.arg(&frame);
+
+	scratch.push(frame.clone());
+	run(context, "ffmpeg", ffmpeg).await?;
+	Ok(frame)
+}
+
+/// Crops the middle of the image at `path`.
+async fn crop(context: &mut Context, path: &Path, scratch: &mut Vec<PathBuf>) -> Result<PathBuf, crate::Error> {
+	let crop = temp::scratch_file(path, Some(OsStr::new(".png")));
+	let mut gm = context.command("gm")?;
+	gm.arg("convert")
+		.arg(path)
+		.args(["-gravity", "Center", "-crop", QaSamples::CROP, "+repage"])
+		.arg(coded("png:", &crop));
+
+	scratch.push(crop.clone());
+	run(context, "gm", gm).await?;
+	Ok(crop)
+}
+
+async fn run(context: &mut Context, name: &str, command: ToolCommand) -> Result<(), crate::Error> {
+	let output = context.output(command).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation(name, output.status, &output.stderr));
+	}
+
+	Ok(())
+}
+
+fn coded(coder: &str, path: &Path) -> OsString {
+	let mut arg = OsString::from(coder);
+	arg.push(path);
+	arg
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::QaSamples;
+
+	#[test]
+	fn samples_every_nth_file() {
+		let mut qa = QaSamples::new(Path::new("/qa"), 3);
+		let sampled: Vec<_> = (0..7).map(|_| qa.next()).collect();
+		assert_eq!(sampled, [true, false, false, true, false, false, true]);
+	}
+
+	#[test]
+	fn names_samples_after_their_sources() {
+		let qa = QaSamples::new(Path::new("/qa"), 1);
+		assert_eq!(qa.path(Path::new("a.jpg")), Path::new("/qa/a.jpg.qa.png"));
+		assert_eq!(qa.path(Path::new("./2024/a.jpg")), Path::new("/qa/2024_a.jpg.qa.png"));
+		assert_eq!(qa.path(Path::new("/srv/media/a.mp4")), Path::new("/qa/srv_media_a.mp4.qa.png"));
+	}
+}
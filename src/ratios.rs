@@ -0,0 +1,156 @@
+use std::fmt;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::stats::Delta;
+
+/// Savings of the files shrunk, each relative to its own original, telling
+/// what a typical file gains apart from the total, which a few huge files
+/// make up most of.
+///
+/// Up to `cap` of them are kept, so that the percentiles are exact; beyond
+/// that, they are estimated from a uniform sample of `cap` of them, so that
+/// the memory used stays bounded however many files there are.
+#[derive(Clone, Debug)]
+pub struct Ratios {
+	cap: usize,
+	/// Savings kept, in hundredths of a percent
+	samples: Vec<u32>,
+	/// Files seen, and the sum of their savings, for an exact mean
+	seen: u64,
+	sum: u128,
+	/// Picks the samples to replace once there are more files than `cap`;
+	/// seeded, so that runs over the same files tell the same
+	rng: StdRng,
+}
+
+/// Summary of the savings relative to the originals, in hundredths of a
+/// percent
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Distribution {
+	pub mean: u32,
+	pub median: u32,
+	pub p10: u32,
+	pub p90: u32,
+}
+
+impl Ratios {
+	pub const DEFAULT_CAP: usize = 100_000;
+
+	pub fn new(cap: usize) -> Self {
+		Ratios { cap: cap.max(1), samples: Vec::new(), seen: 0, sum: 0, rng: StdRng::seed_from_u64(0) }
+	}
+
+	pub fn add(&mut self, delta: Delta) {
+		// saving at most all of it, so this fits
+		let ratio = delta.percent().hundredths() as u32;
+		self.seen += 1;
+		self.sum += u128::from(ratio);
+		if self.samples.len() < self.cap {
+			self.samples.push(ratio);
+			return;
+		}
+
+		// every file seen so far is as likely to be among the samples
+		let index = self.rng.gen_range(0..self.seen);
+		if let Some(sample) = self.samples.get_mut(index as usize) {
+			*sample = ratio;
+		}
+	}
+
+	/// Whether the percentiles are estimated rather than exact.
+	pub fn is_estimate(&self) -> bool {
+		self.seen > self.samples.len() as u64
+	}
+
+	pub fn distribution(&self) -> Option<Distribution> {
+		if self.seen == 0 {
+			return None;
+		}
+
+		let mut sorted = self.samples.clone();
+		sorted.sort_unstable();
+		// nearest rank
+		let percentile = |p: usize| sorted[(p * sorted.len()).div_ceil(100).max(1) - 1];
+		Some(Distribution {
+			mean: (self.sum / u128::from(self.seen)) as u32,
+			median: percentile(50),
+			p10: percentile(10),
+			p90: percentile(90),
+		})
+	}
+}
+
+impl Default for Ratios {
+	fn default() -> Self {
+		Ratios::new(Ratios::DEFAULT_CAP)
+	}
+}
+
+/// Hundredths of a percent, shown like [`crate::stats::Percent`]
+pub struct Hundredths(pub u32);
+
+impl fmt::Display for Hundredths {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}.{:02} %", self.0 / 100, self.0 % 100)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Distribution, Hundredths, Ratios};
+	use crate::stats::Delta;
+
+	/// Ratios of shrinking files of 10000 bytes to each of `sizes`.
+	fn ratios(cap: usize, sizes: impl IntoIterator<Item = u64>) -> Ratios {
+		let mut ratios = Ratios::new(cap);
+		for size in sizes {
+			ratios.add(Delta::new(10_000, size));
+		}
+
+		ratios
+	}
+
+	#[test]
+	fn tells_exact_percentiles_of_small_runs() {
+		assert_eq!(ratios(10, []).distribution(), None);
+
+		// savings of 1 % to 100 %
+		let ratios = ratios(100, (0..100).map(|x| x * 100));
+		assert!(!ratios.is_estimate());
+		assert_eq!(ratios.distribution(), Some(Distribution { mean: 5050, median: 5000, p10: 1000, p90: 9000 }));
+	}
+
+	#[test]
+	fn keeps_the_mean_of_files_of_any_size() {
+		// one huge video saving little does not drown out the rest
+		let mut ratios = ratios(10, [2_000, 2_000, 2_000]);
+		ratios.add(Delta::new(1 << 40, (1 << 40) - (1 << 30)));
+		let distribution = ratios.distribution().unwrap();
+		assert_eq!(distribution.mean, (8_000 * 3 + 10) / 4);
+		assert_eq!(distribution.median, 8_000);
+		assert_eq!(distribution.p10, 10);
+	}
+
+	#[test]
+	fn estimates_percentiles_of_large_runs() {
+		// savings spread evenly over 0 % to 100 %, many times over
+		let sizes = (0..200_000u64).map(|x| (x * 7_919) % 10_001);
+		let ratios = ratios(2_000, sizes);
+		assert!(ratios.is_estimate());
+		assert_eq!(ratios.samples.len(), 2_000);
+
+		let distribution = ratios.distribution().unwrap();
+		assert!(distribution.mean.abs_diff(5_000) <= 1, "{:?}", distribution);
+		for (estimate, exact) in [(distribution.median, 5_000), (distribution.p10, 1_000), (distribution.p90, 9_000)] {
+			assert!(estimate.abs_diff(exact) < 300, "{:?}", distribution);
+		}
+	}
+
+	#[test]
+	fn shows_hundredths_as_percent() {
+		assert_eq!(Hundredths(5050).to_string(), "50.50 %");
+		assert_eq!(Hundredths(7).to_string(), "0.07 %");
+	}
+}
@@ -0,0 +1,79 @@
+use std::fs::Metadata;
+use std::path::Path;
+
+use tokio::fs;
+use tracing::{debug, trace};
+
+use crate::error::Stage;
+use crate::image::{Dimensions, ImageInfo};
+use crate::video::{Drift, Stream};
+
+/// Everything learnt about an input while it goes through the stages of
+/// processing, so that nothing has to be looked up twice; on network
+/// filesystems every `stat` is a round trip.
+#[derive(Debug)]
+pub struct InputRecord<'a> {
+	pub path: &'a Path,
+	/// Metadata of the input as of before the conversion
+	pub metadata: Metadata,
+	pub mime: Option<String>,
+	pub image: Option<ImageInfo>,
+	pub streams: Option<Vec<Stream>>,
+	/// Backend that converted the file, if the preferred one failed
+	pub backend: Option<&'static str>,
+	/// What converted the file
+	pub tool: Option<String>,
+	/// Whether the output goes elsewhere because it cannot replace the input
+	pub redirected: bool,
+	/// Dimensions of the converted image, measured in verbose mode
+	pub output_dimensions: Option<Dimensions>,
+	/// How far the converted video strays from the input, if noticeably
+	pub drift: Option<Drift>,
+}
+
+impl<'a> InputRecord<'a> {
+	pub fn new(path: &'a Path, metadata: Metadata) -> Self {
+		InputRecord {
+			path,
+			metadata,
+			mime: None,
+			image: None,
+			streams: None,
+			backend: None,
+			tool: None,
+			redirected: false,
+			output_dimensions: None,
+			drift: None,
+		}
+	}
+
+	/// Fails if the input changed since the record was made, e.g. because
+	/// something else wrote to it while it was being converted.
+	pub async fn check_unchanged(&self) -> Result<(), crate::Error> {
+		trace!("checking whether `{}` changed", self.path.display());
+		let current = fs::metadata(self.path).await.map_err(crate::Error::input_io(Stage::Inspect, self.path))?;
+		if !same_file(&self.metadata, &current) {
+			debug!("`{}` changed: {:?} before, {:?} now", self.path.display(), self.metadata, current);
+			return Err(crate::Error::InputChanged(self.path.to_path_buf(), None));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(target_family = "unix")]
+fn same_file(before: &Metadata, after: &Metadata) -> bool {
+	use std::os::unix::fs::MetadataExt;
+
+	// a file renamed over the input has another inode
+	before.dev() == after.dev()
+		&& before.ino() == after.ino()
+		&& before.len() == after.len()
+		&& before.mtime() == after.mtime()
+		&& before.mtime_nsec() == after.mtime_nsec()
+}
+
+#[cfg(not(target_family = "unix"))]
+fn same_file(before: &Metadata, after: &Metadata) -> bool {
+	before.len() == after.len() && before.modified().ok() == after.modified().ok()
+}
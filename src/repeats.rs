@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Warnings given so far, by kind and optionally by directory, so that those
+/// coming up file after file are only shown the first time, and the repeats
+/// counted instead
+#[derive(Debug, Default)]
+pub struct Repeats {
+	index: HashMap<(String, Option<PathBuf>), usize>,
+	/// First message of each kind, in the order they came, and how many times
+	/// it was left out since
+	seen: Vec<(String, usize)>,
+}
+
+impl Repeats {
+	/// Counts a warning of `kind` about `message`, within `scope` if given,
+	/// returning whether it is the first and so to be shown.
+	pub fn first(&mut self, kind: &str, scope: Option<&Path>, message: &str) -> bool {
+		let key = (kind.to_string(), scope.map(Path::to_path_buf));
+		match self.index.get(&key) {
+			Some(&index) => {
+				self.seen[index].1 += 1;
+				false
+			}
+			None => {
+				self.index.insert(key, self.seen.len());
+				self.seen.push((message.to_string(), 0));
+				true
+			}
+		}
+	}
+
+	/// Messages of the warnings which were repeated, with how many times each.
+	pub fn suppressed(&self) -> impl Iterator<Item = (&str, usize)> {
+		self.seen.iter().filter(|(_, x)| *x > 0).map(|(message, count)| (message.as_str(), *count))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::Repeats;
+
+	#[test]
+	fn counts_repeats_by_kind() {
+		let mut repeats = Repeats::default();
+		assert!(repeats.first("gif", None, "GIF files are not supported"));
+		assert!(repeats.first("delegate", None, "gm lacks a HEIC delegate"));
+		for _ in 0..1245 {
+			assert!(!repeats.first("gif", None, "GIF files are not supported"));
+		}
+
+		assert!(!repeats.first("delegate", None, "gm lacks a HEIC delegate for `b.heic`"));
+		assert_eq!(repeats.suppressed().collect::<Vec<_>>(), [
+			("GIF files are not supported", 1245),
+			("gm lacks a HEIC delegate", 1),
+		]);
+	}
+
+	#[test]
+	fn counts_repeats_by_directory() {
+		let mut repeats = Repeats::default();
+		assert!(repeats.first("tracked", Some(Path::new("a")), "tracked in a"));
+		assert!(repeats.first("tracked", Some(Path::new("b")), "tracked in b"));
+		assert!(!repeats.first("tracked", Some(Path::new("a")), "tracked in a"));
+		assert!(repeats.first("tracked", None, "tracked"));
+		assert_eq!(repeats.suppressed().collect::<Vec<_>>(), [("tracked in a", 1)]);
+	}
+}
@@ -0,0 +1,49 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// CPU time of the children waited for so far that has been taken already
+static TAKEN: Mutex<Duration> = Mutex::new(Duration::ZERO);
+
+/// CPU time, user and system, the children waited for since the last call
+/// took, along with anything they waited for in turn; so that each of them
+/// counts once, however many contexts run tools at once. Nothing where it is
+/// unknown.
+pub fn take() -> Duration {
+	let Some(total) = children() else {
+		return Duration::ZERO;
+	};
+
+	let mut taken = TAKEN.lock().unwrap();
+	let since = total.saturating_sub(*taken);
+	*taken = total;
+	since
+}
+
+#[cfg(target_family = "unix")]
+fn children() -> Option<Duration> {
+	use nix::sys::resource::{getrusage, UsageWho};
+
+	let usage = getrusage(UsageWho::RUSAGE_CHILDREN).ok()?;
+	let time = |x: nix::sys::time::TimeVal| Duration::new(x.tv_sec() as u64, x.tv_usec() as u32 * 1000);
+	Some(time(usage.user_time()) + time(usage.system_time()))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn children() -> Option<Duration> {
+	None
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+	use std::process::Command;
+
+	use super::children;
+
+	#[test]
+	fn counts_time_of_children_waited_for() {
+		let before = children().unwrap();
+		let status = Command::new("sh").args(["-c", "i=0; while [ $i -lt 200000 ]; do i=$((i + 1)); done"]).status();
+		assert!(status.unwrap().success());
+		assert!(children().unwrap() > before);
+	}
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Run of inputs in the same directory whose names only differ by a
+/// consecutive number, like `frame_00001.png`, `frame_00002.png`, …
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sequence {
+	dir: PathBuf,
+	prefix: String,
+	first: String,
+	last: String,
+	suffix: String,
+	len: usize,
+}
+
+impl Sequence {
+	/// Fewest files worth collapsing into a sequence
+	pub const MIN_LEN: usize = 3;
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Collapsed name of the sequence, e.g. `frame_00001..04821.png`.
+	pub fn name(&self) -> PathBuf {
+		self.dir.join(format!("{}{}..{}{}", self.prefix, self.first, self.last, self.suffix))
+	}
+}
+
+/// Sequences found among the inputs, in order
+#[derive(Clone, Debug, Default)]
+pub struct Sequences {
+	sequences: Vec<Sequence>,
+	members: HashMap<PathBuf, usize>,
+}
+
+impl Sequences {
+	/// Finds runs of at least [`Sequence::MIN_LEN`] consecutively numbered
+	/// files, as they appear in `inputs`.
+	pub fn detect<'a>(inputs: impl IntoIterator<Item = &'a PathBuf>) -> Self {
+		let mut sequences = Sequences::default();
+		let mut run: Vec<&PathBuf> = Vec::new();
+		let mut current: Option<(Parts, u64)> = None;
+
+		for input in inputs {
+			let next = Parts::of(input).and_then(|x| x.number().map(|n| (x, n)));
+			let continues = match (&current, &next) {
+				(Some((a, m)), Some((b, n))) => a.same_pattern(b) && m.checked_add(1) == Some(*n),
+				_ => false,
+			};
+
+			if !continues {
+				sequences.push(&run);
+				run.clear();
+			}
+
+			if next.is_some() {
+				run.push(input);
+			}
+
+			current = next;
+		}
+
+		sequences.push(&run);
+		sequences
+	}
+
+	/// Index of the sequence `path` belongs to.
+	pub fn get(&self, path: &Path) -> Option<usize> {
+		self.members.get(path).copied()
+	}
+
+	pub fn sequence(&self, index: usize) -> &Sequence {
+		&self.sequences[index]
+	}
+
+	fn push(&mut self, run: &[&PathBuf]) {
+		if run.len() < Sequence::MIN_LEN {
+			return;
+		}
+
+		let (Some(first), Some(last)) = (Parts::of(run[0]), Parts::of(run[run.len() - 1])) else {
+			return;
+		};
+
+		let index = self.sequences.len();
+		self.sequences.push(Sequence {
+			dir: first.dir.to_path_buf(),
+			prefix: first.prefix.to_string(),
+			first: first.digits.to_string(),
+			last: last.digits.to_string(),
+			suffix: first.suffix.to_string(),
+			len: run.len(),
+		});
+
+		for path in run {
+			self.members.insert(path.to_path_buf(), index);
+		}
+	}
+}
+
+/// File name split around its last number
+struct Parts<'a> {
+	dir: &'a Path,
+	prefix: &'a str,
+	digits: &'a str,
+	suffix: &'a str,
+}
+
+impl<'a> Parts<'a> {
+	fn of(path: &'a Path) -> Option<Self> {
+		let name = path.file_name()?.to_str()?;
+		let end = name.rfind(|x: char| x.is_ascii_digit())? + 1;
+		let start = name[..end].rfind(|x: char| !x.is_ascii_digit()).map_or(0, |x| x + 1);
+		let dir = path.parent().unwrap_or(Path::new(""));
+
+		Some(Parts { dir, prefix: &name[..start], digits: &name[start..end], suffix: &name[end..] })
+	}
+
+	fn number(&self) -> Option<u64> {
+		self.digits.parse().ok()
+	}
+
+	/// Whether both names belong to the same sequence, apart from the number.
+	/// Zero-padded numbers have to keep their width.
+	fn same_pattern(&self, other: &Parts) -> bool {
+		let padded = self.digits.starts_with('0') || other.digits.starts_with('0');
+		self.dir == other.dir
+			&& self.prefix == other.prefix
+			&& self.suffix == other.suffix
+			&& (!padded || self.digits.len() == other.digits.len())
+	}
+}
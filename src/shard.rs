@@ -0,0 +1,82 @@
+use std::path::Path;
+
+/// Share of the inputs to process out of `count` equal ones, so that several
+/// hosts can split a batch between them without talking to each other
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Shard {
+	/// Starting from 0, unlike on the command line
+	index: u64,
+	count: u64,
+}
+
+impl Shard {
+	/// Whether `path`, canonicalized, belongs to this share.
+	///
+	/// Which share a path belongs to only depends on the 64-bit FNV-1a hash of
+	/// its UTF-8 bytes (invalid sequences replaced with U+FFFD), which is the
+	/// same on every platform and must never change between versions.
+	pub fn contains(&self, path: &Path) -> bool {
+		hash(path) % self.count == self.index
+	}
+}
+
+fn hash(path: &Path) -> u64 {
+	const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+	const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+	path.to_string_lossy().bytes().fold(OFFSET, |hash, x| (hash ^ x as u64).wrapping_mul(PRIME))
+}
+
+/// Parses a share given on the command line as `K/N`, K starting from 1.
+pub fn parse(value: &str) -> Result<Shard, String> {
+	let Some((index, count)) = value.split_once('/') else {
+		return Err(format!("`{}` is not a shard, like `1/3`", value));
+	};
+
+	let index: u64 = index.trim().parse().map_err(|_| format!("`{}` is not a shard number", index))?;
+	let count: u64 = count.trim().parse().map_err(|_| format!("`{}` is not a number of shards", count))?;
+	if count == 0 {
+		return Err("there must be at least one shard".into());
+	}
+
+	if index == 0 || index > count {
+		return Err(format!("shard number must be between 1 and {}", count));
+	}
+
+	Ok(Shard { index: index - 1, count })
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::{hash, parse, Shard};
+
+	#[test]
+	fn hashes_paths_stably() {
+		assert_eq!(hash(Path::new("")), 0xcbf2_9ce4_8422_2325);
+		assert_eq!(hash(Path::new("a")), 0xaf63_dc4c_8601_ec8c);
+		assert_eq!(hash(Path::new("foobar")), 0x8594_4171_f739_67e8);
+	}
+
+	#[test]
+	fn splits_paths_between_shards() {
+		let shards = [parse("1/3").unwrap(), parse("2/3").unwrap(), parse("3/3").unwrap()];
+		for path in ["/a", "/b", "/photos/IMG_0001.jpg", "/photos/IMG_0002.jpg"].map(Path::new) {
+			assert_eq!(shards.iter().filter(|x| x.contains(path)).count(), 1);
+		}
+
+		assert!(parse("1/1").unwrap().contains(Path::new("/anything")));
+	}
+
+	#[test]
+	fn parses_shards() {
+		assert_eq!(parse("1/3"), Ok(Shard { index: 0, count: 3 }));
+		assert_eq!(parse("3/3"), Ok(Shard { index: 2, count: 3 }));
+		assert!(parse("0/3").is_err());
+		assert!(parse("4/3").is_err());
+		assert!(parse("1/0").is_err());
+		assert!(parse("3").is_err());
+		assert!(parse("a/b").is_err());
+	}
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+/// Parses the argument of `--since`, which is either an absolute time
+/// (RFC 3339, or a local date/time without an offset), a duration relative to
+/// now (e.g. `7d`, `12h 30m`), or `@PATH` to use the modification time of a
+/// reference file.
+pub fn parse(value: &str) -> Result<SystemTime, String> {
+	let value = value.trim();
+	if let Some(path) = value.strip_prefix('@') {
+		return reference_file(path);
+	}
+
+	if let Ok(x) = DateTime::parse_from_rfc3339(value) {
+		return Ok(x.into());
+	}
+
+	if let Some(x) = local_time(value) {
+		return Ok(x);
+	}
+
+	match humantime::parse_duration(value) {
+		Ok(x) => SystemTime::now().checked_sub(x).ok_or_else(|| "duration is too large".into()),
+		Err(_) => Err("expected an RFC 3339 timestamp, a duration (e.g. `7d`) or `@PATH`".into()),
+	}
+}
+
+fn reference_file(path: &str) -> Result<SystemTime, String> {
+	let path = Path::new(path);
+	std::fs::metadata(path)
+		.and_then(|x| x.modified())
+		.map_err(|x| format!("cannot read modification time of `{}`: {}", path.display(), x))
+}
+
+/// Parses a local date/time without an offset.
+pub fn local_time(value: &str) -> Option<SystemTime> {
+	let time = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"]
+		.iter()
+		.find_map(|x| NaiveDateTime::parse_from_str(value, x).ok())
+		.or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0))?;
+
+	// an ambiguous local time (DST fold) resolves to the earlier instant, so
+	// nothing modified in the window gets skipped
+	Local.from_local_datetime(&time).earliest().map(SystemTime::from)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+	use chrono::{Local, NaiveDate, TimeZone};
+
+	use super::parse;
+
+	/// 2024-03-01T12:00:00Z
+	const NOON: u64 = 1_709_294_400;
+
+	fn at(seconds: u64) -> SystemTime {
+		UNIX_EPOCH + Duration::from_secs(seconds)
+	}
+
+	#[test]
+	fn parses_rfc_3339_times() {
+		assert_eq!(parse("2024-03-01T12:00:00Z"), Ok(at(NOON)));
+		assert_eq!(parse("2024-03-01T14:30:00+02:30"), Ok(at(NOON)));
+		assert_eq!(parse("2024-03-01T07:00:00-05:00"), Ok(at(NOON)));
+		assert_eq!(parse(" 2024-03-01T12:00:00.5Z "), Ok(at(NOON) + Duration::from_millis(500)));
+	}
+
+	#[test]
+	fn parses_local_times() {
+		let time = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(12, 30, 0).unwrap();
+		let expected = SystemTime::from(Local.from_local_datetime(&time).earliest().unwrap());
+		for value in ["2024-03-01T12:30:00", "2024-03-01 12:30:00", "2024-03-01T12:30", "2024-03-01 12:30"] {
+			assert_eq!(parse(value), Ok(expected), "{}", value);
+		}
+
+		let midnight = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+		let expected = SystemTime::from(Local.from_local_datetime(&midnight).earliest().unwrap());
+		assert_eq!(parse("2024-03-01"), Ok(expected));
+	}
+
+	#[test]
+	fn parses_durations_back_from_now() {
+		let before = SystemTime::now();
+		let since = parse("7d 12h").unwrap();
+		let after = SystemTime::now();
+		let span = Duration::from_secs((7 * 24 + 12) * 3600);
+		assert!(since >= before - span && since <= after - span, "{:?}", since);
+		assert_eq!(parse("500000000000y"), Err("duration is too large".into()));
+	}
+
+	#[test]
+	fn takes_modification_times_of_reference_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("reference");
+		std::fs::write(&path, "").unwrap();
+		filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(NOON as i64, 0)).unwrap();
+		assert_eq!(parse(&format!("@{}", path.display())), Ok(at(NOON)));
+
+		let missing = dir.path().join("missing");
+		let error = parse(&format!("@{}", missing.display())).unwrap_err();
+		assert!(error.starts_with(&format!("cannot read modification time of `{}`", missing.display())), "{}", error);
+	}
+
+	#[test]
+	fn rejects_anything_else() {
+		for value in ["", "yesterday", "2024-13-01", "2024-03-01T25:00:00Z", "12 parsecs"] {
+			let error = parse(value).unwrap_err();
+			assert!(error.starts_with("expected an RFC 3339 timestamp"), "{}: {}", value, error);
+		}
+	}
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use semver::Version;
+
+use crate::provenance::Fingerprint;
+use crate::video::Drift;
+
+/// Why a file was left as it was rather than converted, which is not a
+/// failure
+#[derive(Clone, Debug, PartialEq)]
+pub enum SkipReason {
+	UnknownFormat,
+	NotModifiedSince,
+	/// Below `--min-size`
+	TooSmall,
+	/// By the version of shrink-ray its comment records
+	AlreadyConverted { version: Version },
+	OptimizedBy(Fingerprint),
+	/// The build of the tool, and the format it lacks
+	FormatUnsupported(&'static str, &'static str),
+	/// With the root of the repository
+	TrackedByGit(PathBuf),
+	/// With the still it goes with
+	LivePhotoVideo(PathBuf),
+	/// With the path it was given under first
+	Duplicate(PathBuf),
+	Drifted(Drift),
+	/// With the distance between the perceptual hashes, in bits
+	Unlike(u32),
+	/// With `--max-output-size`, as shown
+	SizeLimit(String),
+	Symlink(PathBuf),
+	Directory(PathBuf),
+	/// Deleted while it was being processed
+	Disappeared,
+	/// Output of `shrink-ray prune` with no single source, and why
+	NoSource(String),
+}
+
+impl SkipReason {
+	/// Tag of the kind of reason, as reports spell it; readers rely on them,
+	/// so they must never change.
+	pub fn tag(&self) -> &'static str {
+		match self {
+			SkipReason::UnknownFormat => "unknown_format",
+			SkipReason::NotModifiedSince => "not_modified_since",
+			SkipReason::TooSmall => "too_small",
+			SkipReason::AlreadyConverted { .. } => "already_converted",
+			SkipReason::OptimizedBy(_) => "optimized_by",
+			SkipReason::FormatUnsupported(..) => "format_unsupported",
+			SkipReason::TrackedByGit(_) => "tracked_by_git",
+			SkipReason::LivePhotoVideo(_) => "live_photo_video",
+			SkipReason::Duplicate(_) => "duplicate",
+			SkipReason::Drifted(_) => "drifted",
+			SkipReason::Unlike(_) => "unlike",
+			SkipReason::SizeLimit(_) => "size_limit",
+			SkipReason::Symlink(_) => "symlink",
+			SkipReason::Directory(_) => "directory",
+			SkipReason::Disappeared => "disappeared",
+			SkipReason::NoSource(_) => "no_source",
+		}
+	}
+
+	/// What to do about it, told along with the reason on the terminal.
+	pub fn hint(&self) -> Option<&'static str> {
+		match self {
+			SkipReason::AlreadyConverted { .. } => Some("--force converts it again"),
+			_ => None,
+		}
+	}
+
+	/// Whether it is only worth telling with `--verbose`, as it is what the
+	/// user asked for.
+	pub fn is_quiet(&self) -> bool {
+		matches!(self, SkipReason::NotModifiedSince | SkipReason::Duplicate(_))
+	}
+}
+
+impl fmt::Display for SkipReason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SkipReason::UnknownFormat => f.write_str("unknown file format"),
+			SkipReason::NotModifiedSince => f.write_str("not modified recently"),
+			SkipReason::TooSmall => f.write_str("below minimum size"),
+			SkipReason::AlreadyConverted { version } => write!(f, "file already converted by shrink-ray {}", version),
+			SkipReason::OptimizedBy(x) => write!(f, "already optimized by {}", x),
+			SkipReason::FormatUnsupported(tool, format) => write!(f, "{} build lacks {} support", tool, format),
+			SkipReason::TrackedByGit(x) => write!(f, "tracked by the git repository at `{}`", x.display()),
+			SkipReason::LivePhotoVideo(x) => write!(f, "video of the Live Photo `{}`", x.display()),
+			SkipReason::Duplicate(x) => write!(f, "same file as `{}`, given earlier", x.display()),
+			SkipReason::Drifted(x) => write!(f, "output drifted from the source ({})", x),
+			SkipReason::Unlike(x) => {
+				write!(f, "output looks unlike the source (perceptual hashes {} of 64 bits apart)", x)
+			}
+			SkipReason::SizeLimit(x) => write!(f, "could not meet size limit of {}", x),
+			SkipReason::Symlink(x) => {
+				write!(f, "`{}` is a symlink; use `--follow-symlinks` to convert its target", x.display())
+			}
+			SkipReason::Directory(x) => {
+				write!(f, "`{}` is a directory; use `--recursive` to convert the files within it", x.display())
+			}
+			SkipReason::Disappeared => f.write_str("file disappeared"),
+			SkipReason::NoSource(x) => f.write_str(x),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use semver::Version;
+
+	use super::SkipReason;
+	use crate::provenance::Fingerprint;
+	use crate::video::Drift;
+
+	#[test]
+	fn tags_every_kind_apart_in_snake_case() {
+		let reasons = [
+			SkipReason::UnknownFormat,
+			SkipReason::NotModifiedSince,
+			SkipReason::TooSmall,
+			SkipReason::AlreadyConverted { version: Version::new(0, 1, 0) },
+			SkipReason::OptimizedBy(Fingerprint::Jpegoptim),
+			SkipReason::FormatUnsupported("gm", "JXL"),
+			SkipReason::TrackedByGit("repo".into()),
+			SkipReason::LivePhotoVideo("a.jpg".into()),
+			SkipReason::Duplicate("a.jpg".into()),
+			SkipReason::Drifted(Drift { duration: 1.0, frames: None, percent: 10.0 }),
+			SkipReason::Unlike(12),
+			SkipReason::SizeLimit("1 MiB".into()),
+			SkipReason::Symlink("a.jpg".into()),
+			SkipReason::Directory("photos".into()),
+			SkipReason::Disappeared,
+			SkipReason::NoSource(String::new()),
+		];
+		let tags: HashSet<_> = reasons.iter().map(SkipReason::tag).collect();
+		assert_eq!(tags.len(), reasons.len());
+		assert!(tags.iter().all(|x| x.bytes().all(|x| x.is_ascii_lowercase() || x == b'_')));
+		assert_eq!(SkipReason::TooSmall.tag(), "too_small");
+		assert_eq!(SkipReason::NoSource(String::new()).tag(), "no_source");
+	}
+
+	#[test]
+	fn tells_the_version_files_were_converted_by() {
+		let reason = SkipReason::AlreadyConverted { version: Version::new(0, 1, 0) };
+		assert_eq!(reason.to_string(), "file already converted by shrink-ray 0.1.0");
+		assert_eq!(reason.hint(), Some("--force converts it again"));
+	}
+}
@@ -0,0 +1,147 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+/// What a running tool shows of itself, which changes as long as it works
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Signs {
+	/// Bytes of output lines read from the tools
+	pub output: u64,
+	/// Sizes of the files the tools write
+	pub sizes: Vec<u64>,
+	/// CPU time of the tools, in clock ticks, where it is known
+	pub cpu: Vec<Option<u64>>,
+}
+
+/// Tells when running tools show no signs of life for a while, e.g. because
+/// they are stuck on I/O with an unresponsive network filesystem; the time is
+/// that spent running them, so pauses do not count
+#[derive(Debug)]
+pub struct Watchdog {
+	timeout: Duration,
+	signs: Signs,
+	/// When the signs last changed
+	changed: Duration,
+}
+
+impl Watchdog {
+	pub fn new(timeout: Duration) -> Self {
+		Self { timeout, signs: Signs::default(), changed: Duration::ZERO }
+	}
+
+	/// How often to look at the signs of life, so that a stall is told
+	/// soon after the timeout.
+	pub fn period(&self) -> Duration {
+		(self.timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1))
+	}
+
+	/// Takes in the `signs` at `elapsed` into the run, returning how long they
+	/// have not changed for if that is longer than the timeout.
+	pub fn check(&mut self, signs: Signs, elapsed: Duration) -> Option<Duration> {
+		if signs != self.signs {
+			self.signs = signs;
+			self.changed = elapsed;
+			return None;
+		}
+
+		let idle = elapsed.saturating_sub(self.changed);
+		(idle >= self.timeout).then_some(idle)
+	}
+}
+
+/// File `command` writes to, taken as its last argument, like for ffmpeg and
+/// GraphicsMagick, without any `FORMAT:` prefix.
+pub fn output_of(command: &Command) -> Option<PathBuf> {
+	let last = command.get_args().last()?.to_str()?;
+	let path = match last.split_once(':') {
+		Some((format, path)) if !format.is_empty() && format.bytes().all(|x| x.is_ascii_alphanumeric()) => path,
+		_ => last,
+	};
+
+	(path != "-").then(|| PathBuf::from(OsStr::new(path)))
+}
+
+/// Size of the file at `path`, if it exists yet.
+pub fn size(path: &Path) -> u64 {
+	std::fs::metadata(path).map_or(0, |x| x.len())
+}
+
+/// CPU time the process `pid` has taken so far, in clock ticks, as procfs
+/// tells; unknown elsewhere.
+#[cfg(target_os = "linux")]
+pub fn cpu_time(pid: u32) -> Option<u64> {
+	let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+	parse_stat(&stat)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cpu_time(_pid: u32) -> Option<u64> {
+	None
+}
+
+/// User and system time of a `/proc/PID/stat` line; the name of the process
+/// comes in parentheses, and may contain anything, even spaces.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_stat(stat: &str) -> Option<u64> {
+	let (_, fields) = stat.rsplit_once(')')?;
+	let mut fields = fields.split_whitespace().skip(11);
+	let user: u64 = fields.next()?.parse().ok()?;
+	let system: u64 = fields.next()?.parse().ok()?;
+	Some(user + system)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+	use std::process::Command;
+	use std::time::Duration;
+
+	use super::{output_of, parse_stat, Signs, Watchdog};
+
+	#[test]
+	fn tells_stalls() {
+		let at = Duration::from_secs;
+		let signs = |output, size, cpu| Signs { output, sizes: vec![size], cpu: vec![cpu] };
+
+		let mut watchdog = Watchdog::new(Duration::from_secs(10));
+		assert_eq!(watchdog.check(signs(0, 0, Some(1)), at(5)), None);
+		assert_eq!(watchdog.check(signs(0, 0, Some(1)), at(14)), None);
+		assert_eq!(watchdog.check(signs(0, 0, Some(1)), at(15)), Some(Duration::from_secs(10)));
+
+		// any sign of life will do
+		assert_eq!(watchdog.check(signs(0, 0, Some(2)), at(16)), None);
+		assert_eq!(watchdog.check(signs(0, 4096, Some(2)), at(30)), None);
+		assert_eq!(watchdog.check(signs(80, 4096, Some(2)), at(45)), None);
+		assert_eq!(watchdog.check(signs(80, 4096, Some(2)), at(56)), Some(Duration::from_secs(11)));
+
+		// unknown CPU times never change
+		let mut watchdog = Watchdog::new(Duration::from_secs(10));
+		assert_eq!(watchdog.check(signs(0, 0, None), at(1)), None);
+		assert_eq!(watchdog.check(signs(0, 0, None), at(11)), Some(Duration::from_secs(10)));
+	}
+
+	#[test]
+	fn finds_outputs_of_commands() {
+		let mut ffmpeg = Command::new("ffmpeg");
+		ffmpeg.args(["-i", "a.mp4", "-c:v", "libsvtav1", "/tmp/a.webm"]);
+		assert_eq!(output_of(&ffmpeg), Some(PathBuf::from("/tmp/a.webm")));
+
+		let mut gm = Command::new("gm");
+		gm.args(["convert", "a.png", "webp:/tmp/a.webp"]);
+		assert_eq!(output_of(&gm), Some(PathBuf::from("/tmp/a.webp")));
+
+		let mut first_pass = Command::new("ffmpeg");
+		first_pass.args(["-i", "a.mp4", "-f", "null", "-"]);
+		assert_eq!(output_of(&first_pass), None);
+		assert_eq!(output_of(&Command::new("true")), None);
+	}
+
+	#[test]
+	fn parses_cpu_times() {
+		let stat = "4242 (my (tool) x) S 1 4242 4242 0 -1 4194560 122 0 0 0 37 5 0 0 20 0 1 0 81 5644288 225";
+		assert_eq!(parse_stat(stat), Some(42));
+		assert_eq!(parse_stat("4242 (ffmpeg) S 1 4242"), None);
+		assert_eq!(parse_stat("garbage"), None);
+	}
+}
@@ -1,14 +1,26 @@
-use size::Size;
+use std::collections::BTreeMap;
+use std::fmt;
 
-#[derive(Copy, Clone, Debug, Default)]
+use crate::skip::SkipReason;
+use crate::timing::Timings;
+
+#[derive(Clone, Debug, Default)]
 pub struct Statistics {
 	processed: u64,
 	saved: u64,
 	wasted: u64,
+	/// Sizes of the originals that were deleted in favour of their outputs,
+	/// and of those outputs
+	replaced: u64,
+	replacements: u64,
 	shrunk: usize,
 	grew: usize,
-	skipped: usize,
+	/// Files skipped, by the tag of the kind of reason
+	skipped: BTreeMap<&'static str, usize>,
 	failed: usize,
+	/// Files whose result was not shown, as the change was negligible
+	hidden: usize,
+	timings: Timings,
 }
 
 impl Statistics {
@@ -24,28 +36,49 @@ impl Statistics {
 		self.grew += 1;
 	}
 
-	pub fn skip(&mut self) {
-		self.skipped += 1;
+	/// Accounts for the original of a conversion being gone, so that the
+	/// difference actually shows on disk.
+	pub fn reclaim(&mut self, delta: Delta) {
+		self.replaced += delta.original;
+		self.replacements += delta.new;
+	}
+
+	pub fn skip(&mut self, reason: &SkipReason) {
+		*self.skipped.entry(reason.tag()).or_default() += 1;
 	}
 
 	pub fn fail(&mut self) {
 		self.failed += 1;
 	}
 
-	pub fn shrunk_files(&self) -> usize {
-		self.shrunk
+	pub fn hide(&mut self) {
+		self.hidden += 1;
 	}
 
-	pub fn saved_size(&self) -> Size {
-		Size::from_bytes(self.saved)
+	pub fn processed_bytes(&self) -> u64 {
+		self.processed
 	}
 
-	pub fn grew_files(&self) -> usize {
-		self.grew
+	pub fn saved_bytes(&self) -> u64 {
+		self.saved
+	}
+
+	pub fn wasted_bytes(&self) -> u64 {
+		self.wasted
 	}
 
-	pub fn wasted_size(&self) -> Size {
-		Size::from_bytes(self.wasted)
+	/// Space freed by replacing originals, as opposed to outputs merely being
+	/// smaller; nothing when the originals are kept elsewhere.
+	pub fn reclaimed_bytes(&self) -> u64 {
+		self.replaced.saturating_sub(self.replacements)
+	}
+
+	pub fn shrunk_files(&self) -> usize {
+		self.shrunk
+	}
+
+	pub fn grew_files(&self) -> usize {
+		self.grew
 	}
 
 	pub fn delta(&self) -> Delta {
@@ -53,12 +86,30 @@ impl Statistics {
 	}
 
 	pub fn skipped_files(&self) -> usize {
-		self.skipped
+		self.skipped.values().sum()
+	}
+
+	/// Files skipped for each kind of reason any were, by its tag, in the
+	/// order of the tags.
+	pub fn skipped_by(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+		self.skipped.iter().map(|(x, y)| (*x, *y))
 	}
 
 	pub fn failed_files(&self) -> usize {
 		self.failed
 	}
+
+	pub fn hidden_files(&self) -> usize {
+		self.hidden
+	}
+
+	pub fn timings(&self) -> &Timings {
+		&self.timings
+	}
+
+	pub fn timings_mut(&mut self) -> &mut Timings {
+		&mut self.timings
+	}
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -76,27 +127,91 @@ impl Delta {
 		self.original >= self.new
 	}
 
-	pub fn original_size(&self) -> Size {
-		Size::from_bytes(self.original)
+	pub fn difference(&self) -> u64 {
+		self.original.abs_diff(self.new)
 	}
 
-	pub fn new_size(&self) -> Size {
-		Size::from_bytes(self.new)
+	/// Difference relative to the original size.
+	pub fn percent(&self) -> Percent {
+		Percent { part: self.difference(), whole: self.original }
 	}
+}
 
-	pub fn size_difference(&self) -> Size {
-		Size::from_bytes(self.difference())
+/// Share of a whole, computed in integers so that a few bytes of difference
+/// between huge files do not round away
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Percent {
+	part: u64,
+	whole: u64,
+}
+
+impl Percent {
+	/// Hundredths of a percent, rounded to the nearest.
+	pub fn hundredths(&self) -> u128 {
+		if self.whole == 0 {
+			return 0;
+		}
+
+		let whole = u128::from(self.whole);
+		(u128::from(self.part) * 10_000 + whole / 2) / whole
 	}
+}
 
-	pub fn difference(&self) -> u64 {
-		if self.original > self.new {
-			self.original - self.new
-		} else {
-			self.new - self.original
+impl fmt::Display for Percent {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let hundredths = self.hundredths();
+		if hundredths == 0 && self.part > 0 && self.whole > 0 {
+			return f.write_str("<0.01 %");
 		}
+
+		write!(f, "{}.{:02} %", hundredths / 100, hundredths % 100)
 	}
+}
 
-	pub fn ratio(&self) -> f64 {
-		self.difference() as f64 / self.original as f64
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::options::Units;
+
+	const GB: u64 = 1_000_000_000;
+
+	#[test]
+	fn counts_skips_by_reason() {
+		let mut stats = Statistics::default();
+		stats.skip(&SkipReason::TooSmall);
+		stats.skip(&SkipReason::Duplicate("a.jpg".into()));
+		stats.skip(&SkipReason::TooSmall);
+		assert_eq!(stats.skipped_files(), 3);
+		assert_eq!(stats.skipped_by().collect::<Vec<_>>(), [("duplicate", 1), ("too_small", 2)]);
+	}
+
+	#[test]
+	fn keeps_tiny_differences_of_huge_files() {
+		assert_eq!(Delta::new(6 * GB, 6 * GB - 1).percent().to_string(), "<0.01 %");
+		assert_eq!(Delta::new(6 * GB, 6 * GB).percent().to_string(), "0.00 %");
+		assert_eq!(Delta::new(6 * GB, 6 * GB - 600_000).percent().to_string(), "0.01 %");
+		assert_eq!(Delta::new(5 * GB, 2 * GB).percent().to_string(), "60.00 %");
+		assert_eq!(Delta::new(u64::MAX, u64::MAX / 3).percent().to_string(), "66.67 %");
+		assert_eq!(Delta::new(2 * GB, 5 * GB).percent().to_string(), "150.00 %");
+		assert_eq!(Delta::new(0, 0).percent().to_string(), "0.00 %");
+	}
+
+	#[test]
+	fn formats_sizes_in_either_unit_system() {
+		let delta = Delta::new(6 * GB, 6 * GB - 3 * GB / 2);
+		assert_eq!(Units::Binary.format(delta.original), "5.59 GiB");
+		assert_eq!(Units::Decimal.format(delta.original), "6.00 GB");
+		assert_eq!(Units::Binary.format(delta.difference()), "1.40 GiB");
+		assert_eq!(Units::Decimal.format(delta.difference()), "1.50 GB");
+
+		let stats = {
+			let mut stats = Statistics::default();
+			stats.shrink(delta);
+			stats.shrink(Delta::new(5 * GB, 4 * GB));
+			stats
+		};
+		assert_eq!(Units::Decimal.format(stats.saved_bytes()), "2.50 GB");
+		assert_eq!(Units::Binary.format(stats.saved_bytes()), "2.33 GiB");
+		assert_eq!(stats.delta().percent().to_string(), "22.73 %");
 	}
 }
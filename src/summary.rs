@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::options::Outcome;
+
+/// Files to list once the run is done, grouped by outcome
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+	limit: usize,
+	lists: BTreeMap<Outcome, Listing>,
+}
+
+/// Files of one outcome, along with the reason for it
+#[derive(Clone, Debug, Default)]
+pub struct Listing {
+	pub files: Vec<(PathBuf, String)>,
+	/// Files with the outcome that did not fit in the list
+	pub more: usize,
+}
+
+impl Summary {
+	/// Lists files of the given `outcomes`, at most `limit` of each.
+	pub fn new(outcomes: &[Outcome], limit: usize) -> Self {
+		Summary { limit, lists: outcomes.iter().map(|x| (*x, Listing::default())).collect() }
+	}
+
+	/// Whether any files were listed, so that there is something to show.
+	pub fn is_empty(&self) -> bool {
+		self.lists.values().all(|x| x.files.is_empty() && x.more == 0)
+	}
+
+	/// Lists `file` if files of that outcome were asked for; the reason is
+	/// only built then.
+	pub fn add(&mut self, outcome: Outcome, file: &Path, reason: impl FnOnce() -> String) {
+		let Some(listing) = self.lists.get_mut(&outcome) else {
+			return;
+		};
+
+		if listing.files.len() < self.limit {
+			listing.files.push((file.to_path_buf(), reason()));
+		} else {
+			listing.more += 1;
+		}
+	}
+
+	/// Non-empty lists, in the order the outcomes are declared.
+	pub fn iter(&self) -> impl Iterator<Item = (Outcome, &Listing)> {
+		self.lists.iter().filter(|(_, x)| !x.files.is_empty() || x.more > 0).map(|(x, listing)| (*x, listing))
+	}
+}
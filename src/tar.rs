@@ -0,0 +1,106 @@
+use std::path::{Component, Path, PathBuf};
+
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tokio_tar::{Archive, Builder, EntryType};
+use tracing::{error, trace, warn, Instrument};
+
+use crate::context::Context;
+use crate::options::Options;
+use crate::{input_span, run_input, temp, Flow, Processed, Run};
+
+/// Converts the files of a tar archive read from the standard input, and
+/// writes the results as a tar archive to the standard output.
+///
+/// Entries are extracted one at a time into a scratch directory and run
+/// through the usual pipeline; files that are not converted, and entries that
+/// are not files, are passed through unchanged.
+pub async fn run(options: &Options, context: &mut Context, run: &mut Run) -> Result<Flow, crate::Error> {
+	let workspace = temp::scratch_file(Path::new("shrink-ray"), None);
+	trace!("creating workspace `{}`", workspace.display());
+	fs::create_dir(&workspace).await?;
+	context.terminal.set_root(&workspace);
+
+	let result = stream(options, context, run, &workspace).await;
+
+	trace!("deleting workspace `{}`", workspace.display());
+	if let Err(x) = fs::remove_dir_all(&workspace).await {
+		error!("failed to delete workspace `{}`: {}", workspace.display(), x);
+	}
+
+	result
+}
+
+async fn stream(
+	options: &Options, context: &mut Context, run: &mut Run, workspace: &Path,
+) -> Result<Flow, crate::Error> {
+	let mut archive = Archive::new(io::stdin());
+	let mut entries = archive.entries()?;
+	let mut builder = Builder::new(io::stdout());
+	let mut flow = Flow::Continue;
+
+	while let Some(entry) = entries.next().await {
+		let mut entry = entry?;
+		let path = entry.path()?.into_owned();
+		let mut header = entry.header().clone();
+
+		let is_file = matches!(header.entry_type(), EntryType::Regular | EntryType::Continuous);
+		if is_file && flow == Flow::Continue && run.out_of_time(options, context) {
+			flow = Flow::Stop;
+		}
+
+		if !is_file || flow != Flow::Continue {
+			trace!("passing through `{}`", path.display());
+			builder.append_data(&mut header, &path, entry).await?;
+			continue;
+		}
+
+		if !entry.unpack_in(workspace).await? {
+			warn!("skipping entry `{}`, which points outside of the archive", path.display());
+			continue;
+		}
+
+		let relative: PathBuf = path.components().filter(|x| matches!(x, Component::Normal(_))).collect();
+		let file = workspace.join(&relative);
+		let metadata = std::fs::symlink_metadata(&file);
+		let span = input_span(&file);
+		let result = run_input(&file, metadata, options, context, run.stats.timings_mut());
+		let result = result.instrument(span.clone()).await;
+		let output = match &result {
+			Ok(Processed::Converted(x)) => x.output.clone(),
+			Ok(Processed::Skipped(_)) | Err(_) => file.clone(),
+		};
+
+		flow = run.report(&file, result, options, context).instrument(span).await;
+		if flow == Flow::Abort {
+			return Ok(flow);
+		}
+
+		let name = match output.file_name() {
+			Some(x) => relative.with_file_name(x),
+			None => relative,
+		};
+
+		let data = File::open(&output).await?;
+		let metadata = data.metadata().await?;
+		header.set_size(metadata.len());
+		header.set_mtime(filetime::FileTime::from_last_modification_time(&metadata).unix_seconds().max(0) as u64);
+
+		trace!("writing `{}` to the archive as `{}`", output.display(), name.display());
+		builder.append_data(&mut header, &name, data).await?;
+
+		for path in [&output, &file] {
+			if let Err(x) = fs::remove_file(path).await {
+				if x.kind() != std::io::ErrorKind::NotFound {
+					error!("failed to delete `{}` from the workspace: {}", path.display(), x);
+				}
+			}
+		}
+	}
+
+	let mut stdout = builder.into_inner().await?;
+	stdout.flush().await?;
+
+	Ok(flow)
+}
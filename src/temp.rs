@@ -1,31 +1,128 @@
+use std::env;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 
-const ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
-const LENGTH: usize = 8;
+/// Characters of generated names, which are safe in file names everywhere
+/// and never end one with a dot or a space
+pub const ALPHABET: &[u8; 36] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+/// Length of generated names, enough for 128 random bits in [`ALPHABET`], so
+/// that names never collide, even generated by many runs at once
+pub const LENGTH: usize = 25;
+/// Longest file name most filesystems take, in bytes
+pub const NAME_MAX: usize = 255;
 
+/// Environment variable which makes the generated names reproducible, for
+/// testing
+const SEED_VAR: &str = "RAY_TEMP_SEED";
+
+fn seeded() -> Option<&'static Mutex<StdRng>> {
+	static RNG: OnceLock<Option<Mutex<StdRng>>> = OnceLock::new();
+	RNG
+		.get_or_init(|| {
+			let seed = env::var(SEED_VAR).ok()?.parse().ok()?;
+			Some(Mutex::new(StdRng::seed_from_u64(seed)))
+		})
+		.as_ref()
+}
+
+/// Random name of `LENGTH` characters of `ALPHABET`.
+fn name() -> String {
+	let mut seeded = seeded().map(|x| x.lock().unwrap());
+	let mut thread = rand::thread_rng();
+	let rng: &mut dyn RngCore = match seeded.as_deref_mut() {
+		Some(x) => x,
+		None => &mut thread,
+	};
+
+	let mut bits: u128 = rng.gen();
+	let mut name = String::with_capacity(LENGTH);
+	for _ in 0..LENGTH {
+		name.push(ALPHABET[(bits % ALPHABET.len() as u128) as usize] as char);
+		bits /= ALPHABET.len() as u128;
+	}
+
+	name
+}
+
+/// Path beside `path`, named after its stem followed by a random name and
+/// `suffix`, which is not checked for: nothing else can come up with the same
+/// name, and whoever creates it can do so with `create_new` anyway. The stem is
+/// cut short if the name would not fit in [`NAME_MAX`] otherwise.
 pub fn file(path: impl AsRef<Path>, suffix: Option<&OsStr>) -> PathBuf {
 	let path = path.as_ref();
-	let mut rng = rand::thread_rng();
-	let mut prefix = path.parent().unwrap().join(path.file_stem().unwrap()).into_os_string();
-	prefix.push("-");
-	loop {
-		let mut buf = prefix.clone();
-
-		for _ in 0..LENGTH {
-			let index = rng.gen_range(0..ALPHABET.len());
-			buf.push(&ALPHABET[index..=index]);
-		}
-
-		if let Some(suffix) = suffix {
-			buf.push(suffix);
-		}
-
-		let path = PathBuf::from(buf);
-		if !path.exists() {
-			return path;
-		}
+	let stem = path.file_stem().unwrap();
+	let room = NAME_MAX.saturating_sub(1 + LENGTH + suffix.map_or(0, |x| x.len()));
+	let mut buf = match stem.len() > room {
+		true => path.parent().unwrap().join(shorten(&stem.to_string_lossy(), room)).into_os_string(),
+		false => path.parent().unwrap().join(stem).into_os_string(),
+	};
+	buf.push("-");
+	buf.push(name());
+	if let Some(suffix) = suffix {
+		buf.push(suffix);
+	}
+
+	PathBuf::from(buf)
+}
+
+/// Longest start of `name` of at most `len` bytes, cut between characters.
+fn shorten(name: &str, len: usize) -> &str {
+	let end = (0..=len.min(name.len())).rev().find(|x| name.is_char_boundary(*x)).unwrap_or(0);
+	&name[..end]
+}
+
+/// Like [`file`], but in the system temporary directory rather than next to
+/// `path`, for intermediate files that never end up near the input.
+pub fn scratch_file(path: impl AsRef<Path>, suffix: Option<&OsStr>) -> PathBuf {
+	let name = path.as_ref().file_name().unwrap();
+	file(env::temp_dir().join(name), suffix)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+	use std::path::Path;
+
+	use super::{file, name, ALPHABET, LENGTH, NAME_MAX};
+
+	#[test]
+	fn generates_unique_names() {
+		let names: HashSet<_> = (0..1_000_000).map(|_| name()).collect();
+		assert_eq!(names.len(), 1_000_000);
+		assert!(names.iter().all(|x| x.len() == LENGTH && x.bytes().all(|x| ALPHABET.contains(&x))));
+	}
+
+	#[test]
+	fn generates_valid_file_names() {
+		let path = file(Path::new("photos/con.jpg"), Some(".jpg".as_ref()));
+		let name = path.file_name().unwrap().to_str().unwrap();
+		assert_eq!(path.parent(), Some(Path::new("photos")));
+		assert!(name.starts_with("con-") && name.ends_with(".jpg"), "{}", name);
+		assert_eq!(name.len(), "con-".len() + LENGTH + ".jpg".len());
+
+		// nothing Windows would choke on: reserved characters, nor a trailing
+		// dot or space
+		let name = file(Path::new("a"), None).file_name().unwrap().to_str().unwrap().to_string();
+		assert!(!name.ends_with(['.', ' ']));
+		assert!(!name.contains(['<', '>', ':', '"', '/', '\\', '|', '?', '*']));
+	}
+
+	#[test]
+	fn shortens_long_names() {
+		let stem = "é".repeat(126);
+		let path = file(Path::new("photos").join(format!("{}.jpg", stem)), Some(".jpg".as_ref()));
+		let name = path.file_name().unwrap().to_str().unwrap();
+		assert!(name.len() <= NAME_MAX, "{}", name.len());
+		assert!(name.len() > NAME_MAX - 2, "{}", name.len());
+		assert!(name.starts_with("éé") && name.ends_with(".jpg"), "{}", name);
+		assert_eq!(name.len(), name.rfind('-').unwrap() + 1 + LENGTH + ".jpg".len());
+
+		// short names stay whole
+		let name = file(Path::new("a.jpg"), Some(".jpg".as_ref())).file_name().unwrap().to_str().unwrap().to_string();
+		assert!(name.starts_with("a-"), "{}", name);
 	}
 }
@@ -0,0 +1,207 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::str::FromStr;
+
+/// Command line argument with `{name}` placeholders, expanded without going
+/// through a shell, so values never have to be quoted. `{{` and `}}` stand for
+/// literal braces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+	parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+	Literal(String),
+	Placeholder(Placeholder),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Placeholder {
+	Input,
+	Output,
+	Quality,
+	Comment,
+	/// How processing the file went, for hooks
+	Status,
+	/// Bytes saved by the conversion, negative if the file grew
+	SavedBytes,
+	Mime,
+}
+
+impl Placeholder {
+	/// Placeholders of the arguments of user-defined tools
+	pub const TOOL: &'static [Placeholder] =
+		&[Placeholder::Input, Placeholder::Output, Placeholder::Quality, Placeholder::Comment];
+	/// Placeholders of the commands run after each file
+	pub const HOOK: &'static [Placeholder] =
+		&[Placeholder::Input, Placeholder::Output, Placeholder::Status, Placeholder::SavedBytes, Placeholder::Mime];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Placeholder::Input => "input",
+			Placeholder::Output => "output",
+			Placeholder::Quality => "quality",
+			Placeholder::Comment => "comment",
+			Placeholder::Status => "status",
+			Placeholder::SavedBytes => "saved_bytes",
+			Placeholder::Mime => "mime",
+		}
+	}
+}
+
+impl fmt::Display for Placeholder {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{{{}}}", self.name())
+	}
+}
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum TemplateError {
+	#[error("unknown placeholder `{{{}}}` (expected one of {})", .0, placeholder_names(.1))]
+	UnknownPlaceholder(String, &'static [Placeholder]),
+	#[error("unmatched `{}`; write `{0}{0}` for a literal one", .0)]
+	Unmatched(char),
+	#[error("no program to run")]
+	NoProgram,
+}
+
+fn placeholder_names(placeholders: &[Placeholder]) -> String {
+	placeholders.iter().map(|x| format!("`{}`", x)).collect::<Vec<_>>().join(", ")
+}
+
+impl Template {
+	/// Parses `s`, which may only use `placeholders`.
+	pub fn parse(s: &str, placeholders: &'static [Placeholder]) -> Result<Self, TemplateError> {
+		let mut parts = Vec::new();
+		let mut literal = String::new();
+		let mut chars = s.chars().peekable();
+
+		while let Some(c) = chars.next() {
+			match c {
+				'{' if chars.peek() == Some(&'{') => {
+					chars.next();
+					literal.push('{');
+				}
+				'}' if chars.peek() == Some(&'}') => {
+					chars.next();
+					literal.push('}');
+				}
+				'{' => {
+					let mut name = String::new();
+					loop {
+						match chars.next() {
+							Some('}') => break,
+							Some(x) => name.push(x),
+							None => return Err(TemplateError::Unmatched('{')),
+						}
+					}
+
+					let Some(placeholder) = placeholders.iter().copied().find(|x| x.name() == name) else {
+						return Err(TemplateError::UnknownPlaceholder(name, placeholders));
+					};
+
+					if !literal.is_empty() {
+						parts.push(Part::Literal(std::mem::take(&mut literal)));
+					}
+
+					parts.push(Part::Placeholder(placeholder));
+				}
+				'}' => return Err(TemplateError::Unmatched('}')),
+				x => literal.push(x),
+			}
+		}
+
+		if !literal.is_empty() {
+			parts.push(Part::Literal(literal));
+		}
+
+		Ok(Template { parts })
+	}
+
+	/// Whether expanding the template needs a value for `placeholder`.
+	pub fn uses(&self, placeholder: Placeholder) -> bool {
+		self.parts.contains(&Part::Placeholder(placeholder))
+	}
+
+	/// Builds the argument, taking placeholder values from `value`.
+	pub fn expand<'a>(&self, value: impl Fn(Placeholder) -> &'a OsStr) -> OsString {
+		let mut arg = OsString::new();
+		for part in &self.parts {
+			match part {
+				Part::Literal(x) => arg.push(x),
+				Part::Placeholder(x) => arg.push(value(*x)),
+			}
+		}
+
+		arg
+	}
+}
+
+/// Program to run with templates for arguments, written as a single string
+/// split on whitespace, as nothing goes through a shell
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandLine {
+	pub program: String,
+	pub args: Vec<Template>,
+}
+
+impl CommandLine {
+	/// Parses `s`, which may only use `placeholders`.
+	pub fn parse(s: &str, placeholders: &'static [Placeholder]) -> Result<Self, TemplateError> {
+		let mut words = s.split_whitespace();
+		let program = words.next().ok_or(TemplateError::NoProgram)?.to_string();
+		let args = words.map(|x| Template::parse(x, placeholders)).collect::<Result<_, _>>()?;
+		Ok(CommandLine { program, args })
+	}
+}
+
+impl FromStr for Template {
+	type Err = TemplateError;
+
+	/// Parses an argument of a user-defined tool.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Template::parse(s, Placeholder::TOOL)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsStr;
+
+	use super::{CommandLine, Placeholder, Template, TemplateError};
+
+	fn value(placeholder: Placeholder) -> &'static OsStr {
+		OsStr::new(match placeholder {
+			Placeholder::Input => "my photos/a b.jpg",
+			Placeholder::Output => "out/a b.webp",
+			_ => "",
+		})
+	}
+
+	#[test]
+	fn expands_paths_with_spaces_as_one_argument() {
+		let template: Template = "--out={output}".parse().unwrap();
+		assert_eq!(template.expand(value), "--out=out/a b.webp");
+
+		let command = CommandLine::parse("cp  {input}\t{output}", Placeholder::HOOK).unwrap();
+		assert_eq!(command.program, "cp");
+		let args: Vec<_> = command.args.iter().map(|x| x.expand(value)).collect();
+		assert_eq!(args, ["my photos/a b.jpg", "out/a b.webp"]);
+	}
+
+	#[test]
+	fn rejects_missing_placeholders() {
+		let error = Template::parse("{status}", Placeholder::TOOL).unwrap_err();
+		assert_eq!(error, TemplateError::UnknownPlaceholder("status".into(), Placeholder::TOOL));
+		assert!(error.to_string().contains("`{input}`, `{output}`, `{quality}`, `{comment}`"), "{}", error);
+		assert!(Template::parse("{status}", Placeholder::HOOK).is_ok());
+		assert!(Template::parse("{quality}", Placeholder::HOOK).is_err());
+		assert!(Template::parse("{}", Placeholder::HOOK).is_err());
+
+		assert_eq!(Template::parse("{input", Placeholder::HOOK), Err(TemplateError::Unmatched('{')));
+		assert_eq!(Template::parse("input}", Placeholder::HOOK), Err(TemplateError::Unmatched('}')));
+		assert_eq!(Template::parse("{{input}}", Placeholder::HOOK).unwrap().expand(value), "{input}");
+		assert_eq!(CommandLine::parse(" \t", Placeholder::HOOK), Err(TemplateError::NoProgram));
+	}
+}
@@ -1,187 +1,973 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::fmt;
-use std::io::{stdout, StdoutLock, Write};
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use crossterm::cursor::MoveToColumn;
 use crossterm::style::Stylize;
 use crossterm::terminal::{Clear, ClearType};
+use serde_json::json;
+use tracing::{debug, error, warn, Level};
 
+use crate::advisor::Utilization;
+use crate::events::Events;
+use crate::fsutil;
+use crate::image::{Classification, Dimensions};
+use crate::options::{Format, Outcome, Units};
+use crate::ratios::{Hundredths, Ratios};
+use crate::repeats::Repeats;
+use crate::sequences::Sequence;
+use crate::skip::SkipReason;
 use crate::stats::{Delta, Statistics};
+use crate::summary::Summary;
+use crate::timing::{Timing, Timings};
+use crate::tools::Tool;
+use crate::top::Top;
+use crate::video::Drift;
 
-macro_rules! safe_write {
-	($($args:expr),*) => {
-		{
-			let result = write!($($args),*);
-			if cfg!(debug_assertions) {
-				result.unwrap();
-			}
+/// What else to tell about a converted file, after its change in size
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Details<'a> {
+	/// What converted the file, if not the preferred backend
+	pub backend: Option<&'a str>,
+	/// Dimensions of the input and the output
+	pub resize: Option<(Dimensions, Dimensions)>,
+	/// Format picked for the image by what it depicts
+	pub classified: Option<Classification>,
+	/// How far the converted video strays from the input
+	pub drift: Option<Drift>,
+	/// Anything else worth pointing out
+	pub note: Option<&'a str>,
+	/// How busy the tools kept the CPU
+	pub utilization: Option<Utilization>,
+}
+
+impl fmt::Display for Details<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if let Some(backend) = self.backend {
+			write!(f, ", using {}", backend)?;
+		}
+
+		if let Some((source, output)) = self.resize {
+			write!(f, ", {} → {}", source, output)?;
+		}
+
+		if let Some(classified) = self.classified {
+			write!(f, ", {}", classified)?;
 		}
+
+		if let Some(drift) = self.drift {
+			write!(f, ", {}", drift)?;
+		}
+
+		if let Some(note) = self.note {
+			write!(f, ", {}", note)?;
+		}
+
+		if let Some(utilization) = self.utilization {
+			write!(f, ", {}", utilization)?;
+		}
+
+		Ok(())
 	}
 }
 
-macro_rules! safe_writeln {
-	($($args:expr),*) => {
-		{
-			let result = writeln!($($args),*);
-			if cfg!(debug_assertions) {
-				result.unwrap();
-			}
+/// What is happening to the file being processed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Activity {
+	Running,
+	Cancelling,
+	Paused,
+	/// No signs of life from the tools for a while
+	Stalled,
+}
+
+/// Destination of the output, which goes quiet once it cannot be written to
+/// anymore, e.g. because the reading end of the pipe went away.
+///
+/// Its `write_fmt` shadows the one of [`Write`], so it works with `write!`.
+struct Sink {
+	/// Shared with the terminals forked off, which write whole lines at a time
+	inner: Rc<RefCell<Box<dyn Write>>>,
+	error: Option<io::ErrorKind>,
+	/// Whether to write JSON objects instead of everything else
+	json: bool,
+}
+
+impl Sink {
+	fn write_fmt(&mut self, args: fmt::Arguments) {
+		if !self.json {
+			self.write_raw(args);
+		}
+	}
+
+	/// Writes `value` on a line of its own, if writing JSON.
+	fn write_json(&mut self, value: impl FnOnce() -> serde_json::Value) {
+		if self.json {
+			self.write_raw(format_args!("{}\n", value()));
 		}
 	}
-}
 
-macro_rules! safe_flush {
-	($stream:expr) => {{
-		let result = $stream.flush();
-		if cfg!(debug_assertions) {
-			result.unwrap();
+	fn write_raw(&mut self, args: fmt::Arguments) {
+		if self.error.is_none() {
+			let result = self.inner.borrow_mut().write_fmt(args);
+			self.check(result);
 		}
-	}};
+	}
+
+	fn flush(&mut self) {
+		if self.error.is_none() {
+			let result = self.inner.borrow_mut().flush();
+			self.check(result);
+		}
+	}
+
+	fn check(&mut self, result: io::Result<()>) {
+		let Err(x) = result else {
+			return;
+		};
+
+		if x.kind() == io::ErrorKind::BrokenPipe {
+			debug!("output closed; not writing anything anymore");
+		} else {
+			error!("failed to write output, not writing anything anymore: {}", x);
+		}
+
+		self.error = Some(x.kind());
+	}
+}
+
+pub struct Terminal {
+	out: Sink,
+	spinner: Option<Duration>,
+	units: Units,
+	paths: Paths,
+	/// Warnings and notices given so far, shown only the first time
+	repeats: Rc<RefCell<Repeats>>,
+	/// Share of the file being processed done, once known, shown instead of
+	/// the animation
+	done: Option<f64>,
+	/// Progress events written alongside, shared with the terminals forked off
+	events: Option<Rc<RefCell<Events>>>,
+	/// When the file being processed was started, to tell how long it will
+	/// take yet
+	started: Option<Instant>,
 }
 
-pub struct Terminal(StdoutLock<'static>);
+/// How paths are displayed
+#[derive(Clone, Debug, Default)]
+pub enum PathStyle {
+	/// As given on the command line
+	#[default]
+	AsGiven,
+	/// In canonical form
+	Absolute,
+	/// Relative to this canonical directory
+	RelativeTo(PathBuf),
+}
+
+#[derive(Debug, Default)]
+struct Paths {
+	/// Directory the displayed paths are relative to, for those not given by
+	/// the user
+	root: Option<PathBuf>,
+	style: PathStyle,
+	/// Last path displayed in another style, and how; the same one is
+	/// usually displayed over and over while it is processed
+	last: RefCell<Option<(PathBuf, PathBuf)>>,
+}
+
+impl Paths {
+	fn show<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+		match fsutil::unextended(path) {
+			Cow::Borrowed(x) => self.show_plain(x),
+			Cow::Owned(x) => Cow::Owned(self.show_plain(&x).into_owned()),
+		}
+	}
+
+	fn show_plain<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+		let path = match &self.root {
+			Some(root) => path.strip_prefix(root).unwrap_or(path),
+			None => path,
+		};
+
+		if matches!(self.style, PathStyle::AsGiven) || crate::download::is_url(path) {
+			return Cow::Borrowed(path);
+		}
+
+		let mut last = self.last.borrow_mut();
+		if let Some((_, shown)) = last.as_ref().filter(|(x, _)| x == path) {
+			return Cow::Owned(shown.clone());
+		}
+
+		let canonical = fsutil::canonical_path(path);
+		let shown = match &self.style {
+			PathStyle::RelativeTo(dir) => fsutil::relative_path(&canonical, dir),
+			_ => canonical,
+		};
+
+		*last = Some((path.to_path_buf(), shown.clone()));
+		Cow::Owned(shown)
+	}
+}
 
 impl Terminal {
 	const ANIMATION: &'static [&'static str] = &["⠋", "⠙", "⠸", "⠴", "⠦", "⠇"];
 
-	pub fn new() -> Self {
-		Terminal(stdout().lock())
+	/// Creates a terminal which animates the progress every `spinner`, or
+	/// just writes plain lines if `None`.
+	pub fn new(out: impl Write + 'static, spinner: Option<Duration>, units: Units) -> Self {
+		let out = Sink { inner: Rc::new(RefCell::new(Box::new(out))), error: None, json: false };
+		let paths = Paths::default();
+		Terminal { out, spinner, units, paths, repeats: Rc::default(), done: None, events: None, started: None }
+	}
+
+	/// Another terminal writing to the same output, for converting files
+	/// alongside each other: it just writes plain lines, as animating the
+	/// progress of several files on the same line would garble it, and it
+	/// counts repeated warnings and notices along with this one.
+	pub fn fork(&self) -> Self {
+		let out = Sink { inner: self.out.inner.clone(), error: self.out.error, json: self.out.json };
+		let paths = Paths { root: self.paths.root.clone(), style: self.paths.style.clone(), last: RefCell::default() };
+		let (repeats, events) = (self.repeats.clone(), self.events.clone());
+		Terminal { out, spinner: None, units: self.units, paths, repeats, done: None, events, started: None }
+	}
+
+	/// Whether whoever was reading the output went away.
+	pub fn is_closed(&self) -> bool {
+		self.out.error == Some(io::ErrorKind::BrokenPipe)
+	}
+
+	/// Displays paths below `root` relative to it.
+	pub fn set_root(&mut self, root: impl Into<PathBuf>) {
+		self.paths.root = Some(root.into());
+	}
+
+	/// Displays the paths of inputs and outputs in `style`.
+	pub fn set_path_style(&mut self, style: PathStyle) {
+		self.paths.style = style;
+	}
+
+	/// Reports results in `format`; with JSON, nothing else is written.
+	pub fn set_format(&mut self, format: Format) {
+		self.out.json = format == Format::Json;
+	}
+
+	/// Writes progress events to `events` as well, see [`Events`].
+	pub fn set_events(&mut self, events: Events) {
+		self.events = Some(Rc::new(RefCell::new(events)));
 	}
 
-	pub fn write_shrink(&mut self, file: impl AsRef<Path>, delta: Delta) {
-		safe_writeln!(
-			self.0,
+	pub fn spinner_interval(&self) -> Option<Duration> {
+		self.spinner
+	}
+
+	pub fn write_shrink(&mut self, file: impl AsRef<Path>, delta: Delta, details: &Details) {
+		self.write_json_delta(file.as_ref(), "shrunk", delta);
+		self.write_finished(file.as_ref(), "shrunk", Some(delta), None);
+		writeln!(
+			self.out,
 			"      {} {} {}",
 			"Shrunk".green().bold(),
-			file.as_ref().display(),
-			format!("(-{}, -{:.2} %)", delta.size_difference(), 100.0 * delta.ratio()).dim()
+			self.paths.show(file.as_ref()).display(),
+			format!("(-{}, -{}{})", self.units.format(delta.difference()), delta.percent(), details).dim()
 		);
 	}
 
-	pub fn write_grow(&mut self, file: impl AsRef<Path>, delta: Delta) {
-		safe_writeln!(
-			self.0,
+	pub fn write_grow(&mut self, file: impl AsRef<Path>, delta: Delta, details: &Details) {
+		self.write_json_delta(file.as_ref(), "grew", delta);
+		self.write_finished(file.as_ref(), "grew", Some(delta), None);
+		writeln!(
+			self.out,
 			"        {} {} {}",
 			"Grew".dark_yellow().bold(),
-			file.as_ref().display(),
-			format!("(+{}, +{:.2} %)", delta.size_difference(), 100.0 * delta.ratio()).dim()
+			self.paths.show(file.as_ref()).display(),
+			format!("(+{}, +{}{})", self.units.format(delta.difference()), delta.percent(), details).dim()
+		);
+	}
+
+	pub fn write_sequence(&mut self, sequence: &Sequence, files: usize, stats: &Statistics) {
+		let delta = stats.delta();
+		let name = sequence.name();
+		let (verb, sign) = if delta.is_smaller() {
+			(format!("{:>12}", "Shrunk").green().bold(), '-')
+		} else {
+			(format!("{:>12}", "Grew").dark_yellow().bold(), '+')
+		};
+
+		writeln!(
+			self.out,
+			"{} {} {}",
+			verb,
+			self.paths.show(&name).display(),
+			format!("({} files, {}{}, {}{})", files, sign, self.units.format(delta.difference()), sign, delta.percent())
+				.dim()
 		);
 	}
 
-	pub fn write_skip(&mut self, file: impl AsRef<Path>, reason: impl fmt::Display) {
-		safe_writeln!(
-			self.0,
+	pub fn write_skip(&mut self, file: impl AsRef<Path>, reason: &SkipReason) {
+		let tag = reason.tag();
+		let reason = match reason.hint() {
+			Some(hint) => format!("{}; {}", reason, hint),
+			None => reason.to_string(),
+		};
+		let path = self.paths.show(file.as_ref());
+		self.out.write_json(|| {
+			json!({"path": path.to_string_lossy(), "status": "skipped", "reason": reason, "skip_reason": tag})
+		});
+		self.write_finished(file.as_ref(), "skipped", None, Some(&reason));
+		writeln!(
+			self.out,
 			"     {} {} {}",
 			"Skipped".magenta().bold(),
-			file.as_ref().display(),
+			self.paths.show(file.as_ref()).display(),
 			format!("({})", reason).dim()
 		);
 	}
 
 	pub fn write_fail(&mut self, file: impl AsRef<Path>, reason: impl fmt::Display) {
-		safe_writeln!(
-			self.0,
+		let path = self.paths.show(file.as_ref());
+		self.out.write_json(|| {
+			json!({"path": path.to_string_lossy(), "status": "failed", "reason": reason.to_string()})
+		});
+		self.write_finished(file.as_ref(), "failed", None, Some(&reason));
+		writeln!(
+			self.out,
 			"      {} {} {}",
 			"Failed".red().bold(),
-			file.as_ref().display(),
+			self.paths.show(file.as_ref()).display(),
 			format!("({})", reason).dim()
 		);
 	}
 
+	pub fn write_redirect(&mut self, file: impl AsRef<Path>, output: impl AsRef<Path>) {
+		writeln!(
+			self.out,
+			"  {} {} {}",
+			"Redirected".blue().bold(),
+			self.paths.show(file.as_ref()).display(),
+			format!("(to `{}`)", self.paths.show(output.as_ref()).display()).dim()
+		);
+	}
+
+	pub fn write_restore(&mut self, file: impl AsRef<Path>, backup: impl AsRef<Path>) {
+		writeln!(
+			self.out,
+			"    {} {} {}",
+			"Restored".green().bold(),
+			self.paths.show(file.as_ref()).display(),
+			format!("(from `{}`)", self.paths.show(backup.as_ref()).display()).dim()
+		);
+	}
+
+	pub fn write_tool(&mut self, tool: &Tool) {
+		writeln!(
+			self.out,
+			"        {} {} {}",
+			"Tool".blue().bold(),
+			tool.name,
+			format!("({} to .{}, using `{}`)", tool.mime, tool.extension, tool.binary).dim()
+		);
+	}
+
+	pub fn write_tools_valid(&mut self, path: impl AsRef<Path>, count: usize) {
+		writeln!(
+			self.out,
+			"       {} {} {}",
+			"Valid".green().bold(),
+			self.paths.show(path.as_ref()).display(),
+			format!("({} tools)", count).dim()
+		);
+	}
+
+	pub fn write_audit_valid(&mut self, path: impl AsRef<Path>, entries: usize, head: &str) {
+		writeln!(
+			self.out,
+			"      {} {} {}",
+			"Intact".green().bold(),
+			self.paths.show(path.as_ref()).display(),
+			format!("({} entries, last {})", entries, head).dim()
+		);
+	}
+
+	pub fn write_prune(&mut self, file: impl AsRef<Path>, source: impl AsRef<Path>, delta: Delta, dry_run: bool) {
+		let (path, shown) = (self.paths.show(file.as_ref()), self.paths.show(source.as_ref()));
+		let status = if dry_run { "would_prune" } else { "pruned" };
+		self.out.write_json(|| {
+			let (path, source) = (path.to_string_lossy(), shown.to_string_lossy());
+			json!({"path": path, "status": status, "source": source, "bytes": delta.new})
+		});
+		let verb = if dry_run { " Would prune" } else { "      Pruned" };
+		writeln!(
+			self.out,
+			"{} {} {}",
+			verb.dark_yellow().bold(),
+			self.paths.show(file.as_ref()).display(),
+			format!(
+				"(+{}, +{} over `{}`)",
+				self.units.format(delta.difference()),
+				delta.percent(),
+				self.paths.show(source.as_ref()).display()
+			)
+			.dim()
+		);
+	}
+
+	pub fn write_hidden(&mut self, files: usize) {
+		let files = if files == 1 { "1 file".to_string() } else { format!("{} files", files) };
+		writeln!(self.out, "{}", format!("{:>12} {} with negligible change hidden", "…", files).dim());
+	}
+
 	pub fn write_cancel(&mut self, file: impl AsRef<Path>) {
-		safe_writeln!(self.0, "   {} {}", "Cancelled".red().bold(), file.as_ref().display());
+		let path = self.paths.show(file.as_ref());
+		self.out.write_json(|| json!({"path": path.to_string_lossy(), "status": "cancelled"}));
+		self.write_finished(file.as_ref(), "cancelled", None, None);
+		writeln!(self.out, "   {} {}", "Cancelled".red().bold(), self.paths.show(file.as_ref()).display());
+	}
+
+	pub fn write_note(&mut self, message: impl fmt::Display) {
+		writeln!(self.out, "{} {}", format!("{:>12}", "Note").blue().bold(), message);
+	}
+
+	/// Like [`Terminal::write_note`], for notices of `kind` coming up file
+	/// after file, within `scope` if given: only the first is written, the
+	/// others are counted for [`Terminal::write_suppressed`].
+	pub fn write_note_once(&mut self, kind: &str, scope: Option<&Path>, message: impl fmt::Display) {
+		let message = message.to_string();
+		if self.repeats.borrow_mut().first(kind, scope, &message) {
+			self.write_note(message);
+		}
 	}
 
-	pub fn write_stats(&mut self, stats: Statistics) {
-		safe_write!(
-			self.0,
+	/// Logs a warning like `warn!`, counting repeats like
+	/// [`Terminal::write_note_once`]; as long as warnings are not shown, they
+	/// are not counted either.
+	pub fn warn_once(&mut self, kind: &str, scope: Option<&Path>, message: impl fmt::Display) {
+		if !tracing::enabled!(Level::WARN) {
+			return;
+		}
+
+		let message = message.to_string();
+		if self.repeats.borrow_mut().first(kind, scope, &message) {
+			warn!("{}", message);
+		} else {
+			debug!("{} (repeated)", message);
+		}
+	}
+
+	/// Writes how many times each warning and notice was left out, if any.
+	pub fn write_suppressed(&mut self) {
+		let suppressed: Vec<_> = self.repeats.borrow().suppressed().map(|(x, y)| (x.to_string(), y)).collect();
+		for (message, count) in suppressed {
+			let repeats = if count == 1 { "repeat" } else { "repeats" };
+			self.write_note(format_args!("suppressed {} {} of: {}", thousands(count), repeats, message));
+		}
+	}
+
+	pub fn write_newline(&mut self) {
+		writeln!(self.out);
+	}
+
+	/// `in_place` tells whether outputs replace their inputs, so that the
+	/// savings also free up space.
+	pub fn write_stats(&mut self, stats: &Statistics, in_place: bool) {
+		self.out.write_json(|| json!({"summary": summary(stats, in_place)}));
+		write!(
+			self.out,
 			"{} {} {}, ",
 			"Shrunk".green().bold(),
 			stats.shrunk_files(),
-			format!("(-{})", stats.saved_size()).dim()
+			format!("(-{})", self.units.format(stats.saved_bytes())).dim()
 		);
-		safe_write!(
-			self.0,
+		write!(
+			self.out,
 			"{} {} {}, ",
 			"Grew".dark_yellow().bold(),
 			stats.grew_files(),
-			format!("(+{})", stats.wasted_size()).dim()
+			format!("(+{})", self.units.format(stats.wasted_bytes())).dim()
 		);
-		safe_write!(self.0, "{} {}, ", "Skipped".magenta().bold(), stats.skipped_files());
-		safe_writeln!(self.0, "{} {} ", "Failed".red().bold(), stats.failed_files());
+		write!(self.out, "{} {}, ", "Skipped".magenta().bold(), stats.skipped_files());
+		writeln!(self.out, "{} {} ", "Failed".red().bold(), stats.failed_files());
 
 		let delta = stats.delta();
-		safe_write!(self.0, "Processed {}, ", delta.original_size());
+		let difference = self.units.format(delta.difference());
+		write!(self.out, "Processed {}, ", self.units.format(delta.original));
+		let (saving, wasting) = if in_place { ("saving", "wasting") } else { ("outputs saving", "outputs wasting") };
 		if delta.is_smaller() {
-			let ratio = format!("(-{:.2} %)", 100.0 * delta.ratio());
-			safe_writeln!(
-				self.0,
-				"{} -{} {}",
-				"saving".green().bold(),
-				delta.size_difference(),
-				ratio.dim()
-			);
+			let ratio = format!("(-{})", delta.percent());
+			write!(self.out, "{} -{} {}", saving.green().bold(), difference, ratio.dim());
+		} else {
+			let ratio = format!("(+{})", delta.percent());
+			write!(self.out, "{} +{} {}", wasting.dark_yellow().bold(), difference, ratio.dim());
+		}
+
+		if in_place {
+			writeln!(self.out, ", {} {}", "reclaimed".green().bold(), self.units.format(stats.reclaimed_bytes()));
 		} else {
-			let ratio = format!("(+{:.2} %)", 100.0 * delta.ratio());
-			safe_writeln!(
-				self.0,
-				"{} +{} {}",
-				"wasting".dark_yellow().bold(),
-				delta.size_difference(),
-				ratio.dim()
-			);
+			writeln!(self.out, ", {}", "originals kept".bold());
+		}
+
+		let cpu = stats.timings().cpu();
+		if cpu.is_zero() {
+			return;
+		}
+
+		write!(self.out, "{} {:.2?}", "CPU time".bold(), cpu);
+		let (megabyte, symbol) = self.units.megabyte();
+		let saved = delta.is_smaller().then(|| delta.difference() as f64 / megabyte as f64).filter(|x| *x > 0.0);
+		match saved.map(|x| Duration::from_secs_f64(cpu.as_secs_f64() / x)) {
+			Some(x) => writeln!(self.out, ", {:.2?} per {} saved", x, symbol),
+			None => writeln!(self.out),
+		};
+
+		if let Some(advice) = Utilization::new(cpu, stats.timings().total().tools).and_then(Utilization::advice) {
+			self.write_note(advice);
+		}
+	}
+
+	/// Savings of the files shrunk, each relative to its own size.
+	pub fn write_ratios(&mut self, ratios: &Ratios) {
+		let Some(distribution) = ratios.distribution() else {
+			return;
+		};
+
+		let estimate = if ratios.is_estimate() { " (estimated)" } else { "" };
+		writeln!(
+			self.out,
+			"{} mean -{}, median -{}, 10th to 90th percentile -{} to -{}{}",
+			"Per file".green().bold(),
+			Hundredths(distribution.mean),
+			Hundredths(distribution.median),
+			Hundredths(distribution.p10),
+			Hundredths(distribution.p90),
+			estimate.dim()
+		);
+	}
+
+	/// Totals of `shrink-ray prune`, where the outputs that grew are those
+	/// pruned and the others are kept.
+	pub fn write_prune_stats(&mut self, stats: Statistics, dry_run: bool) {
+		self.out.write_json(|| {
+			json!({"summary": {
+				"pruned_files": stats.grew_files(),
+				"kept_files": stats.shrunk_files(),
+				"skipped_files": stats.skipped_files(),
+				"skipped_by": skipped_by(&stats),
+				"failed_files": stats.failed_files(),
+				"reclaimed_bytes": stats.reclaimed_bytes(),
+				"dry_run": dry_run,
+			}})
+		});
+		let pruned = if dry_run { "Would prune" } else { "Pruned" };
+		write!(
+			self.out,
+			"{} {} {}, ",
+			pruned.dark_yellow().bold(),
+			stats.grew_files(),
+			format!("(+{} over sources)", self.units.format(stats.wasted_bytes())).dim()
+		);
+		write!(self.out, "{} {}, ", "Kept".green().bold(), stats.shrunk_files());
+		write!(self.out, "{} {}, ", "Skipped".magenta().bold(), stats.skipped_files());
+		writeln!(self.out, "{} {}", "Failed".red().bold(), stats.failed_files());
+
+		let reclaimed = if dry_run { "Would reclaim" } else { "Reclaimed" };
+		writeln!(self.out, "{} {}", reclaimed.green().bold(), self.units.format(stats.reclaimed_bytes()));
+	}
+
+	pub fn write_top(&mut self, top: &Top) {
+		let lists = [
+			("Largest savings:".green().bold(), top.savings(), '-'),
+			("Largest growths:".dark_yellow().bold(), top.growths(), '+'),
+		];
+		for (heading, files, sign) in lists.into_iter().filter(|(_, x, _)| !x.is_empty()) {
+			writeln!(self.out, "{}", heading);
+			for (file, delta) in files {
+				let details = format!(
+					"({}{}, {}{}, {} to {})",
+					sign,
+					self.units.format(delta.difference()),
+					sign,
+					delta.percent(),
+					self.units.format(delta.original),
+					self.units.format(delta.new)
+				);
+				writeln!(self.out, "  {} {}", self.paths.show(file).display(), details.dim());
+			}
+		}
+	}
+
+	pub fn write_summary(&mut self, summary: &Summary) {
+		for (outcome, listing) in summary.iter() {
+			let heading = match outcome {
+				Outcome::Failed => "Failed files:".red().bold(),
+				Outcome::Skipped => "Skipped files:".magenta().bold(),
+				Outcome::Grew => "Grown files:".dark_yellow().bold(),
+			};
+
+			writeln!(self.out, "{}", heading);
+			for (file, reason) in &listing.files {
+				writeln!(self.out, "  {} {}", self.paths.show(file).display(), format!("({})", reason).dim());
+			}
+
+			if listing.more > 0 {
+				writeln!(self.out, "  {}", format!("… and {} more", listing.more).dim());
+			}
+
+			writeln!(self.out);
 		}
 	}
 
+	pub fn write_timings(&mut self, timings: &Timings) {
+		writeln!(
+			self.out,
+			"{} {:>10} {:>10} {:>10} {:>10}",
+			format!("{:>12}", "Stage").bold(),
+			"tools",
+			"overhead",
+			"per file",
+			"files"
+		);
+		for (phase, timing) in timings.iter() {
+			self.write_timing(phase, timing);
+		}
+
+		self.write_timing("total", &timings.total());
+	}
+
+	fn write_timing(&mut self, name: impl fmt::Display, timing: &Timing) {
+		writeln!(
+			self.out,
+			"{} {:>10} {:>10} {:>10} {:>10}",
+			format!("{:>12}", name).bold(),
+			format!("{:.2?}", timing.tools),
+			format!("{:.2?}", timing.overhead()),
+			format!("{:.2?}", timing.per_file()),
+			timing.files
+		);
+	}
+
+	/// Ends the progress events with the statistics of the run, and whether it
+	/// was `cancelled`; the terminal shows them with
+	/// [`Terminal::write_stats`], if at all.
+	pub fn end_run(&mut self, stats: &Statistics, in_place: bool, cancelled: bool) {
+		self.write_event("run_finished", || json!({"stats": summary(stats, in_place), "cancelled": cancelled}));
+	}
+
+	/// Tells the progress events that processing `file` started, before any
+	/// tools run for it.
+	pub fn start_file(&mut self, file: impl AsRef<Path>) {
+		self.started = Some(Instant::now());
+		let path = self.paths.show(file.as_ref()).to_string_lossy().into_owned();
+		self.write_event("file_started", || json!({"path": path}));
+	}
+
 	pub fn start_processing(&mut self, file: impl AsRef<Path>) {
+		self.done = None;
+		if self.spinner.is_none() {
+			writeln!(self.out, "   {} {}", "Shrinking".cyan().bold(), self.paths.show(file.as_ref()).display());
+			return;
+		}
+
 		self.write_shrinking(file, 0);
-		safe_flush!(self.0);
+		self.out.flush();
 	}
 
-	pub fn update_processing(&mut self, file: impl AsRef<Path>, progress: usize, cancel: bool) {
-		safe_write!(self.0, "{}{}", MoveToColumn(0), Clear(ClearType::UntilNewLine));
-		if cancel {
-			self.write_cancelling(file, progress);
-		} else {
-			self.write_shrinking(file, progress);
+	/// Shows the share `done` of the file being processed from now on, until
+	/// the next one, which has `written` bytes of output so far if known.
+	pub fn set_done(&mut self, file: impl AsRef<Path>, done: f64, written: Option<u64>) {
+		self.done = Some(done);
+		let path = self.paths.show(file.as_ref()).to_string_lossy().into_owned();
+		let started = self.started;
+		self.write_event("progress", || {
+			let eta = started.filter(|_| done > 0.0).map(|x| x.elapsed().as_secs_f64() * (1.0 - done) / done);
+			json!({"path": path, "percent": done * 100.0, "eta_s": eta, "bytes_out": written})
+		});
+	}
+
+	pub fn update_processing(&mut self, file: impl AsRef<Path>, progress: usize, activity: Activity) {
+		if self.spinner.is_none() {
+			return;
 		}
 
-		safe_flush!(self.0);
+		write!(self.out, "{}{}", MoveToColumn(0), Clear(ClearType::UntilNewLine));
+		self.write_activity(file, progress, activity);
+		self.out.flush();
 	}
 
-	pub fn write_processing(&mut self, file: impl AsRef<Path>, progress: usize, cancel: bool, line: impl AsRef<str>) {
-		safe_write!(self.0, "{}{}", MoveToColumn(0), Clear(ClearType::UntilNewLine));
-		let _ = write!(self.0, "             {}", line.as_ref().dim());
-		if cancel {
-			self.write_cancelling(file, progress);
-		} else {
-			self.write_shrinking(file, progress);
+	pub fn write_processing(
+		&mut self, file: impl AsRef<Path>, progress: usize, activity: Activity, line: impl AsRef<str>,
+	) {
+		if self.spinner.is_none() {
+			return;
+		}
+
+		write!(self.out, "{}{}", MoveToColumn(0), Clear(ClearType::UntilNewLine));
+		write!(self.out, "             {}", line.as_ref().dim());
+		self.write_activity(file, progress, activity);
+		self.out.flush();
+	}
+
+	/// Shows that processing `file` was paused or resumed.
+	pub fn write_pause(&mut self, file: impl AsRef<Path>, progress: usize, paused: bool) {
+		if self.spinner.is_some() {
+			let activity = if paused { Activity::Paused } else { Activity::Running };
+			return self.update_processing(file, progress, activity);
+		}
+
+		let verb = if paused { "Paused" } else { "Resumed" };
+		let verb = format!("{:>12}", verb).yellow().bold();
+		writeln!(self.out, "{} {}", verb, self.paths.show(file.as_ref()).display());
+	}
+
+	/// Shows that processing `file` possibly stalled, without signs of life
+	/// for `timeout`, or that it went on.
+	pub fn write_stall(&mut self, file: impl AsRef<Path>, progress: usize, timeout: Option<Duration>) {
+		if self.spinner.is_some() {
+			let activity = if timeout.is_some() { Activity::Stalled } else { Activity::Running };
+			return self.update_processing(file, progress, activity);
+		}
+
+		let file = self.paths.show(file.as_ref());
+		let file = file.display();
+		match timeout {
+			Some(timeout) => {
+				let reason = format!("(possibly; no signs of life for {})", humantime::format_duration(timeout));
+				writeln!(self.out, "{} {} {}", format!("{:>12}", "Stalled").yellow().bold(), file, reason.dim());
+			}
+			None => writeln!(self.out, "{} {}", format!("{:>12}", "Recovered").yellow().bold(), file),
+		}
+	}
+
+	/// Shows that no more files are started until the system is idle.
+	pub fn write_waiting(&mut self, progress: usize) {
+		if self.spinner.is_none() {
+			if progress == 0 {
+				writeln!(self.out, "{} for idle…", format!("{:>12}", "Waiting").yellow().bold());
+			}
+
+			return;
 		}
 
-		safe_flush!(self.0)
+		write!(self.out, "{}{}", MoveToColumn(0), Clear(ClearType::UntilNewLine));
+		write!(
+			self.out,
+			"{} for idle… {}",
+			format!("{:>12}", "Waiting").yellow().bold(),
+			Self::ANIMATION[progress % Self::ANIMATION.len()]
+		);
+		self.out.flush();
 	}
 
 	pub fn end_processing(&mut self) {
-		safe_write!(self.0, "{}{}", MoveToColumn(0), Clear(ClearType::UntilNewLine));
-		safe_flush!(self.0);
+		if self.spinner.is_none() {
+			return;
+		}
+
+		write!(self.out, "{}{}", MoveToColumn(0), Clear(ClearType::UntilNewLine));
+		self.out.flush();
+	}
+
+	/// Writes the progress event of `event`, if writing them at all.
+	fn write_event(&mut self, event: &str, fields: impl FnOnce() -> serde_json::Value) {
+		if let Some(events) = &self.events {
+			events.borrow_mut().write(event, fields());
+		}
+	}
+
+	/// Writes the progress event of `file` finishing with `outcome`.
+	fn write_finished(
+		&mut self, file: &Path, outcome: &str, delta: Option<Delta>, reason: Option<&dyn fmt::Display>,
+	) {
+		let path = self.paths.show(file).to_string_lossy().into_owned();
+		self.write_event("file_finished", || {
+			let delta = delta.map(|x| json!({"original_bytes": x.original, "new_bytes": x.new}));
+			json!({"path": path, "outcome": outcome, "delta": delta, "reason": reason.map(|x| x.to_string())})
+		});
+	}
+
+	/// Writes the JSON object of `file`, which changed by `delta`.
+	fn write_json_delta(&mut self, file: &Path, status: &str, delta: Delta) {
+		let path = self.paths.show(file);
+		self.out.write_json(|| {
+			let ratio = (delta.original > 0).then(|| delta.new as f64 / delta.original as f64);
+			json!({
+				"path": path.to_string_lossy(),
+				"status": status,
+				"original_bytes": delta.original,
+				"new_bytes": delta.new,
+				"ratio": ratio,
+			})
+		});
 	}
 
 	fn write_shrinking(&mut self, file: impl AsRef<Path>, progress: usize) {
-		safe_write!(self.0, "   {} ", "Shrinking".cyan().bold());
+		write!(self.out, "   {} ", "Shrinking".cyan().bold());
 		self.write_processing_file(file, progress)
 	}
 
-	fn write_cancelling(&mut self, file: impl AsRef<Path>, progress: usize) {
-		safe_write!(self.0, "  {} ", "Cancelling".red().bold());
-		self.write_processing_file(file, progress)
+	fn write_activity(&mut self, file: impl AsRef<Path>, progress: usize, activity: Activity) {
+		match activity {
+			Activity::Running => self.write_shrinking(file, progress),
+			Activity::Cancelling => {
+				write!(self.out, "  {} ", "Cancelling".red().bold());
+				self.write_processing_file(file, progress)
+			}
+			Activity::Paused => {
+				write!(self.out, "      {} ", "Paused".yellow().bold());
+				self.write_processing_file(file, progress)
+			}
+			Activity::Stalled => {
+				write!(self.out, "     {} ", "Stalled".yellow().bold());
+				self.write_processing_file(file, progress)
+			}
+		}
 	}
 
 	fn write_processing_file(&mut self, file: impl AsRef<Path>, progress: usize) {
-		safe_write!(
-			self.0,
-			"{} {}",
-			Self::ANIMATION[progress % Self::ANIMATION.len()],
-			file.as_ref().display()
-		);
+		let file = self.paths.show(file.as_ref());
+		match self.done {
+			Some(done) => write!(self.out, "{:>3} % {}", (done * 100.0).floor() as u8, file.display()),
+			None => write!(self.out, "{} {}", Self::ANIMATION[progress % Self::ANIMATION.len()], file.display()),
+		}
+	}
+}
+
+/// Everything `stats` count, as the JSON object of the summary; `in_place`
+/// tells whether the savings also free up space.
+fn summary(stats: &Statistics, in_place: bool) -> serde_json::Value {
+	let delta = stats.delta();
+	json!({
+		"shrunk_files": stats.shrunk_files(),
+		"grew_files": stats.grew_files(),
+		"skipped_files": stats.skipped_files(),
+		"skipped_by": skipped_by(stats),
+		"failed_files": stats.failed_files(),
+		"original_bytes": delta.original,
+		"new_bytes": delta.new,
+		"saved_bytes": stats.saved_bytes(),
+		"wasted_bytes": stats.wasted_bytes(),
+		"reclaimed_bytes": in_place.then(|| stats.reclaimed_bytes()),
+		"cpu_seconds": stats.timings().cpu().as_secs_f64(),
+	})
+}
+
+/// Files `stats` count as skipped, as a JSON object from the tag of each kind
+/// of reason to the number of them.
+fn skipped_by(stats: &Statistics) -> serde_json::Value {
+	stats.skipped_by().map(|(tag, count)| (tag.to_owned(), json!(count))).collect()
+}
+
+/// `count` with its thousands separated by commas, e.g. `1,245`.
+fn thousands(count: usize) -> String {
+	let digits = count.to_string();
+	let mut separated = String::new();
+	for (index, digit) in digits.chars().enumerate() {
+		if index > 0 && (digits.len() - index).is_multiple_of(3) {
+			separated.push(',');
+		}
+
+		separated.push(digit);
+	}
+
+	separated
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::io::{self, Write};
+	use std::rc::Rc;
+	use std::time::Duration;
+
+	use semver::Version;
+
+	use super::{thousands, Activity, Details, Terminal};
+	use crate::options::{Format, Units};
+	use crate::skip::SkipReason;
+	use crate::stats::Delta;
+
+	#[derive(Clone, Default)]
+	struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.borrow_mut().write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn separates_thousands() {
+		assert_eq!(thousands(0), "0");
+		assert_eq!(thousands(999), "999");
+		assert_eq!(thousands(1245), "1,245");
+		assert_eq!(thousands(1_234_567), "1,234,567");
+	}
+
+	#[test]
+	fn forks_write_plain_lines_to_the_same_output() {
+		let buffer = Buffer::default();
+		let mut terminal = Terminal::new(buffer.clone(), Some(Duration::from_millis(100)), Units::default());
+		let mut fork = terminal.fork();
+		assert_eq!(fork.spinner_interval(), None);
+		fork.start_processing("a.jpg");
+		fork.write_note_once("kind", None, "first");
+		terminal.write_note_once("kind", None, "again");
+		terminal.write_suppressed();
+
+		let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+		let lines: Vec<_> = output.lines().collect();
+		assert_eq!(lines.len(), 3, "{}", output);
+		assert!(lines[0].contains("Shrinking") && lines[0].ends_with(" a.jpg"), "{}", output);
+		assert!(lines[1].ends_with(" first"), "{}", output);
+		assert!(lines[2].ends_with("suppressed 1 repeat of: first"), "{}", output);
+	}
+
+	#[test]
+	fn shows_share_done_once_known() {
+		let buffer = Buffer::default();
+		let mut terminal = Terminal::new(buffer.clone(), Some(Duration::from_millis(100)), Units::default());
+		terminal.start_processing("a.mp4");
+		terminal.set_done("a.mp4", 0.257, None);
+		terminal.update_processing("a.mp4", 1, Activity::Running);
+		let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+		assert!(output.ends_with(" 25 % a.mp4"), "{:?}", output);
+
+		// but not for the next file
+		terminal.start_processing("b.mp4");
+		terminal.update_processing("b.mp4", 1, Activity::Running);
+		let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+		assert!(!output.ends_with(" % b.mp4") && output.ends_with(" b.mp4"), "{:?}", output);
+	}
+
+	#[test]
+	fn writes_nothing_but_json_objects_in_json_format() {
+		let buffer = Buffer::default();
+		let mut terminal = Terminal::new(buffer.clone(), None, Units::default());
+		terminal.set_format(Format::Json);
+		let mut fork = terminal.fork();
+		terminal.start_processing("a.jpg");
+		terminal.write_note("something");
+		terminal.write_shrink("a.jpg", Delta::new(100, 25), &Details::default());
+		let reason = SkipReason::AlreadyConverted { version: Version::new(0, 1, 0) };
+		fork.write_skip("b\"c.jpg", &reason);
+		terminal.write_newline();
+
+		let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+		let lines: Vec<_> = output.lines().collect();
+		assert_eq!(lines.len(), 2, "{}", output);
+		let shrunk: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+		assert_eq!(shrunk["status"], "shrunk");
+		assert_eq!(shrunk["ratio"], 0.25);
+		let skipped: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+		assert_eq!(skipped["path"], "b\"c.jpg");
+		assert_eq!(skipped["reason"], "file already converted by shrink-ray 0.1.0; --force converts it again");
+		assert_eq!(skipped["skip_reason"], "already_converted");
 	}
 }
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+/// Stages of processing a single input, timed separately
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+	Identify,
+	Probe,
+	Convert,
+	Replace,
+}
+
+impl Phase {
+	pub const ALL: [Phase; 4] = [Phase::Identify, Phase::Probe, Phase::Convert, Phase::Replace];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Phase::Identify => "identify",
+			Phase::Probe => "probe",
+			Phase::Convert => "convert",
+			Phase::Replace => "replace",
+		}
+	}
+}
+
+impl fmt::Display for Phase {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// Time spent in a phase, split into the time spent waiting for tools and
+/// everything else
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timing {
+	pub files: usize,
+	pub total: Duration,
+	pub tools: Duration,
+}
+
+impl Timing {
+	pub fn overhead(&self) -> Duration {
+		self.total.saturating_sub(self.tools)
+	}
+
+	pub fn per_file(&self) -> Duration {
+		match u32::try_from(self.files) {
+			Ok(0) => Duration::ZERO,
+			Ok(x) => self.total / x,
+			Err(_) => Duration::from_secs_f64(self.total.as_secs_f64() / self.files as f64),
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timings {
+	phases: [Timing; Phase::ALL.len()],
+	/// CPU time the tools took, which is unrelated to how long they ran for
+	/// with several at once
+	cpu: Duration,
+}
+
+impl Timings {
+	pub fn get(&self, phase: Phase) -> &Timing {
+		&self.phases[phase as usize]
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (Phase, &Timing)> {
+		Phase::ALL.into_iter().zip(self.phases.iter())
+	}
+
+	/// Sum of all phases; `files` is the number of files that got identified
+	pub fn total(&self) -> Timing {
+		let mut total = Timing { files: self.get(Phase::Identify).files, ..Timing::default() };
+		for timing in &self.phases {
+			total.total += timing.total;
+			total.tools += timing.tools;
+		}
+
+		total
+	}
+
+	/// Accounts the time since `mark` to `phase`, `tools` being the total
+	/// time spent in tools so far.
+	pub fn record(&mut self, phase: Phase, mark: Mark, tools: Duration) {
+		let (elapsed, tools) = (mark.start.elapsed(), tools.saturating_sub(mark.tools));
+		let (elapsed_ms, tools_ms) = (elapsed.as_millis() as u64, tools.as_millis() as u64);
+		debug!(phase = phase.name(), elapsed_ms, tools_ms, "timed");
+
+		let timing = &mut self.phases[phase as usize];
+		timing.files += 1;
+		timing.total += elapsed;
+		timing.tools += tools;
+	}
+
+	/// Accounts `other` too, e.g. the timings of a file converted alongside
+	/// others.
+	pub fn add(&mut self, other: &Timings) {
+		for (timing, other) in self.phases.iter_mut().zip(&other.phases) {
+			timing.files += other.files;
+			timing.total += other.total;
+			timing.tools += other.tools;
+		}
+
+		self.cpu += other.cpu;
+	}
+
+	/// Accounts `cpu` more CPU time taken by the tools.
+	pub fn spend_cpu(&mut self, cpu: Duration) {
+		self.cpu += cpu;
+	}
+
+	pub fn cpu(&self) -> Duration {
+		self.cpu
+	}
+}
+
+/// Point in time a phase started at
+#[derive(Copy, Clone, Debug)]
+pub struct Mark {
+	start: ActiveInstant,
+	tools: Duration,
+}
+
+impl Mark {
+	pub fn new(tools: Duration) -> Self {
+		Mark { start: ActiveInstant::now(), tools }
+	}
+}
+
+/// Reading of a monotonic clock which stands still while the run is paused,
+/// so that pauses count towards neither timings nor time limits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ActiveInstant(Duration);
+
+impl ActiveInstant {
+	pub fn now() -> Self {
+		let pauses = pauses().lock().unwrap();
+		let now = pauses.since.unwrap_or_else(Instant::now);
+		ActiveInstant(now.duration_since(pauses.origin).saturating_sub(pauses.total))
+	}
+
+	/// Active time since this reading.
+	pub fn elapsed(self) -> Duration {
+		Self::now().0.saturating_sub(self.0)
+	}
+}
+
+struct Pauses {
+	origin: Instant,
+	/// Time spent paused so far, not counting the current pause
+	total: Duration,
+	/// When the current pause started
+	since: Option<Instant>,
+}
+
+fn pauses() -> &'static Mutex<Pauses> {
+	static PAUSES: OnceLock<Mutex<Pauses>> = OnceLock::new();
+	PAUSES.get_or_init(|| Mutex::new(Pauses { origin: Instant::now(), total: Duration::ZERO, since: None }))
+}
+
+/// Stops the active clock, unless it is stopped already.
+pub fn pause() {
+	let mut pauses = pauses().lock().unwrap();
+	pauses.since.get_or_insert_with(Instant::now);
+}
+
+/// Starts the active clock again.
+pub fn resume() {
+	let mut pauses = pauses().lock().unwrap();
+	if let Some(since) = pauses.since.take() {
+		pauses.total += since.elapsed();
+	}
+}
@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::Deserialize;
+use tokio::fs;
+use tracing::{debug, error, trace};
+
+use crate::comment::Comment;
+use crate::config;
+use crate::context::Context;
+use crate::options::{OutputOptions, ToolsCommand, ToolsOptions};
+use crate::template::{Placeholder, Template};
+use crate::terminal::Terminal;
+
+/// Converter defined by the user, tried before the built-in ones
+#[derive(Clone, Debug)]
+pub struct Tool {
+	pub name: String,
+	/// MIME type of the files it converts: exact, `type/*` or `*`
+	pub mime: String,
+	/// Binary to run, found like the built-in ones (so `RAY_BIN_*` work too)
+	pub binary: String,
+	args: Vec<Template>,
+	/// Extension of the output, without the leading dot
+	pub extension: String,
+	quality: Option<String>,
+	comment: Option<CommentProbe>,
+}
+
+/// How to read the comment back from a converted file
+#[derive(Clone, Debug)]
+struct CommentProbe {
+	args: Vec<Template>,
+	pattern: Regex,
+}
+
+/// Tool definitions, as loaded from a tools file
+#[derive(Clone, Debug, Default)]
+pub struct Tools {
+	path: Option<PathBuf>,
+	tools: Vec<Tool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ToolsFile {
+	#[serde(default)]
+	tool: Vec<ToolDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ToolDefinition {
+	name: String,
+	mime: String,
+	binary: Option<String>,
+	args: Vec<String>,
+	extension: String,
+	quality: Option<String>,
+	comment: Option<CommentDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommentDefinition {
+	args: Vec<String>,
+	/// Regular expression matching the comment in the output of the probe; the
+	/// first group if it has any, the whole match otherwise
+	pattern: String,
+}
+
+impl Tools {
+	/// `$XDG_CONFIG_HOME/shrink-ray/tools.toml`, falling back to
+	/// `~/.config/shrink-ray/tools.toml`.
+	pub fn default_path() -> Option<PathBuf> {
+		Some(config::dir()?.join("tools.toml"))
+	}
+
+	/// Loads the tools defined in `path`, or in the default tools file, which
+	/// does not have to exist unless `required`.
+	pub async fn load(path: Option<&Path>, required: bool) -> Result<Self, crate::Error> {
+		let (path, required) = match path {
+			Some(x) => (x.to_path_buf(), true),
+			None => match Self::default_path() {
+				Some(x) => (x, required),
+				None => return Ok(Tools::default()),
+			},
+		};
+
+		trace!("loading tool definitions from `{}`", path.display());
+		let text = match fs::read_to_string(&path).await {
+			Ok(x) => x,
+			Err(x) if x.kind() == std::io::ErrorKind::NotFound && !required => {
+				trace!("no tools file found; using built-in tools only");
+				return Ok(Tools::default());
+			}
+			Err(x) => return Err(crate::Error::ToolsFile(path, vec![x.to_string()])),
+		};
+
+		match Self::parse(&text) {
+			Ok(tools) => {
+				debug!("loaded {} tool definitions from `{}`", tools.len(), path.display());
+				Ok(Tools { path: Some(path), tools })
+			}
+			Err(x) => Err(crate::Error::ToolsFile(path, x)),
+		}
+	}
+
+	/// Parses and checks tool definitions, returning every problem found.
+	fn parse(text: &str) -> Result<Vec<Tool>, Vec<String>> {
+		let file: ToolsFile = toml::from_str(text).map_err(|x| match x.span() {
+			Some(span) => vec![format!("line {}: {}", text[..span.start].matches('\n').count() + 1, x.message())],
+			None => vec![x.message().to_string()],
+		})?;
+		let mut problems = Vec::new();
+		let mut names = HashSet::new();
+		let mut tools = Vec::new();
+
+		for definition in file.tool {
+			if !names.insert(definition.name.clone()) {
+				problems.push(format!("tool `{}`: defined more than once", definition.name));
+			}
+
+			match Tool::new(definition) {
+				Ok(x) => tools.push(x),
+				Err(x) => problems.extend(x),
+			}
+		}
+
+		match problems.is_empty() {
+			true => Ok(tools),
+			false => Err(problems),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.tools.len()
+	}
+
+	/// Extensions of the outputs of all tools.
+	pub fn extensions(&self) -> impl Iterator<Item = &str> {
+		self.tools.iter().map(|x| x.extension.as_str())
+	}
+
+	/// First tool converting files of type `mime`.
+	pub fn find(&self, mime: &str) -> Option<&Tool> {
+		self.tools.iter().find(|x| x.matches(mime))
+	}
+}
+
+impl Tool {
+	fn new(definition: ToolDefinition) -> Result<Self, Vec<String>> {
+		let name = definition.name;
+		let mut problems = Vec::new();
+		let mut problem = |x: String| problems.push(format!("tool `{}`: {}", name, x));
+
+		if name.is_empty() {
+			problem("empty name".into());
+		}
+
+		let mime = definition.mime.to_ascii_lowercase();
+		let valid_mime = match mime.split_once('/') {
+			Some((kind, subtype)) => !kind.is_empty() && !subtype.is_empty() && !subtype.contains('/') && kind != "*",
+			None => mime == "*",
+		};
+		if !valid_mime {
+			problem(format!("`{}` is not a MIME type, `type/*` or `*`", definition.mime));
+		}
+
+		let extension = definition.extension.trim_start_matches('.').to_string();
+		if extension.is_empty() || extension.contains(['/', '\\']) {
+			problem(format!("`{}` is not a file extension", definition.extension));
+		}
+
+		let args = parse_args(&definition.args, "args", &mut problem);
+		for placeholder in [Placeholder::Input, Placeholder::Output] {
+			if !args.iter().any(|x| x.uses(placeholder)) {
+				problem(format!("`args` never mention `{}`", placeholder));
+			}
+		}
+
+		let mut uses_quality = args.iter().any(|x| x.uses(Placeholder::Quality));
+		let comment = match definition.comment {
+			Some(comment) => {
+				let args = parse_args(&comment.args, "comment.args", &mut problem);
+				if !args.iter().any(|x| x.uses(Placeholder::Input)) {
+					problem(format!("`comment.args` never mention `{}`", Placeholder::Input));
+				}
+
+				for placeholder in [Placeholder::Output, Placeholder::Comment] {
+					if args.iter().any(|x| x.uses(placeholder)) {
+						problem(format!("`comment.args` cannot use `{}`", placeholder));
+					}
+				}
+
+				uses_quality |= args.iter().any(|x| x.uses(Placeholder::Quality));
+				match Regex::new(&comment.pattern) {
+					Ok(pattern) => Some(CommentProbe { args, pattern }),
+					Err(x) => {
+						problem(format!("invalid `comment.pattern`: {}", x));
+						None
+					}
+				}
+			}
+			None => None,
+		};
+
+		if uses_quality && definition.quality.is_none() {
+			problem(format!("`{}` is used, but `quality` is not set", Placeholder::Quality));
+		}
+
+		if !problems.is_empty() {
+			return Err(problems);
+		}
+
+		let binary = definition.binary.unwrap_or_else(|| name.clone());
+		Ok(Tool { name, mime, binary, args, extension, quality: definition.quality, comment })
+	}
+
+	fn matches(&self, mime: &str) -> bool {
+		match self.mime.strip_suffix("/*") {
+			Some(kind) => mime.split_once('/').is_some_and(|(x, _)| x.eq_ignore_ascii_case(kind)),
+			None => self.mime == "*" || self.mime.eq_ignore_ascii_case(mime),
+		}
+	}
+
+	/// Expands `templates` for converting `input` into `output`.
+	fn expand<'a>(
+		&'a self, templates: &'a [Template], input: &'a Path, output: &'a Path, comment: &'a str,
+	) -> impl Iterator<Item = OsString> + 'a {
+		templates.iter().map(move |x| {
+			x.expand(|placeholder| match placeholder {
+				Placeholder::Input => input.as_os_str(),
+				Placeholder::Output => output.as_os_str(),
+				Placeholder::Quality => OsStr::new(self.quality.as_deref().unwrap_or_default()),
+				Placeholder::Comment => OsStr::new(comment),
+				Placeholder::Status | Placeholder::SavedBytes | Placeholder::Mime => OsStr::new(""),
+			})
+		})
+	}
+
+	/// Reads the comment of `path`, if the tool knows how to.
+	pub async fn get_comment(&self, context: &mut Context, path: &Path) -> Result<Option<Comment>, crate::Error> {
+		let Some(probe) = &self.comment else {
+			return Ok(None);
+		};
+
+		let mut command = context.command(&self.binary)?;
+		command.args(self.expand(&probe.args, path, Path::new(""), ""));
+
+		let output = context.output(command).await?;
+		if !output.status.success() {
+			return Err(crate::Error::invocation(&self.binary, output.status, &output.stderr));
+		}
+
+		for stream in [&output.stdout, &output.stderr] {
+			let text = String::from_utf8_lossy(stream);
+			if let Some(captures) = probe.pattern.captures(&text) {
+				let comment = captures.get(1).or_else(|| captures.get(0)).map_or("", |x| x.as_str());
+				return comment.trim().parse().map(Some).map_err(crate::Error::from);
+			}
+		}
+
+		Ok(None)
+	}
+
+	pub async fn convert(
+		&self, context: &mut Context, options: &OutputOptions, comment: Comment, input: &Path,
+	) -> Result<PathBuf, crate::Error> {
+		options.check_extension(&self.extension)?;
+		let output = context.get_output_file(options, input, format!(".{}", self.extension)).await?;
+		let comment = comment.to_string();
+
+		debug!("converting `{}` with tool `{}`", input.display(), self.name);
+		let mut command = context.command(&self.binary)?;
+		command.args(self.expand(&self.args, input, &output, &comment));
+
+		match context.run(&self.binary, command, input).await {
+			Ok(_) => Ok(output),
+			Err(x) => {
+				if output.exists() {
+					trace!("error raised; deleting output file `{}`...", output.display());
+					if let Err(x) = fs::remove_file(&output).await {
+						error!("failed to delete output file `{}`: {}", output.display(), x);
+					}
+				}
+
+				Err(x)
+			}
+		}
+	}
+}
+
+fn parse_args(args: &[String], field: &str, problem: &mut impl FnMut(String)) -> Vec<Template> {
+	let mut templates = Vec::new();
+	for (index, arg) in args.iter().enumerate() {
+		match arg.parse() {
+			Ok(x) => templates.push(x),
+			Err(x) => problem(format!("`{}` argument {} (`{}`): {}", field, index + 1, arg, x)),
+		}
+	}
+
+	templates
+}
+
+/// Runs the `tools` subcommand, returning whether it succeeded.
+pub async fn run(terminal: &mut Terminal, options: &ToolsOptions) -> bool {
+	let required = matches!(options.command, ToolsCommand::Validate);
+	let tools = match Tools::load(options.tools_file.as_deref(), required).await {
+		Ok(x) => x,
+		Err(x) => {
+			eprintln!("{}", x);
+			return false;
+		}
+	};
+
+	match options.command {
+		ToolsCommand::List => {
+			for tool in &tools.tools {
+				terminal.write_tool(tool);
+			}
+		}
+		ToolsCommand::Validate => {
+			if let Some(path) = &tools.path {
+				terminal.write_tools_valid(path, tools.len());
+			}
+		}
+	}
+
+	true
+}
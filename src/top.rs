@@ -0,0 +1,148 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::path::{Path, PathBuf};
+
+use crate::stats::Delta;
+
+/// Files which changed the most in a run, the `limit` largest savings and
+/// growths each, kept as they come in without holding on to the others
+#[derive(Clone, Debug, Default)]
+pub struct Top {
+	limit: usize,
+	/// Files seen so far, to rank the earlier of those changed as much first
+	seen: usize,
+	savings: BinaryHeap<Reverse<Entry>>,
+	growths: BinaryHeap<Reverse<Entry>>,
+}
+
+/// A file ranked by how much it changed, then by how early it came, so that
+/// the least of them is the one to drop
+#[derive(Clone, Debug)]
+struct Entry {
+	difference: u64,
+	order: Reverse<usize>,
+	path: PathBuf,
+	delta: Delta,
+}
+
+impl Entry {
+	fn rank(&self) -> (u64, Reverse<usize>) {
+		(self.difference, self.order)
+	}
+}
+
+impl PartialEq for Entry {
+	fn eq(&self, other: &Self) -> bool {
+		self.rank() == other.rank()
+	}
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Entry {
+	fn cmp(&self, other: &Self) -> Ordering {
+		self.rank().cmp(&other.rank())
+	}
+}
+
+impl Top {
+	pub fn new(limit: usize) -> Self {
+		Top { limit, ..Top::default() }
+	}
+
+	/// Whether there are files to show.
+	pub fn is_empty(&self) -> bool {
+		self.savings.is_empty() && self.growths.is_empty()
+	}
+
+	/// Ranks `file`, changed by `delta`; files which did not change at all
+	/// are not ranked.
+	pub fn add(&mut self, file: &Path, delta: Delta) {
+		self.seen += 1;
+		if self.limit == 0 || delta.difference() == 0 {
+			return;
+		}
+
+		let heap = if delta.is_smaller() { &mut self.savings } else { &mut self.growths };
+		let entry = Entry { difference: delta.difference(), order: Reverse(self.seen), path: file.into(), delta };
+		if heap.len() < self.limit {
+			heap.push(Reverse(entry));
+		} else if heap.peek().is_some_and(|Reverse(least)| entry > *least) {
+			heap.pop();
+			heap.push(Reverse(entry));
+		}
+	}
+
+	/// Files which shrunk the most, the most first.
+	pub fn savings(&self) -> Vec<(&Path, Delta)> {
+		Self::ranked(&self.savings)
+	}
+
+	/// Files which grew the most, the most first.
+	pub fn growths(&self) -> Vec<(&Path, Delta)> {
+		Self::ranked(&self.growths)
+	}
+
+	fn ranked(heap: &BinaryHeap<Reverse<Entry>>) -> Vec<(&Path, Delta)> {
+		let mut entries: Vec<_> = heap.iter().map(|Reverse(x)| x).collect();
+		entries.sort_by(|x, y| y.cmp(x));
+		entries.into_iter().map(|x| (x.path.as_path(), x.delta)).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::Top;
+	use crate::stats::Delta;
+
+	fn names(files: Vec<(&Path, Delta)>) -> Vec<&str> {
+		files.into_iter().map(|(x, _)| x.to_str().unwrap()).collect()
+	}
+
+	#[test]
+	fn keeps_largest_changes() {
+		let mut top = Top::new(2);
+		assert!(top.is_empty());
+		let files = [("a", 100, 90), ("b", 100, 20), ("c", 100, 150), ("d", 1000, 900), ("e", 10, 40)];
+		for (name, original, new) in files {
+			top.add(Path::new(name), Delta::new(original, new));
+		}
+
+		assert_eq!(names(top.savings()), ["d", "b"]);
+		assert_eq!(names(top.growths()), ["c", "e"]);
+		let (_, delta) = top.savings()[0];
+		assert_eq!((delta.original, delta.new), (1000, 900));
+	}
+
+	#[test]
+	fn ranks_ties_in_the_order_seen() {
+		let mut top = Top::new(3);
+		for name in ["a", "b", "c", "d", "e"] {
+			top.add(Path::new(name), Delta::new(100, 50));
+		}
+
+		assert_eq!(names(top.savings()), ["a", "b", "c"]);
+		top.add(Path::new("f"), Delta::new(100, 49));
+		assert_eq!(names(top.savings()), ["f", "a", "b"]);
+	}
+
+	#[test]
+	fn leaves_out_unchanged_files() {
+		let mut top = Top::new(5);
+		top.add(Path::new("a"), Delta::new(100, 100));
+		top.add(Path::new("b"), Delta::new(0, 0));
+		assert!(top.is_empty());
+
+		let mut top = Top::new(0);
+		top.add(Path::new("a"), Delta::new(100, 10));
+		assert!(top.is_empty());
+	}
+}
@@ -1,8 +1,34 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
 use tokio::fs;
-use tracing::{error, trace};
+use tracing::{debug, error, trace, warn};
+
+use crate::comment::Comment;
+use crate::context::Context;
+use crate::options::{OutputOptions, Streams, VideoFormat, VideoOptions};
+use crate::progress::Progress;
+use crate::temp;
 
-use crate::{comment::Comment, context::Context};
+#[derive(Clone, Debug)]
+pub struct Stream {
+	index: usize,
+	codec_type: String,
+	channels: u32,
+	attached_pic: bool,
+	frames: Option<u64>,
+	duration: Option<f64>,
+}
+
+impl Stream {
+	/// Cover art and thumbnails show up as video streams, but are not what the
+	/// file is about
+	fn is_main_video(&self) -> bool {
+		self.codec_type == "video" && !self.attached_pic
+	}
+}
 
 pub async fn get_comment(context: &mut Context, path: impl AsRef<Path>) -> Result<Option<Comment>, crate::Error> {
 	let path = path.as_ref();
@@ -11,17 +37,15 @@ pub async fn get_comment(context: &mut Context, path: impl AsRef<Path>) -> Resul
 		.args(["-hide_banner"])
 		.arg(path);
 
-	let output = gm.output().await?;
+	let output = context.output(gm).await?;
 	if !output.status.success() {
-		return Err(crate::Error::Invocation("ffprobe", output.status))
+		return Err(crate::Error::invocation("ffprobe", output.status, &output.stderr))
 	}
 
+	// tags of WebM and Matroska are upper case, those of MP4 lower case
 	let output = String::from_utf8_lossy(output.stderr.as_ref());
-	let Some(index) = output.find("COMMENT") else {
-		return Ok(None)
-	};
-
-	let Some((_, comment)) = output[index..].lines().next().map(str::trim).and_then(|i| i.split_once(':')) else {
+	let mut tags = output.lines().filter_map(|x| x.split_once(':'));
+	let Some((_, comment)) = tags.find(|(key, _)| key.trim().eq_ignore_ascii_case("comment")) else {
 		return Ok(None)
 	};
 
@@ -29,20 +53,519 @@ pub async fn get_comment(context: &mut Context, path: impl AsRef<Path>) -> Resul
 	comment.parse().map(Some).map_err(crate::Error::from)
 }
 
-pub async fn convert(context: &mut Context, comment: Comment, input: impl AsRef<Path>) -> Result<PathBuf, crate::Error> {
+/// Encoder settings, in the order they are tried with `--fallback`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Variant {
+	/// Whatever ffmpeg picks for VP9, or x265 for MP4
+	Default,
+	/// The same encoder, libvpx for VP9, with plain 8-bit 4:2:0, which copes
+	/// with pixel formats the default path chokes on
+	Yuv420p,
+}
+
+impl Variant {
+	pub const CHAIN: [Variant; 2] = [Variant::Default, Variant::Yuv420p];
+
+	/// Name of the variant encoding videos to `format`.
+	pub fn name(self, format: VideoFormat) -> &'static str {
+		match (self, format) {
+			(Variant::Default, _) => "ffmpeg",
+			(Variant::Yuv420p, VideoFormat::Mp4) => "ffmpeg with libx265 and yuv420p",
+			(Variant::Yuv420p, VideoFormat::Webm | VideoFormat::Mkv) => "ffmpeg with libvpx-vp9 and yuv420p",
+		}
+	}
+
+	fn codec_args(self, format: VideoFormat) -> &'static [&'static str] {
+		match (self, format) {
+			// tagged the way Apple players insist on
+			(Variant::Default, VideoFormat::Mp4) => &["-c:v", "libx265", "-tag:v", "hvc1"],
+			(Variant::Yuv420p, VideoFormat::Mp4) => &["-c:v", "libx265", "-pix_fmt", "yuv420p", "-tag:v", "hvc1"],
+			(Variant::Default, VideoFormat::Webm | VideoFormat::Mkv) => &["-c:v", "vp9", "-row-mt", "1"],
+			(Variant::Yuv420p, VideoFormat::Webm | VideoFormat::Mkv) => {
+				&["-c:v", "libvpx-vp9", "-pix_fmt", "yuv420p", "-row-mt", "1"]
+			}
+		}
+	}
+}
+
+/// Variant of the encoder, and how it is set up
+#[derive(Copy, Clone, Debug)]
+struct Encoder {
+	variant: Variant,
+	format: VideoFormat,
+	/// Constant rate factor, if not left to ffmpeg
+	crf: Option<u8>,
+}
+
+impl Encoder {
+	fn args(self) -> Vec<String> {
+		let mut args: Vec<_> = self.variant.codec_args(self.format).iter().map(|x| x.to_string()).collect();
+		if let Some(crf) = self.crf {
+			args.extend(["-crf".into(), crf.to_string()]);
+			if self.format != VideoFormat::Mp4 {
+				// constant quality, without a bitrate for libvpx to stay within
+				args.extend(["-b:v".into(), "0".into()]);
+			}
+		}
+
+		args
+	}
+
+	/// Whether a first pass makes the output any smaller: it does with
+	/// libvpx, but x265 gets to a constant rate factor just as well in one,
+	/// its passes only helping to hit a bitrate.
+	fn two_pass(self) -> bool {
+		self.format != VideoFormat::Mp4
+	}
+
+	/// Settings trading some compression for speed
+	fn quick_args(self) -> &'static [&'static str] {
+		match self.format {
+			VideoFormat::Webm | VideoFormat::Mkv => &Effort::QUICK_ARGS,
+			VideoFormat::Mp4 => &Effort::QUICK_X265_ARGS,
+		}
+	}
+
+	fn audio_args(self) -> &'static [&'static str] {
+		match self.format {
+			VideoFormat::Webm | VideoFormat::Mkv => &["-c:a", "opus", "-strict", "-2"],
+			VideoFormat::Mp4 => &["-c:a", "aac"],
+		}
+	}
+
+	/// Arguments writing the container.
+	fn muxer_args(self) -> &'static [&'static str] {
+		match self.format {
+			VideoFormat::Webm => &["-f", "webm"],
+			// the index up front, for playing while downloading
+			VideoFormat::Mp4 => &["-movflags", "+faststart", "-f", "mp4"],
+			VideoFormat::Mkv => &["-f", "matroska"],
+		}
+	}
+
+	fn suffix(self) -> String {
+		format!(".{}", self.format.extension())
+	}
+}
+
+/// How much CPU time to put into encoding a video
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Effort {
+	/// Two passes, for the smallest output at the quality
+	Full,
+	/// A single pass with faster settings, for files whose savings are not
+	/// worth the CPU time of two
+	Quick,
+}
+
+impl Effort {
+	/// Settings of libvpx trading some compression for speed
+	const QUICK_ARGS: [&'static str; 4] = ["-deadline", "good", "-cpu-used", "4"];
+	/// The same for x265
+	const QUICK_X265_ARGS: [&'static str; 2] = ["-preset", "fast"];
+}
+
+/// Longest video that is still considered a single frame, in seconds
+const STILL_DURATION: f64 = 0.2;
+/// Container of broadcast and camcorder captures, as ffprobe names it
+pub const MPEG_TS: &str = "mpegts";
+
+/// Entries of the streams ffprobe is asked for, in either format
+const STREAM_ENTRIES: &str =
+	"stream=index,codec_type,channels,nb_frames,duration:stream_disposition=attached_pic:stream_tags=DURATION";
+
+/// Streams of the file at `path`, read from the JSON ffprobe writes, or from
+/// its plain output if that is broken; if neither can be read, the file is
+/// converted without knowing its streams, as if it had none, which leaves out
+/// whatever depends on them rather than failing it.
+pub async fn probe_streams(context: &mut Context, path: impl AsRef<Path>) -> Result<Vec<Stream>, crate::Error> {
+	let path = path.as_ref();
+	let output = run_probe(context, path, "json").await?;
+	if let Some(streams) = parse_json_streams(&output) {
+		debug!("probed streams of `{}`: {:?}", path.display(), streams);
+		return Ok(streams);
+	}
+
+	warn!("ffprobe wrote broken JSON for `{}`; probing it again for plain output", path.display());
+	let streams = parse_streams(&run_probe(context, path, "compact=p=0").await?);
+	if streams.is_empty() {
+		warn!("no streams of `{}` could be probed; converting it without knowing them", path.display());
+	}
+
+	debug!("probed streams of `{}`: {:?}", path.display(), streams);
+	Ok(streams)
+}
+
+/// What ffprobe writes about the streams of `path` in `format`.
+async fn run_probe(context: &mut Context, path: &Path, format: &str) -> Result<String, crate::Error> {
+	let mut ffprobe = context.command("ffprobe")?;
+	ffprobe.args(["-v", "error", "-show_entries", STREAM_ENTRIES, "-of", format]).arg(path);
+
+	let output = context.output(ffprobe).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation("ffprobe", output.status, &output.stderr))
+	}
+
+	Ok(String::from_utf8_lossy(output.stdout.as_ref()).into_owned())
+}
+
+/// Name of the container of the file at `path`, as ffprobe gives it.
+pub async fn probe_container(context: &mut Context, path: impl AsRef<Path>) -> Result<String, crate::Error> {
+	let path = path.as_ref();
+	let mut ffprobe = context.command("ffprobe")?;
+	ffprobe
+		.args(["-v", "error", "-show_entries", "format=format_name", "-of", "default=nw=1:nk=1"])
+		.arg(path);
+
+	let output = context.output(ffprobe).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation("ffprobe", output.status, &output.stderr))
+	}
+
+	let container = String::from_utf8_lossy(output.stdout.as_ref()).trim().to_string();
+	debug!("probed container of `{}`: {}", path.display(), container);
+	Ok(container)
+}
+
+/// Copies the streams of the MPEG-TS capture `input` into a Matroska file in
+/// the temporary directory, to convert instead: captures often lack the
+/// timestamps encoders need, or start with broken packets, which make for
+/// wrong durations. The caller deletes it with [`remove_remux`].
+pub async fn remux(context: &mut Context, input: impl AsRef<Path>) -> Result<PathBuf, crate::Error> {
+	let input = input.as_ref();
+	let remuxed = temp::scratch_file(input, Some(".mkv".as_ref()));
+	let mut ffmpeg = context.command("ffmpeg")?;
+	ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-fflags", "+genpts", "-i"])
+		.arg(input)
+		.args(["-map", "0", "-c", "copy", "-f", "matroska"])
+		.arg(&remuxed);
+
+	debug!("remuxing `{}` into `{}`", input.display(), remuxed.display());
+	match context.run("ffmpeg", ffmpeg, input).await {
+		Ok(_) => Ok(remuxed),
+		Err(x) => {
+			remove_remux(&remuxed).await;
+			Err(x)
+		}
+	}
+}
+
+pub async fn remove_remux(remuxed: &Path) {
+	if !remuxed.exists() {
+		return;
+	}
+
+	trace!("deleting remuxed file `{}`...", remuxed.display());
+	if let Err(x) = fs::remove_file(remuxed).await {
+		error!("failed to delete remuxed file `{}`: {}", remuxed.display(), x);
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbedFile {
+	#[serde(default)]
+	streams: Vec<ProbedStream>,
+}
+
+/// Stream as `ffprobe -of json` lists it, which gives numbers it may not
+/// know as strings like `N/A`
+#[derive(Debug, Deserialize)]
+struct ProbedStream {
+	index: usize,
+	#[serde(default)]
+	codec_type: String,
+	#[serde(default)]
+	channels: u32,
+	nb_frames: Option<String>,
+	duration: Option<String>,
+	#[serde(default)]
+	disposition: BTreeMap<String, u8>,
+	#[serde(default)]
+	tags: BTreeMap<String, String>,
+}
+
+impl From<ProbedStream> for Stream {
+	fn from(probed: ProbedStream) -> Self {
+		let tagged_duration = probed.tags.get("DURATION").and_then(|x| parse_timestamp(x));
+		Stream {
+			index: probed.index,
+			codec_type: probed.codec_type,
+			channels: probed.channels,
+			attached_pic: probed.disposition.get("attached_pic") == Some(&1),
+			frames: probed.nb_frames.and_then(|x| x.parse().ok()),
+			// Matroska and WebM only have the duration of streams as a tag
+			duration: probed.duration.and_then(|x| x.parse().ok()).or(tagged_duration),
+		}
+	}
+}
+
+/// Parses streams as listed by `ffprobe -of json`, past any lines around the
+/// JSON, like warnings about broken files which make it to the standard
+/// output; `None` if it is broken, e.g. cut short.
+fn parse_json_streams(output: &str) -> Option<Vec<Stream>> {
+	let parsed = match serde_json::from_str::<ProbedFile>(output) {
+		Ok(x) => x,
+		Err(x) => {
+			debug!("failed to parse the JSON of ffprobe as is: {}", x);
+			let lines: Vec<_> = output.lines().collect();
+			let start = lines.iter().position(|x| x.starts_with('{'))?;
+			let end = lines.iter().rposition(|x| x.starts_with('}')).filter(|x| *x >= start)?;
+			match serde_json::from_str(&lines[start..=end].join("\n")) {
+				Ok(x) => x,
+				Err(x) => {
+					debug!("failed to parse the JSON of ffprobe between other lines: {}", x);
+					return None;
+				}
+			}
+		}
+	};
+
+	Some(parsed.streams.into_iter().map(Stream::from).collect())
+}
+
+/// Parses streams as listed by `ffprobe -of compact=p=0`.
+fn parse_streams(output: &str) -> Vec<Stream> {
+	let mut streams = Vec::new();
+	for line in output.lines() {
+		let mut stream = Stream {
+			index: 0,
+			codec_type: String::new(),
+			channels: 0,
+			attached_pic: false,
+			frames: None,
+			duration: None,
+		};
+		let mut has_index = false;
+		let mut tagged_duration = None;
+		for (key, value) in line.split('|').filter_map(|x| x.split_once('=')) {
+			match key {
+				"index" => {
+					stream.index = value.parse().unwrap_or_default();
+					has_index = true;
+				}
+				"codec_type" => stream.codec_type = value.to_string(),
+				"channels" => stream.channels = value.parse().unwrap_or_default(),
+				"disposition:attached_pic" => stream.attached_pic = value == "1",
+				"nb_frames" => stream.frames = value.parse().ok(),
+				"duration" => stream.duration = value.parse().ok(),
+				"tag:DURATION" => tagged_duration = parse_timestamp(value),
+				_ => {}
+			}
+		}
+
+		// Matroska and WebM only have the duration of streams as a tag
+		stream.duration = stream.duration.or(tagged_duration);
+		if has_index {
+			streams.push(stream);
+		}
+	}
+
+	streams
+}
+
+/// Parses a timestamp like `00:01:02.500000000`, in seconds.
+fn parse_timestamp(value: &str) -> Option<f64> {
+	let mut parts = value.trim().rsplit(':');
+	let seconds: f64 = parts.next()?.parse().ok()?;
+	let minutes: u64 = parts.next().map_or(Some(0), |x| x.parse().ok())?;
+	let hours: u64 = parts.next().map_or(Some(0), |x| x.parse().ok())?;
+	if parts.next().is_some() {
+		return None;
+	}
+
+	Some(((hours * 60 + minutes) * 60) as f64 + seconds)
+}
+
+/// Whether the video is just a still image, like screenshots saved as
+/// single-frame videos
+pub fn is_still(streams: &[Stream]) -> bool {
+	// a still image with a soundtrack is still a video
+	if streams.iter().any(|x| x.codec_type == "audio") {
+		return false;
+	}
+
+	let Some(video) = streams.iter().find(|x| x.is_main_video()) else {
+		return false;
+	};
+
+	video.frames == Some(1) || video.duration.is_some_and(|x| x < STILL_DURATION)
+}
+
+/// Length of the main video stream, in seconds.
+pub fn duration(streams: &[Stream]) -> Option<f64> {
+	streams.iter().find(|x| x.is_main_video()).and_then(|x| x.duration)
+}
+
+/// Drift in percent below which the output is deemed as long as the source
+const DRIFT: f64 = 0.5;
+/// Precision of the timestamps of Matroska and WebM, which round durations
+const TIMESTAMP_PRECISION: f64 = 0.001;
+
+/// How far the main video stream of an output strays from that of its source,
+/// which typically means frames were dropped on decoding errors, or that a
+/// variable frame rate was mishandled
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Drift {
+	/// Duration of the output minus that of the source, in seconds
+	pub duration: f64,
+	/// Frames of the output minus those of the source, if both are known
+	pub frames: Option<i64>,
+	/// Largest of the two, relative to the source, in percent
+	pub percent: f64,
+}
+
+impl fmt::Display for Drift {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "duration {:+.1} s", self.duration)?;
+		if let Some(frames) = self.frames.filter(|x| *x != 0) {
+			write!(f, ", {:+} frames", frames)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Drift of `output` from `source`, if noticeable.
+///
+/// Differences the containers account for are ignored: up to one frame, as
+/// the last one may or may not be counted in, and the rounding of timestamps.
+pub fn drift(source: &[Stream], output: &[Stream]) -> Option<Drift> {
+	let source = source.iter().find(|x| x.is_main_video())?;
+	let output = output.iter().find(|x| x.is_main_video())?;
+	let source_duration = source.duration.filter(|x| *x > 0.0)?;
+	let frame = source.frames.filter(|x| *x > 0).map_or(0.0, |x| source_duration / x as f64);
+
+	let mut duration = output.duration? - source_duration;
+	if duration.abs() <= frame + TIMESTAMP_PRECISION {
+		duration = 0.0;
+	}
+
+	let frames = source.frames.zip(output.frames).filter(|(source, _)| *source > 0);
+	let frames = frames.map(|(source, output)| (output as i64 - source as i64, source));
+	let frames = frames.map(|(x, source)| if x.abs() <= 1 { (0, source) } else { (x, source) });
+
+	let percent = frames.map_or(0.0, |(x, source)| x.abs() as f64 / source as f64 * 100.0);
+	let percent = percent.max(duration.abs() / source_duration * 100.0);
+	(percent > DRIFT).then_some(Drift { duration, frames: frames.map(|(x, _)| x), percent })
+}
+
+/// Main video stream and audio streams to keep, unless raw specifiers were
+/// given.
+fn select_streams<'a>(options: &VideoOptions, streams: &'a [Stream]) -> (Option<&'a Stream>, Vec<&'a Stream>) {
+	// cover art and thumbnails must never be picked as the main stream
+	let video = streams.iter().find(|x| x.is_main_video());
+	let mut audio: Vec<_> = streams.iter().filter(|x| x.codec_type == "audio").collect();
+	if matches!(options.streams, Streams::Best) {
+		// same heuristic as ffmpeg's own selection: the most channels wins
+		let best = audio.iter().copied().rev().max_by_key(|x| x.channels);
+		audio = best.into_iter().collect();
+	}
+
+	(video, audio)
+}
+
+fn map_args(options: &VideoOptions, streams: &[Stream]) -> Vec<String> {
+	if let Streams::Map(specs) = &options.streams {
+		return specs.iter().flat_map(|x| ["-map".to_string(), x.clone()]).collect();
+	}
+
+	let (video, audio) = select_streams(options, streams);
+	let mut args = Vec::new();
+	for stream in video.into_iter().chain(audio) {
+		args.push("-map".to_string());
+		args.push(format!("0:{}", stream.index));
+	}
+
+	args
+}
+
+/// Converts the video `input`, read from `source`: the file itself, or a
+/// [`remux`] of it, whose `streams` these are, with `effort`.
+#[allow(clippy::too_many_arguments)]
+pub async fn convert(
+	context: &mut Context, options: &OutputOptions, video_options: &VideoOptions, streams: &[Stream], comment: Comment,
+	input: impl AsRef<Path>, source: impl AsRef<Path>, variant: Variant, effort: Effort,
+) -> Result<PathBuf, crate::Error> {
 	let input = input.as_ref();
-	let output = context.get_output_file(input, ".webm").await?;
-	let log_file = context.get_output_file(input, "").await?;
+	let source = source.as_ref();
+	let encoder = Encoder { variant, format: video_options.video_format, crf: video_options.crf };
+	options.check_extension(encoder.format.extension())?;
+	if video_options.segment_encode && effort == Effort::Full {
+		if let Some(plan) = plan_segments(context, video_options, streams, source).await? {
+			return convert_segments(context, options, &plan, comment, input, source, encoder).await;
+		}
+	}
+
+	let maps = map_args(video_options, streams);
+	let output = context.get_output_file(options, input, encoder.suffix()).await?;
 	let metadata = format!("comment={}", comment);
+	let progress = Progress::new(duration(streams));
+	let result = match effort {
+		Effort::Full if encoder.two_pass() => {
+			convert_twice(context, &maps, encoder, metadata, progress, input, source, &output).await
+		}
+		_ => {
+			let mut ffmpeg = context.command("ffmpeg")?;
+			ffmpeg.args(["-hide_banner", "-loglevel", "error"])
+				.args(Progress::args(progress))
+				.args(["-y", "-i"])
+				.arg(source)
+				.args(&maps)
+				.args(encoder.args());
+			if effort == Effort::Quick {
+				debug!("`{}` is small; encoding it in a single pass", input.display());
+				ffmpeg.args(encoder.quick_args());
+			}
+
+			ffmpeg.args(encoder.audio_args())
+				.args(["-map_metadata", "-1", "-metadata"])
+				.arg(metadata)
+				.args(encoder.muxer_args())
+				.arg(&output);
+
+			context.run_ffmpeg(ffmpeg, input, progress).await.map(drop)
+		}
+	};
+
+	match result {
+		Ok(()) => Ok(output),
+		Err(x) => {
+			if output.exists() {
+				trace!("error raised; deleting output file `{}`...", output.display());
+				if let Err(x) = fs::remove_file(&output).await {
+					error!("failed to delete output file `{}`: {}", output.display(), x);
+				}
+			}
+
+			Err(x)
+		}
+	}
+}
+
+/// Encodes `source` into `output` in two passes, the first one only writing
+/// the pass log.
+#[allow(clippy::too_many_arguments)]
+async fn convert_twice(
+	context: &mut Context, maps: &[String], encoder: Encoder, metadata: String, progress: Option<Progress>,
+	input: &Path, source: &Path, output: &Path,
+) -> Result<(), crate::Error> {
+	// the pass log is never wanted next to the output, which may well be on a
+	// slow share or even lack an extension to tell it apart
+	let log_file = temp::scratch_file(input, None);
 
 	let mut ffmpeg = context.command("ffmpeg")?;
-	ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
-		.arg(input)
-		.args(["-c:v", "vp9", "-an", "-sn", "-strict", "-2", "-row-mt", "1", "-pass", "1", "-passlogfile"])
+	ffmpeg.args(["-hide_banner", "-loglevel", "error"])
+		.args(Progress::args(progress))
+		.args(["-y", "-i"])
+		.arg(source)
+		.args(maps)
+		.args(encoder.args())
+		.args(["-an", "-sn", "-strict", "-2", "-pass", "1", "-passlogfile"])
 		.arg(&log_file)
 		.args(["-f", "null", "-"]);
 
-	if let Err(x) = context.run(ffmpeg, input).await {
+	// each pass reads the whole file, so takes about half of the time
+	if let Err(x) = context.run_ffmpeg(ffmpeg, input, progress.map(|x| x.pass(0, 2))).await {
 		let log_file = full_log_file_name(log_file);
 		if log_file.exists() {
 			trace!("error raised; deleting pass log file `{}`...", log_file.display());
@@ -55,24 +578,112 @@ pub async fn convert(context: &mut Context, comment: Comment, input: impl AsRef<
 	}
 
 	let mut ffmpeg = context.command("ffmpeg")?;
-	ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
-		.arg(input)
-		.args(["-c:v", "vp9", "-c:a", "opus", "-strict", "-2", "-row-mt", "1", "-map_metadata", "-1", "-metadata"])
+	ffmpeg.args(["-hide_banner", "-loglevel", "error"])
+		.args(Progress::args(progress))
+		.args(["-y", "-i"])
+		.arg(source)
+		.args(maps)
+		.args(encoder.args())
+		.args(encoder.audio_args())
+		.args(["-map_metadata", "-1", "-metadata"])
 		.arg(metadata)
 		.args(["-pass", "2", "-passlogfile"])
 		.arg(&log_file)
-		.args(["-f", "webm"])
-		.arg(&output);
+		.args(encoder.muxer_args())
+		.arg(output);
 
-	let result = context.run(ffmpeg, input).await;
+	let result = context.run_ffmpeg(ffmpeg, input, progress.map(|x| x.pass(1, 2))).await;
 	let log_file = full_log_file_name(log_file);
 	trace!("deleting pass log file `{}`...", log_file.display());
 	if let Err(x) = fs::remove_file(&log_file).await {
 		error!("failed to delete pass log file `{}`: {}", log_file.display(), x);
 	}
 
+	result.map(drop)
+}
+
+/// How to split a video for encoding it in parallel
+#[derive(Clone, Debug)]
+struct SegmentPlan {
+	video: usize,
+	audio: Vec<usize>,
+	/// Keyframes to cut at, in seconds
+	cuts: Vec<f64>,
+}
+
+/// Picks keyframes splitting the video into as many similarly long segments
+/// as tools may run at once, if it is worth splitting at all.
+async fn plan_segments(
+	context: &mut Context, options: &VideoOptions, streams: &[Stream], input: &Path,
+) -> Result<Option<SegmentPlan>, crate::Error> {
+	let (Some(video), audio) = select_streams(options, streams) else {
+		return Ok(None);
+	};
+
+	let count = context.jobs();
+	let duration = video.duration.unwrap_or_default();
+	if matches!(options.streams, Streams::Map(_)) || count < 2 || duration <= 0.0 {
+		debug!("not splitting `{}` into segments", input.display());
+		return Ok(None);
+	}
+
+	let mut ffprobe = context.command("ffprobe")?;
+	ffprobe
+		.args(["-v", "error", "-select_streams"])
+		.arg(format!("{}", video.index))
+		.args(["-skip_frame", "nokey", "-show_entries", "frame=pts_time", "-of", "csv=p=0"])
+		.arg(input);
+
+	let output = context.output(ffprobe).await?;
+	if !output.status.success() {
+		return Err(crate::Error::invocation("ffprobe", output.status, &output.stderr));
+	}
+
+	let output = String::from_utf8_lossy(&output.stdout);
+	let keyframes: Vec<f64> = output.lines().filter_map(|x| x.trim().trim_end_matches(',').parse().ok()).collect();
+
+	// the first keyframe at or after each even split
+	let mut cuts: Vec<f64> = Vec::new();
+	for index in 1..count {
+		let target = duration * index as f64 / count as f64;
+		let Some(cut) = keyframes.iter().copied().find(|x| *x >= target && *x > 0.0 && *x < duration) else {
+			continue;
+		};
+
+		if cuts.last().is_none_or(|x| *x < cut) {
+			cuts.push(cut);
+		}
+	}
+
+	debug!("splitting `{}` at {:?} out of {} keyframes", input.display(), cuts, keyframes.len());
+	if cuts.is_empty() {
+		return Ok(None);
+	}
+
+	let audio = audio.into_iter().map(|x| x.index).collect();
+	Ok(Some(SegmentPlan { video: video.index, audio, cuts }))
+}
+
+/// Encodes the video of `input`, read from `source`, in segments split as
+/// planned, concatenating them along with the audio encoded in one go, which
+/// avoids artifacts at the segment boundaries.
+async fn convert_segments(
+	context: &mut Context, options: &OutputOptions, plan: &SegmentPlan, comment: Comment, input: &Path,
+	source: &Path, encoder: Encoder,
+) -> Result<PathBuf, crate::Error> {
+	let output = context.get_output_file(options, input, encoder.suffix()).await?;
+	let dir = temp::scratch_file(input, None);
+	trace!("creating segment directory `{}`", dir.display());
+	fs::create_dir(&dir).await?;
+
+	let result = encode_segments(context, plan, comment, input, source, encoder, &dir, &output).await;
+	trace!("deleting segment directory `{}`...", dir.display());
+	if let Err(x) = fs::remove_dir_all(&dir).await {
+		error!("failed to delete segment directory `{}`: {}", dir.display(), x);
+	}
+
 	match result {
-		Ok(_) => Ok(output),
+		Ok(()) => Ok(output),
 		Err(x) => {
 			if output.exists() {
 				trace!("error raised; deleting output file `{}`...", output.display());
@@ -86,8 +697,386 @@ pub async fn convert(context: &mut Context, comment: Comment, input: impl AsRef<
 	}
 }
 
+#[allow(clippy::too_many_arguments)]
+async fn encode_segments(
+	context: &mut Context, plan: &SegmentPlan, comment: Comment, input: &Path, source: &Path, encoder: Encoder,
+	dir: &Path, output: &Path,
+) -> Result<(), crate::Error> {
+	let cuts: Vec<_> = plan.cuts.iter().map(|x| format!("{:.6}", x)).collect();
+	let mut ffmpeg = context.command("ffmpeg")?;
+	ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+		.arg(source)
+		.args(["-map", &format!("0:{}", plan.video), "-c", "copy", "-f", "segment", "-segment_times"])
+		.arg(cuts.join(","))
+		.args(["-reset_timestamps", "1"])
+		.arg(dir.join("segment%03d.mkv"));
+	context.run("ffmpeg", ffmpeg, input).await?;
+
+	let mut segments = Vec::new();
+	let mut entries = fs::read_dir(dir).await?;
+	while let Some(entry) = entries.next_entry().await? {
+		if entry.file_name().to_string_lossy().starts_with("segment") {
+			segments.push(entry.path());
+		}
+	}
+
+	segments.sort();
+	debug!("encoding {} segments of `{}`", segments.len(), input.display());
+
+	// named apart from the segments, which are Matroska themselves
+	let encoded = |segment: &Path| segment.with_extension(format!("out.{}", encoder.format.extension()));
+	if encoder.two_pass() {
+		let mut first_pass = Vec::new();
+		for segment in &segments {
+			let mut ffmpeg = context.command("ffmpeg")?;
+			ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+				.arg(segment)
+				.args(encoder.args())
+				.args(["-an", "-sn", "-strict", "-2", "-pass", "1", "-passlogfile"])
+				.arg(segment.with_extension(""))
+				.args(["-f", "null", "-"]);
+			first_pass.push(ffmpeg);
+		}
+
+		context.run_all("ffmpeg", first_pass, input).await?;
+	}
+
+	let mut second_pass = Vec::new();
+	for segment in &segments {
+		let mut ffmpeg = context.command("ffmpeg")?;
+		ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+			.arg(segment)
+			.args(encoder.args())
+			.args(["-an", "-sn"]);
+		if encoder.two_pass() {
+			ffmpeg.args(["-strict", "-2", "-pass", "2", "-passlogfile"]).arg(segment.with_extension(""));
+		}
+
+		ffmpeg.args(encoder.muxer_args()).arg(encoded(segment));
+		second_pass.push(ffmpeg);
+	}
+
+	let audio = dir.join(format!("audio{}", encoder.suffix()));
+	if !plan.audio.is_empty() {
+		let mut ffmpeg = context.command("ffmpeg")?;
+		ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-i"]).arg(source);
+		for index in &plan.audio {
+			ffmpeg.args(["-map", &format!("0:{}", index)]);
+		}
+
+		ffmpeg.args(["-vn", "-sn"]).args(encoder.audio_args()).args(encoder.muxer_args()).arg(&audio);
+		second_pass.push(ffmpeg);
+	}
+
+	context.run_all("ffmpeg", second_pass, input).await?;
+
+	// the concat demuxer reads paths relative to the list, quoted like in a
+	// shell
+	let list = dir.join("segments.txt");
+	let mut entries = String::new();
+	for segment in &segments {
+		let name = encoded(segment);
+		let name = name.file_name().unwrap_or_default().to_string_lossy().replace('\'', "'\\''");
+		entries.push_str(&format!("file '{}'\n", name));
+	}
+
+	fs::write(&list, entries).await?;
+
+	let metadata = format!("comment={}", comment);
+	let mut ffmpeg = context.command("ffmpeg")?;
+	ffmpeg.args(["-hide_banner", "-loglevel", "error", "-y", "-f", "concat", "-safe", "0", "-i"]).arg(&list);
+	if !plan.audio.is_empty() {
+		ffmpeg.arg("-i").arg(&audio).args(["-map", "0:v", "-map", "1:a"]);
+	}
+
+	ffmpeg.args(["-c", "copy", "-map_metadata", "-1", "-metadata"])
+		.arg(metadata)
+		.args(encoder.muxer_args())
+		.arg(output);
+	context.run("ffmpeg", ffmpeg, input).await?;
+	Ok(())
+}
+
 fn full_log_file_name(path: PathBuf) -> PathBuf {
 	let mut path = path.into_os_string();
 	path.push("-0.log");
 	PathBuf::from(path)
 }
+
+#[cfg(test)]
+mod tests {
+	use clap::Parser;
+
+	use super::{drift, map_args, parse_json_streams, parse_streams, parse_timestamp, Encoder, Stream, Variant};
+	use crate::options::{Options, VideoFormat};
+
+	const SOURCE: &str = "index=0|codec_type=video|channels=N/A|nb_frames=1500|duration=60.060000|\
+		disposition:attached_pic=0\n\
+		index=1|codec_type=audio|channels=2|nb_frames=2813|duration=60.010667|disposition:attached_pic=0";
+
+	/// As WebM has it, without a frame count and with the duration as a tag
+	fn webm(duration: &str) -> String {
+		format!(
+			"index=0|codec_type=video|channels=N/A|nb_frames=N/A|duration=N/A|disposition:attached_pic=0|\
+			tag:DURATION={}\n\
+			index=1|codec_type=audio|channels=2|nb_frames=N/A|duration=N/A|disposition:attached_pic=0|\
+			tag:DURATION=00:01:00.010000000",
+			duration
+		)
+	}
+
+	/// Cover art, the main video, stereo and surround audio, then a data
+	/// stream and subtitles, as found in files from phones and cameras
+	const STREAMS: &str = "index=0|codec_type=video|channels=N/A|nb_frames=1|duration=N/A|disposition:attached_pic=1\n\
+		index=1|codec_type=video|channels=N/A|nb_frames=1500|duration=60.060000|disposition:attached_pic=0\n\
+		index=2|codec_type=audio|channels=2|nb_frames=2813|duration=60.010667|disposition:attached_pic=0\n\
+		index=3|codec_type=audio|channels=6|nb_frames=2813|duration=60.010667|disposition:attached_pic=0\n\
+		index=4|codec_type=data|channels=N/A|nb_frames=N/A|duration=60.060000|disposition:attached_pic=0\n\
+		index=5|codec_type=subtitle|channels=N/A|nb_frames=N/A|duration=N/A|disposition:attached_pic=0";
+
+	fn maps(streams: &str, args: &[&str]) -> Vec<String> {
+		let options = Options::try_parse_from(["shrink-ray"].iter().chain(args).chain(&["a.mp4"])).unwrap();
+		map_args(&options.video, &parse_streams(streams))
+	}
+
+	#[test]
+	fn maps_main_video_and_best_audio() {
+		assert_eq!(maps(STREAMS, &[]), ["-map", "0:1", "-map", "0:3"]);
+		assert_eq!(maps(STREAMS, &["--streams", "best"]), ["-map", "0:1", "-map", "0:3"]);
+	}
+
+	#[test]
+	fn maps_every_audio_stream_with_all() {
+		assert_eq!(maps(STREAMS, &["--streams", "all"]), ["-map", "0:1", "-map", "0:2", "-map", "0:3"]);
+	}
+
+	#[test]
+	fn never_maps_attached_pictures_or_data() {
+		for args in [&[][..], &["--streams", "all"]] {
+			let maps = maps(STREAMS, args);
+			for excluded in ["0:0", "0:4", "0:5"] {
+				assert!(!maps.iter().any(|x| x == excluded), "{:?}", maps);
+			}
+		}
+
+		// nothing but cover art and data leaves only the audio
+		let streams = "index=0|codec_type=video|channels=N/A|nb_frames=1|duration=N/A|disposition:attached_pic=1\n\
+			index=1|codec_type=audio|channels=2|nb_frames=2813|duration=60.010667|disposition:attached_pic=0\n\
+			index=2|codec_type=data|channels=N/A|nb_frames=N/A|duration=60.060000|disposition:attached_pic=0";
+		assert_eq!(maps(streams, &["--streams", "all"]), ["-map", "0:1"]);
+	}
+
+	#[test]
+	fn maps_specifiers_as_given() {
+		assert_eq!(maps(STREAMS, &["--streams", "map:0:v:0, 0:a"]), ["-map", "0:v:0", "-map", "0:a"]);
+	}
+
+	#[test]
+	fn parses_timestamps() {
+		assert_eq!(parse_timestamp("00:01:00.060000000"), Some(60.06));
+		assert_eq!(parse_timestamp("01:00:02.5"), Some(3602.5));
+		assert_eq!(parse_timestamp("2.5"), Some(2.5));
+		assert_eq!(parse_timestamp("N/A"), None);
+		assert_eq!(parse_timestamp("1:2:3:4"), None);
+	}
+
+	#[test]
+	fn ignores_container_rounding() {
+		let source = parse_streams(SOURCE);
+		assert_eq!(drift(&source, &parse_streams(&webm("00:01:00.060000000"))), None);
+		// rounded to the millisecond, and one frame short
+		assert_eq!(drift(&source, &parse_streams(&webm("00:01:00.021000000"))), None);
+
+		let output = SOURCE.replace("nb_frames=1500", "nb_frames=1499").replace("60.060000", "60.020000");
+		assert_eq!(drift(&source, &parse_streams(&output)), None);
+	}
+
+	#[test]
+	fn reports_drift() {
+		let source = parse_streams(SOURCE);
+		let found = drift(&source, &parse_streams(&webm("00:00:58.260000000"))).unwrap();
+		assert!((found.duration + 1.8).abs() < 1e-6, "{:?}", found);
+		assert_eq!(found.frames, None);
+		assert!((found.percent - 2.997).abs() < 0.001, "{:?}", found);
+		assert_eq!(found.to_string(), "duration -1.8 s");
+
+		// frames dropped, with the timestamps of the others kept
+		let output = SOURCE.replace("nb_frames=1500", "nb_frames=1455");
+		let found = drift(&source, &parse_streams(&output)).unwrap();
+		assert_eq!(found.frames, Some(-45));
+		assert_eq!(found.duration, 0.0);
+		assert_eq!(found.to_string(), "duration +0.0 s, -45 frames");
+
+		// nothing to compare against
+		let still = "index=0|codec_type=video|channels=N/A|nb_frames=N/A|duration=N/A|disposition:attached_pic=0";
+		assert_eq!(drift(&parse_streams(still), &parse_streams(&webm("00:00:58.260000000"))), None);
+	}
+
+	/// Outputs of `ffprobe -of json` captured off files in the wild
+	mod captured {
+		/// A plain MP4
+		pub const CLEAN: &str = r#"{
+    "programs": [
+
+    ],
+    "streams": [
+        {
+            "index": 0,
+            "codec_type": "video",
+            "nb_frames": "1500",
+            "duration": "60.060000",
+            "disposition": {
+                "attached_pic": 0
+            }
+        },
+        {
+            "index": 1,
+            "codec_type": "audio",
+            "channels": 2,
+            "nb_frames": "2813",
+            "duration": "60.010667",
+            "disposition": {
+                "attached_pic": 0
+            }
+        }
+    ]
+}
+"#;
+
+		/// A WebM, with the durations as tags only
+		pub const MATROSKA: &str = r#"{
+    "programs": [
+
+    ],
+    "streams": [
+        {
+            "index": 0,
+            "codec_type": "video",
+            "disposition": {
+                "attached_pic": 0
+            },
+            "tags": {
+                "DURATION": "00:01:00.060000000"
+            }
+        }
+    ]
+}
+"#;
+
+		/// AAC wrapped in ID3 tags, whose demuxer warns on the standard output
+		pub const ID3_AAC: &str = r#"[mp3 @ 0x55d0c8a4e2c0] Skipping 417 bytes of junk at 0.
+{
+    "programs": [
+
+    ],
+    "streams": [
+        {
+            "index": 0,
+            "codec_type": "audio",
+            "channels": 2,
+            "nb_frames": "N/A",
+            "duration": "N/A",
+            "disposition": {
+                "attached_pic": 0
+            }
+        },
+        {
+            "index": 1,
+            "codec_type": "video",
+            "nb_frames": "1",
+            "disposition": {
+                "attached_pic": 1
+            }
+        }
+    ]
+}
+[aac @ 0x55d0c8a51a80] Number of bands (21) exceeds limit (14).
+"#;
+
+		/// An MP4 only partly downloaded, ffprobe giving up halfway
+		pub const TRUNCATED: &str = r#"{
+    "programs": [
+
+    ],
+    "streams": [
+        {
+            "index": 0,
+            "codec_type": "video",
+            "nb_fr"#;
+
+		/// Warnings in the middle of the JSON, which no stripping gets rid of
+		pub const INTERLEAVED: &str = r#"{
+    "programs": [
+
+    ],
+    "streams": [
+[h264 @ 0x5631b7f1c440] non-existing PPS 0 referenced
+        {
+            "index": 0,
+            "codec_type": "video",
+            "nb_frames": "250",
+            "duration": "10.000000"
+        }
+    ]
+}
+"#;
+	}
+
+	/// Index, type, channels, whether it is cover art, frames and duration
+	type Summary<'a> = (usize, &'a str, u32, bool, Option<u64>, Option<f64>);
+
+	fn summary(streams: &[Stream]) -> Vec<Summary<'_>> {
+		streams.iter().map(|x| (x.index, &*x.codec_type, x.channels, x.attached_pic, x.frames, x.duration)).collect()
+	}
+
+	#[test]
+	fn parses_json_streams() {
+		let streams = parse_json_streams(captured::CLEAN).unwrap();
+		assert_eq!(summary(&streams)[0], (0, "video", 0, false, Some(1500), Some(60.06)));
+		assert_eq!(summary(&streams)[1], (1, "audio", 2, false, Some(2813), Some(60.010667)));
+		// the same as from the plain output
+		assert_eq!(summary(&streams), summary(&parse_streams(SOURCE)));
+
+		let streams = parse_json_streams(captured::MATROSKA).unwrap();
+		assert_eq!(summary(&streams), [(0, "video", 0, false, None, Some(60.06))]);
+		assert_eq!(summary(&parse_json_streams("{}").unwrap()), []);
+	}
+
+	#[test]
+	fn parses_json_streams_between_warnings() {
+		let streams = parse_json_streams(captured::ID3_AAC).unwrap();
+		assert_eq!(summary(&streams), [(0, "audio", 2, false, None, None), (1, "video", 0, true, Some(1), None)]);
+	}
+
+	#[test]
+	fn gives_up_on_broken_json() {
+		assert!(parse_json_streams(captured::TRUNCATED).is_none());
+		assert!(parse_json_streams(captured::INTERLEAVED).is_none());
+		assert!(parse_json_streams("").is_none());
+		assert!(parse_json_streams("[mov,mp4,m4a,3gp,3g2,mj2 @ 0x5581] moov atom not found\n").is_none());
+		// and so does the plain parser, leaving the streams unknown
+		assert_eq!(summary(&parse_streams(captured::TRUNCATED)), []);
+	}
+
+	#[test]
+	fn encodes_to_each_format() {
+		let encoder = |format| Encoder { variant: Variant::Default, format, crf: Some(30) };
+		assert_eq!(encoder(VideoFormat::Webm).args(), ["-c:v", "vp9", "-row-mt", "1", "-crf", "30", "-b:v", "0"]);
+		assert_eq!(encoder(VideoFormat::Mkv).args(), encoder(VideoFormat::Webm).args());
+		assert_eq!(encoder(VideoFormat::Mp4).args(), ["-c:v", "libx265", "-tag:v", "hvc1", "-crf", "30"]);
+		assert_eq!(encoder(VideoFormat::Webm).muxer_args(), ["-f", "webm"]);
+		assert_eq!(encoder(VideoFormat::Mkv).muxer_args(), ["-f", "matroska"]);
+		assert_eq!(encoder(VideoFormat::Mp4).muxer_args(), ["-movflags", "+faststart", "-f", "mp4"]);
+		assert_eq!(encoder(VideoFormat::Mp4).audio_args(), ["-c:a", "aac"]);
+		assert_eq!(encoder(VideoFormat::Mkv).suffix(), ".mkv");
+	}
+
+	#[test]
+	fn encodes_only_vp9_in_two_passes() {
+		let encoder = |format| Encoder { variant: Variant::Yuv420p, format, crf: None };
+		assert!(encoder(VideoFormat::Webm).two_pass());
+		assert!(encoder(VideoFormat::Mkv).two_pass());
+		assert!(!encoder(VideoFormat::Mp4).two_pass());
+		assert_eq!(encoder(VideoFormat::Mp4).args(), ["-c:v", "libx265", "-pix_fmt", "yuv420p", "-tag:v", "hvc1"]);
+	}
+}
@@ -0,0 +1,3396 @@
+#![cfg(target_family = "unix")]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use common::{stdout, Sandbox};
+
+#[test]
+fn shrinks_image_in_place() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let output = sandbox.run(&["a.jpg"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk a.jpg"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+	assert!(sandbox.size("a.jpg") < original);
+}
+
+#[test]
+fn shrinks_video_into_webm() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+
+	let output = sandbox.run(&["a.mp4"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk a.mp4"));
+	assert_eq!(sandbox.files(), ["a.webm"]);
+}
+
+#[test]
+fn encodes_videos_at_crf_given() {
+	let sandbox = Sandbox::new();
+	let log = sandbox.path("args.log");
+	let comment = |args: &[&str]| {
+		sandbox.mp4("a.mp4");
+		let output = sandbox.command().args(args).arg("a.mp4").env("MOCK_ARGS_LOG", &log).output().unwrap();
+		assert!(output.status.success(), "{}", stdout(&output));
+		let logged = fs::read_to_string(&log).unwrap();
+		let passes: Vec<_> = logged.lines().filter(|x| x.contains(" -pass ")).map(String::from).collect();
+		fs::remove_file(&log).unwrap();
+		fs::remove_file(sandbox.path("a.webm")).unwrap();
+		assert_eq!(passes.len(), 2);
+		let comment: Vec<_> = passes[1].split("-metadata comment=").nth(1).unwrap().split(' ').take(2).collect();
+		let comment = comment.join(" ");
+		(passes, comment)
+	};
+
+	let (passes, given) = comment(&["--crf", "30"]);
+	assert!(passes.iter().all(|x| x.contains(" -crf 30 -b:v 0 ")), "{:?}", passes);
+	let (passes, default) = comment(&[]);
+	assert!(passes.iter().all(|x| !x.contains("-crf")), "{:?}", passes);
+	// so that a later run tells them apart
+	assert_ne!(given, default);
+	assert_eq!(comment(&["--video-quality", "30"]).1, given);
+
+	assert_eq!(sandbox.run(&["--crf", "64", "a.mp4"]).status.code(), Some(2));
+}
+
+#[test]
+fn converts_videos_despite_broken_probes() {
+	for (probe, warning) in [
+		("noisy", None),
+		("truncated", Some("ffprobe wrote broken JSON for `a.mp4`; probing it again for plain output")),
+		("broken", Some("no streams of `a.mp4` could be probed; converting it without knowing them")),
+	] {
+		let sandbox = Sandbox::new();
+		sandbox.mp4("a.mp4");
+		let log = sandbox.path("args.log");
+
+		let mut command = sandbox.command();
+		command.arg("a.mp4").env("MOCK_PROBE", probe).env("MOCK_ARGS_LOG", &log).env("RUST_LOG", "warn");
+		let output = command.output().unwrap();
+		let shown = stdout(&output);
+		assert!(output.status.success(), "{}: {}", probe, shown);
+		assert!(shown.contains("Shrunk a.mp4"), "{}: {}", probe, shown);
+
+		let errors = String::from_utf8_lossy(&output.stderr);
+		match warning {
+			Some(x) => assert!(errors.contains(x), "{}: {}", probe, errors),
+			None => assert!(!errors.contains("WARN"), "{}: {}", probe, errors),
+		}
+
+		// the streams picked where known, ffmpeg left to pick them otherwise
+		let passes = fs::read_to_string(&log).unwrap();
+		let pass = passes.lines().find(|x| x.contains(" -pass 2 ")).unwrap();
+		assert_eq!(pass.contains(" -map 0:0 -map 0:1 "), probe != "broken", "{}: {}", probe, pass);
+	}
+}
+
+#[test]
+fn reports_progress_of_videos_of_known_length() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	sandbox.mp4("b.mp4");
+
+	let output = sandbox.command().arg("a.mp4").env("MOCK_ARGS_LOG", sandbox.path("args.log")).output().unwrap();
+	let shown = stdout(&output);
+	assert!(output.status.success(), "{}", shown);
+	assert!(!shown.contains("out_time_ms") && !shown.contains("progress="), "{}", shown);
+	let log = fs::read_to_string(sandbox.path("args.log")).unwrap();
+	assert_eq!(log.matches(" -progress pipe:1 -nostats ").count(), 2, "{}", log);
+
+	// just the animation without a length to go by
+	let output = sandbox
+		.command()
+		.arg("b.mp4")
+		.env("MOCK_DURATION", "N/A")
+		.env("MOCK_ARGS_LOG", sandbox.path("unknown.log"))
+		.output()
+		.unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	let log = fs::read_to_string(sandbox.path("unknown.log")).unwrap();
+	assert!(log.contains(" -pass 2 ") && !log.contains("-progress"), "{}", log);
+}
+
+#[test]
+fn keeps_grown_output_by_default() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let output = sandbox.command().arg("a.jpg").env("MOCK_MODE", "grow").output().unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Grew a.jpg"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+	assert!(sandbox.size("a.jpg") > original);
+}
+
+#[test]
+fn discards_grown_output_with_no_grow() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let output = sandbox.command().args(["--no-grow", "a.jpg"]).env("MOCK_MODE", "grow").output().unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Grew a.jpg"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn skips_already_converted() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mark_converted("a.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let output = sandbox.run(&["-s", "a.jpg"]);
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Skipped a.jpg (file already converted by shrink-ray 0.1.0; --force converts it again)"));
+	assert!(stdout.contains("Skipped 1"));
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn reconverts_already_converted_with_force() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	sandbox.mark_converted("a.jpg");
+	sandbox.mark_converted("b.mp4");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	let output = command.args(["-s", "--force", "a.jpg", "b.mp4"]).env("MOCK_ARGS_LOG", &log).output().unwrap();
+	let shown = stdout(&output);
+	assert!(output.status.success(), "{}", shown);
+	assert!(shown.contains("Shrunk a.jpg") && shown.contains("Shrunk b.mp4"), "{}", shown);
+	assert!(shown.contains("Shrunk 2") && shown.contains("Skipped 0"), "{}", shown);
+
+	// marked anew, with this version
+	let log = fs::read_to_string(&log).unwrap();
+	let version = format!("shrink-ray/{} ", env!("CARGO_PKG_VERSION"));
+	assert!(log.contains(&format!("-comment {}", version)), "{}", log);
+	assert!(log.contains(&format!("-metadata comment={}", version)), "{}", log);
+}
+
+#[test]
+fn reconverts_files_marked_with_other_settings() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let log = sandbox.path("args.log");
+
+	let output = sandbox.command().arg("a.jpg").env("MOCK_ARGS_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+	let log = fs::read_to_string(&log).unwrap();
+	let comment: Vec<_> = log.split("-comment ").nth(1).unwrap().split(' ').take(2).collect();
+	let comment = comment.join(" ");
+	assert!(comment.starts_with("shrink-ray/") && comment.contains(" settings/1:"), "{}", comment);
+	// as if the mock tools had kept it in the output
+	fs::write(sandbox.path("a.jpg.comment"), &comment).unwrap();
+
+	let expected = format!("Skipped a.jpg (file already converted by shrink-ray {};", env!("CARGO_PKG_VERSION"));
+	let skipped = |args: &[&str]| stdout(&sandbox.run(args)).contains(&expected);
+	assert!(skipped(&["a.jpg"]));
+	// only options about the conversions count
+	assert!(skipped(&["--no-grow", "--jobs=2", "a.jpg"]));
+	assert!(skipped(&["--streams=best", "a.jpg"]));
+	assert!(skipped(&["--auto-format", "--ignore-marker-settings", "a.jpg"]));
+	assert!(!skipped(&["--auto-format", "a.jpg"]));
+	assert!(stdout(&sandbox.run(&["--auto-format", "a.jpg"])).contains("Shrunk a.jpg"));
+}
+
+#[test]
+fn respects_markers_of_other_tools() {
+	let sandbox = Sandbox::new();
+	sandbox.webp("a.webp");
+	sandbox.jpeg("b.jpg");
+	fs::write(sandbox.path("b.jpg.comment"), "optimized with jpegoptim").unwrap();
+	sandbox.jpeg("c.jpg");
+	sandbox.mark_converted("c.jpg");
+
+	// only the comments of shrink-ray count by default
+	let output = sandbox.run(&["-o", "out.webp", "a.webp"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk a.webp"));
+
+	let output = sandbox.run(&["--respect-markers", "jpegoptim,generic", "a.webp", "b.jpg", "c.jpg"]);
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Skipped a.webp (already optimized by cwebp)"), "{}", stdout);
+	assert!(stdout.contains("Skipped b.jpg (already optimized by jpegoptim)"), "{}", stdout);
+	assert!(stdout.contains("Shrunk c.jpg"), "{}", stdout);
+}
+
+#[test]
+fn lists_files_by_outcome_at_the_end() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b.jpg", "c.jpg"] {
+		sandbox.jpeg(name);
+		sandbox.mark_converted(name);
+	}
+	sandbox.jpeg("d.jpg");
+	sandbox.jpeg("e.jpg");
+
+	let output = sandbox
+		.command()
+		.args(["-k", "--summary-list", "skipped,failed,grew", "--summary-limit", "2"])
+		.args(["a.jpg", "b.jpg", "c.jpg", "d.jpg", "e.jpg"])
+		.env("MOCK_FAIL", "d.jpg")
+		.output()
+		.unwrap();
+	assert!(!output.status.success());
+	let stdout = stdout(&output);
+	let (_, summary) = stdout.split_once("Failed files:\n").unwrap();
+	let (failed, skipped) = summary.split_once("Skipped files:\n").unwrap();
+	assert!(failed.starts_with("  d.jpg ("), "{}", stdout);
+	assert_eq!(
+		skipped,
+		"  a.jpg (file already converted by shrink-ray 0.1.0)\n  \
+		 b.jpg (file already converted by shrink-ray 0.1.0)\n  … and 1 more\n\n"
+	);
+	assert!(!stdout.contains("Grown files:"));
+}
+
+#[test]
+fn displays_paths_as_asked() {
+	let sandbox = Sandbox::new();
+	let root = sandbox.path(".").canonicalize().unwrap();
+	fs::create_dir(sandbox.path("photos")).unwrap();
+	sandbox.jpeg("photos/a.jpg");
+	sandbox.jpeg("photos/b.jpg");
+	sandbox.mark_converted("photos/b.jpg");
+
+	let output = sandbox.run(&["./photos/../photos/a.jpg"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk ./photos/../photos/a.jpg"), "{}", stdout(&output));
+
+	let output = sandbox.run(&["--absolute-paths", "--summary-list", "skipped", "photos/a.jpg", "photos/b.jpg"]);
+	assert!(output.status.success());
+	let lines = stdout(&output);
+	let (absolute, skipped) = (root.join("photos/a.jpg"), root.join("photos/b.jpg"));
+	assert!(lines.contains(&format!("Shrunk {} (", absolute.display())), "{}", lines);
+	assert!(lines.contains(&format!("Skipped files:\n  {} (", skipped.display())), "{}", lines);
+
+	let output = sandbox.run(&["--relative-to", "photos", "photos/a.jpg"]);
+	assert!(stdout(&output).contains("Shrunk a.jpg ("), "{}", stdout(&output));
+	fs::create_dir(sandbox.path("videos")).unwrap();
+	let output = sandbox.run(&["--relative-to", "videos", absolute.to_str().unwrap()]);
+	assert!(stdout(&output).contains("Shrunk ../photos/a.jpg ("), "{}", stdout(&output));
+
+	let output = sandbox.run(&["--relative-to", "missing", "photos/a.jpg"]);
+	assert!(!output.status.success());
+	assert_eq!(sandbox.files(), ["photos", "videos"]);
+}
+
+#[test]
+fn sorts_inputs_by_size() {
+	let sandbox = Sandbox::new();
+	for (name, padding) in [("a.jpg", 0), ("b.jpg", 400_000), ("c.jpg", 0), ("d.jpg", 10_000), ("e.jpg", 0)] {
+		let mut contents = fs::read(sandbox.jpeg(name)).unwrap();
+		contents.resize(contents.len() + padding, 0);
+		fs::write(sandbox.path(name), contents).unwrap();
+	}
+
+	let order = |sort: &str| {
+		// grown outputs are discarded, so the inputs stay the same
+		let output = sandbox
+			.command()
+			.args(["--sort", sort, "--no-grow", "a.jpg", "b.jpg", "c.jpg", "d.jpg", "e.jpg"])
+			.env("MOCK_MODE", "grow")
+			.output()
+			.unwrap();
+		assert!(output.status.success(), "{}", stdout(&output));
+		let names: Vec<_> = stdout(&output)
+			.lines()
+			.filter_map(|x| x.trim_start().strip_prefix("Grew "))
+			.map(|x| x[..1].to_string())
+			.collect();
+		names.concat()
+	};
+
+	assert_eq!(order("largest"), "bdace");
+	assert_eq!(order("smallest"), "acedb");
+	assert_eq!(order("given"), "abcde");
+	// the big file once the small ones are about as far behind its share of
+	// the total as it would get ahead of it
+	assert_eq!(order("balanced"), "acbed");
+}
+
+#[test]
+fn converts_files_with_long_paths() {
+	let sandbox = Sandbox::new();
+	// deeper than Windows takes, with names close to what filesystems take
+	let dir: std::path::PathBuf = (0..6).map(|x| format!("{}{}", x, "d".repeat(49))).collect();
+	fs::create_dir_all(sandbox.path(".").join(&dir)).unwrap();
+	let image = dir.join(format!("{}.jpg", "a".repeat(246)));
+	let video = dir.join(format!("{}.mp4", "b".repeat(251)));
+	sandbox.jpeg(image.to_str().unwrap());
+	sandbox.mp4(video.to_str().unwrap());
+	let original = sandbox.size(image.to_str().unwrap());
+
+	let output = sandbox.run(&[image.to_str().unwrap(), video.to_str().unwrap()]);
+	let out = stdout(&output);
+	assert!(!output.status.success(), "{}", out);
+	assert!(out.contains(&format!("Shrunk {}", image.display())), "{}", out);
+	assert!(sandbox.size(image.to_str().unwrap()) < original);
+
+	// the video would end up as a `.webm`, whose name is too long: it fails
+	// before being converted, leaving nothing behind
+	assert!(out.contains("is too long for the filesystem"), "{}", out);
+	let mut files: Vec<_> = fs::read_dir(sandbox.path(".").join(&dir)).unwrap().map(|x| x.unwrap().path()).collect();
+	files.sort();
+	assert_eq!(files, [sandbox.path(".").join(&image), sandbox.path(".").join(&video)]);
+}
+
+#[test]
+fn stops_at_failed_invocation() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.command().args(["a.jpg", "b.jpg"]).env("MOCK_MODE", "fail").output().unwrap();
+	assert!(!output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Failed a.jpg"));
+	assert!(!stdout.contains("b.jpg"));
+	assert_eq!(sandbox.files(), ["a.jpg", "b.jpg"]);
+}
+
+#[test]
+fn keeps_going_after_failed_invocation() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.command().args(["-k", "-s", "a.jpg", "b.jpg"]).env("MOCK_MODE", "fail").output().unwrap();
+	assert!(!output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Failed a.jpg"));
+	assert!(stdout.contains("Failed b.jpg"));
+	assert!(stdout.contains("Failed 2"));
+	assert_eq!(sandbox.files(), ["a.jpg", "b.jpg"]);
+}
+
+#[test]
+fn cancels_on_interrupt() {
+	cancel(false);
+}
+
+#[test]
+fn cancels_when_tool_ignores_interrupt() {
+	cancel(true);
+}
+
+fn cancel(ignore: bool) {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+	let started = sandbox.path("started");
+
+	let mut command = sandbox.command();
+	command.args(["a.jpg", "b.jpg"]).env("MOCK_MODE", "hang").env("MOCK_STARTED", &started);
+	if ignore {
+		command.env("MOCK_IGNORE_INT", "1");
+	}
+
+	let child = command.stdout(std::process::Stdio::piped()).spawn().unwrap();
+	let deadline = Instant::now() + Duration::from_secs(10);
+	while !started.exists() {
+		assert!(Instant::now() < deadline, "mock tool never started");
+		thread::sleep(Duration::from_millis(10));
+	}
+
+	let status = std::process::Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+	assert!(status.success());
+
+	let output = child.wait_with_output().unwrap();
+	assert_eq!(output.status.code(), Some(255));
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Cancelled a.jpg"));
+	assert!(!stdout.contains("b.jpg"));
+	assert_eq!(sandbox.files(), ["a.jpg", "started"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+/// The objects written with `--format json`, one per line.
+fn json_lines(output: &process::Output) -> Vec<serde_json::Value> {
+	let shown = String::from_utf8(output.stdout.clone()).unwrap();
+	shown.lines().map(|x| serde_json::from_str(x).unwrap_or_else(|_| panic!("not JSON: {}", x))).collect()
+}
+
+#[test]
+fn reports_results_as_json() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	sandbox.mark_converted("b.jpg");
+	sandbox.jpeg("c.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let mut command = sandbox.command();
+	command.args(["--format", "json", "-k", "-v", "a.jpg", "b.jpg", "c.jpg"]).env("MOCK_FAIL", "c.jpg");
+	let output = command.output().unwrap();
+	assert!(!output.status.success());
+	let objects = json_lines(&output);
+	assert_eq!(objects.len(), 4, "{:?}", objects);
+
+	let shrunk = &objects[0];
+	assert_eq!(shrunk["path"], "a.jpg");
+	assert_eq!(shrunk["status"], "shrunk");
+	assert_eq!(shrunk["original_bytes"], original);
+	assert_eq!(shrunk["new_bytes"], original / 2);
+	assert_eq!(shrunk["ratio"], 0.5);
+
+	assert_eq!(objects[1]["path"], "b.jpg");
+	assert_eq!(objects[1]["status"], "skipped");
+	assert!(objects[1]["reason"].as_str().unwrap().contains("already converted"), "{}", objects[1]);
+	assert_eq!(objects[2]["path"], "c.jpg");
+	assert_eq!(objects[2]["status"], "failed");
+	assert!(objects[2]["reason"].is_string(), "{}", objects[2]);
+
+	let summary = &objects[3]["summary"];
+	assert_eq!(summary["shrunk_files"], 1);
+	assert_eq!(summary["skipped_files"], 1);
+	assert_eq!(summary["failed_files"], 1);
+	assert_eq!(summary["saved_bytes"], original - original / 2);
+}
+
+#[test]
+fn tags_skip_reasons_in_json() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mark_converted("a.jpg");
+	sandbox.jpeg("b.jpg");
+	sandbox.mark_converted("b.jpg");
+	std::fs::write(sandbox.path("c.txt"), "not a picture\n".repeat(400)).unwrap();
+	std::fs::write(sandbox.path("d.jpg"), "tiny").unwrap();
+
+	let output = sandbox.run(&["--format", "json", "--min-size", "1K", "a.jpg", "b.jpg", "c.txt", "d.jpg"]);
+	assert!(output.status.success());
+	let objects = json_lines(&output);
+	assert_eq!(objects.len(), 5, "{:?}", objects);
+	let tags: Vec<_> = objects[..4].iter().map(|x| x["skip_reason"].as_str().unwrap()).collect();
+	assert_eq!(tags, ["already_converted", "already_converted", "unknown_format", "too_small"]);
+	assert_eq!(objects[2]["reason"], "unknown file format");
+	assert_eq!(objects[3]["reason"], "below minimum size");
+
+	let summary = &objects[4]["summary"];
+	assert_eq!(summary["skipped_files"], 4);
+	assert_eq!(summary["skipped_by"], serde_json::json!({"already_converted": 2, "unknown_format": 1, "too_small": 1}));
+}
+
+#[test]
+fn reports_cancelled_runs_as_json() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let started = sandbox.path("started");
+
+	let mut command = sandbox.command();
+	command.args(["--format", "json", "a.jpg", "b.jpg"]).env("MOCK_MODE", "hang").env("MOCK_STARTED", &started);
+	let child = command.stdout(std::process::Stdio::piped()).spawn().unwrap();
+	let deadline = Instant::now() + Duration::from_secs(10);
+	while !started.exists() {
+		assert!(Instant::now() < deadline, "mock tool never started");
+		thread::sleep(Duration::from_millis(10));
+	}
+
+	let status = std::process::Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+	assert!(status.success());
+
+	let output = child.wait_with_output().unwrap();
+	assert_eq!(output.status.code(), Some(255));
+	let objects = json_lines(&output);
+	assert_eq!(objects.len(), 2, "{:?}", objects);
+	assert_eq!(objects[0]["path"], "a.jpg");
+	assert_eq!(objects[0]["status"], "cancelled");
+	assert_eq!(objects[1]["summary"]["shrunk_files"], 0);
+}
+
+/// Progress events written, but for those of `progress`, with the events
+/// they were written between.
+fn progress_events(written: &str) -> (Vec<serde_json::Value>, Vec<serde_json::Value>) {
+	let events = written.lines().map(|x| serde_json::from_str(x).unwrap_or_else(|_| panic!("not JSON: {}", x)));
+	events.partition(|x: &serde_json::Value| x["event"] != "progress")
+}
+
+#[test]
+fn reports_progress_events_on_a_pipe() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	sandbox.jpeg("b.jpg");
+	let pipe = sandbox.path("events");
+	assert!(process::Command::new("mkfifo").arg(&pipe).status().unwrap().success());
+	let reader = thread::spawn(move || fs::read_to_string(pipe).unwrap());
+
+	let output = sandbox
+		.command()
+		.args(["--progress-pipe", "events", "a.mp4", "b.jpg"])
+		.env("MOCK_INTERRUPT", "convert b.jpg")
+		.output()
+		.unwrap();
+	assert!(!output.status.success());
+	// whatever the terminal shows
+	assert!(stdout(&output).contains("Cancelled b.jpg"), "{}", stdout(&output));
+
+	let (events, progress) = progress_events(&reader.join().unwrap());
+	let sequence: Vec<_> = events.iter().map(|x| (x["event"].as_str().unwrap(), x["path"].as_str())).collect();
+	assert_eq!(
+		sequence,
+		[
+			("file_started", Some("a.mp4")),
+			("file_finished", Some("a.mp4")),
+			("file_started", Some("b.jpg")),
+			("file_finished", Some("b.jpg")),
+			("run_finished", None),
+		],
+		"{:?}",
+		events
+	);
+	assert!(events.iter().all(|x| x["version"] == 1), "{:?}", events);
+	assert_eq!(events[1]["outcome"], "shrunk");
+	assert_eq!(events[1]["delta"]["original_bytes"], sandbox.size("a.webm") * 2);
+	assert_eq!(events[3]["outcome"], "cancelled");
+	assert_eq!(events[4]["cancelled"], true);
+	assert_eq!(events[4]["stats"]["shrunk_files"], 1);
+
+	assert!(!progress.is_empty());
+	for event in progress {
+		assert_eq!(event["path"], "a.mp4");
+		let percent = event["percent"].as_f64().unwrap();
+		assert!(percent > 0.0 && percent <= 100.0, "{}", event);
+		assert!(event["bytes_out"] == 1024 || event["bytes_out"] == 2048, "{}", event);
+		assert!(event["eta_s"].as_f64().is_some_and(|x| x >= 0.0), "{}", event);
+	}
+}
+
+#[test]
+fn reports_progress_events_on_inherited_descriptor() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	sandbox.mark_converted("b.jpg");
+
+	// starting shrink-ray through a shell, which opens the descriptor for it
+	let command = sandbox.command();
+	let mut shell = process::Command::new("sh");
+	shell
+		.args(["-c", "exec \"$0\" --progress-fd 3 \"$@\" 3>events.jsonl"])
+		.arg(command.get_program())
+		.args(["--format", "json", "a.jpg", "b.jpg"])
+		.current_dir(command.get_current_dir().unwrap());
+	for (key, value) in command.get_envs() {
+		match value {
+			Some(x) => shell.env(key, x),
+			None => shell.env_remove(key),
+		};
+	}
+
+	let output = shell.output().unwrap();
+	assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+	// the results go to the standard output regardless
+	assert_eq!(json_lines(&output).len(), 3);
+
+	let (events, progress) = progress_events(&fs::read_to_string(sandbox.path("events.jsonl")).unwrap());
+	assert!(progress.is_empty(), "{:?}", progress);
+	let outcomes: Vec<_> = events.iter().filter_map(|x| x["outcome"].as_str()).collect();
+	assert_eq!(outcomes, ["shrunk", "skipped"]);
+	assert_eq!(events[3]["reason"], "file already converted by shrink-ray 0.1.0; --force converts it again");
+	assert_eq!(events[4]["event"], "run_finished");
+	assert_eq!(events[4]["cancelled"], false);
+	assert_eq!(events[4]["stats"]["skipped_files"], 1);
+
+	let output = sandbox.run(&["--progress-fd", "9", "a.jpg"]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("failed to open progress channel file descriptor 9"));
+}
+
+#[test]
+fn keeps_going_after_per_file_error() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	sandbox.jpeg("a.webm");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.run(&["-k", "a.mp4", "b.jpg"]);
+	assert!(!output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Failed a.mp4 (output file `a.webm` already exists)"));
+	assert!(stdout.contains("Shrunk b.jpg"));
+	assert_eq!(sandbox.files(), ["a.mp4", "a.webm", "b.jpg"]);
+}
+
+#[test]
+fn aborts_on_fatal_error_despite_keep_going() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.command().args(["-k", "a.jpg", "b.jpg"]).env("RAY_BIN_GM", "/nonexistent/gm").output().unwrap();
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("binary `/nonexistent/gm` not found"));
+	assert!(!stdout(&output).contains("b.jpg"));
+}
+
+#[cfg(target_family = "unix")]
+#[test]
+fn redirects_outputs_of_unwritable_directories() {
+	use std::os::unix::fs::PermissionsExt;
+
+	let sandbox = Sandbox::new();
+	fs::create_dir(sandbox.path("photos")).unwrap();
+	sandbox.jpeg("photos/a.jpg");
+	let original = sandbox.size("photos/a.jpg");
+	let set_mode = |mode| fs::set_permissions(sandbox.path("photos"), fs::Permissions::from_mode(mode)).unwrap();
+	set_mode(0o555);
+	if fs::write(sandbox.path("photos/probe"), "").is_ok() {
+		// permissions do not hold back root
+		set_mode(0o755);
+		eprintln!("skipping, as the sandbox stays writable");
+		return;
+	}
+
+	let refused = sandbox.run(&["photos/a.jpg"]);
+	let redirected = sandbox.run(&["--auto-output-dir", "out", "photos/a.jpg"]);
+	set_mode(0o755);
+
+	assert!(!refused.status.success());
+	let shown = stdout(&refused);
+	assert!(shown.contains("`photos` is not writable; use `--output-dir` or `--auto-output-dir`"), "{}", shown);
+
+	assert!(redirected.status.success());
+	let shown = stdout(&redirected);
+	assert!(shown.contains("Redirected photos/a.jpg (to `out/photos/a.jpg`)"), "{}", shown);
+	assert_eq!(sandbox.size("photos/a.jpg"), original);
+	assert_eq!(sandbox.size("out/photos/a.jpg"), original / 2);
+}
+
+#[test]
+fn identifies_files_with_magic_databases_given() {
+	let sandbox = Sandbox::new();
+	std::fs::write(sandbox.path("a.dat"), "SHRINKRAYTEST, of a format only the fixture knows").unwrap();
+	let database = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/custom.magic");
+
+	let mut command = sandbox.command();
+	let output = command.arg("--magic-db").arg(&database).arg("a.dat").env("RUST_LOG", "warn").output().unwrap();
+	assert!(output.status.success());
+	let logged = String::from_utf8_lossy(&output.stderr);
+	assert!(logged.contains("unsupported file format: application/x-shrink-ray-test"), "{}", logged);
+	assert!(stdout(&output).contains("Skipped a.dat (unknown file format)"));
+}
+
+#[test]
+fn falls_back_on_built_in_magic_database() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	std::fs::write(sandbox.path("broken.mgc"), b"\x1c\x04\x1e\xf1garbage\n\t\x00").unwrap();
+
+	for database in ["broken.mgc", "missing.mgc"] {
+		let mut command = sandbox.command();
+		let output = command.args(["--magic-db", database, "a.jpg", "b.mp4"]).env("RUST_LOG", "warn").output().unwrap();
+		assert!(output.status.success());
+		assert!(String::from_utf8_lossy(&output.stderr).contains("failed to load libmagic database"));
+
+		let shown = stdout(&output);
+		assert!(shown.contains("identifying files with the libmagic database built into shrink-ray"), "{}", shown);
+		assert!(shown.contains("Shrunk a.jpg"), "{}", shown);
+		assert!(shown.contains("Shrunk b.mp4"), "{}", shown);
+		for file in ["a.jpg", "b.webm"] {
+			std::fs::remove_file(sandbox.path(file)).unwrap();
+		}
+
+		sandbox.jpeg("a.jpg");
+		sandbox.mp4("b.mp4");
+	}
+}
+
+#[test]
+fn reports_files_before_fatal_error() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	sandbox.jpeg("c.jpg");
+
+	let args = ["-s", "--metrics-file", "run.prom", "a.jpg", "b.mp4", "c.jpg"];
+	let output = sandbox.command().args(args).env("RAY_BIN_FFPROBE", "/nonexistent/ffprobe").output().unwrap();
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("binary `/nonexistent/ffprobe` not found"));
+
+	let shown = stdout(&output);
+	assert!(shown.contains("Shrunk a.jpg"));
+	assert!(shown.contains("stopped by a fatal error; only the files before it are counted"));
+	assert!(!shown.contains("c.jpg"));
+
+	let metrics = std::fs::read_to_string(sandbox.path("run.prom")).unwrap();
+	assert!(metrics.contains("shrink_ray_files{outcome=\"shrunk\"} 1\n"));
+	assert!(metrics.contains("shrink_ray_run_aborted{reason=\"binary_not_found\"} 1\n"));
+	assert!(metrics.contains("shrink_ray_last_run_timestamp_seconds "));
+}
+
+#[test]
+fn runs_tools_in_c_locale() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let log = sandbox.path("env.log");
+
+	let output = sandbox.command().arg("a.jpg").env("LC_ALL", "de_DE.UTF-8").env("MOCK_ENV_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+
+	let env = std::fs::read_to_string(log).unwrap();
+	let lines: Vec<_> = env.lines().collect();
+	assert!(lines.contains(&"LC_ALL=C"));
+	assert!(lines.contains(&"LANG=C"));
+	assert!(!lines.contains(&"LC_ALL=de_DE.UTF-8"));
+	assert!(!lines.iter().any(|x| x.starts_with("RAY_")));
+}
+
+#[test]
+fn clears_environment_of_tools() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	let log = sandbox.path("env.log");
+	fs::create_dir(sandbox.path("tmp")).unwrap();
+
+	let output = sandbox
+		.command()
+		.arg("a.jpg")
+		.env("FFREPORT", "file=report.log")
+		.env("MAGICK_LIMIT_MEMORY", "1MB")
+		.env("MAGICK_TMPDIR", "/elsewhere")
+		.env("TMPDIR", sandbox.path("tmp"))
+		.env("MOCK_ENV_LOG", &log)
+		.output()
+		.unwrap();
+	assert!(output.status.success());
+
+	let env = fs::read_to_string(&log).unwrap();
+	let lines: Vec<_> = env.lines().collect();
+	assert!(!lines.iter().any(|x| x.starts_with("FFREPORT=") || x.starts_with("MAGICK_")), "{}", env);
+	assert!(lines.contains(&format!("TMPDIR={}", sandbox.path("tmp").display()).as_str()), "{}", env);
+
+	fs::remove_file(&log).unwrap();
+	let output = sandbox
+		.command()
+		.args(["--passthrough-env", "MAGICK_LIMIT_MEMORY", "b.jpg"])
+		.env("MAGICK_LIMIT_MEMORY", "1MB")
+		.env("MAGICK_TMPDIR", "/elsewhere")
+		.env("MOCK_ENV_LOG", &log)
+		.output()
+		.unwrap();
+	assert!(output.status.success());
+
+	let env = fs::read_to_string(&log).unwrap();
+	let lines: Vec<_> = env.lines().collect();
+	assert!(lines.contains(&"MAGICK_LIMIT_MEMORY=1MB"), "{}", env);
+	assert!(!lines.iter().any(|x| x.starts_with("MAGICK_TMPDIR=")), "{}", env);
+}
+
+#[test]
+fn processes_inputs_beyond_pipeline_depth() {
+	let sandbox = Sandbox::new();
+	let names: Vec<_> = (0..5).map(|x| format!("{}.jpg", x)).collect();
+	for name in &names {
+		sandbox.jpeg(name);
+	}
+
+	let mut args = vec!["-k", "-s", "--pipeline-depth", "2", "missing.jpg"];
+	args.extend(names.iter().map(String::as_str));
+	let output = sandbox.run(&args);
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Failed missing.jpg"));
+	assert!(names.iter().all(|x| stdout.contains(&format!("Shrunk {}", x))));
+	assert!(stdout.contains("Shrunk 5"));
+}
+
+/// Walks 100,000 tiny files, a hundredth of them photos and the rest skipped
+/// as of unknown formats, within bounds of memory and of the time to the first
+/// conversion; run with `--ignored`.
+#[test]
+#[ignore = "slow"]
+fn processes_huge_batches_within_bounds() {
+	use std::io::{BufRead, BufReader};
+
+	use nix::sys::resource::{getrusage, UsageWho};
+
+	let sandbox = Sandbox::new();
+	for dir in 0..100 {
+		let dir = format!("dump/{:03}", dir);
+		fs::create_dir_all(sandbox.path(&dir)).unwrap();
+		sandbox.jpeg(&format!("{}/000.jpg", dir));
+		for file in 1..1000 {
+			fs::write(sandbox.path(&format!("{}/{:03}.txt", dir, file)), "x").unwrap();
+		}
+	}
+
+	let start = Instant::now();
+	let mut command = sandbox.command();
+	let mut child = command.args(["-s", "-r", "dump"]).stdout(process::Stdio::piped()).spawn().unwrap();
+	let mut lines = BufReader::new(child.stdout.take().unwrap()).lines().map(Result::unwrap);
+	assert!(lines.by_ref().any(|x| x.contains("Shrunk") && x.contains("dump/000/000.jpg")));
+	let first = start.elapsed();
+	let stats = lines.find(|x| x.contains("Skipped") && x.contains("Failed")).unwrap();
+	assert!(child.wait().unwrap().success());
+	// the largest of the processes waited for; the tools, and the runs of
+	// other tests, are far smaller
+	let peak = getrusage(UsageWho::RUSAGE_CHILDREN).unwrap().max_rss() * 1024;
+	assert!(stats.contains(" 99900,"), "{}", stats);
+	assert!(first < Duration::from_secs(5), "first conversion after {:?}", first);
+	assert!(peak < 128 << 20, "peak resident set of {} bytes", peak);
+}
+
+#[test]
+fn reports_stage_timings_when_verbose() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+
+	let stdout = stdout(&sandbox.run(&["-s", "-v", "a.jpg"]));
+	for stage in ["identify", "probe", "convert", "replace", "total"] {
+		assert!(stdout.lines().any(|x| x.trim_start().starts_with(stage)), "no timing for {}", stage);
+	}
+
+	assert!(!common::stdout(&sandbox.run(&["-s", "a.jpg"])).contains("overhead"));
+}
+
+#[test]
+fn reports_cpu_time_of_tools() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+
+	let output = sandbox
+		.command()
+		.args(["-s", "--metrics-file", "metrics.prom", "a.jpg", "b.mp4"])
+		.env("MOCK_BURN", "100000")
+		.output()
+		.unwrap();
+	let shown = stdout(&output);
+	assert!(output.status.success(), "{}", shown);
+	let line = shown.lines().find(|x| x.starts_with("CPU time ")).expect(&shown);
+	assert!(line.ends_with(" per MiB saved"), "{}", line);
+
+	let metrics = fs::read_to_string(sandbox.path("metrics.prom")).unwrap();
+	let seconds = metrics.lines().find_map(|x| x.strip_prefix("shrink_ray_tools_cpu_seconds ")).expect(&metrics);
+	assert!(seconds.parse::<f64>().unwrap() > 0.0, "{}", metrics);
+}
+
+#[test]
+fn tells_io_bound_from_cpu_bound_conversions() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+
+	let output = sandbox.command().args(["-v", "-s", "a.jpg"]).env("MOCK_DELAY", "1").output().unwrap();
+	let shown = stdout(&output);
+	assert!(output.status.success(), "{}", shown);
+	let line = shown.lines().find(|x| x.contains("Shrunk a.jpg")).expect(&shown);
+	assert!(line.contains(", IO-bound ("), "{}", line);
+	assert!(shown.contains("run was predominantly IO-bound — consider increasing --jobs"), "{}", shown);
+
+	sandbox.jpeg("a.jpg");
+	let output = sandbox.command().args(["-v", "-s", "a.jpg"]).env("MOCK_BURN", "200000").output().unwrap();
+	let shown = stdout(&output);
+	let line = shown.lines().find(|x| x.contains("Shrunk a.jpg")).expect(&shown);
+	assert!(line.contains(", CPU-bound ("), "{}", line);
+	assert!(!shown.contains("predominantly"), "{}", shown);
+
+	// only when verbose
+	sandbox.jpeg("a.jpg");
+	let output = sandbox.command().arg("a.jpg").env("MOCK_DELAY", "1").output().unwrap();
+	assert!(!stdout(&output).contains("bound"), "{}", stdout(&output));
+}
+
+#[test]
+fn animates_progress_only_when_asked_off_terminals() {
+	const FRAMES: [char; 6] = ['⠋', '⠙', '⠸', '⠴', '⠦', '⠇'];
+
+	let sandbox = Sandbox::new();
+	let animated = |args: &[&str]| {
+		sandbox.jpeg("a.jpg");
+		let output = sandbox.command().args(args).arg("a.jpg").env("MOCK_DELAY", "0.5").output().unwrap();
+		assert!(output.status.success());
+		String::from_utf8(output.stdout).unwrap().contains(FRAMES)
+	};
+
+	// the standard output of tests is a pipe
+	assert!(!animated(&[]));
+	assert!(!animated(&["--no-spinner"]));
+	assert!(animated(&["--progress-interval", "20"]));
+}
+
+#[test]
+fn encodes_small_videos_once_for_efficiency() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	sandbox.mp4("b.mp4");
+	let size = sandbox.size("a.mp4");
+
+	let output = sandbox
+		.command()
+		.args(["--efficiency", "--efficiency-threshold", &(size + 1).to_string(), "a.mp4"])
+		.env("MOCK_ARGS_LOG", sandbox.path("small.log"))
+		.output()
+		.unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert!(sandbox.path("a.webm").exists());
+	let log = fs::read_to_string(sandbox.path("small.log")).unwrap();
+	let encodes: Vec<_> = log.lines().filter(|x| x.contains(" -f webm ")).collect();
+	assert_eq!(encodes.len(), 1, "{}", log);
+	assert!(encodes[0].contains(" -deadline good -cpu-used 4 ") && !log.contains("-pass"), "{}", log);
+
+	// from the threshold on, videos get both passes
+	let output = sandbox
+		.command()
+		.args(["--efficiency", "--efficiency-threshold", &size.to_string(), "b.mp4"])
+		.env("MOCK_ARGS_LOG", sandbox.path("large.log"))
+		.output()
+		.unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	let log = fs::read_to_string(sandbox.path("large.log")).unwrap();
+	assert!(log.contains(" -pass 1 ") && log.contains(" -pass 2 ") && !log.contains("-cpu-used"), "{}", log);
+}
+
+#[test]
+fn decodes_with_ffmpeg_when_gm_lacks_delegate() {
+	let sandbox = Sandbox::new();
+	sandbox.webp("a.webp");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.arg("a.webp").env("MOCK_NO_DELEGATE", "webp").env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk a.webp"));
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log"]);
+
+	let args = std::fs::read_to_string(log).unwrap();
+	assert!(args.lines().any(|x| x.starts_with("ffmpeg ") && x.contains("a.webp")));
+	assert!(args.lines().any(|x| x.starts_with("gm convert ") && x.contains(".png -strip -comment shrink-ray/")));
+}
+
+#[test]
+fn routes_formats_gm_lacks_to_capable_backends() {
+	let sandbox = Sandbox::new();
+	// JPEG XL codestreams
+	fs::write(sandbox.path("a.jxl"), b"\xff\x0a\xfa\x1f\x00\x00\x00\x00").unwrap();
+	fs::write(sandbox.path("b.jxl"), b"\xff\x0a\xfa\x1f\x00\x00\x00\x00").unwrap();
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["a.jxl", "b.jxl"]).env("MOCK_NO_DELEGATE", "jxl").env("MOCK_MAGICK_NO_DELEGATE", "");
+	let output = command.env("MOCK_ARGS_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Shrunk a.jxl"), "{}", stdout);
+	assert!(stdout.contains("Shrunk b.jxl"), "{}", stdout);
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log", "b.jpg"]);
+
+	let args = fs::read_to_string(&log).unwrap();
+	assert!(args.lines().any(|x| x == "magick identify -verbose a.jxl"), "{}", args);
+	assert!(args.lines().any(|x| x.starts_with("magick a.jxl ")), "{}", args);
+	assert!(!args.lines().any(|x| x.starts_with("gm identify ")), "{}", args);
+	// once for each tool
+	assert_eq!(args.lines().filter(|x| x.ends_with("-list format")).count(), 2, "{}", args);
+}
+
+#[test]
+fn skips_formats_no_backend_reads() {
+	let sandbox = Sandbox::new();
+	fs::write(sandbox.path("a.jxl"), b"\xff\x0a\xfa\x1f\x00\x00\x00\x00").unwrap();
+
+	let output = sandbox.command().arg("a.jxl").env("MOCK_NO_DELEGATE", "jxl").env("MOCK_FAIL", "ffprobe").output();
+	let output = output.unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Skipped a.jxl (gm build lacks JXL support)"), "{}", stdout(&output));
+	assert_eq!(sandbox.files(), ["a.jxl"]);
+}
+
+#[test]
+fn reports_dimensions_when_verbose() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.command().args(["-v", "a.jpg"]).env("MOCK_GEOMETRY", "4032x3024").output().unwrap();
+	assert!(stdout(&output).contains("-50.00 %, 4032×3024 → 4032×3024, "), "{}", stdout(&output));
+
+	let output = sandbox.run(&["b.jpg"]);
+	assert!(!stdout(&output).contains("640×480"));
+}
+
+#[test]
+fn converts_tar_stream() {
+	let sandbox = Sandbox::new();
+	std::fs::create_dir_all(sandbox.path("in/sub")).unwrap();
+	sandbox.jpeg("in/a.jpg");
+	sandbox.mp4("in/sub/b.mp4");
+	std::fs::write(sandbox.path("in/sub/c.txt"), "text").unwrap();
+
+	let archive =
+		std::process::Command::new("tar").args(["c", "-C", "in", "."]).current_dir(sandbox.path("")).output().unwrap();
+	assert!(archive.status.success());
+
+	let mut child = sandbox
+		.command()
+		.arg("--tar")
+		.stdin(std::process::Stdio::piped())
+		.stdout(std::process::Stdio::piped())
+		.stderr(std::process::Stdio::piped())
+		.spawn()
+		.unwrap();
+	let mut stdin = child.stdin.take().unwrap();
+	let writer = thread::spawn(move || std::io::Write::write_all(&mut stdin, &archive.stdout).unwrap());
+	let output = child.wait_with_output().unwrap();
+	writer.join().unwrap();
+	assert!(output.status.success());
+
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("Shrunk") && stderr.contains("a.jpg"));
+	assert!(stderr.contains("sub/c.txt"));
+
+	std::fs::write(sandbox.path("out.tar"), &output.stdout).unwrap();
+	let list =
+		std::process::Command::new("tar").args(["tf", "out.tar"]).current_dir(sandbox.path("")).output().unwrap();
+	let list = String::from_utf8_lossy(&list.stdout);
+	let mut names: Vec<_> = list.lines().map(|x| x.trim_start_matches("./")).filter(|x| !x.is_empty()).collect();
+	names.sort();
+	assert_eq!(names, ["a.jpg", "sub/", "sub/b.webm", "sub/c.txt"]);
+	assert_eq!(sandbox.files(), ["in", "out.tar"]);
+}
+
+#[test]
+fn collapses_numbered_sequences() {
+	let sandbox = Sandbox::new();
+	let names = ["IMG_0009.jpg", "IMG_0010.jpg", "IMG_0011.jpg", "IMG_0013.jpg", "other.jpg"];
+	for name in names {
+		sandbox.jpeg(name);
+	}
+
+	let mut args = vec!["-s", "--collapse-sequences"];
+	args.extend(names);
+	let stdout = stdout(&sandbox.run(&args));
+	assert!(stdout.contains("Shrunk IMG_0009..0011.jpg (3 files, -"));
+	assert!(!stdout.contains("Shrunk IMG_0010.jpg"));
+	assert!(stdout.contains("Shrunk IMG_0013.jpg"));
+	assert!(stdout.contains("Shrunk other.jpg"));
+	assert!(stdout.contains("Shrunk 5"));
+}
+
+#[test]
+fn converts_single_frame_video_as_image() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	let log = sandbox.path("args.log");
+
+	let output = sandbox.command().arg("a.mp4").env("MOCK_STILL", "1").env("MOCK_ARGS_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk a.mp4"));
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log"]);
+
+	let args = std::fs::read_to_string(log).unwrap();
+	assert!(!args.contains("-pass"));
+	assert!(args.lines().any(|x| x.starts_with("gm convert ") && x.contains(".png -strip -comment shrink-ray/")));
+}
+
+#[test]
+fn keeps_pass_log_away_from_output_file() {
+	for name in ["out.webm", "out"] {
+		let sandbox = Sandbox::new();
+		sandbox.mp4("a.mp4");
+		let temp = tempfile::tempdir().unwrap();
+
+		let output = sandbox.command().args(["-o", name, "a.mp4"]).env("TMPDIR", temp.path()).output().unwrap();
+		assert!(output.status.success());
+		assert_eq!(sandbox.files(), ["a.mp4", name]);
+		assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+	}
+}
+
+#[test]
+fn restores_backup_with_suffix() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+
+	assert!(sandbox.run(&["--backup", "a.jpg"]).status.success());
+	assert_eq!(sandbox.files(), ["a.jpg", "a.jpg.orig"]);
+	assert!(sandbox.size("a.jpg") < original);
+
+	let output = sandbox.run(&["restore", "a.jpg"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Restored a.jpg (from `a.jpg.orig`)"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn restores_backup_from_dir() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	let original = sandbox.size("a.mp4");
+
+	assert!(sandbox.run(&["--backup-dir", "backups", "a.mp4"]).status.success());
+	assert_eq!(sandbox.files(), ["a.webm", "backups"]);
+
+	let output = sandbox.run(&["restore", "--backup-dir", "backups", "a.webm"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Restored a.webm"));
+	assert_eq!(sandbox.files(), ["a.mp4", "backups"]);
+	assert_eq!(sandbox.size("a.mp4"), original);
+}
+
+#[test]
+fn restore_without_backup_changes_nothing() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+
+	let output = sandbox.run(&["restore", "a.jpg"]);
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("Failed a.jpg (no backup of `a.jpg` found)"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+}
+
+#[test]
+fn stops_when_output_is_closed() {
+	for (action, code, converted) in [("quit", 141, 1), ("continue-silent", 0, 3)] {
+		let sandbox = Sandbox::new();
+		let names = ["a.jpg", "b.jpg", "c.jpg"];
+		for name in names {
+			sandbox.jpeg(name);
+		}
+
+		let original = sandbox.size("a.jpg");
+
+		let (reader, writer) = std::io::pipe().unwrap();
+		drop(reader);
+
+		let mut command = sandbox.command();
+		command.args(["--on-broken-pipe", action]).args(names).stdout(writer);
+		let output = command.output().unwrap();
+		assert_eq!(output.status.code(), Some(code), "{}", action);
+		assert_eq!(names.iter().filter(|x| sandbox.size(x) < original).count(), converted, "{}", action);
+	}
+}
+
+const TOOLS: &str = r#"
+[[tool]]
+name = "tool"
+mime = "image/webp"
+args = ["convert", "{input}", "{output}", "-q{quality}", "{comment}"]
+extension = "jxl"
+quality = "80"
+
+[tool.comment]
+args = ["comment", "{input}"]
+pattern = "marker=(.*)"
+"#;
+
+#[test]
+fn converts_with_user_tool() {
+	let sandbox = Sandbox::new();
+	sandbox.tools(TOOLS);
+	sandbox.webp("a.webp");
+	sandbox.webp("b.webp");
+	sandbox.mark_converted("b.webp");
+	let log = sandbox.path("args.log");
+
+	let output = sandbox.command().args(["a.webp", "b.webp"]).env("MOCK_ARGS_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Shrunk a.webp"));
+	assert!(stdout.contains("Skipped b.webp (file already converted by shrink-ray 0.1.0; --force converts it again)"));
+	assert_eq!(sandbox.files(), ["a.jxl", "args.log", "b.webp", "b.webp.comment", "config"]);
+
+	let args = std::fs::read_to_string(log).unwrap();
+	let expected = concat!(".jxl -q80 shrink-ray/", env!("CARGO_PKG_VERSION"), " settings/1:");
+	assert!(args.lines().any(|x| x.starts_with("tool convert a.webp ") && x.contains(expected)));
+	assert!(!args.lines().any(|x| x.starts_with("gm ")));
+}
+
+#[test]
+fn validates_tool_definitions() {
+	let sandbox = Sandbox::new();
+	sandbox.tools(TOOLS);
+
+	let output = sandbox.run(&["tools", "validate"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Valid"));
+
+	let output = sandbox.run(&["tools", "list"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Tool tool (image/webp to .jxl, using `tool`)"));
+
+	sandbox.tools(
+		r#"
+[[tool]]
+name = "bad"
+mime = "image"
+args = ["{input}", "{out}"]
+extension = "x"
+"#,
+	);
+	let output = sandbox.run(&["tools", "validate"]);
+	assert!(!output.status.success());
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("tool `bad`: `image` is not a MIME type"), "{}", stderr);
+	assert!(stderr.contains("unknown placeholder `{out}`"), "{}", stderr);
+	assert!(stderr.contains("never mention `{output}`"), "{}", stderr);
+
+	let output = sandbox.run(&["a.jpg"]);
+	assert!(!output.status.success());
+}
+
+#[test]
+fn falls_back_to_next_image_backend() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["-s", "a.jpg"]).env("MOCK_FAIL", "gm convert a.jpg");
+	let output = command.output().unwrap();
+	assert!(!output.status.success());
+
+	let mut command = sandbox.command();
+	command.args(["-s", "--fallback", "a.jpg"]).env("MOCK_FAIL", "gm convert a.jpg").env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Shrunk a.jpg"), "{}", stdout);
+	assert!(stdout.contains("using ImageMagick)"), "{}", stdout);
+	assert!(stdout.contains("Shrunk 1"), "{}", stdout);
+	assert!(stdout.contains("Failed 0"), "{}", stdout);
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log"]);
+
+	let args = std::fs::read_to_string(log).unwrap();
+	assert!(args.lines().any(|x| x.starts_with("magick a.jpg ")));
+}
+
+#[test]
+fn falls_back_to_next_video_variant() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+
+	let mut command = sandbox.command();
+	command.args(["-s", "--fallback", "a.mp4"]).env("MOCK_FAIL", "-c:v vp9 ");
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("using ffmpeg with libvpx-vp9 and yuv420p)"), "{}", stdout);
+	assert!(stdout.contains("Shrunk 1"), "{}", stdout);
+	assert_eq!(sandbox.files(), ["a.webm"]);
+}
+
+/// Runs shrink-ray with `args` on `a.jpg`, which has a color profile,
+/// returning the lines gm was run with, those given to `gm batch` as
+/// `gm batch: …`, and whether any scratch files were left behind.
+fn convert_with_profile(args: &[&str]) -> (Vec<String>, bool) {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	fs::create_dir(sandbox.path("tmp")).unwrap();
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(args).arg("a.jpg").env("MOCK_ICC", "1").env("MOCK_ARGS_LOG", &log);
+	let output = command.env("TMPDIR", sandbox.path("tmp").canonicalize().unwrap()).output().unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert!(stdout(&output).contains("Shrunk a.jpg"), "{}", stdout(&output));
+
+	let logged = fs::read_to_string(log).unwrap();
+	let lines = logged.lines().filter(|x| x.starts_with("gm ")).map(String::from);
+	let scratch = fs::read_dir(sandbox.path("tmp")).unwrap().filter_map(Result::ok).any(|x| x.path().is_file());
+	(lines.collect(), scratch)
+}
+
+#[test]
+fn converts_colors_to_srgb() {
+	let (lines, scratch) = convert_with_profile(&["--srgb-profile", "srgb.icc"]);
+	let converted = lines.iter().find(|x| x.starts_with("gm convert a.jpg ")).unwrap();
+	assert!(converted.contains(" -intent perceptual -profile srgb.icc "), "{}", converted);
+	assert!(converted.contains(" -strip "), "{}", converted);
+	assert!(!lines.iter().any(|x| x.contains("icc:")), "{:?}", lines);
+	assert!(!scratch);
+}
+
+#[test]
+fn embeds_color_profiles_kept() {
+	for batch in [false, true] {
+		let mut args = vec!["--keep-metadata", "icc", "--srgb-profile", "srgb.icc"];
+		if batch {
+			args.push("--gm-batch");
+		}
+
+		let (lines, scratch) = convert_with_profile(&args);
+		let gm = if batch { "gm batch: convert a.jpg" } else { "gm convert a.jpg" };
+		let extracted = lines.iter().find(|x| x.contains(" icc:")).unwrap_or_else(|| panic!("{:?}", lines));
+		let profile = extracted.strip_prefix(&format!("{} icc:", gm)).unwrap_or_else(|| panic!("{}", extracted));
+		assert!(profile.starts_with("tmp/a-") && profile.ends_with(".icc"), "{}", profile);
+
+		let converted = lines.iter().find(|x| x.starts_with(&format!("{} -", gm))).unwrap();
+		assert!(converted.contains(&format!(" -strip -profile {} ", profile)), "{}", converted);
+		assert!(!converted.contains("-intent") && !converted.contains("srgb.icc"), "{}", converted);
+		assert!(!scratch, "extracted profile left behind");
+	}
+}
+
+#[test]
+fn fails_once_fallbacks_are_exhausted() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["-s", "--fallback", "a.jpg"]).env("MOCK_FAIL", "jpeg:").env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("Failed 1"));
+
+	// the probe, gm, ImageMagick, and ffmpeg decoding for gm to encode
+	let args = std::fs::read_to_string(log).unwrap();
+	let tools: Vec<_> = args.lines().map(|x| x.split(' ').next().unwrap()).collect();
+	assert_eq!(tools, ["gm", "gm", "magick", "ffmpeg", "gm"]);
+}
+
+#[test]
+fn encodes_to_format_of_output_file() {
+	for (name, coder) in [("out.jpg", "jpeg:"), ("out.png", "png:"), ("out.webp", "webp:")] {
+		let sandbox = Sandbox::new();
+		sandbox.jpeg("a.jpg");
+		let log = sandbox.path("args.log");
+
+		let output = sandbox.command().args(["-o", name, "a.jpg"]).env("MOCK_ARGS_LOG", &log).output().unwrap();
+		assert!(output.status.success(), "{}", name);
+		assert_eq!(sandbox.files(), ["a.jpg", "args.log", name]);
+
+		let args = std::fs::read_to_string(log).unwrap();
+		let expected = format!(" {}{}", coder, name);
+		assert!(args.lines().any(|x| x.starts_with("gm convert a.jpg ") && x.ends_with(&expected)), "{}", args);
+	}
+}
+
+#[test]
+fn encodes_images_at_quality_given() {
+	let sandbox = Sandbox::new();
+	let log = sandbox.path("args.log");
+	let convert = |args: &[&str]| {
+		sandbox.jpeg("a.jpg");
+		let output = sandbox.command().args(args).arg("a.jpg").env("MOCK_ARGS_LOG", &log).output().unwrap();
+		assert!(output.status.success(), "{}", stdout(&output));
+		let logged = fs::read_to_string(&log).unwrap();
+		fs::remove_file(&log).unwrap();
+		logged.lines().find(|x| x.starts_with("gm convert a.jpg ")).unwrap().to_string()
+	};
+
+	let given = convert(&["--image-quality", "85"]);
+	assert!(given.contains(" -strip -quality 85 -comment "), "{}", given);
+	// spelled out in the comment
+	assert!(given.contains(" quality/85 jpeg:"), "{}", given);
+
+	let default = convert(&[]);
+	assert!(!default.contains("quality"), "{}", default);
+	// PNG has a quality of its own, which is about compressing it
+	let png = convert(&["--image-quality", "85", "-o", "out.png"]);
+	assert!(png.contains(" -quality 95 ") && !png.contains("quality 85") && !png.contains("quality/"), "{}", png);
+
+	for quality in ["0", "101"] {
+		assert_eq!(sandbox.run(&["--image-quality", quality, "a.jpg"]).status.code(), Some(2), "{}", quality);
+	}
+}
+
+#[test]
+fn filters_images_before_encoding_them() {
+	let sandbox = Sandbox::new();
+	let log = sandbox.path("args.log");
+	let convert = |args: &[&str]| {
+		sandbox.jpeg("a.jpg");
+		let output = sandbox.command().args(args).arg("a.jpg").env("MOCK_ARGS_LOG", &log).output().unwrap();
+		assert!(output.status.success(), "{}", stdout(&output));
+		let logged = fs::read_to_string(&log).unwrap();
+		fs::remove_file(&log).unwrap();
+		let converted = logged.lines().find(|x| x.contains(" convert a.jpg ") || x.starts_with("magick a.jpg "));
+		converted.unwrap().to_string()
+	};
+
+	let gm = convert(&["--trim-borders", "--auto-level"]);
+	assert!(gm.starts_with("gm convert a.jpg -trim -normalize -strip "), "{}", gm);
+
+	// only ImageMagick straightens images
+	let magick = convert(&["--auto-level", "--deskew", "40", "--trim-borders"]);
+	assert!(magick.starts_with("magick a.jpg -deskew 40% -trim +repage -auto-level -strip "), "{}", magick);
+
+	let unfiltered = convert(&[]);
+	assert!(unfiltered.starts_with("gm convert a.jpg -strip "), "{}", unfiltered);
+	assert_eq!(sandbox.run(&["--deskew", "skewed", "a.jpg"]).status.code(), Some(2));
+}
+
+#[test]
+fn skips_files_below_minimum_size() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["-s", "--min-size", "1M", "a.jpg", "b.mp4"]).env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	let shown = stdout(&output);
+	assert!(shown.contains("Skipped a.jpg (below minimum size)"), "{}", shown);
+	assert!(shown.contains("Skipped 2"), "{}", shown);
+	// without even identifying them
+	assert!(!log.exists());
+
+	let output = sandbox.run(&["--min-size", "1K", "a.jpg"]);
+	assert!(stdout(&output).contains("Shrunk a.jpg"));
+	assert_eq!(sandbox.run(&["--min-size", "tiny", "a.jpg"]).status.code(), Some(2));
+}
+
+#[test]
+fn lowers_quality_until_outputs_fit() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["--max-output-size", "2800", "a.jpg", "b.mp4"]).env("MOCK_MODE", "quality");
+	let output = command.env("MOCK_ARGS_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+	let shown = stdout(&output);
+	assert!(shown.contains("Shrunk a.jpg"), "{}", shown);
+	assert!(shown.contains("Shrunk b.mp4"), "{}", shown);
+	assert!(sandbox.size("a.jpg") <= 2800);
+	assert!(sandbox.size("b.webm") <= 2800);
+
+	let log = fs::read_to_string(&log).unwrap();
+	assert!(log.contains(" -quality 60 "), "{}", log);
+	assert!(!log.contains(" -quality 45 "), "{}", log);
+	// estimated from how far over the first attempt came out, fitting at once
+	assert!(log.contains(" -crf 35 "), "{}", log);
+	assert_eq!(log.matches(" -crf ").count(), 2, "{}", log);
+}
+
+#[test]
+fn keeps_originals_which_cannot_fit() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let size = sandbox.size("a.jpg");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["--max-output-size", "100", "--max-attempts", "2", "a.jpg"]).env("MOCK_MODE", "quality");
+	let output = command.env("MOCK_ARGS_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+	let shown = stdout(&output);
+	assert!(shown.contains("Skipped a.jpg (could not meet size limit of 100 bytes)"), "{}", shown);
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log"]);
+	assert_eq!(sandbox.size("a.jpg"), size);
+	let log = fs::read_to_string(&log).unwrap();
+	assert!(log.contains(" -quality 60 "), "{}", log);
+	assert!(!log.contains(" -quality 45 "), "{}", log);
+
+	// lossless outputs are not tried again, as the quality does not matter
+	let output = sandbox.command().args(["--max-output-size", "100", "-o", "a.png", "a.jpg"]).output().unwrap();
+	assert!(stdout(&output).contains("could not meet size limit"));
+	assert_eq!(sandbox.run(&["--max-attempts", "2", "a.jpg"]).status.code(), Some(2));
+}
+
+#[cfg(target_family = "unix")]
+#[test]
+fn skips_symlinks_unless_followed() {
+	use std::os::unix::fs::symlink;
+
+	let sandbox = Sandbox::new();
+	fs::create_dir(sandbox.path("archive")).unwrap();
+	sandbox.jpeg("archive/a.jpg");
+	sandbox.mp4("archive/b.mp4");
+	let size = sandbox.size("archive/a.jpg");
+	symlink("archive/a.jpg", sandbox.path("a.jpg")).unwrap();
+	symlink("archive/b.mp4", sandbox.path("b.mp4")).unwrap();
+	symlink("archive/gone.jpg", sandbox.path("gone.jpg")).unwrap();
+
+	let output = sandbox.run(&["-s", "a.jpg", "b.mp4"]);
+	assert!(output.status.success());
+	let shown = stdout(&output);
+	assert!(shown.contains("Skipped a.jpg (`a.jpg` is a symlink; use `--follow-symlinks`"), "{}", shown);
+	assert!(shown.contains("Skipped 2"), "{}", shown);
+	assert_eq!(sandbox.size("archive/a.jpg"), size);
+
+	let output = sandbox.run(&["gone.jpg"]);
+	assert!(stdout(&output).contains("Failed gone.jpg (input file `gone.jpg` not found)"));
+
+	let output = sandbox.run(&["--follow-symlinks", "a.jpg", "b.mp4"]);
+	assert!(output.status.success());
+	let shown = stdout(&output);
+	assert!(shown.contains("Shrunk a.jpg"), "{}", shown);
+	assert!(shown.contains("Shrunk b.mp4"), "{}", shown);
+	// the targets are replaced, with the links pointing at them
+	assert!(sandbox.size("archive/a.jpg") < size);
+	assert_eq!(fs::read_link(sandbox.path("a.jpg")).unwrap(), Path::new("archive/a.jpg"));
+	assert_eq!(fs::read_link(sandbox.path("b.mp4")).unwrap(), Path::new("archive/b.webm"));
+	assert!(!sandbox.path("archive/b.mp4").exists());
+	assert_eq!(sandbox.files(), ["a.jpg", "archive", "b.mp4", "gone.jpg"]);
+}
+
+#[cfg(target_family = "unix")]
+#[test]
+fn follows_symlinks_within_directories() {
+	let sandbox = Sandbox::new();
+	fs::create_dir(sandbox.path("photos")).unwrap();
+	sandbox.jpeg("a.jpg");
+	let size = sandbox.size("a.jpg");
+	std::os::unix::fs::symlink("../a.jpg", sandbox.path("photos/a.jpg")).unwrap();
+
+	let output = sandbox.run(&["-r", "photos"]);
+	assert!(!stdout(&output).contains("a.jpg"));
+	assert_eq!(sandbox.size("a.jpg"), size);
+
+	let output = sandbox.run(&["-r", "--follow-symlinks", "photos"]);
+	assert!(stdout(&output).contains("Shrunk photos/a.jpg"));
+	assert!(sandbox.size("a.jpg") < size);
+}
+
+#[test]
+fn writes_each_output_for_a_single_input() {
+	let sandbox = Sandbox::new();
+	fs::create_dir(sandbox.path("a")).unwrap();
+	fs::create_dir(sandbox.path("b")).unwrap();
+	sandbox.jpeg("a/Photo.jpg");
+	sandbox.jpeg("b/Photo.jpg");
+
+	let output = sandbox.run(&["-k", "-d", "out", "a/Photo.jpg", "b/Photo.jpg"]);
+	assert!(!output.status.success());
+	let shown = stdout(&output);
+	assert!(shown.contains("Shrunk a/Photo.jpg"), "{}", shown);
+	let failed = "Failed b/Photo.jpg (output file `out/Photo.jpg` is that of `a/Photo.jpg` too)";
+	assert!(shown.contains(failed), "{}", shown);
+	// however often it is converted for one
+	let mut command = sandbox.command();
+	command.args(["-d", "other", "--max-output-size", "2800", "a/Photo.jpg"]).env("MOCK_MODE", "quality");
+	let output = command.output().unwrap();
+	assert!(stdout(&output).contains("Shrunk a/Photo.jpg"));
+}
+
+#[test]
+fn replaces_inputs_given_as_their_own_output() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	let size = sandbox.size("a.jpg");
+
+	let output = sandbox.run(&["-o", "./a.jpg", "a.jpg"]);
+	assert!(stdout(&output).contains("Shrunk a.jpg"));
+	assert_eq!(sandbox.size("a.jpg"), size / 2);
+
+	let output = sandbox.run(&["-d", ".", "b.jpg"]);
+	assert!(stdout(&output).contains("Shrunk b.jpg"));
+	assert_eq!(sandbox.size("b.jpg"), size / 2);
+	assert_eq!(sandbox.files(), ["a.jpg", "b.jpg"]);
+}
+
+#[test]
+fn rejects_output_file_extension_without_encoder() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+
+	let output = sandbox.run(&["-o", "out.bmp", "a.jpg"]);
+	assert_eq!(output.status.code(), Some(2));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("no conversion produces `.bmp` files"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+
+	let output = sandbox.run(&["-o", "out.webm", "a.jpg"]);
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("does not match the output format"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+}
+
+#[test]
+fn encodes_to_formats_asked_for() {
+	let sandbox = Sandbox::new();
+	let log = sandbox.path("args.log");
+	let convert = |args: &[&str], input: &str| {
+		let output = sandbox.command().args(args).arg(input).env("MOCK_ARGS_LOG", &log).output().unwrap();
+		assert!(output.status.success(), "{}", stdout(&output));
+		let logged = fs::read_to_string(&log).unwrap();
+		fs::remove_file(&log).unwrap();
+		logged
+	};
+
+	sandbox.jpeg("a.jpg");
+	convert(&["--image-format", "png"], "a.jpg");
+	assert_eq!(sandbox.files(), ["a.png"]);
+
+	// only ImageMagick writes AVIF
+	sandbox.jpeg("b.jpg");
+	let logged = convert(&["--image-format", "avif", "--image-quality", "60"], "b.jpg");
+	assert!(logged.lines().any(|x| x.starts_with("magick b.jpg ") && x.contains(" -quality 60 ")), "{}", logged);
+	assert_eq!(sandbox.files(), ["a.png", "b.avif"]);
+
+	// replacing the input, as it has the same extension
+	sandbox.mp4("c.mp4");
+	let logged = convert(&["--video-format", "mp4"], "c.mp4");
+	assert!(logged.contains("-c:v libx265 -tag:v hvc1 "), "{}", logged);
+	assert!(logged.contains(" -c:a aac "), "{}", logged);
+	assert!(logged.contains(" -f mp4 c-"), "{}", logged);
+	assert!(!logged.contains("-pass"), "{}", logged);
+	assert_eq!(sandbox.files(), ["a.png", "b.avif", "c.mp4"]);
+
+	sandbox.mp4("d.mp4");
+	let logged = convert(&["--video-format", "mkv"], "d.mp4");
+	assert!(logged.contains(" -pass 2 "), "{}", logged);
+	assert!(logged.contains(" -f matroska "), "{}", logged);
+	assert_eq!(sandbox.files(), ["a.png", "b.avif", "c.mp4", "d.mkv"]);
+}
+
+#[test]
+fn rejects_formats_that_do_not_go_together() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+
+	let rejected = [
+		&["--image-format", "png", "-o", "out.jpg", "a.jpg"][..],
+		&["--image-format", "png", "--auto-format", "a.jpg"],
+		&["--video-format", "mp4", "-o", "out.webm", "b.mp4"],
+		&["-o", "out.mkv", "b.mp4"],
+		&["--video-format", "mp4", "--crf", "52", "b.mp4"],
+		&["--image-format", "bmp", "a.jpg"],
+	];
+	for args in rejected {
+		let output = sandbox.run(args);
+		assert_eq!(output.status.code(), Some(2), "{:?}", args);
+	}
+
+	let output = sandbox.run(&["--video-format", "mkv", "-o", "out.mkv", "b.mp4"]);
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert_eq!(sandbox.files(), ["a.jpg", "b.mp4", "out.mkv"]);
+}
+
+#[test]
+fn skips_inputs_deleted_during_conversion() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let mut command = sandbox.command();
+	command.args(["-s", "a.jpg", "b.jpg"]).env("MOCK_REMOVE_INPUT", "1").env("MOCK_MODE", "fail");
+	let output = command.output().unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	let shown = stdout(&output);
+	assert!(shown.contains("Skipped a.jpg (file disappeared)"), "{}", shown);
+	assert!(shown.contains("Skipped b.jpg (file disappeared)"), "{}", shown);
+	assert!(shown.contains("Skipped 2") && shown.contains("Failed 0"), "{}", shown);
+	assert!(sandbox.files().is_empty(), "{:?}", sandbox.files());
+
+	// failures are still failures while the input is there
+	sandbox.jpeg("a.jpg");
+	let output = sandbox.command().arg("a.jpg").env("MOCK_MODE", "fail").output().unwrap();
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("Failed a.jpg"), "{}", stdout(&output));
+}
+
+#[test]
+fn keeps_input_changed_during_conversion() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let output = sandbox.command().arg("a.jpg").env("MOCK_MODIFY_INPUT", "1").output().unwrap();
+	assert!(!output.status.success());
+	let message = "Failed a.jpg (input file `a.jpg` changed while it was being converted; its output is kept as `";
+	assert!(stdout(&output).contains(message), "{}", stdout(&output));
+	assert_eq!(sandbox.size("a.jpg"), original + 1);
+	let files = sandbox.files();
+	assert_eq!(files.len(), 2, "{:?}", files);
+	let kept = files.iter().find(|x| *x != "a.jpg").unwrap();
+	assert!(stdout(&output).contains(&format!("kept as `{}`", kept)), "{}", stdout(&output));
+	assert_eq!(sandbox.size(kept), original / 2);
+
+	fs::remove_file(sandbox.path(kept)).unwrap();
+	let output = sandbox
+		.command()
+		.args(["--on-input-change", "discard", "a.jpg"])
+		.env("MOCK_MODIFY_INPUT", "1")
+		.output()
+		.unwrap();
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("Failed a.jpg (input file `a.jpg` changed while it was being converted)"));
+	assert_eq!(sandbox.files(), ["a.jpg"]);
+	assert_eq!(sandbox.size("a.jpg"), original + 2);
+}
+
+#[test]
+fn looks_up_metadata_of_inputs_twice() {
+	let sandbox = Sandbox::new();
+	fs::create_dir_all(sandbox.path("dump/2021")).unwrap();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("dump/b.jpg");
+	sandbox.jpeg("dump/2021/c.jpg");
+
+	// once up front, once to tell whether it changed before replacing it
+	let Some(lookups) = metadata_lookups(&sandbox, &["a.jpg"]) else {
+		return;
+	};
+
+	assert_eq!(lookups_of(&lookups, "a.jpg"), 2, "{:#?}", lookups);
+	let lookups = metadata_lookups(&sandbox, &["-r", "dump"]).unwrap();
+	assert_eq!(lookups_of(&lookups, "dump/b.jpg"), 2, "{:#?}", lookups);
+	assert_eq!(lookups_of(&lookups, "dump/2021/c.jpg"), 2, "{:#?}", lookups);
+}
+
+/// Calls looking up the metadata of paths, or renaming them, which the run
+/// with `args` made, rather than its tools: the name of each with its paths.
+/// They are logged by a library preloaded into the run, which is built with
+/// the C compiler, if there is one.
+fn metadata_lookups(sandbox: &Sandbox, args: &[&str]) -> Option<Vec<Vec<String>>> {
+	let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("mock").join("lookups.c");
+	let library = sandbox.path("lookups.so");
+	let mut cc = process::Command::new("cc");
+	cc.args(["-shared", "-fPIC", "-o"]).arg(&library).arg(source).arg("-ldl");
+	if !cc.status().is_ok_and(|x| x.success()) {
+		eprintln!("no C compiler to build the library counting lookups with; not counting them");
+		return None;
+	}
+
+	let log = sandbox.path("lookups.log");
+	let _ = fs::remove_file(&log);
+	let mut command = sandbox.command();
+	let child = command.args(args).env("LD_PRELOAD", &library).env("LOOKUPS_LOG", &log).spawn().unwrap();
+	let pid = child.id().to_string();
+	assert!(child.wait_with_output().unwrap().status.success());
+
+	let log = fs::read_to_string(&log).unwrap();
+	let calls = log.lines().map(|x| x.split('\t').map(str::to_string).collect::<Vec<_>>());
+	Some(calls.filter(|x| x[0] == pid).map(|x| x[1..].to_vec()).collect())
+}
+
+/// Number of `lookups` of the input `path`, before an output was renamed over
+/// it.
+fn lookups_of(lookups: &[Vec<String>], path: &str) -> usize {
+	let is_input = |x: &String| x == path || x.ends_with(&format!("/{}", path));
+	let before = lookups.iter().take_while(|x| !(x[0].starts_with("rename") && is_input(&x[2])));
+	before.filter(|x| !x[0].starts_with("rename") && is_input(&x[1])).count()
+}
+
+#[test]
+fn pauses_tool_on_sigusr1() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let started = sandbox.path("started");
+
+	let mut command = sandbox.command();
+	command.args(["-s", "-v", "a.jpg"]).env("MOCK_DELAY", "1").env("MOCK_STARTED", &started);
+	let child = command.stdout(std::process::Stdio::piped()).spawn().unwrap();
+	let deadline = Instant::now() + Duration::from_secs(10);
+	while std::fs::read_to_string(&started).map_or(true, |x| !x.ends_with('\n')) {
+		assert!(Instant::now() < deadline, "mock tool never started");
+		thread::sleep(Duration::from_millis(10));
+	}
+
+	let tool = std::fs::read_to_string(&started).unwrap().trim().to_string();
+	let state = || {
+		let stat = std::fs::read_to_string(format!("/proc/{}/stat", tool)).ok()?;
+		stat.split(' ').nth(2).map(String::from)
+	};
+	let signal = || {
+		let status = std::process::Command::new("kill").args(["-USR1", &child.id().to_string()]).status().unwrap();
+		assert!(status.success());
+		thread::sleep(Duration::from_millis(200));
+	};
+
+	signal();
+	assert_eq!(state().as_deref(), Some("T"));
+	thread::sleep(Duration::from_secs(2));
+	signal();
+	assert_ne!(state().as_deref(), Some("T"));
+
+	let output = child.wait_with_output().unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Paused a.jpg"), "{}", stdout);
+	assert!(stdout.contains("Resumed a.jpg"), "{}", stdout);
+	assert!(stdout.contains("Shrunk a.jpg"), "{}", stdout);
+
+	// the two seconds spent paused do not count
+	let convert = stdout.lines().find(|x| x.trim_start().starts_with("convert")).unwrap();
+	let tools = convert.split_whitespace().nth(1).unwrap();
+	let seconds: f64 = match tools.strip_suffix("ms") {
+		Some(x) => x.parse::<f64>().unwrap() / 1000.0,
+		None => tools.trim_end_matches('s').parse().unwrap(),
+	};
+	assert!(seconds < 1.5, "{}", convert);
+}
+
+#[test]
+fn measures_allocated_size() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b.jpg"] {
+		// a sparse tail takes up no space on disk, but the conversion writes
+		// half of it out for real
+		let file = fs::OpenOptions::new().write(true).open(sandbox.jpeg(name)).unwrap();
+		file.set_len(1 << 20).unwrap();
+	}
+
+	let apparent = stdout(&sandbox.run(&["--units", "decimal", "a.jpg"]));
+	assert!(apparent.contains("Shrunk a.jpg (-524 KB"), "{}", apparent);
+
+	let allocated = stdout(&sandbox.run(&["--measure", "allocated", "--units", "decimal", "b.jpg"]));
+	assert!(allocated.contains("Grew b.jpg (+"), "{}", allocated);
+}
+
+#[test]
+fn leaves_files_tracked_by_git_alone() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b.jpg", "c.jpg", "d.jpg"] {
+		sandbox.jpeg(name);
+	}
+
+	let git = |args: &[&str]| {
+		let status = process::Command::new("git").current_dir(sandbox.path("")).args(args).output().unwrap().status;
+		assert!(status.success());
+	};
+	git(&["init", "-q"]);
+	git(&["add", "a.jpg", "c.jpg", "d.jpg"]);
+
+	let output = sandbox.run(&["--git", "skip-tracked", "a.jpg", "b.jpg"]);
+	assert!(output.status.success());
+	let skipped = stdout(&output);
+	assert!(skipped.contains("Skipped a.jpg (tracked by the git repository at `"), "{}", skipped);
+	assert!(skipped.contains("Shrunk b.jpg"));
+
+	let output = sandbox.run(&["c.jpg", "d.jpg"]);
+	assert!(output.status.success());
+	let warned = stdout(&output);
+	assert_eq!(warned.matches("Note `c.jpg` is tracked by the git repository").count(), 1, "{}", warned);
+	assert!(warned.contains("Note suppressed 1 repeat of: `c.jpg` is tracked by the git repository"), "{}", warned);
+	assert!(warned.contains("Shrunk c.jpg"));
+	assert!(warned.contains("Shrunk d.jpg"));
+}
+
+#[test]
+fn skips_inputs_given_under_other_paths() {
+	let sandbox = Sandbox::new();
+	std::fs::create_dir(sandbox.path("a")).unwrap();
+	std::fs::create_dir(sandbox.path("b")).unwrap();
+	sandbox.jpeg("a/x.jpg");
+	sandbox.jpeg("a/y.jpg");
+	// the same file under another path, like through a bind mount
+	std::fs::hard_link(sandbox.path("a/x.jpg"), sandbox.path("b/x.jpg")).unwrap();
+	let original = sandbox.size("b/x.jpg");
+
+	let output = sandbox.run(&["-v", "a/x.jpg", "a/y.jpg", "./a/y.jpg", "b/x.jpg"]);
+	assert!(output.status.success());
+	let skipped = stdout(&output);
+	assert!(skipped.contains("Shrunk a/x.jpg"), "{}", skipped);
+	assert!(skipped.contains("Shrunk a/y.jpg"), "{}", skipped);
+	assert!(skipped.contains("Skipped ./a/y.jpg (same file as `a/y.jpg`, given earlier)"), "{}", skipped);
+	assert!(skipped.contains("Skipped b/x.jpg (same file as `a/x.jpg`, given earlier)"), "{}", skipped);
+	assert_eq!(sandbox.size("b/x.jpg"), original);
+
+	// only counted otherwise
+	let output = sandbox.run(&["-s", "a/y.jpg", "./a/y.jpg"]);
+	let skipped = stdout(&output);
+	assert!(!skipped.contains("same file"), "{}", skipped);
+	assert!(skipped.contains("Skipped 1"), "{}", skipped);
+}
+
+#[test]
+fn preserves_inode_of_originals() {
+	use std::os::unix::fs::MetadataExt;
+
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	std::fs::create_dir(sandbox.path("links")).unwrap();
+	for (file, link) in [("a.jpg", "links/a.jpg"), ("b.mp4", "links/b.mp4")] {
+		std::fs::hard_link(sandbox.path(file), sandbox.path(link)).unwrap();
+	}
+
+	let inode = |x| fs::metadata(sandbox.path(x)).unwrap().ino();
+	let (image, video) = (inode("a.jpg"), inode("b.mp4"));
+	let original = sandbox.size("a.jpg");
+
+	let output = sandbox.run(&["--preserve-inode", "a.jpg", "b.mp4"]);
+	assert!(output.status.success());
+	let shown = stdout(&output);
+	assert!(shown.contains("Shrunk a.jpg"), "{}", shown);
+	assert!(shown.contains("Shrunk b.mp4"), "{}", shown);
+	assert_eq!(sandbox.files(), ["a.jpg", "b.webm", "links"]);
+
+	assert_eq!(sandbox.size("a.jpg"), original / 2);
+	assert_eq!(fs::read(sandbox.path("links/a.jpg")).unwrap(), fs::read(sandbox.path("a.jpg")).unwrap());
+	assert_eq!(fs::read(sandbox.path("links/b.mp4")).unwrap(), fs::read(sandbox.path("b.webm")).unwrap());
+	for (file, inode) in [("a.jpg", image), ("b.webm", video)] {
+		let metadata = fs::metadata(sandbox.path(file)).unwrap();
+		assert_eq!(metadata.ino(), inode, "{}", file);
+		assert_eq!(metadata.nlink(), 2, "{}", file);
+	}
+}
+
+#[test]
+fn suppresses_repeated_warnings() {
+	let sandbox = Sandbox::new();
+	for name in ["a.txt", "b.txt", "c.txt"] {
+		fs::write(sandbox.path(name), "not a photo").unwrap();
+	}
+
+	let output = sandbox.command().args(["a.txt", "b.txt", "c.txt"]).env("RUST_LOG", "warn").output().unwrap();
+	let warnings = String::from_utf8_lossy(&output.stderr);
+	assert_eq!(warnings.matches("unsupported file format: text/plain").count(), 1, "{}", warnings);
+	let shown = stdout(&output);
+	assert!(shown.contains("Note suppressed 2 repeats of: unsupported file format: text/plain"), "{}", shown);
+
+	// nothing to count without warnings
+	let output = sandbox.run(&["a.txt", "b.txt"]);
+	assert!(!stdout(&output).contains("suppressed"));
+}
+
+#[test]
+fn never_lets_tools_inherit_standard_streams() {
+	let sandbox = Sandbox::new();
+	sandbox.tools(TOOLS);
+	let streams = tempfile::tempdir().unwrap();
+	let log = sandbox.path("args.log");
+	let inherited = sandbox.path("inherited.log");
+	let run = |args: &[&str]| {
+		let file = |name: &str| fs::File::create(streams.path().join(name)).unwrap();
+		let mut command = sandbox.command();
+		command.args(args).env("MOCK_ARGS_LOG", &log).env("MOCK_INHERITED", &inherited);
+		let status = command.stdin(file("in")).stdout(file("out")).stderr(file("err")).status().unwrap();
+		assert!(status.success(), "{}", fs::read_to_string(streams.path().join("out")).unwrap());
+	};
+
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	sandbox.webp("c.webp");
+	let hook = ["--on-success", "ffprobe {output}", "--hook-errors", "fail"];
+	run(&[&hook[..], &["-j", "2", "--segment-encode", "--verify", "perceptual", "a.jpg", "b.mp4", "c.webp"]].concat());
+	sandbox.jpeg("d.jpg");
+	run(&["--gm-batch", "d.jpg"]);
+	sandbox.gif("e.gif");
+	run(&["--image-format", "avif", "e.gif"]);
+
+	// probing, converting, verifying and hooks alike
+	let logged = fs::read_to_string(&log).unwrap();
+	let tools = ["ffprobe -v error", "ffmpeg ", "gm identify ", "gm batch ", "magick ", "tool convert "];
+	for tool in tools.into_iter().chain(["ffprobe b.webm"]) {
+		assert!(logged.lines().any(|x| x.starts_with(tool)), "{}: {}", tool, logged);
+	}
+
+	assert!(!inherited.exists(), "{}", fs::read_to_string(&inherited).unwrap());
+}
+
+#[test]
+fn encodes_video_in_segments() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	fs::create_dir(sandbox.path("tmp")).unwrap();
+
+	let output = sandbox
+		.command()
+		.args(["--segment-encode", "-j", "3", "a.mp4"])
+		.env("TMPDIR", sandbox.path("tmp"))
+		.env("MOCK_ARGS_LOG", sandbox.path("args.log"))
+		.output()
+		.unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert!(stdout(&output).contains("Shrunk a.mp4"));
+	assert!(sandbox.path("a.webm").exists());
+	assert_eq!(fs::read_dir(sandbox.path("tmp")).unwrap().count(), 0);
+
+	let log = fs::read_to_string(sandbox.path("args.log")).unwrap();
+	assert!(log.contains("-segment_times 4.000000,8.000000 "), "{}", log);
+	assert_eq!(log.matches("-pass 1").count(), 3, "{}", log);
+	assert_eq!(log.matches("-pass 2").count(), 3, "{}", log);
+	// audio is encoded once over the whole file
+	assert_eq!(log.matches("-vn -sn -c:a opus").count(), 1, "{}", log);
+	let mut ffmpeg = log.lines().filter(|x| x.starts_with("ffmpeg "));
+	assert!(ffmpeg.next_back().unwrap().contains("-f concat"), "{}", log);
+}
+
+#[test]
+fn cleans_up_after_failed_segment() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	fs::create_dir(sandbox.path("tmp")).unwrap();
+
+	let output = sandbox
+		.command()
+		.args(["--segment-encode", "-j", "3", "a.mp4"])
+		.env("TMPDIR", sandbox.path("tmp"))
+		.env("MOCK_FAIL", "segment001.mkv")
+		.output()
+		.unwrap();
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("Failed a.mp4"));
+	assert_eq!(sandbox.files(), ["a.mp4", "tmp"]);
+	assert_eq!(fs::read_dir(sandbox.path("tmp")).unwrap().count(), 0);
+}
+
+#[test]
+fn runs_tools_in_scratch_directories() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	fs::create_dir(sandbox.path("tmp")).unwrap();
+
+	let output = sandbox
+		.command()
+		.args(["a.jpg", "b.mp4"])
+		.env("TMPDIR", sandbox.path("tmp"))
+		.env("MOCK_LITTER", "1")
+		.output()
+		.unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert_eq!(sandbox.files(), ["a.jpg", "b.webm", "tmp"]);
+	assert_eq!(fs::read_dir(sandbox.path("tmp")).unwrap().count(), 0);
+
+	sandbox.jpeg("c.jpg");
+	let output = sandbox
+		.command()
+		.args(["--keep-temp", "c.jpg"])
+		.env("TMPDIR", sandbox.path("tmp"))
+		.env("MOCK_LITTER", "1")
+		.output()
+		.unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	let kept: Vec<_> = fs::read_dir(sandbox.path("tmp")).unwrap().map(|x| x.unwrap().path()).collect();
+	assert!(!kept.is_empty());
+	assert!(kept.iter().any(|x| x.join("junk.txt").exists()), "{:?}", kept);
+}
+
+#[test]
+fn hides_negligible_changes() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	sandbox.jpeg("c.jpg");
+
+	// the mock halves every file, saving about 2 KiB
+	let output = sandbox.run(&["-s", "--hide-below", "4K,60%", "a.jpg", "b.jpg"]);
+	let hidden = stdout(&output);
+	assert!(!hidden.contains("Shrunk a.jpg"), "{}", hidden);
+	assert!(hidden.contains("… 2 files with negligible change hidden"), "{}", hidden);
+	assert!(hidden.contains("Shrunk 2"), "{}", hidden);
+
+	let output = sandbox.run(&["--hide-below", "4K,10%", "c.jpg"]);
+	let shown = stdout(&output);
+	assert!(shown.contains("Shrunk c.jpg"));
+	assert!(!shown.contains("hidden"));
+}
+
+#[test]
+fn tells_savings_from_reclaimed_space() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.run(&["-s", "-d", "out", "a.jpg"]);
+	let kept = stdout(&output);
+	assert!(kept.contains("outputs saving -2.01 KiB (-50.00 %), originals kept"), "{}", kept);
+
+	let output = sandbox.run(&["-s", "b.jpg"]);
+	let replaced = stdout(&output);
+	assert!(replaced.contains(", saving -2.01 KiB (-50.00 %), reclaimed 2.01 KiB"), "{}", replaced);
+
+	sandbox.jpeg("c.jpg");
+	let output = sandbox.run(&["-s", "--backup", "c.jpg"]);
+	let backed_up = stdout(&output);
+	assert!(backed_up.contains(", saving -2.01 KiB (-50.00 %), reclaimed 0 bytes"), "{}", backed_up);
+}
+
+#[test]
+fn splits_inputs_between_shards() {
+	let sandbox = Sandbox::new();
+	let names: Vec<_> = (1..=6).map(|x| format!("{}.jpg", x)).collect();
+	for name in &names {
+		sandbox.jpeg(name);
+	}
+
+	let mut shrunk = Vec::new();
+	for shard in ["1/3", "2/3", "3/3"] {
+		let mut args = vec!["-s", "-d", "out", "--shard", shard];
+		args.extend(names.iter().map(String::as_str));
+		let output = sandbox.run(&args);
+		let shared = stdout(&output);
+		assert!(output.status.success());
+		// files of the other shards are not skipped, they are left out
+		assert!(shared.contains("Skipped 0"), "{}", shared);
+		shrunk.extend(names.iter().filter(|x| shared.contains(&format!("Shrunk {}", x))).cloned());
+	}
+
+	shrunk.sort();
+	assert_eq!(shrunk, names);
+
+	for shard in ["0/3", "4/3", "1/0", "1"] {
+		let output = sandbox.run(&["--shard", shard, "1.jpg"]);
+		assert!(!output.status.success());
+	}
+}
+
+#[test]
+fn picks_format_by_image_content() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("photo.jpg");
+	sandbox.jpeg("screenshot.jpg");
+	sandbox.jpeg("transparent.jpg");
+
+	let output = sandbox.run(&["-v", "--auto-format", "photo.jpg"]);
+	assert!(stdout(&output).contains("classified as photo → jpeg"), "{}", stdout(&output));
+	assert!(sandbox.path("photo.jpg").exists());
+
+	let output = sandbox
+		.command()
+		.args(["-v", "--auto-format", "screenshot.jpg"])
+		.env("MOCK_CONTENT", "graphic")
+		.env("MOCK_COLORS", "3571")
+		.env("MOCK_ARGS_LOG", sandbox.path("args.log"))
+		.output()
+		.unwrap();
+	assert!(stdout(&output).contains("classified as graphic → webp-lossless"), "{}", stdout(&output));
+	assert!(sandbox.path("screenshot.webp").exists());
+	assert!(fs::read_to_string(sandbox.path("args.log")).unwrap().contains("-define webp:lossless=true"));
+
+	let output = sandbox.command().args(["-v", "--auto-format", "transparent.jpg"]).env("MOCK_ALPHA", "True").output();
+	assert!(stdout(&output.unwrap()).contains("classified as photo → webp"));
+	assert!(sandbox.path("transparent.webp").exists());
+
+	// not worth mentioning unless verbose
+	sandbox.jpeg("quiet.jpg");
+	let output = sandbox.run(&["--auto-format", "quiet.jpg"]);
+	assert!(!stdout(&output).contains("classified"));
+}
+
+#[test]
+fn keeps_transparency_of_images() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("logo.jpg");
+	sandbox.jpeg("photo.jpg");
+
+	let output = sandbox.command().arg("logo.jpg").env("MOCK_ALPHA", "True").output().unwrap();
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert!(stdout(&output).contains("Shrunk logo.jpg"), "{}", stdout(&output));
+	let output = sandbox.run(&["photo.jpg"]);
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert_eq!(sandbox.files(), ["logo.webp", "photo.jpg"]);
+
+	// as asked for
+	sandbox.jpeg("a.jpg");
+	let output = sandbox
+		.command()
+		.args(["-o", "out.jpg", "a.jpg"])
+		.env("MOCK_ALPHA", "True")
+		.env("RUST_LOG", "warn")
+		.output()
+		.unwrap();
+	assert!(sandbox.path("out.jpg").exists());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("`a.jpg` has transparency, which JPEG flattens"));
+}
+
+#[test]
+fn stops_starting_files_near_max_runtime() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	sandbox.jpeg("c.jpg");
+	let original = sandbox.size("b.jpg");
+
+	// after the first file, there is not enough time left for one more
+	let mut command = sandbox.command();
+	command.args(["-s", "--max-runtime", "1500ms", "a.jpg", "b.jpg", "c.jpg"]).env("MOCK_DELAY", "1");
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	let stopped = stdout(&output);
+	assert!(stopped.contains("Shrunk a.jpg"), "{}", stopped);
+	assert!(stopped.contains("maximum runtime nearly reached; not starting any more files"), "{}", stopped);
+	assert!(stopped.contains("Shrunk 1"), "{}", stopped);
+	assert!(!stopped.contains("b.jpg"), "{}", stopped);
+	assert_eq!(sandbox.size("b.jpg"), original);
+}
+
+#[test]
+fn cancels_at_deadline() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let deadline = humantime::format_rfc3339(SystemTime::now() + Duration::from_secs(1)).to_string();
+	let start = Instant::now();
+	let output = sandbox.command().args(["--deadline", &deadline, "a.jpg", "b.jpg"]).env("MOCK_MODE", "hang").output();
+	let output = output.unwrap();
+	assert!(start.elapsed() < Duration::from_secs(10));
+	assert!(output.status.success());
+	let stopped = stdout(&output);
+	assert!(stopped.contains("Cancelled a.jpg"), "{}", stopped);
+	assert!(stopped.contains("deadline reached; stopping"), "{}", stopped);
+	assert!(!stopped.contains("b.jpg"), "{}", stopped);
+	assert_eq!(sandbox.files(), ["a.jpg", "b.jpg"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn waits_for_idle_until_deadline() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+
+	// no load is below zero
+	let deadline = humantime::format_rfc3339(SystemTime::now() + Duration::from_secs(1)).to_string();
+	let output = sandbox.run(&["--only-when-idle", "--idle-load", "0", "--deadline", &deadline, "a.jpg"]);
+	assert!(output.status.success());
+	let waited = stdout(&output);
+	assert!(waited.contains("Waiting for idle…"), "{}", waited);
+	assert!(waited.contains("deadline reached; not starting any more files"), "{}", waited);
+	assert!(!waited.contains("Shrunk"), "{}", waited);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn converts_images_in_one_gm_batch() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b c.jpg", "it's.jpg"] {
+		sandbox.jpeg(name);
+	}
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["--gm-batch", "a.jpg", "b c.jpg", "it's.jpg"]).env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Shrunk a.jpg"), "{}", stdout);
+	assert!(stdout.contains("Shrunk b c.jpg"), "{}", stdout);
+	assert!(stdout.contains("Shrunk it's.jpg"), "{}", stdout);
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log", "b c.jpg", "it's.jpg"]);
+
+	let args = std::fs::read_to_string(log).unwrap();
+	assert_eq!(args.lines().filter(|x| x.starts_with("gm batch -")).count(), 1, "{}", args);
+	assert!(args.lines().any(|x| x.starts_with("gm batch: convert a.jpg -strip ")), "{}", args);
+	assert!(args.lines().any(|x| x.starts_with("gm batch: convert b c.jpg -strip ")), "{}", args);
+	// a quote is left to a process of its own
+	assert!(args.lines().any(|x| x.starts_with("gm convert it's.jpg -strip ")), "{}", args);
+}
+
+#[test]
+fn fails_single_images_in_gm_batch() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b.jpg", "c.jpg"] {
+		sandbox.jpeg(name);
+	}
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["-k", "--gm-batch", "a.jpg", "b.jpg", "c.jpg"]).env("MOCK_FAIL", "b.jpg").env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(!output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Shrunk a.jpg"), "{}", stdout);
+	assert!(stdout.contains("Failed b.jpg (gm invocation failed"), "{}", stdout);
+	assert!(stdout.contains("Shrunk c.jpg"), "{}", stdout);
+
+	// the batch goes on after the failure
+	let args = std::fs::read_to_string(log).unwrap();
+	assert_eq!(args.lines().filter(|x| x.starts_with("gm batch -")).count(), 1, "{}", args);
+	assert!(args.lines().any(|x| x.starts_with("gm batch: convert c.jpg ")), "{}", args);
+	assert!(!args.lines().any(|x| x.starts_with("gm convert ")), "{}", args);
+}
+
+#[test]
+fn falls_back_once_gm_batch_exits() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b.jpg", "c.jpg"] {
+		sandbox.jpeg(name);
+	}
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["--gm-batch", "a.jpg", "b.jpg", "c.jpg"]).env("MOCK_BATCH_EXIT", "b.jpg").env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	for name in ["a.jpg", "b.jpg", "c.jpg"] {
+		assert!(stdout.contains(&format!("Shrunk {}", name)), "{}", stdout);
+	}
+
+	let args = std::fs::read_to_string(log).unwrap();
+	assert!(args.lines().any(|x| x.starts_with("gm batch: convert a.jpg ")), "{}", args);
+	assert!(args.lines().any(|x| x.starts_with("gm convert b.jpg ")), "{}", args);
+	assert!(args.lines().any(|x| x.starts_with("gm convert c.jpg ")), "{}", args);
+	assert_eq!(args.lines().filter(|x| x.starts_with("gm batch -")).count(), 1, "{}", args);
+}
+
+#[test]
+fn cancels_gm_batch_at_deadline() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let deadline = humantime::format_rfc3339(SystemTime::now() + Duration::from_secs(1)).to_string();
+	let start = Instant::now();
+	let mut command = sandbox.command();
+	command.args(["--gm-batch", "--deadline", &deadline, "a.jpg", "b.jpg"]).env("MOCK_MODE", "hang");
+	let output = command.output().unwrap();
+	assert!(start.elapsed() < Duration::from_secs(10));
+	let stopped = stdout(&output);
+	assert!(stopped.contains("Cancelled a.jpg"), "{}", stopped);
+	assert!(!stopped.contains("b.jpg"), "{}", stopped);
+	assert_eq!(sandbox.files(), ["a.jpg", "b.jpg"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn points_out_stalled_tools() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+
+	let output = sandbox.command().args(["--stall-timeout", "200ms", "a.jpg"]).env("MOCK_DELAY", "1").output();
+	let output = output.unwrap();
+	assert!(output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Stalled a.jpg (possibly; no signs of life for 200ms)"), "{}", stdout);
+	assert!(stdout.contains("Shrunk a.jpg"), "{}", stdout);
+}
+
+#[test]
+fn interrupts_stalled_tools() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	let original = sandbox.size("a.jpg");
+
+	let start = Instant::now();
+	let mut command = sandbox.command();
+	command.args(["--stall-timeout", "200ms", "--on-stall", "fail", "-k", "a.jpg", "b.jpg"]);
+	let output = command.env("MOCK_MODE", "hang").output().unwrap();
+	assert!(start.elapsed() < Duration::from_secs(10));
+	assert!(!output.status.success());
+	let stdout = stdout(&output);
+	assert!(stdout.contains("Failed a.jpg (stalled, without signs of life for 200ms)"), "{}", stdout);
+	assert!(stdout.contains("Failed b.jpg"), "{}", stdout);
+	assert_eq!(sandbox.files(), ["a.jpg", "b.jpg"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn keeps_videos_of_live_photos() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("IMG_0001.HEIC");
+	sandbox.mp4("IMG_0001.MOV");
+	sandbox.mp4("IMG_0002.MOV");
+	sandbox.jpeg("IMG_0003.HEIC");
+	sandbox.jpeg("IMG_0004.HEIC");
+	sandbox.mp4("IMG_0004.MOV");
+	let day_before = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+	filetime::set_file_mtime(sandbox.path("IMG_0004.MOV"), day_before).unwrap();
+
+	let mut command = sandbox.command();
+	command.args(["--live-photos", "keep", "IMG_0001.MOV", "IMG_0002.MOV", "IMG_0004.MOV"]).env("MOCK_DURATION", "3");
+	let output = command.output().unwrap();
+	let kept = stdout(&output);
+	assert!(output.status.success());
+	assert!(kept.contains("IMG_0001.MOV (video of the Live Photo `IMG_0001.HEIC`)"), "{}", kept);
+	// no still of the same name, or one taken at another time
+	assert!(kept.contains("Shrunk IMG_0002.MOV"), "{}", kept);
+	assert!(kept.contains("Shrunk IMG_0004.MOV"), "{}", kept);
+	assert!(sandbox.path("IMG_0001.MOV").exists());
+
+	// too long to be the motion part of a Live Photo
+	sandbox.jpeg("IMG_0005.HEIC");
+	sandbox.mp4("IMG_0005.MOV");
+	let output = sandbox.run(&["--live-photos", "keep", "IMG_0005.MOV"]);
+	assert!(stdout(&output).contains("Shrunk IMG_0005.MOV"), "{}", stdout(&output));
+}
+
+#[test]
+fn drops_videos_of_live_photos() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("IMG_0001.HEIC");
+	sandbox.mp4("IMG_0001.MOV");
+	let size = sandbox.size("IMG_0001.MOV");
+
+	let mut command = sandbox.command();
+	command.args(["-s", "--live-photos", "drop-video", "IMG_0001.HEIC", "IMG_0001.MOV"]).env("MOCK_DURATION", "3");
+	let output = command.output().unwrap();
+	let dropped = stdout(&output);
+	assert!(output.status.success());
+	assert!(dropped.contains("-100.00 %, Live Photo video dropped"), "{}", dropped);
+	assert!(dropped.contains("Shrunk 2"), "{}", dropped);
+	assert_eq!(sandbox.files(), ["IMG_0001.JPG"]);
+
+	// originals are kept when the outputs go elsewhere
+	sandbox.jpeg("IMG_0002.HEIC");
+	sandbox.mp4("IMG_0002.MOV");
+	let mut command = sandbox.command();
+	command.args(["--live-photos", "drop-video", "-d", "out", "IMG_0002.MOV"]).env("MOCK_DURATION", "3");
+	let output = command.output().unwrap();
+	assert!(stdout(&output).contains("Skipped"), "{}", stdout(&output));
+	assert_eq!(sandbox.size("IMG_0002.MOV"), size);
+
+	sandbox.mp4("IMG_0003.MOV");
+	sandbox.jpeg("IMG_0003.jpg");
+	let mut command = sandbox.command();
+	command.args(["--live-photos", "drop-video", "--backup", "IMG_0003.MOV"]).env("MOCK_DURATION", "3");
+	assert!(command.output().unwrap().status.success());
+	assert!(!sandbox.path("IMG_0003.MOV").exists());
+	assert_eq!(sandbox.size("IMG_0003.MOV.orig"), size);
+}
+
+#[test]
+fn traces_each_file_to_trace_file() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mark_converted("b.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.run(&["--trace-file", "trace.json", "a.jpg", "b.jpg"]);
+	assert!(output.status.success());
+
+	// the span of each file is written once it closes, with all its fields
+	let trace = fs::read_to_string(sandbox.path("trace.json")).unwrap();
+	let closed: Vec<_> = trace.lines().filter(|x| x.contains(r#""message":"close""#)).collect();
+	assert_eq!(closed.len(), 2, "{}", trace);
+	for field in [
+		r#""path":"a.jpg""#,
+		r#""mime":"image/jpeg""#,
+		r#""tool":"GraphicsMagick""#,
+		r#""outcome":"shrunk""#,
+		r#""bytes_before":4116"#,
+		r#""bytes_after":2058"#,
+	] {
+		assert!(closed[0].contains(field), "{} not in {}", field, closed[0]);
+	}
+
+	assert!(closed[1].contains(r#""path":"b.jpg""#), "{}", closed[1]);
+	assert!(closed[1].contains(r#""outcome":"skipped""#), "{}", closed[1]);
+	assert!(trace.contains(r#""reason":"file already converted by shrink-ray 0.1.0""#), "{}", trace);
+	assert!(trace.contains(r#""phase":"convert""#), "{}", trace);
+}
+
+#[test]
+fn keeps_case_of_extension() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("PHOTO.JPG");
+	sandbox.jpeg("scan.Jpeg");
+	sandbox.mp4("CLIP.MP4");
+
+	let output = sandbox.run(&["PHOTO.JPG", "scan.Jpeg", "CLIP.MP4"]);
+	assert!(output.status.success());
+	assert_eq!(sandbox.files(), ["CLIP.WEBM", "PHOTO.JPG", "scan.Jpeg"]);
+
+	sandbox.jpeg("IMG_0001.HEIC");
+	let output = sandbox.run(&["-d", "out", "IMG_0001.HEIC"]);
+	assert!(output.status.success());
+	assert!(sandbox.path("out/IMG_0001.JPG").exists());
+}
+
+#[test]
+fn prunes_outputs_larger_than_sources() {
+	let sandbox = Sandbox::new();
+	fs::create_dir_all(sandbox.path("src/2024")).unwrap();
+	fs::create_dir_all(sandbox.path("out/2024")).unwrap();
+	let files = [
+		("src/a.jpg", 1000),
+		("out/a.jpg", 3000),
+		("src/b.jpg", 1000),
+		("out/b.jpg", 500),
+		("src/2024/c.mp4", 2000),
+		("out/2024/c.webm", 5000),
+		("src/d.png", 10),
+		("src/d.jpg", 10),
+		("out/d.webp", 4000),
+		("out/e.jpg", 4000),
+		// not an output, but the journal of the runs writing them
+		("out/.shrink-ray-journal", 100),
+	];
+	for (name, size) in files {
+		fs::write(sandbox.path(name), vec![0; size]).unwrap();
+	}
+
+	let output = sandbox.run(&["prune", "-d", "out", "--against", "src", "--dry-run"]);
+	let out = stdout(&output);
+	assert!(output.status.success(), "{}", out);
+	assert!(out.contains("Would prune out/a.jpg (+1.95 KiB, +200.00 % over `src/a.jpg`)"), "{}", out);
+	assert!(out.contains("Would prune out/2024/c.webm"), "{}", out);
+	assert!(out.contains("Skipped out/d.webp (several sources of `out/d.webp` found: `src/d.jpg`, `src/d.png`)"));
+	assert!(out.contains("Skipped out/e.jpg (no source of `out/e.jpg` found)"), "{}", out);
+	assert!(out.contains("Would prune 2 (+4.88 KiB over sources), Kept 1, Skipped 2, Failed 0"), "{}", out);
+	assert!(out.contains("Would reclaim 7.81 KiB"), "{}", out);
+	assert!(!out.contains("b.jpg"), "{}", out);
+	for (name, _) in files {
+		assert!(sandbox.path(name).exists(), "{}", name);
+	}
+
+	let output = sandbox.run(&["prune", "-d", "out", "--against", "src"]);
+	let out = stdout(&output);
+	assert!(output.status.success(), "{}", out);
+	assert!(out.contains("      Pruned out/a.jpg"), "{}", out);
+	assert!(out.contains("Reclaimed 7.81 KiB"), "{}", out);
+	let left: Vec<_> = files.iter().map(|(x, _)| *x).filter(|x| sandbox.path(x).exists()).collect();
+	let kept = ["src/a.jpg", "src/b.jpg", "out/b.jpg", "src/2024/c.mp4", "src/d.png", "src/d.jpg", "out/d.webp"];
+	assert_eq!(left, [&kept[..], &["out/e.jpg", "out/.shrink-ray-journal"]].concat());
+}
+
+#[test]
+fn lists_largest_changes() {
+	let sandbox = Sandbox::new();
+	for (name, padding) in [("a.jpg", 0), ("b.jpg", 4000), ("c.jpg", 8000)] {
+		let mut contents = fs::read(sandbox.jpeg(name)).unwrap();
+		contents.extend((0..padding).map(|x| (x % 7) as u8));
+		fs::write(sandbox.path(name), contents).unwrap();
+	}
+
+	// hidden results still count
+	let output = sandbox.run(&["--top", "2", "--hide-below", "100%", "a.jpg", "b.jpg", "c.jpg"]);
+	let out = stdout(&output);
+	assert!(output.status.success(), "{}", out);
+	assert!(!out.contains("Shrunk"), "{}", out);
+	let top = out.split("Largest savings:\n").nth(1).unwrap();
+	let lines: Vec<_> = top.lines().collect();
+	assert_eq!(lines.len(), 2, "{}", out);
+	assert!(lines[0].starts_with("  c.jpg (-5.92 KiB, -50.00 %, 11.8 KiB to 5.92 KiB)"), "{}", out);
+	assert!(lines[1].starts_with("  b.jpg (-"), "{}", out);
+	assert!(!out.contains("Largest growths"), "{}", out);
+
+	let mut command = sandbox.command();
+	let output = command.args(["--top", "1", "--no-grow", "a.jpg", "b.jpg"]).env("MOCK_MODE", "grow").output().unwrap();
+	let out = stdout(&output);
+	assert!(out.contains("Largest growths:\n  b.jpg (+"), "{}", out);
+	assert!(!out.contains("Largest savings"), "{}", out);
+}
+
+#[test]
+fn shows_savings_per_file() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.run(&["-s", "a.jpg", "b.jpg"]);
+	let out = stdout(&output);
+	let expected = "Per file mean -50.00 %, median -50.00 %, 10th to 90th percentile -50.00 % to -50.00 %\n";
+	assert!(out.contains(expected), "{}", out);
+
+	let mut command = sandbox.command();
+	let output = command.args(["-s", "a.jpg"]).env("MOCK_MODE", "grow").output().unwrap();
+	let out = stdout(&output);
+	assert!(out.contains("Grew 1"), "{}", out);
+	assert!(!out.contains("Per file"), "{}", out);
+}
+
+#[test]
+fn writes_qa_samples() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b.jpg", "c.jpg"] {
+		sandbox.jpeg(name);
+	}
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["--qa-samples", "qa", "--qa-rate", "2", "a.jpg", "b.jpg", "c.jpg"]).env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	let mut qa: Vec<_> = std::fs::read_dir(sandbox.path("qa")).unwrap().map(|x| x.unwrap().file_name()).collect();
+	qa.sort();
+	assert_eq!(qa, ["a.jpg.qa.png", "c.jpg.qa.png"]);
+
+	// crops at 1:1 scale, next to each other
+	let args = std::fs::read_to_string(log).unwrap();
+	assert!(args.lines().any(|x| x.starts_with("gm convert a.jpg -gravity Center -crop 256x256+0+0 ")), "{}", args);
+	assert!(args.lines().any(|x| x.contains(" +append png:qa/a.jpg.qa.png")), "{}", args);
+	assert!(!args.lines().any(|x| x.contains("-crop") && x.contains("b.jpg")), "{}", args);
+}
+
+#[test]
+fn writes_qa_samples_of_videos() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["--qa-samples", "qa", "--qa-rate", "1", "a.mp4"]).env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	assert!(sandbox.path("qa/a.mp4.qa.png").exists());
+
+	let args = std::fs::read_to_string(log).unwrap();
+	assert!(args.lines().any(|x| x.starts_with("ffmpeg") && x.contains("-ss 5.000 -i a.mp4 -frames:v 1")), "{}", args);
+}
+
+#[test]
+fn converts_whatever_becomes_of_qa_samples() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let log = sandbox.path("args.log");
+
+	let output = sandbox.command().arg("a.jpg").env("MOCK_ARGS_LOG", &log).output().unwrap();
+	assert!(output.status.success());
+	assert!(!sandbox.path("qa").exists());
+	assert!(!std::fs::read_to_string(&log).unwrap().contains("+append"));
+
+	sandbox.jpeg("b.jpg");
+	let mut command = sandbox.command();
+	command.args(["--qa-samples", "qa", "b.jpg"]).env("MOCK_FAIL", "+append");
+	let output = command.output().unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk b.jpg"));
+	assert!(!sandbox.path("qa/b.jpg.qa.png").exists());
+}
+
+#[test]
+fn logs_replaced_files_for_audits() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	sandbox.jpeg("c.jpg");
+	let original = fs::read(sandbox.path("a.jpg")).unwrap();
+
+	let output = sandbox.run(&["--audit-log", "audit.log", "--units", "decimal", "a.jpg", "b.mp4"]);
+	assert!(output.status.success(), "{}", stdout(&output));
+	// discarded or kept elsewhere, nothing is replaced
+	let output = sandbox.run(&["--audit-log", "audit.log", "-d", "out", "c.jpg"]);
+	assert!(output.status.success(), "{}", stdout(&output));
+
+	let log = fs::read_to_string(sandbox.path("audit.log")).unwrap();
+	let entries: Vec<_> = log.lines().collect();
+	assert_eq!(entries.len(), 2, "{}", log);
+	let root = sandbox.path(".").canonicalize().unwrap();
+	let hash = blake3::hash(&original).to_hex().to_string();
+	let path = root.join("a.jpg");
+	let digest = format!(r#""original":{{"path":"{}","size":{},"blake3":"{}"}}"#, path.display(), original.len(), hash);
+	assert!(entries[0].contains(&digest), "{}", entries[0]);
+	let hash = blake3::hash(&fs::read(sandbox.path("a.jpg")).unwrap()).to_hex().to_string();
+	assert!(entries[0].contains(&format!(r#""size":{},"blake3":"{}"}}"#, original.len() / 2, hash)), "{}", entries[0]);
+	assert!(entries[0].contains(r#""tool":"GraphicsMagick","tool_version":"GraphicsMagick 1.3.42 (mocked)"#));
+	assert!(entries[0].contains(r#""units":"\"decimal\"""#), "{}", entries[0]);
+	assert!(entries[1].contains(&format!(r#""new":{{"path":"{}","#, root.join("b.webm").display())), "{}", entries[1]);
+	assert!(entries[1].contains(r#""tool":"ffmpeg","tool_version":"ffmpeg version 6.1.1 (mocked)""#));
+	let head = blake3::hash(entries[1].as_bytes()).to_hex().to_string();
+	assert!(entries[1].contains(&format!(r#""previous":"{}""#, blake3::hash(entries[0].as_bytes()).to_hex())));
+
+	let output = sandbox.run(&["audit", "verify", "audit.log"]);
+	assert!(output.status.success());
+	assert!(stdout(&output).contains(&format!("Intact audit.log (2 entries, last {})", head)), "{}", stdout(&output));
+
+	// hiding that the original was larger
+	let tampered = log.replacen(&format!(r#""size":{}"#, original.len()), r#""size":100"#, 1);
+	fs::write(sandbox.path("audit.log"), tampered).unwrap();
+	let output = sandbox.run(&["audit", "verify", "audit.log"]);
+	assert!(!output.status.success());
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("audit log `audit.log` is not intact: entry 2: does not follow"), "{}", stderr);
+
+	// nothing gets chained to it anymore
+	sandbox.jpeg("d.jpg");
+	let output = sandbox.run(&["--audit-log", "audit.log", "d.jpg"]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("is not intact"));
+	assert_eq!(sandbox.size("d.jpg"), original.len() as u64);
+}
+
+#[test]
+fn applies_settings_of_profiles() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+	fs::create_dir_all(sandbox.path("config/shrink-ray")).unwrap();
+	let config = "units = \"binary\"\n\n[profile.careful]\nno-grow = true\nkeep-going = true\n";
+	fs::write(sandbox.path("config/shrink-ray/config.toml"), config).unwrap();
+
+	let output = sandbox.command().args(["--profile", "careful", "a.jpg"]).env("MOCK_MODE", "grow").output().unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Grew a.jpg"));
+	assert_eq!(sandbox.size("a.jpg"), original);
+
+	let output = sandbox.run(&["--print-config", "--profile", "careful", "--keep-going", "--units", "decimal"]);
+	assert!(output.status.success());
+	let settings = stdout(&output);
+	assert!(settings.contains("no-grow = true # profile `careful`\n"), "{}", settings);
+	assert!(settings.contains("keep-going = true # command line\n"), "{}", settings);
+	assert!(settings.contains("units = \"decimal\" # command line\n"), "{}", settings);
+	assert!(settings.contains("fallback = false # default\n"), "{}", settings);
+
+	let output = sandbox.run(&["--print-config"]);
+	assert!(stdout(&output).contains("units = \"binary\" # config\n"));
+	assert!(stdout(&output).contains("no-grow = false # default\n"));
+
+	let output = sandbox.run(&["--profile", "fast", "a.jpg"]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("unknown profile `fast`; available profiles: careful"));
+
+	fs::write(sandbox.path("config/shrink-ray/config.toml"), "[profile.careful]\nno-grwo = true\n").unwrap();
+	let output = sandbox.run(&["--profile", "careful", "a.jpg"]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("`profile.careful.no-grwo`: unknown option"));
+}
+
+#[test]
+fn reads_configuration_file_given() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	let original = sandbox.size("a.jpg");
+	fs::create_dir_all(sandbox.path("config/shrink-ray")).unwrap();
+	fs::write(sandbox.path("config/shrink-ray/config.toml"), "units = \"binary\"\n").unwrap();
+	fs::write(sandbox.path("other.toml"), "no-grow = true\nstats = true\nimage-quality = 80\n").unwrap();
+
+	let output = sandbox.run(&["--config", "other.toml", "--print-config"]);
+	let settings = stdout(&output);
+	assert!(settings.contains("no-grow = true # config\n"), "{}", settings);
+	assert!(settings.contains("image-quality = 80 # config\n"), "{}", settings);
+	assert!(settings.contains("units = \"binary\" # default\n"), "{}", settings);
+
+	let output = sandbox.command().args(["--config", "other.toml", "a.jpg"]).env("MOCK_MODE", "grow").output().unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Grew a.jpg"));
+	assert_eq!(sandbox.size("a.jpg"), original);
+
+	// unlike the default one, it has to exist
+	let output = sandbox.run(&["--config", "missing.toml", "a.jpg"]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("missing.toml"));
+
+	fs::write(sandbox.path("other.toml"), "image-quality = \"high\"\n").unwrap();
+	let output = sandbox.run(&["--config", "other.toml", "a.jpg"]);
+	assert!(!output.status.success());
+	let error = String::from_utf8_lossy(&output.stderr);
+	assert!(error.contains("`image-quality`: invalid value 'high' for '--image-quality <QUALITY>'"), "{}", error);
+	assert_eq!(sandbox.size("a.jpg"), original);
+}
+
+#[test]
+fn reports_drift_of_videos() {
+	let sandbox = Sandbox::new();
+	sandbox.mp4("a.mp4");
+	sandbox.mp4("b.mp4");
+
+	let convert = |args: &[&str], duration| sandbox.command().args(args).env("MOCK_OUTPUT_DURATION", duration).output();
+	let output = convert(&["a.mp4"], "8.2").unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Shrunk a.mp4"));
+	assert!(stdout(&output).contains(", duration -1.8 s)"));
+	assert!(sandbox.path("a.webm").exists());
+
+	let output = convert(&["--fail-on-drift", "5", "b.mp4"], "8.2").unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("Skipped b.mp4 (output drifted from the source (duration -1.8 s))"));
+	assert!(sandbox.path("b.mp4").exists());
+	assert!(!sandbox.path("b.webm").exists());
+
+	// within the tolerance
+	let output = convert(&["--fail-on-drift", "5", "b.mp4"], "9.6").unwrap();
+	assert!(output.status.success());
+	assert!(stdout(&output).contains("duration -0.4 s"));
+	assert!(sandbox.path("b.webm").exists());
+}
+
+#[test]
+fn leaves_only_complete_outputs_when_interrupted() {
+	// the second file is interrupted at each stage in turn: converting it,
+	// checking its drift, verifying it and measuring it
+	let stages = [
+		("b.mp4", "-f webm out/b.webm"),
+		("b.mp4", "-of json out/b.webm"),
+		("b.mp4", "-i out/b.webm -frames:v 1"),
+		("b.jpg", "convert b.jpg"),
+		("b.jpg", "-format %wx%h out/b.jpg"),
+	];
+	for (input, stage) in stages {
+		let sandbox = Sandbox::new();
+		let (first, extension) = match input {
+			"b.mp4" => ("a.mp4", "webm"),
+			_ => ("a.jpg", "jpg"),
+		};
+		for name in [first, input] {
+			match extension {
+				"webm" => sandbox.mp4(name),
+				_ => sandbox.jpeg(name),
+			};
+		}
+
+		let output = sandbox
+			.command()
+			.args(["-v", "-d", "out", "--verify", "perceptual", "--fail-on-drift", "1", first, input])
+			.env("MOCK_INTERRUPT", stage)
+			.output()
+			.unwrap();
+		let shown = stdout(&output);
+		assert!(shown.contains(&format!("Cancelled {}", input)), "{}: {}", stage, shown);
+
+		let mut outputs: Vec<_> =
+			fs::read_dir(sandbox.path("out")).unwrap().map(|x| x.unwrap().file_name().into_string().unwrap()).collect();
+		outputs.sort();
+		let complete = format!("a.{}", extension);
+		assert_eq!(outputs, [".shrink-ray-journal", &complete], "{}: {}", stage, shown);
+
+		let journal = fs::read_to_string(sandbox.path("out/.shrink-ray-journal")).unwrap();
+		let entries: Vec<serde_json::Value> = journal.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+		assert_eq!(entries.len(), 1, "{}: {}", stage, journal);
+		assert_eq!(entries[0]["output"], complete.as_str(), "{}: {}", stage, journal);
+		assert_eq!(entries[0]["size"], sandbox.size(&format!("out/{}", complete)), "{}: {}", stage, journal);
+	}
+}
+
+#[test]
+fn verifies_outputs_perceptually() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	let log = sandbox.path("args.log");
+
+	let mut command = sandbox.command();
+	command.args(["--verify", "perceptual", "a.jpg", "b.mp4"]).env("MOCK_ARGS_LOG", &log);
+	let output = command.output().unwrap();
+	let shown = stdout(&output);
+	assert!(output.status.success(), "{}", shown);
+	assert!(shown.contains("Shrunk a.jpg"), "{}", shown);
+	assert!(shown.contains("Shrunk b.mp4"), "{}", shown);
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log", "b.webm"]);
+
+	let args = std::fs::read_to_string(&log).unwrap();
+	let thumbnails = args.lines().filter(|x| x.ends_with(" gray:-")).count();
+	assert_eq!(thumbnails, 2, "{}", args);
+	// at a quarter, half and three quarters of the 10 seconds
+	for time in ["2.500", "5.000", "7.500"] {
+		let frames = args.lines().filter(|x| x.contains(&format!("-ss {} -i ", time)) && x.ends_with(" rawvideo -"));
+		assert_eq!(frames.count(), 2, "{}", args);
+	}
+}
+
+#[test]
+fn keeps_sources_of_outputs_unlike_them() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.mp4("b.mp4");
+	let original = sandbox.size("a.jpg");
+
+	let verify = |args: &[&str], mangle: &str| {
+		let output = sandbox.command().args(["--verify", "perceptual"]).args(args).env("MOCK_MANGLE", mangle).output();
+		stdout(&output.unwrap())
+	};
+
+	let shown = verify(&["-d", "out", "a.jpg"], "out/");
+	assert!(shown.contains("Skipped a.jpg (output looks unlike the source (perceptual hashes 64 of 64 bits apart))"));
+	assert!(!sandbox.path("out/a.jpg").exists());
+
+	let shown = verify(&["b.mp4"], ".webm");
+	assert!(shown.contains("Skipped b.mp4 (output looks unlike"), "{}", shown);
+	assert_eq!(sandbox.files(), ["a.jpg", "b.mp4", "out"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+
+	// within the tolerance
+	let shown = verify(&["--verify-distance", "64", "b.mp4"], ".webm");
+	assert!(shown.contains("Shrunk b.mp4"), "{}", shown);
+}
+
+/// Serves `body` for `requests` requests on a local port, dropping the first
+/// connection halfway through and honoring `Range` afterwards; returns the
+/// base URL, and the requests received once all are served.
+#[cfg(feature = "http")]
+fn serve(body: Vec<u8>, requests: usize) -> (String, thread::JoinHandle<Vec<String>>) {
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpListener;
+
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}", listener.local_addr().unwrap());
+	let server = thread::spawn(move || {
+		let mut received = Vec::new();
+		for index in 0..requests {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = String::new();
+			let mut reader = BufReader::new(stream.try_clone().unwrap());
+			while reader.read_line(&mut request).unwrap() > 2 && !request.ends_with("\r\n\r\n") {}
+
+			let range = request.lines().find_map(|x| x.strip_prefix("Range: bytes=")?.strip_suffix('-')?.parse().ok());
+			let start: usize = range.unwrap_or(0);
+			let status = if range.is_some() { "206 Partial Content" } else { "200 OK" };
+			let head = format!(
+				"HTTP/1.1 {}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+				status,
+				body.len() - start
+			);
+			stream.write_all(head.as_bytes()).unwrap();
+			let end = if index == 0 { body.len() / 2 } else { body.len() };
+			stream.write_all(&body[start..end]).unwrap();
+			received.push(request);
+		}
+
+		received
+	});
+
+	(url, server)
+}
+
+#[test]
+#[cfg(feature = "http")]
+fn downloads_inputs_given_as_urls() {
+	let sandbox = Sandbox::new();
+	let body = fs::read(sandbox.jpeg("photo.jpg")).unwrap();
+	fs::remove_file(sandbox.path("photo.jpg")).unwrap();
+	let (url, server) = serve(body, 2);
+	let url = format!("{}/photos/photo.jpg?raw=1", url);
+
+	let output = sandbox.run(&["-d", "out", "--post-command", "cp {output} uploaded.jpg", &url]);
+	assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+	assert!(stdout(&output).contains(&format!("Shrunk {}", url)), "{}", stdout(&output));
+	assert_eq!(sandbox.size("out/photo.jpg"), 2058);
+	assert_eq!(sandbox.size("uploaded.jpg"), 2058);
+
+	// the second request resumes where the first one stopped
+	let requests = server.join().unwrap();
+	assert!(!requests[0].contains("Range:"));
+	assert!(requests[1].contains("Range: bytes=2058-"), "{}", requests[1]);
+
+	let output = sandbox.run(&[&url]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("need '--output-dir <PATH>'"));
+}
+
+#[test]
+#[cfg(feature = "http")]
+fn limits_size_of_downloads() {
+	let sandbox = Sandbox::new();
+	let body = fs::read(sandbox.jpeg("photo.jpg")).unwrap();
+	let (url, _server) = serve(body, 1);
+
+	let output = sandbox.run(&["-d", "out", "--max-download-size", "1K", &format!("{}/photo.jpg", url)]);
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("the file is larger than 1024 bytes"), "{}", stdout(&output));
+	assert!(!sandbox.path("out/photo.jpg").exists());
+}
+
+#[test]
+fn runs_post_command_on_kept_outputs() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	fs::create_dir(sandbox.path("done")).unwrap();
+
+	let output = sandbox.run(&["--post-command", "cp {output} done/{output}", "a.jpg"]);
+	assert!(output.status.success());
+	assert_eq!(sandbox.size("done/a.jpg"), 2058);
+
+	let mut command = sandbox.command();
+	command.args(["--no-grow", "--post-command", "cp {output} done/b.jpg", "b.jpg"]).env("MOCK_MODE", "grow");
+	assert!(command.output().unwrap().status.success());
+	assert!(!sandbox.path("done/b.jpg").exists());
+
+	let output = sandbox.run(&["--post-command", "false {input}", "b.jpg"]);
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("Failed b.jpg"), "{}", stdout(&output));
+
+	let output = sandbox.run(&["--post-command", "echo {quality}", "b.jpg"]);
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("unknown placeholder `{quality}` (expected one of `{input}`, `{output}`)"), "{}", stderr);
+}
+
+#[test]
+fn runs_hooks_after_each_file() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("my photo.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.run(&["--on-success", "touch {status}:{saved_bytes}:{output}", "my photo.jpg"]);
+	assert!(output.status.success());
+	assert!(sandbox.path("shrunk:2058:my photo.jpg").exists(), "{:?}", sandbox.files());
+
+	let mut command = sandbox.command();
+	command.args(["-k", "--on-failure", "touch {status}-{input}", "--on-success", "touch nope", "b.jpg"]);
+	assert!(!command.env("MOCK_MODE", "fail").output().unwrap().status.success());
+	assert!(sandbox.path("failed-b.jpg").exists());
+	assert!(!sandbox.path("nope").exists());
+
+	// skipped files are neither
+	sandbox.jpeg("c.jpg");
+	sandbox.mark_converted("c.jpg");
+	let output = sandbox.run(&["--on-success", "touch nope", "--on-failure", "touch nope", "c.jpg"]);
+	assert!(stdout(&output).contains("Skipped c.jpg"));
+	assert!(!sandbox.path("nope").exists());
+}
+
+#[test]
+fn runs_hooks_with_shell() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("it's a photo.jpg");
+
+	let hook = "echo {input} {mime} $((1 + 1)) > log.txt";
+	let output = sandbox.run(&["--hook-shell", "--on-success", hook, "it's a photo.jpg"]);
+	assert!(output.status.success());
+	assert_eq!(fs::read_to_string(sandbox.path("log.txt")).unwrap(), "it's a photo.jpg image/jpeg 2\n");
+}
+
+#[test]
+fn reports_failing_hooks() {
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.run(&["--on-success", "false", "a.jpg"]);
+	assert!(output.status.success());
+	let stdout = common::stdout(&output);
+	assert!(stdout.contains("Shrunk a.jpg"));
+	assert!(stdout.contains("Note hook for `a.jpg` failed: false invocation failed"), "{}", stdout);
+
+	let output = sandbox.run(&["--on-success", "false", "--hook-errors", "fail", "b.jpg"]);
+	assert!(!output.status.success());
+	assert!(common::stdout(&output).contains("Failed b.jpg"));
+}
+
+#[test]
+fn converts_directories_recursively() {
+	let sandbox = Sandbox::new();
+	std::fs::create_dir_all(sandbox.path("dump/2021/06")).unwrap();
+	sandbox.jpeg("dump/b.jpg");
+	sandbox.jpeg("dump/a.jpg");
+	sandbox.jpeg("dump/2021/06/c.jpg");
+	sandbox.jpeg("dump/2021/d.jpg");
+	std::fs::write(sandbox.path("dump/notes.txt"), "not a photo").unwrap();
+
+	let output = sandbox.run(&["-s", "-d", "out", "-r", "dump"]);
+	assert!(output.status.success());
+	let converted = stdout(&output);
+	let order: Vec<_> = ["dump/2021/06/c.jpg", "dump/2021/d.jpg", "dump/a.jpg", "dump/b.jpg", "dump/notes.txt"]
+		.iter()
+		.map(|x| converted.find(x).unwrap_or_else(|| panic!("{} missing from {}", x, converted)))
+		.collect();
+	assert!(order.is_sorted(), "{}", converted);
+	assert!(converted.contains("Shrunk 4"), "{}", converted);
+	assert!(converted.contains("Skipped 1"), "{}", converted);
+	// flat, within `out`, along with the journal
+	assert_eq!(std::fs::read_dir(sandbox.path("out")).unwrap().count(), 5);
+}
+
+#[test]
+fn converts_files_of_overlapping_inputs_once() {
+	let sandbox = Sandbox::new();
+	std::fs::create_dir_all(sandbox.path("dump/2021")).unwrap();
+	sandbox.jpeg("dump/a.jpg");
+	sandbox.jpeg("dump/2021/b.jpg");
+
+	let output = sandbox.run(&["-s", "-r", "dump", "dump/2021", "dump/a.jpg"]);
+	assert!(output.status.success());
+	let converted = stdout(&output);
+	assert!(converted.contains("Shrunk 2"), "{}", converted);
+	assert!(converted.contains("Skipped 0"), "{}", converted);
+	let (b, a) = (converted.find("dump/2021/b.jpg").unwrap(), converted.find("dump/a.jpg").unwrap());
+	assert!(b < a, "{}", converted);
+}
+
+#[test]
+fn marks_directories_done_once_every_file_within_is_converted() {
+	let sandbox = Sandbox::new();
+	std::fs::create_dir_all(sandbox.path("dump/2021/06")).unwrap();
+	std::fs::create_dir_all(sandbox.path("dump/2022")).unwrap();
+	sandbox.jpeg("dump/a.jpg");
+	sandbox.jpeg("dump/2021/b.jpg");
+	sandbox.jpeg("dump/2021/06/c.jpg");
+	sandbox.jpeg("dump/2022/d.jpg");
+	let marked = |dir: &str| sandbox.path(dir).join(".shrink-ray-done").exists();
+
+	let mut command = sandbox.command();
+	command.args(["-s", "-k", "-r", "--write-done-markers", "dump"]).env("MOCK_FAIL", "d.jpg");
+	let output = command.output().unwrap();
+	assert!(!output.status.success());
+	assert!(stdout(&output).contains("Failed dump/2022/d.jpg"), "{}", stdout(&output));
+	assert!(marked("dump/2021") && marked("dump/2021/06"));
+	assert!(!marked("dump") && !marked("dump/2022"));
+	let marker = std::fs::read_to_string(sandbox.path("dump/2021/.shrink-ray-done")).unwrap();
+	let (comment, time) = marker.trim_end().split_once('\n').unwrap();
+	assert!(comment.starts_with("shrink-ray/"), "{}", marker);
+	assert!(time.ends_with('Z'), "{}", marker);
+
+	// the directories marked are skipped whole, within those walked again
+	let output = sandbox.run(&["-s", "-v", "-r", "--write-done-markers", "dump"]);
+	assert!(output.status.success());
+	let converted = stdout(&output);
+	assert!(converted.contains("Note skipping `dump/2021`, marked done"), "{}", converted);
+	assert!(!converted.contains("b.jpg") && !converted.contains("c.jpg"), "{}", converted);
+	assert!(converted.contains("Shrunk dump/2022/d.jpg"), "{}", converted);
+	assert!(marked("dump") && marked("dump/2022"));
+
+	// unless ignored, or marked with other settings
+	let output = sandbox.run(&["-s", "-v", "-r", "--ignore-done-markers", "dump"]);
+	assert!(stdout(&output).contains("dump/2021/06/c.jpg"), "{}", stdout(&output));
+	let output = sandbox.run(&["-s", "-v", "-r", "--auto-format", "dump"]);
+	assert!(stdout(&output).contains("dump/2021/06/c.jpg"), "{}", stdout(&output));
+	assert!(!stdout(&output).contains("marked done"), "{}", stdout(&output));
+}
+
+#[test]
+fn skips_directories_marked_done_within_those_walked() {
+	let sandbox = Sandbox::new();
+	std::fs::create_dir_all(sandbox.path("dump/2021/06")).unwrap();
+	sandbox.jpeg("dump/a.jpg");
+	sandbox.jpeg("dump/2021/b.jpg");
+	sandbox.jpeg("dump/2021/06/c.jpg");
+	std::fs::write(sandbox.path("dump/2021/.shrink-ray-done"), "").unwrap();
+	std::fs::write(sandbox.path("dump/2021/06/.shrink-ray-done"), "").unwrap();
+
+	let output = sandbox.run(&["-s", "-v", "-r", "dump"]);
+	assert!(output.status.success());
+	let converted = stdout(&output);
+	assert_eq!(converted.matches("marked done").count(), 1, "{}", converted);
+	assert!(converted.contains("Note skipping `dump/2021`, marked done"), "{}", converted);
+	assert!(converted.contains("Shrunk dump/a.jpg"), "{}", converted);
+	assert!(!converted.contains("b.jpg") && !converted.contains("c.jpg"), "{}", converted);
+	assert!(converted.contains("Shrunk 1"), "{}", converted);
+}
+
+#[test]
+fn skips_directories_unless_recursive() {
+	let sandbox = Sandbox::new();
+	std::fs::create_dir(sandbox.path("dump")).unwrap();
+	sandbox.jpeg("dump/a.jpg");
+	sandbox.jpeg("b.jpg");
+
+	let output = sandbox.run(&["-s", "dump", "b.jpg"]);
+	assert!(output.status.success());
+	let skipped = stdout(&output);
+	let expected = "Skipped dump (`dump` is a directory; use `--recursive` to convert the files within it)";
+	assert!(skipped.contains(expected), "{}", skipped);
+	assert!(skipped.contains("Shrunk 1"), "{}", skipped);
+	assert!(skipped.contains("Skipped 1"), "{}", skipped);
+}
+
+/// `shrink-ray daemon` on `ray.sock`, killed once dropped if still running
+struct Daemon(Option<process::Child>);
+
+impl Daemon {
+	/// Starts the daemon, waiting for it to listen.
+	fn start(sandbox: &Sandbox, args: &[&str]) -> Self {
+		let mut command = sandbox.command();
+		command.args(["daemon", "--socket", "ray.sock"]).args(args).stdout(process::Stdio::piped());
+		let daemon = Daemon(Some(command.spawn().unwrap()));
+		let deadline = Instant::now() + Duration::from_secs(10);
+		while !sandbox.path("ray.sock").exists() {
+			assert!(Instant::now() < deadline, "daemon never listened");
+			thread::sleep(Duration::from_millis(10));
+		}
+
+		daemon
+	}
+
+	fn id(&self) -> u32 {
+		self.0.as_ref().unwrap().id()
+	}
+
+	fn wait(mut self) -> process::Output {
+		self.0.take().unwrap().wait_with_output().unwrap()
+	}
+}
+
+impl Drop for Daemon {
+	fn drop(&mut self) {
+		if let Some(mut daemon) = self.0.take() {
+			let _ = daemon.kill();
+			let _ = daemon.wait();
+		}
+	}
+}
+
+#[test]
+fn serves_runs_handed_over_to_daemon() {
+	use std::os::unix::fs::PermissionsExt;
+
+	let sandbox = Sandbox::new();
+	sandbox.jpeg("a.jpg");
+	sandbox.jpeg("b.jpg");
+	let original = sandbox.size("a.jpg");
+	let daemon = Daemon::start(&sandbox, &[]);
+	let mode = fs::symlink_metadata(sandbox.path("ray.sock")).unwrap().permissions().mode();
+	assert_eq!(mode & 0o777, 0o600);
+
+	let clients: Vec<_> = ["a.jpg", "b.jpg"]
+		.into_iter()
+		.map(|x| {
+			let mut command = sandbox.command();
+			command.args(["-s", "--via-daemon=ray.sock", x]).stdout(process::Stdio::piped());
+			(x, command.spawn().unwrap())
+		})
+		.collect();
+	for (input, client) in clients {
+		let output = client.wait_with_output().unwrap();
+		assert!(output.status.success(), "{:?}", output);
+		let shown = stdout(&output);
+		assert!(shown.contains(&format!("Shrunk {} (-2.01 KiB, -50.00 %)", input)), "{}", shown);
+		assert!(shown.contains("Shrunk 1 (-2.01 KiB)"), "{}", shown);
+	}
+
+	assert!(sandbox.size("a.jpg") < original);
+	assert!(sandbox.size("b.jpg") < original);
+
+	// and the outcome of the run
+	let output = sandbox.run(&["--via-daemon=ray.sock", "missing.jpg"]);
+	assert_eq!(output.status.code(), Some(1));
+	let shown = stdout(&output);
+	assert!(shown.contains("Failed missing.jpg (input file `missing.jpg` not found)"), "{}", shown);
+
+	let status = process::Command::new("kill").args(["-TERM", &daemon.id().to_string()]).status().unwrap();
+	assert!(status.success());
+	let output = daemon.wait();
+	assert!(output.status.success(), "{:?}", output);
+	assert!(!sandbox.path("ray.sock").exists());
+
+	let output = sandbox.run(&["--via-daemon=ray.sock", "a.jpg"]);
+	assert!(!output.status.success());
+	let error = String::from_utf8_lossy(&output.stderr);
+	assert!(error.contains("failed to reach the daemon at `ray.sock`"), "{}", error);
+}
+
+#[test]
+fn shuts_daemon_down_when_idle() {
+	let sandbox = Sandbox::new();
+	let daemon = Daemon::start(&sandbox, &["--idle-timeout", "1s"]);
+
+	// with the socket taken
+	let output = sandbox.run(&["daemon", "--socket", "ray.sock"]);
+	assert!(!output.status.success());
+	let error = String::from_utf8_lossy(&output.stderr);
+	assert!(error.contains("a daemon is listening on `ray.sock` already"), "{}", error);
+
+	let output = daemon.wait();
+	assert!(output.status.success(), "{:?}", output);
+	assert!(stdout(&output).contains("idle for 1s; shutting down"), "{}", stdout(&output));
+	assert!(!sandbox.path("ray.sock").exists());
+}
+
+#[test]
+fn converts_gifs_by_frame_count() {
+	let sandbox = Sandbox::new();
+	sandbox.gif("still.gif");
+	sandbox.animated_gif("animated.gif", 3);
+	fs::write(sandbox.path("broken.gif"), b"GIF89a\x10\x00\x10\x00\x80").unwrap();
+
+	let mut command = sandbox.command();
+	let args_log = sandbox.path("args.log");
+	command.args(["-k", "still.gif", "animated.gif", "broken.gif"]).env("MOCK_ARGS_LOG", &args_log);
+	let output = command.output().unwrap();
+	let shown = stdout(&output);
+	assert!(shown.contains("Shrunk still.gif"), "{}", shown);
+	assert!(shown.contains("Shrunk animated.gif"), "{}", shown);
+	assert!(shown.contains("Skipped broken.gif (unknown file format)"), "{}", shown);
+	assert_eq!(sandbox.files(), ["animated.webm", "args.log", "broken.gif", "still.jpg"]);
+
+	let args = fs::read_to_string(&args_log).unwrap();
+	assert!(args.lines().any(|x| x.starts_with("gm ") && x.contains("still.gif")), "{}", args);
+	assert!(args.lines().any(|x| x.starts_with("ffmpeg ") && x.contains("animated.gif")), "{}", args);
+	assert!(!args.contains("broken.gif"), "{}", args);
+
+	// marked like the outputs of either
+	sandbox.gif("still.gif");
+	sandbox.animated_gif("animated.gif", 3);
+	sandbox.mark_converted("still.gif");
+	sandbox.mark_converted("animated.gif");
+	let output = sandbox.run(&["still.gif", "animated.gif"]);
+	let shown = stdout(&output);
+	let skipped = |name| format!("Skipped {} (file already converted by shrink-ray 0.1.0; --force converts it", name);
+	assert!(shown.contains(&skipped("still.gif")), "{}", shown);
+	assert!(shown.contains(&skipped("animated.gif")), "{}", shown);
+}
+
+#[test]
+fn remuxes_mpeg_ts_captures_first() {
+	let sandbox = Sandbox::new();
+	sandbox.ts("capture.ts");
+	fs::create_dir(sandbox.path("tmp")).unwrap();
+
+	// the capture itself reports a duration as off as broken timestamps make it
+	let args_log = sandbox.path("args.log");
+	let mut command = sandbox.command();
+	command
+		.args(["-k", "--fail-on-drift", "1", "capture.ts"])
+		.env("TMPDIR", sandbox.path("tmp"))
+		.env("MOCK_TS_DURATION", "95443.7")
+		.env("MOCK_ARGS_LOG", &args_log);
+	let output = command.output().unwrap();
+	let shown = stdout(&output);
+	assert!(output.status.success(), "{}", shown);
+	assert!(shown.contains("Shrunk capture.ts"), "{}", shown);
+	assert!(shown.contains("-50.00 %"), "{}", shown);
+	assert_eq!(sandbox.files(), ["args.log", "capture.webm", "tmp"]);
+	assert!(fs::read_dir(sandbox.path("tmp")).unwrap().next().is_none());
+
+	let args = fs::read_to_string(&args_log).unwrap();
+	let remux = args.lines().position(|x| x.contains("-fflags +genpts -i capture.ts -map 0 -c copy"));
+	let remux = remux.unwrap_or_else(|| panic!("{}", args));
+	let encodes: Vec<_> = args.lines().skip(remux + 1).filter(|x| x.contains("-pass")).collect();
+	assert_eq!(encodes.len(), 2, "{}", args);
+	assert!(encodes.iter().all(|x| x.contains("capture-") && x.contains(".mkv")), "{}", args);
+
+	// and when the conversion fails
+	fs::remove_file(sandbox.path("capture.webm")).unwrap();
+	sandbox.ts("capture.ts");
+	let mut command = sandbox.command();
+	command.args(["-k", "capture.ts"]).env("TMPDIR", sandbox.path("tmp")).env("MOCK_FAIL", "vp9");
+	let output = command.output().unwrap();
+	assert!(!output.status.success());
+	assert!(fs::read_dir(sandbox.path("tmp")).unwrap().next().is_none());
+	assert!(!sandbox.path("capture.webm").exists());
+}
+
+#[test]
+fn converts_files_in_parallel() {
+	let sandbox = Sandbox::new();
+	let names = ["a.jpg", "b.jpg", "c.jpg", "d.jpg"];
+	for name in names {
+		sandbox.jpeg(name);
+	}
+
+	let start = Instant::now();
+	let mut command = sandbox.command();
+	command.args(["-s", "-j", "4"]).args(names).env("MOCK_DELAY", "1");
+	let output = command.output().unwrap();
+	assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+	let shown = stdout(&output);
+	assert!(output.status.success(), "{}", shown);
+	assert!(names.iter().all(|x| shown.contains(&format!("Shrunk {} (", x))), "{}", shown);
+	assert!(shown.contains("Shrunk 4"), "{}", shown);
+	// every line whole, however the files finished
+	let lines: Vec<_> = shown.lines().filter(|x| x.contains(".jpg")).collect();
+	assert_eq!(lines.len(), 8, "{}", shown);
+	assert!(lines.iter().all(|x| x.matches(".jpg").count() == 1), "{}", shown);
+	assert_eq!(sandbox.files(), names);
+}
+
+#[test]
+fn cancels_all_files_in_parallel_on_interrupt() {
+	let sandbox = Sandbox::new();
+	for name in ["a.jpg", "b.jpg", "c.jpg"] {
+		sandbox.jpeg(name);
+	}
+
+	let original = sandbox.size("a.jpg");
+	let args_log = sandbox.path("args.log");
+	let mut command = sandbox.command();
+	command.args(["-j", "2", "a.jpg", "b.jpg", "c.jpg"]).env("MOCK_MODE", "hang").env("MOCK_ARGS_LOG", &args_log);
+	let child = command.stdout(std::process::Stdio::piped()).spawn().unwrap();
+	let converting = || fs::read_to_string(&args_log).unwrap_or_default().matches("gm convert").count();
+	let deadline = Instant::now() + Duration::from_secs(10);
+	while converting() < 2 {
+		assert!(Instant::now() < deadline, "mock tools never started");
+		thread::sleep(Duration::from_millis(10));
+	}
+
+	let status = std::process::Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+	assert!(status.success());
+
+	let output = child.wait_with_output().unwrap();
+	assert_eq!(output.status.code(), Some(255));
+	let shown = stdout(&output);
+	assert!(shown.contains("Cancelled a.jpg"), "{}", shown);
+	assert!(shown.contains("Cancelled b.jpg"), "{}", shown);
+	assert!(!shown.contains("c.jpg"), "{}", shown);
+	assert_eq!(converting(), 2);
+	assert_eq!(sandbox.files(), ["a.jpg", "args.log", "b.jpg", "c.jpg"]);
+	assert_eq!(sandbox.size("a.jpg"), original);
+	assert_eq!(sandbox.size("b.jpg"), original);
+}
@@ -0,0 +1,156 @@
+//! Harness for driving the `shrink-ray` binary against the mock tools in
+//! `tests/mock`, which are injected through the `RAY_BIN_*` variables.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use tempfile::TempDir;
+
+const JPEG_HEADER: &[u8] = b"\xff\xd8\xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00";
+const WEBP_HEADER: &[u8] = b"RIFF\x24\x10\x00\x00WEBPVP8 \x18\x10\x00\x00\x30\x01\x00\x9d\x01\x2a\x10\x00\x10\x00";
+const GIF_HEADER: &[u8] = b"GIF89a\x10\x00\x10\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff";
+const MP4_HEADER: &[u8] = b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00isomiso2avc1mp41";
+const PAYLOAD_SIZE: usize = 4096;
+
+pub struct Sandbox {
+	dir: TempDir,
+}
+
+impl Sandbox {
+	pub fn new() -> Self {
+		Sandbox { dir: tempfile::tempdir().unwrap() }
+	}
+
+	pub fn path(&self, name: &str) -> PathBuf {
+		self.dir.path().join(name)
+	}
+
+	pub fn jpeg(&self, name: &str) -> PathBuf {
+		self.file(name, JPEG_HEADER)
+	}
+
+	pub fn webp(&self, name: &str) -> PathBuf {
+		self.file(name, WEBP_HEADER)
+	}
+
+	pub fn gif(&self, name: &str) -> PathBuf {
+		self.animated_gif(name, 1)
+	}
+
+	/// A GIF of `frames` frames, each of a share of the payload.
+	pub fn animated_gif(&self, name: &str, frames: usize) -> PathBuf {
+		let mut contents = GIF_HEADER.to_vec();
+		let payload: Vec<_> = (0..PAYLOAD_SIZE).map(|x| (x % 251) as u8).collect();
+		for frame in payload.chunks(PAYLOAD_SIZE.div_ceil(frames)) {
+			// delay, then the image descriptor and the LZW minimum code size
+			contents.extend(b"\x21\xf9\x04\x00\x0a\x00\x00\x00");
+			contents.extend(b"\x2c\x00\x00\x00\x00\x10\x00\x10\x00\x00\x02");
+			for block in frame.chunks(255) {
+				contents.push(block.len() as u8);
+				contents.extend(block);
+			}
+
+			contents.push(0);
+		}
+
+		contents.push(0x3b);
+		let path = self.path(name);
+		fs::write(&path, contents).unwrap();
+		path
+	}
+
+	pub fn mp4(&self, name: &str) -> PathBuf {
+		self.file(name, MP4_HEADER)
+	}
+
+	/// An MPEG-TS capture: packets of 188 bytes, each starting with the sync
+	/// byte and a header for the program association table, like the first.
+	pub fn ts(&self, name: &str) -> PathBuf {
+		let payload: Vec<_> = (0..PAYLOAD_SIZE).map(|x| (x % 251) as u8).collect();
+		let mut contents = Vec::new();
+		for packet in payload.chunks(184) {
+			contents.extend(b"\x47\x40\x00\x10");
+			contents.extend(packet);
+			contents.resize(contents.len().next_multiple_of(188), 0xff);
+		}
+
+		let path = self.path(name);
+		fs::write(&path, contents).unwrap();
+		path
+	}
+
+	/// Makes the mock tools report `name` as already converted.
+	pub fn mark_converted(&self, name: &str) {
+		fs::write(self.path(&format!("{}.comment", name)), "shrink-ray/0.1.0").unwrap();
+	}
+
+	/// Writes tool definitions where they are looked for by default.
+	pub fn tools(&self, definitions: &str) {
+		let dir = self.path("config").join("shrink-ray");
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("tools.toml"), definitions).unwrap();
+	}
+
+	pub fn size(&self, name: &str) -> u64 {
+		fs::metadata(self.path(name)).unwrap().len()
+	}
+
+	/// Names of all files in the sandbox, sorted.
+	pub fn files(&self) -> Vec<String> {
+		let mut files: Vec<_> = fs::read_dir(self.dir.path())
+			.unwrap()
+			.map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+			.collect();
+		files.sort();
+		files
+	}
+
+	pub fn command(&self) -> Command {
+		let mock = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("mock");
+		let mut command = Command::new(env!("CARGO_BIN_EXE_shrink-ray"));
+		command
+			.current_dir(self.dir.path())
+			.env("RAY_BIN_GM", mock.join("gm"))
+			.env("RAY_BIN_FFMPEG", mock.join("ffmpeg"))
+			.env("RAY_BIN_FFPROBE", mock.join("ffprobe"))
+			.env("RAY_BIN_MAGICK", mock.join("magick"))
+			.env("RAY_BIN_TOOL", mock.join("tool"))
+			.env("XDG_CONFIG_HOME", self.path("config"))
+			.env("RAY_TEMP_SEED", "0")
+			.env("MOCK_ROOT", self.dir.path().canonicalize().unwrap())
+			.env_remove("RUST_LOG");
+		command
+	}
+
+	pub fn run(&self, args: &[&str]) -> Output {
+		self.command().args(args).output().unwrap()
+	}
+
+	fn file(&self, name: &str, header: &[u8]) -> PathBuf {
+		let mut contents = header.to_vec();
+		contents.extend((0..PAYLOAD_SIZE).map(|x| (x % 251) as u8));
+
+		let path = self.path(name);
+		fs::write(&path, contents).unwrap();
+		path
+	}
+}
+
+/// Standard output of the run, without any styling.
+pub fn stdout(output: &Output) -> String {
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let mut plain = String::with_capacity(stdout.len());
+	let mut chars = stdout.chars();
+	while let Some(c) = chars.next() {
+		if c == '\x1b' {
+			chars.by_ref().find(|x| x.is_ascii_alphabetic());
+		} else {
+			plain.push(c);
+		}
+	}
+
+	plain
+}
@@ -0,0 +1,195 @@
+# shared behaviour of the mock tools, driven by environment variables:
+#
+# - MOCK_MODE: `shrink` (default) writes half of the input, `grow` writes it
+#   twice, `quality` writes the share of it given with `-quality`, or 100 less
+#   `-crf` percent, `fail` exits with an error and `hang` sleeps until
+#   interrupted
+# - MOCK_IGNORE_INT: ignore SIGINT and finish the conversion anyway
+# - MOCK_STARTED: file to write the process ID to once the conversion has
+#   started
+# - MOCK_DELAY: seconds to take for the conversion
+# - MOCK_BURN: count to this first, taking up CPU time like actual tools
+# - MOCK_ENV_LOG: file to append the environment of every invocation to
+# - MOCK_ARGS_LOG: file to append the command line of every invocation to
+# - MOCK_INHERITED: file to append the command line of every invocation to
+#   whose standard streams are regular files, which are those of shrink-ray
+#   run with its streams on files, inherited rather than piped
+# - MOCK_ROOT: directory of the sandbox, set for every test
+# - MOCK_KEYFRAMES: times of the keyframes `ffprobe` reports, in seconds
+# - MOCK_DURATION: length of videos `ffprobe` reports, in seconds
+# - MOCK_OUTPUT_DURATION: length `ffprobe` reports for `.webm` files instead,
+#   like converted videos
+# - MOCK_TS_DURATION: length `ffprobe` reports for `.ts` files instead, like
+#   captures with broken timestamps
+# - MOCK_STILL: make `ffprobe` report videos as a single frame without audio
+# - MOCK_PROBE: make `ffprobe` write its JSON between warnings (`noisy`), cut
+#   it short (`truncated`), or write nothing but a warning in any format
+#   (`broken`)
+# - MOCK_GEOMETRY: dimensions `gm` reports for every image, `640x480` by
+#   default
+# - MOCK_COLORS, MOCK_ALPHA: number of unique colors and alpha channel `gm`
+#   reports for every image; `magick` reports the alpha channel as well
+# - MOCK_ICC: make `gm` report an embedded color profile for every image
+# - MOCK_CONTENT: `photo` (default) or `graphic`, the kind of thumbnails `gm`
+#   makes
+# - MOCK_NO_DELEGATE: extension of files `gm` pretends to have no delegate for,
+#   and leaves out of the formats it lists
+# - MOCK_MAGICK_NO_DELEGATE: format `magick` leaves out of those it lists,
+#   the same as `gm` unless set
+# - MOCK_MODIFY_INPUT: append to the input while converting it
+# - MOCK_REMOVE_INPUT: delete the input while converting it, like another
+#   process cleaning up
+# - MOCK_LITTER: leave a `junk.txt` in the working directory while converting,
+#   like tools writing logs or caches there
+# - MOCK_FAIL: make every invocation whose command line (as logged to
+#   MOCK_ARGS_LOG) contains this fail, like a crash on a specific file
+# - MOCK_INTERRUPT: make every invocation whose command line contains this
+#   interrupt shrink-ray as it exits, like a Ctrl-C right after the tool is
+#   done
+# - MOCK_BATCH_EXIT: make `gm batch` exit without answering once given a
+#   command containing this
+# - MOCK_MANGLE: make the thumbnails of files whose path contains this,
+#   decoded for `--verify perceptual`, look unlike those of the others
+
+last() {
+	for arg; do :; done
+	printf '%s' "$arg"
+}
+
+# prints the formats `-list format` lists, in the layout of GraphicsMagick or
+# ImageMagick, without `$2`
+list_formats() {
+	lacking=$(printf '%s' "$2" | tr '[:lower:]' '[:upper:]')
+	if [ "$1" = gm ]; then
+		echo "   Format L  Mode  Description"
+	else
+		echo "   Format  Module    Mode  Description"
+	fi
+	echo "--------------------------------------------------------------------------------"
+	for format in HEIC JPEG JXL PNG WEBP; do
+		[ "$format" = "$lacking" ] && continue
+		if [ "$1" = gm ]; then
+			echo "     $format P  rw-   $format image"
+		else
+			echo "     $format* $format      rw-   $format image"
+			echo "           (mocked)"
+		fi
+	done
+}
+
+# prints the grayscale 32x32 thumbnail of `$1`: the same gradient for every
+# file, mirrored for those matching MOCK_MANGLE
+mock_thumbnail() {
+	mirror=0
+	case "$1" in
+	*"$MOCK_MANGLE"*)
+		[ -n "$MOCK_MANGLE" ] && mirror=1
+		;;
+	esac
+
+	LC_ALL=C awk -v mirror="$mirror" 'BEGIN {
+		for (i = 0; i < 1024; i++) {
+			x = i % 32
+			if (mirror) x = 31 - x
+			printf "%c", x * 7 + 10
+		}
+	}'
+}
+
+mock_convert() {
+	if [ -n "$MOCK_STARTED" ]; then
+		echo $$ > "$MOCK_STARTED"
+	fi
+
+	if [ -n "$MOCK_DELAY" ]; then
+		sleep "$MOCK_DELAY"
+	fi
+
+	if [ -n "$MOCK_IGNORE_INT" ]; then
+		trap '' INT
+	fi
+
+	if [ -n "$MOCK_MODIFY_INPUT" ]; then
+		printf x >> "$1"
+	fi
+
+	if [ -n "$MOCK_REMOVE_INPUT" ]; then
+		rm -f "$1"
+	fi
+
+	if [ -n "$MOCK_LITTER" ]; then
+		echo "$$" > junk.txt
+	fi
+
+	case "${MOCK_MODE:-shrink}" in
+	shrink)
+		head -c "$(($(wc -c < "$1") / 2))" "$1" > "$2"
+		;;
+	grow)
+		cat "$1" "$1" > "$2"
+		;;
+	quality)
+		percent=$(printf '%s' "$command_line" | sed -n 's/.*-quality \([0-9]*\).*/\1/p')
+		crf=$(printf '%s' "$command_line" | sed -n 's/.*-crf \([0-9]*\).*/\1/p')
+		[ -n "$crf" ] && percent=$((100 - crf))
+		head -c "$(($(wc -c < "$1") * ${percent:-100} / 100))" "$1" > "$2"
+		;;
+	fail)
+		echo "mock: conversion failed" >&2
+		exit 1
+		;;
+	hang)
+		if [ -n "$MOCK_IGNORE_INT" ]; then
+			sleep 2
+			cp "$1" "$2"
+		else
+			exec sleep 30
+		fi
+		;;
+	esac
+}
+
+# the command line, with the paths in the sandbox `MOCK_ROOT` relative to it,
+# the way they are given on the command line of shrink-ray
+command_line="$(basename "$0") $*"
+if [ -n "$MOCK_ROOT" ]; then
+	command_line=$(printf '%s' "$command_line" | sed "s|$MOCK_ROOT/||g")
+fi
+
+if [ -n "$MOCK_ARGS_LOG" ]; then
+	echo "$command_line" >> "$MOCK_ARGS_LOG"
+fi
+
+if [ -n "$MOCK_INHERITED" ]; then
+	for stream in /dev/stdin /dev/stdout /dev/stderr; do
+		if [ -f "$stream" ]; then
+			echo "$command_line ($stream)" >> "$MOCK_INHERITED"
+		fi
+	done
+fi
+
+if [ -n "$MOCK_ENV_LOG" ]; then
+	env >> "$MOCK_ENV_LOG"
+fi
+
+if [ -n "$MOCK_BURN" ]; then
+	i=0
+	while [ "$i" -lt "$MOCK_BURN" ]; do
+		i=$((i + 1))
+	done
+fi
+
+if [ -n "$MOCK_INTERRUPT" ]; then
+	case "$command_line" in
+	*"$MOCK_INTERRUPT"*) trap 'kill -INT "$PPID"' EXIT ;;
+	esac
+fi
+
+if [ -n "$MOCK_FAIL" ]; then
+	case "$command_line" in
+	*"$MOCK_FAIL"*)
+		echo "mock: $(basename "$0") crashed" >&2
+		exit 1
+		;;
+	esac
+fi
@@ -0,0 +1,64 @@
+/*
+ * Preloaded into shrink-ray by the tests, to log the paths it looks up the
+ * metadata of, and those it renames, to the file named by `LOOKUPS_LOG`: a
+ * line each, with the process, the call and its paths, separated by tabs.
+ * Lookups of open files, by descriptor, are not logged.
+ *
+ * Build with `cc -shared -fPIC -o lookups.so lookups.c -ldl`.
+ */
+
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+static void note(const char *call, const char *path, const char *to) {
+	const char *log = getenv("LOOKUPS_LOG");
+	if (!log || !path || !*path) {
+		return;
+	}
+
+	FILE *file = fopen(log, "a");
+	if (!file) {
+		return;
+	}
+
+	if (to) {
+		fprintf(file, "%d\t%s\t%s\t%s\n", getpid(), call, path, to);
+	} else {
+		fprintf(file, "%d\t%s\t%s\n", getpid(), call, path);
+	}
+
+	fclose(file);
+}
+
+/* `name`, taking `params`, passing on `args` to the next definition of it,
+ * after logging `path` */
+#define LOGGED(type, name, params, args, path, to) \
+	type name params { \
+		static type(*next) params; \
+		if (!next) { \
+			next = (type(*) params)dlsym(RTLD_NEXT, #name); \
+		} \
+		note(#name, path, to); \
+		return next args; \
+	}
+
+LOGGED(int, statx, (int dir, const char *path, int flags, unsigned mask, void *buf), (dir, path, flags, mask, buf),
+	path, NULL)
+LOGGED(int, stat, (const char *path, void *buf), (path, buf), path, NULL)
+LOGGED(int, stat64, (const char *path, void *buf), (path, buf), path, NULL)
+LOGGED(int, lstat, (const char *path, void *buf), (path, buf), path, NULL)
+LOGGED(int, lstat64, (const char *path, void *buf), (path, buf), path, NULL)
+LOGGED(int, fstatat, (int dir, const char *path, void *buf, int flags), (dir, path, buf, flags), path, NULL)
+LOGGED(int, fstatat64, (int dir, const char *path, void *buf, int flags), (dir, path, buf, flags), path, NULL)
+LOGGED(int, access, (const char *path, int mode), (path, mode), path, NULL)
+LOGGED(int, faccessat, (int dir, const char *path, int mode, int flags), (dir, path, mode, flags), path, NULL)
+LOGGED(ssize_t, readlink, (const char *restrict path, char *restrict buf, size_t len), (path, buf, len), path, NULL)
+LOGGED(char *, realpath, (const char *restrict path, char *restrict resolved), (path, resolved), path, NULL)
+LOGGED(int, rename, (const char *from, const char *to), (from, to), from, to)
+LOGGED(int, renameat, (int from_dir, const char *from, int to_dir, const char *to), (from_dir, from, to_dir, to), from,
+	to)
+LOGGED(int, renameat2, (int from_dir, const char *from, int to_dir, const char *to, unsigned flags),
+	(from_dir, from, to_dir, to, flags), from, to)